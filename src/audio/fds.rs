@@ -0,0 +1,415 @@
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+// FDS sound: one 64-step wavetable channel with a volume envelope, pitch
+// modulated by a second unit that walks a 32-entry table of deltas. Both
+// run off the CPU clock.
+
+// Master volume ($4089 bits 0-1) as a multiplier out of 36
+const MASTER_VOLUME: [u32; 4] = [36, 24, 17, 14];
+
+// Modulation table entries, as deltas to the mod counter; 4 resets it
+const MOD_DELTAS: [i32; 8] = [0, 1, 2, 4, 0, -4, -2, -1];
+const MOD_RESET: u8 = 4;
+
+// Volume and modulation envelopes share their register layout and the
+// master envelope speed in $408A
+struct Envelope {
+    speed: u8,
+    gain: u8,
+    disabled: bool,
+    increase: bool,
+    frequency: u16,
+    timer: u32,
+    master_speed: u8,
+}
+
+impl Envelope {
+    fn new() -> Self {
+        Envelope {
+            speed: 0,
+            gain: 0,
+            disabled: false,
+            increase: false,
+            frequency: 0,
+            timer: 0,
+            master_speed: 0xE8,
+        }
+    }
+
+    // $4080/$4084 envelope, $4082/$4086 frequency low, $4083/$4087
+    // frequency high
+    fn write(&mut self, reg: u16, data: u8) {
+        match reg & 0x03 {
+            0 => {
+                self.speed = data & 0x3F;
+                self.increase = data & 0x40 != 0;
+                self.disabled = data & 0x80 != 0;
+                self.reset_timer();
+                // With the envelope off the speed bits set the gain directly
+                if self.disabled {
+                    self.gain = self.speed;
+                }
+            }
+            2 => self.frequency = (self.frequency & 0x0F00) | data as u16,
+            3 => self.frequency = (self.frequency & 0x00FF) | (((data & 0x0F) as u16) << 8),
+            _ => {}
+        }
+    }
+
+    fn reset_timer(&mut self) {
+        self.timer = 8 * (self.speed as u32 + 1) * self.master_speed as u32;
+    }
+
+    // Returns whether the gain stepped
+    fn clock(&mut self) -> bool {
+        if self.disabled || self.master_speed == 0 {
+            return false;
+        }
+
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 {
+            return false;
+        }
+
+        self.reset_timer();
+        if self.increase && self.gain < 32 {
+            self.gain += 1;
+        } else if !self.increase && self.gain > 0 {
+            self.gain -= 1;
+        }
+        true
+    }
+}
+
+struct Modulator {
+    envelope: Envelope,
+    counter: i8,
+    halted: bool,
+    table: [u8; 64],
+    position: u8,
+    accumulator: u16,
+    output: i32,
+}
+
+impl Modulator {
+    fn new() -> Self {
+        Modulator {
+            envelope: Envelope::new(),
+            counter: 0,
+            halted: true,
+            table: [0; 64],
+            position: 0,
+            accumulator: 0,
+            output: 0,
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        !self.halted && self.envelope.frequency > 0
+    }
+
+    // The counter is a 7-bit signed value
+    fn set_counter(&mut self, value: i32) {
+        self.counter = (((value & 0x7F) ^ 0x40) - 0x40) as i8;
+    }
+
+    // $4088 only takes effect while the unit is halted. Each write fills two
+    // consecutive steps, the table being clocked twice per entry.
+    fn write_table(&mut self, data: u8) {
+        if self.halted {
+            self.table[self.position as usize] = data & 0x07;
+            self.table[(self.position as usize + 1) & 0x3F] = data & 0x07;
+            self.position = (self.position + 2) & 0x3F;
+        }
+    }
+
+    fn clock(&mut self) -> bool {
+        if !self.enabled() {
+            return false;
+        }
+
+        let (accumulator, overflow) = self.accumulator.overflowing_add(self.envelope.frequency);
+        self.accumulator = accumulator;
+        if !overflow {
+            return false;
+        }
+
+        let entry = self.table[self.position as usize];
+        if entry == MOD_RESET {
+            self.set_counter(0);
+        } else {
+            self.set_counter(self.counter as i32 + MOD_DELTAS[entry as usize]);
+        }
+        self.position = (self.position + 1) & 0x3F;
+        true
+    }
+
+    // Pitch offset for the wave channel, following the hardware's odd
+    // rounding (see the "FDS audio" page on the NESdev wiki)
+    fn update_output(&mut self, pitch: u16) {
+        let mut temp = self.counter as i32 * self.envelope.gain as i32;
+        let remainder = temp & 0x0F;
+        temp >>= 4;
+        if remainder > 0 && temp & 0x80 == 0 {
+            temp += if self.counter < 0 { -1 } else { 2 };
+        }
+
+        if temp >= 192 {
+            temp -= 256;
+        } else if temp < -64 {
+            temp += 256;
+        }
+
+        temp *= pitch as i32;
+        let remainder = temp & 0x3F;
+        temp >>= 6;
+        if remainder >= 32 {
+            temp += 1;
+        }
+        self.output = temp;
+    }
+
+    fn output(&self) -> i32 {
+        if self.enabled() { self.output } else { 0 }
+    }
+}
+
+pub struct FdsAudio {
+    wave_table: [u8; 64],
+    wave_write_enabled: bool,
+    wave_halted: bool,
+    envelopes_halted: bool,
+    wave_accumulator: u16,
+    wave_position: u8,
+    master_volume: u8,
+
+    volume: Envelope,
+    modulator: Modulator,
+
+    output: u8,
+}
+
+impl FdsAudio {
+    pub fn new() -> Self {
+        FdsAudio {
+            wave_table: [0; 64],
+            wave_write_enabled: false,
+            wave_halted: true,
+            envelopes_halted: false,
+            wave_accumulator: 0,
+            wave_position: 0,
+            master_volume: 0,
+            volume: Envelope::new(),
+            modulator: Modulator::new(),
+            output: 0,
+        }
+    }
+
+    // $4040-$4097. Only the wave RAM and the two gain registers are readable.
+    pub fn read(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4040..=0x407F => Some(self.wave_table[(addr & 0x3F) as usize]),
+            0x4090 => Some(self.volume.gain),
+            0x4092 => Some(self.modulator.envelope.gain),
+            _ => None,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4040..=0x407F if self.wave_write_enabled => {
+                self.wave_table[(addr & 0x3F) as usize] = data & 0x3F;
+            }
+            0x4080 | 0x4082 => self.volume.write(addr, data),
+            0x4083 => {
+                self.envelopes_halted = data & 0x40 != 0;
+                self.wave_halted = data & 0x80 != 0;
+                if self.envelopes_halted {
+                    self.volume.reset_timer();
+                    self.modulator.envelope.reset_timer();
+                }
+                self.volume.write(addr, data);
+            }
+            0x4084..=0x4087 => {
+                match addr {
+                    0x4085 => self.modulator.set_counter(data as i32),
+                    0x4087 => {
+                        self.modulator.envelope.write(addr, data);
+                        self.modulator.halted = data & 0x80 != 0;
+                        if self.modulator.halted {
+                            self.modulator.accumulator = 0;
+                        }
+                    }
+                    _ => self.modulator.envelope.write(addr, data),
+                }
+                self.modulator.update_output(self.volume.frequency);
+            }
+            0x4088 => self.modulator.write_table(data),
+            0x4089 => {
+                self.master_volume = data & 0x03;
+                self.wave_write_enabled = data & 0x80 != 0;
+            }
+            0x408A => {
+                self.volume.master_speed = data;
+                self.modulator.envelope.master_speed = data;
+            }
+            _ => {}
+        }
+    }
+
+    // Called once per CPU cycle
+    pub fn clock(&mut self) {
+        let pitch = self.volume.frequency;
+
+        if !self.wave_halted && !self.envelopes_halted {
+            self.volume.clock();
+            if self.modulator.envelope.clock() {
+                self.modulator.update_output(pitch);
+            }
+        }
+
+        if self.modulator.clock() {
+            self.modulator.update_output(pitch);
+        }
+
+        if self.wave_halted {
+            self.wave_position = 0;
+            self.update_output();
+            return;
+        }
+
+        self.update_output();
+
+        // Writing the wave RAM holds the channel at its current step
+        let step = pitch as i32 + self.modulator.output();
+        if step > 0 && !self.wave_write_enabled {
+            let (accumulator, overflow) = self.wave_accumulator.overflowing_add(step as u16);
+            self.wave_accumulator = accumulator;
+            if overflow {
+                self.wave_position = (self.wave_position + 1) & 0x3F;
+            }
+        }
+    }
+
+    fn update_output(&mut self) {
+        let level = self.volume.gain.min(32) as u32 * MASTER_VOLUME[self.master_volume as usize];
+        self.output = (self.wave_table[self.wave_position as usize] as u32 * level / 1152) as u8;
+    }
+
+    // Normalised to [0.0, 1.0]; the channel's DAC is unipolar
+    pub fn output(&self) -> f32 {
+        self.output as f32 / 63.0
+    }
+}
+
+impl Snapshot for Envelope {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.speed);
+        w.u8(self.gain);
+        w.bool(self.disabled);
+        w.bool(self.increase);
+        w.u16(self.frequency);
+        w.u32(self.timer);
+        w.u8(self.master_speed);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.speed = r.u8()?;
+        self.gain = r.u8()?;
+        self.disabled = r.bool()?;
+        self.increase = r.bool()?;
+        self.frequency = r.u16()?;
+        self.timer = r.u32()?;
+        self.master_speed = r.u8()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for Modulator {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.envelope.save_state(w);
+        w.u8(self.counter as u8);
+        w.bool(self.halted);
+        w.bytes(&self.table);
+        w.u8(self.position);
+        w.u16(self.accumulator);
+        w.u32(self.output as u32);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.envelope.load_state(r)?;
+        self.counter = r.u8()? as i8;
+        self.halted = r.bool()?;
+        r.bytes_into(&mut self.table, "FDS modulation table")?;
+        self.position = r.u8()?;
+        self.accumulator = r.u16()?;
+        self.output = r.u32()? as i32;
+        Ok(())
+    }
+}
+
+impl Snapshot for FdsAudio {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.wave_table);
+        w.bool(self.wave_write_enabled);
+        w.bool(self.wave_halted);
+        w.bool(self.envelopes_halted);
+        w.u16(self.wave_accumulator);
+        w.u8(self.wave_position);
+        w.u8(self.master_volume);
+        self.volume.save_state(w);
+        self.modulator.save_state(w);
+        w.u8(self.output);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.bytes_into(&mut self.wave_table, "FDS wave table")?;
+        self.wave_write_enabled = r.bool()?;
+        self.wave_halted = r.bool()?;
+        self.envelopes_halted = r.bool()?;
+        self.wave_accumulator = r.u16()?;
+        self.wave_position = r.u8()?;
+        self.master_volume = r.u8()?;
+        self.volume.load_state(r)?;
+        self.modulator.load_state(r)?;
+        self.output = r.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plays_the_wave_table_at_the_set_pitch() {
+        let mut chip = FdsAudio::new();
+        // Half a square wave, full gain and a step every 32 cycles
+        chip.write(0x4089, 0x80);
+        for addr in 0x4040..0x4060 {
+            chip.write(addr, 0x3F);
+        }
+        chip.write(0x4089, 0x00);
+        chip.write(0x4080, 0x80 | 0x20);
+        chip.write(0x4082, 0x00);
+        chip.write(0x4083, 0x08);
+
+        chip.clock();
+        assert_eq!(chip.output(), 1.0);
+        (1..32 * 32).for_each(|_| chip.clock());
+        assert_eq!(chip.output(), 1.0);
+        chip.clock();
+        assert_eq!(chip.output(), 0.0);
+
+        // Master volume 2/3 once the wave comes back round
+        chip.write(0x4089, 0x01);
+        (0..32 * 32).for_each(|_| chip.clock());
+        assert_eq!(chip.output(), 42.0 / 63.0);
+
+        // Halting the wave holds it at the first step
+        chip.write(0x4083, 0x88);
+        chip.write(0x4089, 0x00);
+        chip.clock();
+        assert_eq!(chip.output(), 1.0);
+    }
+}
@@ -0,0 +1,164 @@
+mod fds;
+mod namco163;
+mod sunsoft5b;
+mod wav;
+
+pub use fds::FdsAudio;
+pub use namco163::Namco163Audio;
+pub use sunsoft5b::Sunsoft5BAudio;
+pub use wav::WavWriter;
+
+// Output of one 2A03 pulse channel at volume 15 through the APU's
+// non-linear mixer; expansion levels are expressed as multiples of it.
+const PULSE_PEAK: f32 = 95.88 / (8128.0 / 15.0 + 100.0);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExpansionChip {
+    Sunsoft5B,
+    Namco163,
+    Fds,
+}
+
+impl ExpansionChip {
+    pub const ALL: [ExpansionChip; 3] = [
+        ExpansionChip::Sunsoft5B,
+        ExpansionChip::Namco163,
+        ExpansionChip::Fds,
+    ];
+
+    // The names the command line knows the chips by
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "5b" | "sunsoft5b" => Some(ExpansionChip::Sunsoft5B),
+            "n163" | "namco163" => Some(ExpansionChip::Namco163),
+            "fds" => Some(ExpansionChip::Fds),
+            _ => None,
+        }
+    }
+
+    // Full-scale chip output as a multiple of PULSE_PEAK
+    pub fn relative_level(self) -> f32 {
+        match self {
+            // A single 5B channel at volume 12 is roughly as loud as a 2A03
+            // pulse at volume 15. Volume 15 is three 3dB steps above that and
+            // the chip output is the sum of all three channels.
+            ExpansionChip::Sunsoft5B => 3.0 * 10f32.powf(9.0 / 20.0),
+            // Varies between boards with the mixing resistor; this is the
+            // middle of the measured range.
+            ExpansionChip::Namco163 => 2.0,
+            // The RAM adapter's output at full gain and master volume
+            ExpansionChip::Fds => 2.4,
+        }
+    }
+}
+
+// What a cartridge's sound chips are outputting this cycle, each normalised
+// to [-1.0, 1.0]. Most boards have at most one chip; NSF rips can use several.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ExpansionOutput {
+    levels: [Option<f32>; ExpansionChip::ALL.len()],
+}
+
+impl ExpansionOutput {
+    pub fn single(chip: ExpansionChip, level: f32) -> Self {
+        let mut output = ExpansionOutput::default();
+        output.set(chip, level);
+        output
+    }
+
+    pub fn set(&mut self, chip: ExpansionChip, level: f32) {
+        self.levels[chip as usize] = Some(level);
+    }
+
+    pub fn get(&self, chip: ExpansionChip) -> Option<f32> {
+        self.levels[chip as usize]
+    }
+}
+
+pub struct Mixer {
+    apu_muted: bool,
+    expansion_muted: [bool; ExpansionChip::ALL.len()],
+}
+
+impl Mixer {
+    pub fn new() -> Self {
+        Mixer {
+            apu_muted: false,
+            expansion_muted: [false; ExpansionChip::ALL.len()],
+        }
+    }
+
+    pub fn set_apu_muted(&mut self, muted: bool) {
+        self.apu_muted = muted;
+    }
+
+    pub fn set_muted(&mut self, chip: ExpansionChip, muted: bool) {
+        self.expansion_muted[chip as usize] = muted;
+    }
+
+    pub fn is_muted(&self, chip: ExpansionChip) -> bool {
+        self.expansion_muted[chip as usize]
+    }
+
+    // apu is the 2A03's mixed output in [0.0, 1.0]; expansion is whatever
+    // the cartridge reports through Mapper::expansion_audio
+    pub fn mix(&self, apu: f32, expansion: ExpansionOutput) -> f32 {
+        let mut sample = if self.apu_muted { 0.0 } else { apu };
+
+        for chip in ExpansionChip::ALL {
+            if let Some(output) = expansion.get(chip)
+                && !self.is_muted(chip)
+            {
+                sample += output * chip.relative_level() * PULSE_PEAK;
+            }
+        }
+
+        sample
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn muted_chips_drop_out_of_the_mix() {
+        let mut expansion = ExpansionOutput::single(ExpansionChip::Sunsoft5B, 0.5);
+        expansion.set(ExpansionChip::Namco163, -0.25);
+        let sunsoft = ExpansionOutput::single(ExpansionChip::Sunsoft5B, 0.5);
+
+        let mut mixer = Mixer::new();
+        let both = mixer.mix(0.1, expansion);
+        assert_ne!(both, mixer.mix(0.1, sunsoft));
+
+        mixer.set_muted(ExpansionChip::Namco163, true);
+        assert!(mixer.is_muted(ExpansionChip::Namco163));
+        assert_eq!(mixer.mix(0.1, expansion), mixer.mix(0.1, sunsoft));
+
+        mixer.set_muted(ExpansionChip::Sunsoft5B, true);
+        assert_eq!(mixer.mix(0.1, expansion), 0.1);
+
+        mixer.set_apu_muted(true);
+        assert_eq!(mixer.mix(0.1, expansion), 0.0);
+
+        mixer.set_muted(ExpansionChip::Namco163, false);
+        assert_eq!(
+            mixer.mix(0.1, expansion),
+            -0.25 * ExpansionChip::Namco163.relative_level() * PULSE_PEAK
+        );
+    }
+
+    #[test]
+    fn chip_names() {
+        assert_eq!(
+            ExpansionChip::from_name("5B"),
+            Some(ExpansionChip::Sunsoft5B)
+        );
+        assert_eq!(
+            ExpansionChip::from_name("n163"),
+            Some(ExpansionChip::Namco163)
+        );
+        assert_eq!(ExpansionChip::from_name("fds"), Some(ExpansionChip::Fds));
+        assert_eq!(ExpansionChip::from_name("vrc6"), None);
+    }
+}
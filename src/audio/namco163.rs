@@ -0,0 +1,179 @@
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+// Namco 163 wavetable sound. Channel registers live in the top of the
+// chip's 128 bytes of internal RAM and the rest holds 4-bit samples. Only
+// one channel is updated every 15 CPU cycles, so the hardware output is
+// time-multiplexed between the enabled channels.
+pub struct Namco163Audio {
+    ram: [u8; 128],
+    address: u8,
+    auto_increment: bool,
+
+    cycle: u8,
+    channel: usize,
+    outputs: [f32; 8],
+}
+
+impl Namco163Audio {
+    pub fn new() -> Self {
+        Namco163Audio {
+            ram: [0x00; 128],
+            address: 0,
+            auto_increment: false,
+            cycle: 0,
+            channel: 7,
+            outputs: [0.0; 8],
+        }
+    }
+
+    // $F800-$FFFF
+    pub fn write_address(&mut self, data: u8) {
+        self.address = data & 0x7F;
+        self.auto_increment = data & 0x80 != 0;
+    }
+
+    // $4800-$4FFF
+    pub fn read_data(&mut self) -> u8 {
+        let data = self.peek_data();
+        self.step_address();
+        data
+    }
+
+    // The byte read_data would return, without stepping the address
+    pub fn peek_data(&self) -> u8 {
+        self.ram[self.address as usize]
+    }
+
+    pub fn write_data(&mut self, data: u8) {
+        self.ram[self.address as usize] = data;
+        self.step_address();
+    }
+
+    fn step_address(&mut self) {
+        if self.auto_increment {
+            self.address = (self.address + 1) & 0x7F;
+        }
+    }
+
+    pub fn ram(&self) -> &[u8; 128] {
+        &self.ram
+    }
+
+    pub fn ram_mut(&mut self) -> &mut [u8; 128] {
+        &mut self.ram
+    }
+
+    fn enabled_channels(&self) -> usize {
+        (((self.ram[0x7F] >> 4) & 0x07) + 1) as usize
+    }
+
+    // Called once per CPU cycle
+    pub fn clock(&mut self) {
+        self.cycle += 1;
+        if self.cycle < 15 {
+            return;
+        }
+        self.cycle = 0;
+
+        self.update_channel(self.channel);
+
+        // Channels are serviced from 7 downwards
+        let lowest = 8 - self.enabled_channels();
+        if self.channel <= lowest {
+            self.channel = 7;
+        } else {
+            self.channel -= 1;
+        }
+    }
+
+    fn update_channel(&mut self, ch: usize) {
+        let base = 0x40 + ch * 8;
+        let regs = &self.ram[base..base + 8];
+
+        let freq = regs[0] as u32 | (regs[2] as u32) << 8 | ((regs[4] & 0x03) as u32) << 16;
+        let mut phase = regs[1] as u32 | (regs[3] as u32) << 8 | (regs[5] as u32) << 16;
+        let length = 256 - (regs[4] & 0xFC) as u32;
+        let offset = regs[6] as u32;
+        let volume = (regs[7] & 0x0F) as f32;
+
+        phase = (phase + freq) % (length << 16);
+
+        let sample_addr = ((phase >> 16) + offset) & 0xFF;
+        let byte = self.ram[(sample_addr >> 1) as usize];
+        let sample = if sample_addr & 1 == 0 {
+            byte & 0x0F
+        } else {
+            byte >> 4
+        };
+
+        self.ram[base + 1] = phase as u8;
+        self.ram[base + 3] = (phase >> 8) as u8;
+        self.ram[base + 5] = (phase >> 16) as u8;
+
+        self.outputs[ch] = (sample as f32 - 8.0) * volume;
+    }
+
+    // Average of the enabled channels, which is what the multiplexing works
+    // out to once filtered, normalised to [-1.0, 1.0]
+    pub fn output(&self) -> f32 {
+        let enabled = self.enabled_channels();
+        let sum: f32 = self.outputs[8 - enabled..].iter().sum();
+        sum / (enabled as f32 * 120.0)
+    }
+}
+
+impl Snapshot for Namco163Audio {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.ram);
+        w.u8(self.address);
+        w.bool(self.auto_increment);
+        w.u8(self.cycle);
+        w.u8(self.channel as u8);
+        self.outputs.iter().for_each(|&output| w.f32(output));
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.bytes_into(&mut self.ram, "Namco 163 RAM")?;
+        self.address = r.u8()?;
+        self.auto_increment = r.bool()?;
+        self.cycle = r.u8()?;
+        self.channel = (r.u8()? & 7) as usize;
+        for output in self.outputs.iter_mut() {
+            *output = r.f32()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plays_the_wavetable_one_step_per_update() {
+        let mut chip = Namco163Audio::new();
+        // Samples 0, 15, 8, 0 and channel 7 stepping one sample per update
+        // through a 4-sample wave at volume 15, alone
+        chip.write_address(0x80);
+        chip.write_data(0xF0);
+        chip.write_data(0x08);
+        chip.write_address(0x80 | 0x7C);
+        for data in [0xFD, 0x00, 0x00, 0x0F] {
+            chip.write_data(data);
+        }
+
+        let outputs: Vec<_> = (0..5)
+            .map(|_| {
+                (0..15).for_each(|_| chip.clock());
+                chip.output()
+            })
+            .collect();
+        assert_eq!(outputs, [0.875, 0.0, -1.0, -1.0, 0.875]);
+
+        // A second, silent channel halves the level and the update rate
+        chip.write_address(0x7F);
+        chip.write_data(0x1F);
+        (0..60).for_each(|_| chip.clock());
+        assert_eq!(chip.output(), -0.5);
+    }
+}
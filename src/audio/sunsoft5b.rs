@@ -0,0 +1,270 @@
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+// Sunsoft 5B sound: a YM2149F (AY-3-8910 compatible) core with three square
+// channels, one noise generator and one envelope generator, clocked from the
+// CPU through an extra divide-by-two.
+pub struct Sunsoft5BAudio {
+    register: u8,
+
+    tone_period: [u16; 3],
+    tone_counter: [u16; 3],
+    tone_output: [bool; 3],
+
+    noise_period: u8,
+    noise_counter: u8,
+    noise_lfsr: u32,
+
+    mixer: u8,
+    volume: [u8; 3],
+
+    envelope_period: u16,
+    envelope_counter: u16,
+    envelope_shape: u8,
+    envelope_step: u8,
+    envelope_invert: u8,
+    envelope_holding: bool,
+
+    prescaler: u8,
+    levels: [f32; 32],
+}
+
+impl Sunsoft5BAudio {
+    pub fn new() -> Self {
+        // 5-bit logarithmic DAC, 1.5dB per step, level 0 is silent
+        let mut levels = [0.0; 32];
+        for (level, amplitude) in levels.iter_mut().enumerate().skip(1) {
+            *amplitude = 10f32.powf(-1.5 * (31 - level) as f32 / 20.0);
+        }
+
+        Sunsoft5BAudio {
+            register: 0,
+            tone_period: [0; 3],
+            tone_counter: [0; 3],
+            tone_output: [false; 3],
+            noise_period: 0,
+            noise_counter: 0,
+            noise_lfsr: 1,
+            mixer: 0xFF,
+            volume: [0; 3],
+            envelope_period: 0,
+            envelope_counter: 0,
+            envelope_shape: 0,
+            envelope_step: 0,
+            envelope_invert: 0,
+            envelope_holding: true,
+            prescaler: 0,
+            levels,
+        }
+    }
+
+    // $C000-$DFFF. Writes with any of the upper bits set deselect the chip.
+    pub fn select(&mut self, data: u8) {
+        self.register = data;
+    }
+
+    // $E000-$FFFF
+    pub fn write(&mut self, data: u8) {
+        if self.register & 0xF0 != 0 {
+            return;
+        }
+
+        match self.register {
+            0x00 | 0x02 | 0x04 => {
+                let ch = (self.register >> 1) as usize;
+                self.tone_period[ch] = (self.tone_period[ch] & 0x0F00) | data as u16;
+            }
+            0x01 | 0x03 | 0x05 => {
+                let ch = (self.register >> 1) as usize;
+                self.tone_period[ch] =
+                    (self.tone_period[ch] & 0x00FF) | ((data as u16 & 0x0F) << 8);
+            }
+            0x06 => self.noise_period = data & 0x1F,
+            0x07 => self.mixer = data,
+            0x08..=0x0A => self.volume[(self.register - 0x08) as usize] = data & 0x1F,
+            0x0B => self.envelope_period = (self.envelope_period & 0xFF00) | data as u16,
+            0x0C => self.envelope_period = (self.envelope_period & 0x00FF) | ((data as u16) << 8),
+            0x0D => {
+                self.envelope_shape = data & 0x0F;
+                self.envelope_step = 0;
+                self.envelope_counter = 0;
+                self.envelope_holding = false;
+                self.envelope_invert = if self.envelope_shape & 0x04 != 0 {
+                    0x00
+                } else {
+                    0x1F
+                };
+            }
+            // I/O ports, not connected on the 5B
+            _ => {}
+        }
+    }
+
+    // Called once per CPU cycle
+    pub fn clock(&mut self) {
+        self.prescaler += 1;
+        if self.prescaler < 16 {
+            return;
+        }
+        self.prescaler = 0;
+
+        for ch in 0..3 {
+            self.tone_counter[ch] += 1;
+            if self.tone_counter[ch] >= self.tone_period[ch].max(1) {
+                self.tone_counter[ch] = 0;
+                self.tone_output[ch] = !self.tone_output[ch];
+            }
+        }
+
+        self.noise_counter += 1;
+        if self.noise_counter >= self.noise_period.max(1) {
+            self.noise_counter = 0;
+            let feedback = (self.noise_lfsr ^ (self.noise_lfsr >> 3)) & 1;
+            self.noise_lfsr = (self.noise_lfsr >> 1) | (feedback << 16);
+        }
+
+        self.envelope_counter += 1;
+        if self.envelope_counter >= self.envelope_period.max(1) {
+            self.envelope_counter = 0;
+            self.clock_envelope();
+        }
+    }
+
+    fn clock_envelope(&mut self) {
+        if self.envelope_holding {
+            return;
+        }
+
+        self.envelope_step += 1;
+        if self.envelope_step < 32 {
+            return;
+        }
+
+        let cont = self.envelope_shape & 0x08 != 0;
+        let alternate = self.envelope_shape & 0x02 != 0;
+        let hold = self.envelope_shape & 0x01 != 0;
+
+        if !cont {
+            self.envelope_holding = true;
+            self.envelope_step = 0;
+            self.envelope_invert = 0x00;
+        } else if hold {
+            self.envelope_holding = true;
+            self.envelope_step = 31;
+            if alternate {
+                self.envelope_invert ^= 0x1F;
+            }
+        } else {
+            self.envelope_step = 0;
+            if alternate {
+                self.envelope_invert ^= 0x1F;
+            }
+        }
+    }
+
+    fn channel_level(&self, ch: usize) -> u8 {
+        let volume = self.volume[ch];
+        if volume & 0x10 != 0 {
+            self.envelope_step ^ self.envelope_invert
+        } else if volume & 0x0F == 0 {
+            0
+        } else {
+            (volume & 0x0F) * 2 + 1
+        }
+    }
+
+    // Sum of the three channels, normalised to [0.0, 1.0]
+    pub fn output(&self) -> f32 {
+        let noise = self.noise_lfsr & 1 != 0;
+
+        let mut sum = 0.0;
+        for ch in 0..3 {
+            let tone_on = self.tone_output[ch] || self.mixer & (1 << ch) != 0;
+            let noise_on = noise || self.mixer & (8 << ch) != 0;
+            if tone_on && noise_on {
+                sum += self.levels[self.channel_level(ch) as usize];
+            }
+        }
+
+        sum / 3.0
+    }
+}
+
+// The level table is derived at construction, so isn't saved
+impl Snapshot for Sunsoft5BAudio {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.register);
+        for ch in 0..3 {
+            w.u16(self.tone_period[ch]);
+            w.u16(self.tone_counter[ch]);
+            w.bool(self.tone_output[ch]);
+            w.u8(self.volume[ch]);
+        }
+        w.u8(self.noise_period);
+        w.u8(self.noise_counter);
+        w.u32(self.noise_lfsr);
+        w.u8(self.mixer);
+        w.u16(self.envelope_period);
+        w.u16(self.envelope_counter);
+        w.u8(self.envelope_shape);
+        w.u8(self.envelope_step);
+        w.u8(self.envelope_invert);
+        w.bool(self.envelope_holding);
+        w.u8(self.prescaler);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.register = r.u8()?;
+        for ch in 0..3 {
+            self.tone_period[ch] = r.u16()?;
+            self.tone_counter[ch] = r.u16()?;
+            self.tone_output[ch] = r.bool()?;
+            self.volume[ch] = r.u8()?;
+        }
+        self.noise_period = r.u8()?;
+        self.noise_counter = r.u8()?;
+        self.noise_lfsr = r.u32()?;
+        self.mixer = r.u8()?;
+        self.envelope_period = r.u16()?;
+        self.envelope_counter = r.u16()?;
+        self.envelope_shape = r.u8()?;
+        self.envelope_step = r.u8()?;
+        self.envelope_invert = r.u8()?;
+        self.envelope_holding = r.bool()?;
+        self.prescaler = r.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(chip: &mut Sunsoft5BAudio, register: u8, data: u8) {
+        chip.select(register);
+        chip.write(data);
+    }
+
+    #[test]
+    fn square_toggles_every_sixteen_cycles() {
+        let mut chip = Sunsoft5BAudio::new();
+        write(&mut chip, 0x00, 0x01);
+        write(&mut chip, 0x07, 0x3E);
+        write(&mut chip, 0x08, 0x0F);
+
+        let mut outputs = Vec::new();
+        for _ in 0..3 {
+            outputs.push(chip.output());
+            (0..16).for_each(|_| chip.clock());
+        }
+        assert_eq!(outputs, [0.0, 1.0 / 3.0, 0.0]);
+
+        // With the square high again, volume 14 is one 3dB step down
+        write(&mut chip, 0x08, 0x0E);
+        let level = chip.output() * 3.0;
+        assert!((level - 10f32.powf(-3.0 / 20.0)).abs() < 1e-6, "{level}");
+
+        // Deselected writes go nowhere
+        write(&mut chip, 0x18, 0x00);
+        assert_eq!(chip.volume[0], 0x0E);
+    }
+}
@@ -0,0 +1,166 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::cartridge::{Cartridge, CartridgeError};
+use crate::console::{CYCLES_PER_FRAME, Console};
+use crate::cpu::{Cpu, Variant};
+use crate::memory::Memory;
+
+// Throughput benchmarks for headless runs: built-in 6502 loops on the bare
+// CPU and on the whole console, and ROMs from power on. Speed is counted in
+// NTSC frames of CPU time, so 60.0988 a second is real time.
+pub const REAL_TIME_FPS: f64 = 60.0988;
+
+// Where workloads are loaded and started from
+const ORIGIN: u16 = 0x8000;
+
+// Each benchmark is run this many times and the fastest run kept, as
+// anything else on the machine only ever slows a run down
+const RUNS: usize = 3;
+
+// A program that loops forever, loaded at $8000
+pub struct Workload {
+    pub name: &'static str,
+    pub code: &'static [u8],
+}
+
+#[rustfmt::skip]
+pub const WORKLOADS: [Workload; 4] = [
+    // Register and immediate arithmetic and logic
+    Workload { name: "alu", code: &[
+        0xA9, 0x00, 0xA2, 0x00, 0xA0, 0x10,     //       LDA #0; LDX #0; LDY #$10
+        0x18, 0x69, 0x07, 0x49, 0x5A, 0x29,     // loop: CLC; ADC #7; EOR #$5A; AND #$F7
+        0xF7, 0x09, 0x01, 0x38, 0xE9, 0x03,     //       ORA #1; SEC; SBC #3
+        0xAA, 0xE8, 0x8A, 0xC9, 0x80, 0x88,     //       TAX; INX; TXA; CMP #$80; DEY
+        0xD0, 0xEC, 0x4C, 0x00, 0x80,           //       BNE loop; JMP $8000
+    ] },
+    // Copying a page through every indexed mode, half the reads crossing
+    Workload { name: "memory", code: &[
+        0xA9, 0x00, 0x85, 0x10, 0xA9, 0x03,     //       LDA #0; STA $10; LDA #3
+        0x85, 0x11, 0xA0, 0x00,                 //       STA $11; LDY #0
+        0xB9, 0x00, 0x02, 0x91, 0x10,           // loop: LDA $0200,Y; STA ($10),Y
+        0xBE, 0x80, 0x02, 0x96, 0x20,           //       LDX $0280,Y; STX $20,Y
+        0xB1, 0x10, 0x9D, 0xFF, 0x03,           //       LDA ($10),Y; STA $03FF,X
+        0xC8, 0xD0, 0xEE, 0x4C, 0x00, 0x80,     //       INY; BNE loop; JMP $8000
+    ] },
+    // Read-modify-write instructions
+    Workload { name: "rmw", code: &[
+        0xA2, 0x00,                             //       LDX #0
+        0xE6, 0x10, 0x06, 0x11, 0x66, 0x12,     // loop: INC $10; ASL $11; ROR $12
+        0xFE, 0x00, 0x02, 0x5E, 0x00, 0x03,     //       INC $0200,X; LSR $0300,X
+        0x36, 0x20, 0xC6, 0x13, 0xCA,           //       ROL $20,X; DEC $13; DEX
+        0xD0, 0xED, 0x4C, 0x00, 0x80,           //       BNE loop; JMP $8000
+    ] },
+    // Subroutines, the stack and short branches
+    Workload { name: "calls", code: &[
+        0xA2, 0x08,                             //       LDX #8
+        0x20, 0x10, 0x80, 0x48, 0x68,           // loop: JSR sub; PHA; PLA
+        0x08, 0x28, 0xCA, 0xD0, 0xF6,           //       PHP; PLP; DEX; BNE loop
+        0x4C, 0x00, 0x80, 0xEA,                 //       JMP $8000; NOP
+        0xA0, 0x04, 0x88, 0x10, 0xFD,           // sub:  LDY #4; -: DEY; BPL -
+        0x6C, 0x18, 0x80, 0x1B, 0x80, 0xEA,     //       JMP ($8018); .word $801B; NOP
+        0x60,                                   //       RTS
+    ] },
+];
+
+#[derive(Clone, Copy, Debug)]
+pub struct Report {
+    pub frames: u64,
+    pub elapsed: Duration,
+}
+
+impl Report {
+    pub fn fps(&self) -> f64 {
+        self.frames as f64 / self.elapsed.as_secs_f64()
+    }
+
+    // How many times faster than the real console
+    pub fn speed(&self) -> f64 {
+        self.fps() / REAL_TIME_FPS
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} frames in {:.2}s: {:.0} fps, {:.1}x real time",
+            self.frames,
+            self.elapsed.as_secs_f64(),
+            self.fps(),
+            self.speed()
+        )
+    }
+}
+
+// Only the core, on 64K of plain RAM
+pub fn run_cpu(workload: &Workload, variant: Variant, frames: u64) -> Report {
+    fastest(|| {
+        let mut ram = Box::new([0x00; 0x10000]);
+        load(&mut *ram, workload);
+
+        let mut cpu = Cpu::with_variant(variant);
+        cpu.reset(&mut *ram);
+        let start = Instant::now();
+        for _ in 0..frames * CYCLES_PER_FRAME {
+            cpu.clock(&mut *ram);
+        }
+        Report {
+            frames,
+            elapsed: start.elapsed(),
+        }
+    })
+}
+
+// The whole console with no cartridge, so the program runs from the RAM
+// behind the cartridge space
+pub fn run_console(workload: &Workload, frames: u64) -> Report {
+    fastest(|| {
+        let mut console = Console::new();
+        load(&mut console.bus, workload);
+        console.reset();
+        time_frames(&mut console, frames)
+    })
+}
+
+// From power on with nothing held. A ROM that won't load fails before any
+// run, and the cartridge isn't given its battery save, so nothing is
+// written back.
+pub fn run_rom<P: AsRef<Path>>(path: P, frames: u64) -> Result<Report, CartridgeError> {
+    let rom = fs::read(path)?;
+    Cartridge::from_bytes(&rom)?;
+    Ok(fastest(|| {
+        let cart = Cartridge::from_bytes(&rom).expect("loaded above");
+        let mut console = Console::with_cartridge(cart);
+        console.power_cycle();
+        time_frames(&mut console, frames)
+    }))
+}
+
+fn load(mem: &mut dyn Memory, workload: &Workload) {
+    for (addr, &byte) in (ORIGIN..).zip(workload.code) {
+        mem.write(addr, byte);
+    }
+    mem.write(0xFFFC, ORIGIN as u8);
+    mem.write(0xFFFD, (ORIGIN >> 8) as u8);
+}
+
+fn fastest(mut run: impl FnMut() -> Report) -> Report {
+    (0..RUNS)
+        .map(|_| run())
+        .min_by_key(|report| report.elapsed)
+        .expect("at least one run")
+}
+
+fn time_frames(console: &mut Console, frames: u64) -> Report {
+    let start = Instant::now();
+    for _ in 0..frames {
+        console.run_frame();
+    }
+    Report {
+        frames,
+        elapsed: start.elapsed(),
+    }
+}
@@ -1,27 +1,358 @@
-use std::{cell::RefCell, rc::Rc};
+use std::io;
 
-use crate::cpu::Cpu;
+use bitflags::bitflags;
+
+use crate::audio::Mixer;
+use crate::cartridge::Cartridge;
+use crate::controller::{Buttons, Controller};
+use crate::framebuffer::{Framebuffer, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::memory::Memory;
+use crate::state::{Snapshot, State, StateError, StateReader, StateWriter};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressSpace {
+    Cpu,
+    Ppu,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+    // An opcode fetch
+    Execute,
+}
+
+bitflags! {
+    // Everything that can pull the CPU's IRQ line low. They're wired
+    // together, so it stays asserted while any of them is.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct IrqSources: u8 {
+        const FRAME_COUNTER = (1 << 0);
+        const DMC           = (1 << 1);
+        const MAPPER        = (1 << 2);
+    }
+}
+
+// A memory access, as seen by a debugger
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Access {
+    pub space: AddressSpace,
+    pub kind: AccessKind,
+    pub addr: u16,
+    pub data: u8,
+    // The PRG or CHR ROM byte the access landed on, as mapped at the time
+    pub rom_offset: Option<usize>,
+}
 
 pub struct Bus {
-    cpu: Cpu,
     ram: Vec<u8>,
+    cart: Option<Cartridge>,
+    pub mixer: Mixer,
+    controllers: [Controller; 2],
+    // Drawn by the PPU, once there is one. Until then it stays black.
+    screen: Framebuffer,
+
+    system_clock_counter: u64,
+    irq_sources: IrqSources,
+
+    // Reads and writes since the last take_accesses, while logging is on
+    accesses: Option<Vec<Access>>,
 }
 
 impl Bus {
-    pub fn new() -> Rc<RefCell<Self>> {
-        let bus = Rc::new(RefCell::new(Bus {
-            cpu: Cpu::new(),
+    pub fn new() -> Self {
+        Bus {
             ram: vec![0x00; 64 * 1024],
-        }));
-        bus.borrow_mut().cpu.connect_bus(Rc::clone(&bus));
-        bus
+            cart: None,
+            mixer: Mixer::new(),
+            controllers: [Controller::new(), Controller::new()],
+            screen: Framebuffer::new(SCREEN_WIDTH, SCREEN_HEIGHT),
+            system_clock_counter: 0,
+            irq_sources: IrqSources::empty(),
+            accesses: None,
+        }
+    }
+
+    pub fn insert_cartridge(&mut self, cart: Cartridge) {
+        self.cart = Some(cart);
+    }
+
+    pub fn cartridge(&self) -> Option<&Cartridge> {
+        self.cart.as_ref()
+    }
+
+    pub fn cartridge_mut(&mut self) -> Option<&mut Cartridge> {
+        self.cart.as_mut()
+    }
+
+    // Writes battery RAM out if it changed since the last flush. When is up
+    // to the frontend, so emulation itself never touches the disk.
+    pub fn flush_save(&mut self) -> io::Result<()> {
+        match self.cart.as_mut() {
+            Some(cart) => cart.flush_save(),
+            None => Ok(()),
+        }
     }
 
     pub fn write(&mut self, addr: u16, data: u8) {
+        let rom_offset = self.rom_offset(AddressSpace::Cpu, addr);
+        self.log(AddressSpace::Cpu, AccessKind::Write, addr, data, rom_offset);
+        if addr == 0x4016 {
+            for controller in &mut self.controllers {
+                controller.write(data);
+            }
+            return;
+        }
+        if addr >= 0x4020
+            && let Some(cart) = self.cart.as_mut()
+            && cart.cpu_write(addr, data)
+        {
+            return;
+        }
         self.ram[addr as usize] = data;
     }
 
-    pub fn read(&self, addr: u16, b_read_only: bool) -> u8 {
+    pub fn read(&mut self, addr: u16) -> u8 {
+        let rom_offset = self.rom_offset(AddressSpace::Cpu, addr);
+        let data = if addr == 0x4016 || addr == 0x4017 {
+            self.controllers[(addr & 0x0001) as usize].read()
+        } else if addr >= 0x4020
+            && let Some(cart) = self.cart.as_mut()
+            && let Some(data) = cart.cpu_read(addr)
+        {
+            data
+        } else {
+            self.ram[addr as usize]
+        };
+        self.log(AddressSpace::Cpu, AccessKind::Read, addr, data, rom_offset);
+        data
+    }
+
+    // What a CPU read would return, without side effects or being logged
+    pub fn peek(&self, addr: u16) -> u8 {
+        if addr == 0x4016 || addr == 0x4017 {
+            return self.controllers[(addr & 0x0001) as usize].peek();
+        }
+        if addr >= 0x4020
+            && let Some(data) = self.cart.as_ref().and_then(|cart| cart.cpu_peek(addr))
+        {
+            return data;
+        }
         self.ram[addr as usize]
     }
+
+    // Changes memory for a debugger, past any registers that share the
+    // address and without being logged. Only RAM can be poked, internal or
+    // on the cartridge; anything else is refused.
+    pub fn poke(&mut self, addr: u16, data: u8) -> bool {
+        if addr < 0x2000 {
+            self.ram[addr as usize] = data;
+            return true;
+        }
+        let Some(cart) = self.cart.as_mut() else {
+            return false;
+        };
+        match cart.prg_ram_offset(addr) {
+            Some(offset) => {
+                cart.mapper_mut().memory_mut().prg_ram[offset] = data;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // PPU accesses to the cartridge. Nametables and palettes live in the
+    // PPU, so only pattern table (and mapper-provided nametable) accesses
+    // come through here. Nothing calls these until there's a PPU.
+    #[allow(dead_code)]
+    pub fn ppu_read(&mut self, addr: u16) -> Option<u8> {
+        // Before the read, as it can flip an MMC2 latch
+        let rom_offset = self.rom_offset(AddressSpace::Ppu, addr);
+        let data = self.cart.as_mut()?.ppu_read(addr);
+        if let Some(data) = data {
+            self.log(AddressSpace::Ppu, AccessKind::Read, addr, data, rom_offset);
+        }
+        data
+    }
+
+    #[allow(dead_code)]
+    pub fn ppu_write(&mut self, addr: u16, data: u8) -> bool {
+        let rom_offset = self.rom_offset(AddressSpace::Ppu, addr);
+        self.log(AddressSpace::Ppu, AccessKind::Write, addr, data, rom_offset);
+        self.cart
+            .as_mut()
+            .is_some_and(|cart| cart.ppu_write(addr, data))
+    }
+
+    pub fn set_access_logging(&mut self, enabled: bool) {
+        self.accesses = enabled.then(Vec::new);
+    }
+
+    pub fn take_accesses(&mut self) -> Vec<Access> {
+        self.accesses
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    // Where in ROM an access would land, worked out only while logging
+    pub fn rom_offset(&self, space: AddressSpace, addr: u16) -> Option<usize> {
+        self.accesses.as_ref()?;
+        let cart = self.cart.as_ref()?;
+        match space {
+            AddressSpace::Cpu if addr >= 0x4020 => cart.prg_rom_offset(addr),
+            AddressSpace::Cpu => None,
+            AddressSpace::Ppu => cart.chr_rom_offset(addr),
+        }
+    }
+
+    fn log(
+        &mut self,
+        space: AddressSpace,
+        kind: AccessKind,
+        addr: u16,
+        data: u8,
+        rom_offset: Option<usize>,
+    ) {
+        if let Some(accesses) = self.accesses.as_mut() {
+            accesses.push(Access {
+                space,
+                kind,
+                addr,
+                data,
+                rom_offset,
+            });
+        }
+    }
+
+    // Advances everything on the bus by one CPU cycle, returning whether
+    // the IRQ line is asserted
+    pub fn clock(&mut self) -> bool {
+        self.system_clock_counter += 1;
+        let mut mapper_irq = false;
+        if let Some(cart) = self.cart.as_mut() {
+            cart.clock();
+            mapper_irq = cart.irq_state();
+        }
+        self.set_irq(IrqSources::MAPPER, mapper_irq);
+        self.irq_line()
+    }
+
+    pub fn set_irq(&mut self, source: IrqSources, asserted: bool) {
+        self.irq_sources.set(source, asserted);
+    }
+
+    pub fn irq_line(&self) -> bool {
+        !self.irq_sources.is_empty()
+    }
+
+    // What the pad in port 0 or 1 has held down
+    pub fn set_buttons(&mut self, port: usize, buttons: Buttons) {
+        self.controllers[port].buttons = buttons;
+    }
+
+    pub fn buttons(&self) -> [Buttons; 2] {
+        self.controllers
+            .each_ref()
+            .map(|controller| controller.buttons)
+    }
+
+    pub fn power_cycle(&mut self) {
+        if let Some(cart) = self.cart.as_mut() {
+            cart.power_cycle();
+        }
+        self.ram.fill(0x00);
+        self.controllers = [Controller::new(), Controller::new()];
+        self.system_clock_counter = 0;
+        self.irq_sources = IrqSources::empty();
+    }
+
+    pub fn write_state(&self, w: &mut StateWriter) {
+        w.chunk(*b"BUS ", |w| {
+            w.bytes(&self.ram);
+            w.u64(self.system_clock_counter);
+        });
+        w.chunk(*b"JOY ", |w| {
+            for controller in &self.controllers {
+                controller.save_state(w);
+            }
+        });
+        if let Some(cart) = &self.cart {
+            w.chunk(*b"CART", |w| cart.save_state(w));
+        }
+    }
+
+    // Nothing changes unless every chunk loads. RAM and the pads are read
+    // into copies first; the cartridge loads in place, so it goes last and
+    // is put back as it was if its chunk turns out to be bad.
+    pub fn read_state(&mut self, state: &State) -> Result<(), StateError> {
+        if self.cart.is_none() && state.has_chunk(*b"CART") {
+            return Err(StateError::WrongCartridge);
+        }
+
+        let mut r = state.chunk(*b"BUS ")?;
+        let mut ram = vec![0; self.ram.len()];
+        r.bytes_into(&mut ram, "RAM size")?;
+        let system_clock_counter = r.u64()?;
+
+        // JOY came in without a version bump, which is safe both ways:
+        // builds from before it skip the chunk like any unknown one, and
+        // states from before it leave the pads as they are
+        let mut controllers = self.controllers.clone();
+        if state.has_chunk(*b"JOY ") {
+            let mut r = state.chunk(*b"JOY ")?;
+            for controller in &mut controllers {
+                controller.load_state(&mut r)?;
+            }
+        }
+
+        if let Some(cart) = &mut self.cart {
+            let mut backup = StateWriter::bare();
+            cart.save_state(&mut backup);
+            if let Err(e) = cart.load_state(&mut state.chunk(*b"CART")?) {
+                cart.load_state(&mut StateReader::new(&backup.finish()))
+                    .expect("state just written");
+                return Err(e);
+            }
+        }
+
+        self.ram = ram;
+        self.system_clock_counter = system_clock_counter;
+        self.controllers = controllers;
+        Ok(())
+    }
+
+    // The last complete frame
+    pub fn screen(&self) -> &Framebuffer {
+        &self.screen
+    }
+
+    pub fn system_clock_counter(&self) -> u64 {
+        self.system_clock_counter
+    }
+
+    // There's no 2A03 APU yet, so the only thing to hear is the cartridge
+    pub fn audio_sample(&self) -> f32 {
+        let expansion = self
+            .cart
+            .as_ref()
+            .map(|cart| cart.mapper().expansion_audio())
+            .unwrap_or_default();
+        self.mixer.mix(0.0, expansion)
+    }
+}
+
+impl Memory for Bus {
+    fn read(&mut self, addr: u16) -> u8 {
+        Bus::read(self, addr)
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        Bus::write(self, addr, data)
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        Bus::peek(self, addr)
+    }
 }
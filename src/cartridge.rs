@@ -0,0 +1,678 @@
+use std::path::{Path, PathBuf};
+use std::{fmt, fs, io};
+
+use bitflags::bitflags;
+
+use crate::database;
+use crate::hash::Crc32;
+use crate::mapper::{
+    self, BarcodeReader, CartridgeMemory, DiskError, DiskImage, Fds, Mapper, NsfMapper,
+};
+use crate::nsf::{Nsf, SoundChips};
+use crate::patch::{self, PatchError};
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+use crate::unif;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    OneScreenLower,
+    OneScreenUpper,
+    FourScreen,
+    // Each of the four nametable slots picks its own CIRAM page
+    Mapped([u8; 4]),
+}
+
+impl Mirroring {
+    // CIRAM page (0 or 1) that backs the nametable slot at $2000 + slot * $400,
+    // for the PPU once there is one
+    #[allow(dead_code)]
+    pub fn page(self, slot: usize) -> u8 {
+        match self {
+            Mirroring::Horizontal => (slot >> 1) as u8,
+            Mirroring::Vertical => (slot & 1) as u8,
+            Mirroring::OneScreenLower => 0,
+            Mirroring::OneScreenUpper => 1,
+            Mirroring::FourScreen => slot as u8,
+            Mirroring::Mapped(pages) => pages[slot & 3],
+        }
+    }
+}
+
+impl Snapshot for Mirroring {
+    fn save_state(&self, w: &mut StateWriter) {
+        let (kind, pages) = match *self {
+            Mirroring::Horizontal => (0, [0; 4]),
+            Mirroring::Vertical => (1, [0; 4]),
+            Mirroring::OneScreenLower => (2, [0; 4]),
+            Mirroring::OneScreenUpper => (3, [0; 4]),
+            Mirroring::FourScreen => (4, [0; 4]),
+            Mirroring::Mapped(pages) => (5, pages),
+        };
+        w.u8(kind);
+        pages.iter().for_each(|&page| w.u8(page));
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        let kind = r.u8()?;
+        let mut pages = [0; 4];
+        for page in pages.iter_mut() {
+            *page = r.u8()?;
+        }
+        *self = match kind {
+            0 => Mirroring::Horizontal,
+            1 => Mirroring::Vertical,
+            2 => Mirroring::OneScreenLower,
+            3 => Mirroring::OneScreenUpper,
+            4 => Mirroring::FourScreen,
+            5 => Mirroring::Mapped(pages),
+            _ => return Err(StateError::Invalid("mirroring")),
+        };
+        Ok(())
+    }
+}
+
+bitflags! {
+    // Controllers a game expects to be plugged in, where the image says.
+    // Bit layout follows the UNIF CTRL chunk.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct InputDevices: u8 {
+        const STANDARD   = (1 << 0);
+        const ZAPPER     = (1 << 1);
+        const ROB        = (1 << 2);
+        const ARKANOID   = (1 << 3);
+        const POWER_PAD  = (1 << 4);
+        const FOUR_SCORE = (1 << 5);
+    }
+}
+
+impl InputDevices {
+    // Translates the NES 2.0 default expansion device (header byte 15, also
+    // used by the game database). Devices with no flag here come out empty.
+    pub fn from_expansion(device: u8) -> Self {
+        match device {
+            0x01 => InputDevices::STANDARD,
+            0x02 | 0x03 => InputDevices::STANDARD | InputDevices::FOUR_SCORE,
+            0x07 | 0x09 => InputDevices::ZAPPER,
+            0x08 => InputDevices::STANDARD | InputDevices::ZAPPER,
+            0x0B | 0x0C => InputDevices::STANDARD | InputDevices::POWER_PAD,
+            0x0F | 0x10 => InputDevices::ARKANOID,
+            _ => InputDevices::empty(),
+        }
+    }
+}
+
+// TV system the game was made for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Region {
+    Ntsc,
+    Pal,
+    // Runs on either
+    Multi,
+    Dendy,
+}
+
+impl Region {
+    // NES 2.0 byte 12 encoding
+    pub fn from_timing(timing: u8) -> Self {
+        match timing & 0x03 {
+            0 => Region::Ntsc,
+            1 => Region::Pal,
+            2 => Region::Multi,
+            _ => Region::Dendy,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CartridgeError {
+    Io(io::Error),
+    InvalidHeader,
+    Truncated,
+    UnsupportedMapper(u16),
+    UnsupportedBoard(String),
+    Disk(DiskError),
+    InvalidBios,
+    Patch(PatchError),
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CartridgeError::Io(e) => write!(f, "i/o error: {e}"),
+            CartridgeError::InvalidHeader => write!(f, "not an iNES or UNIF image"),
+            CartridgeError::Truncated => write!(f, "image is shorter than its header claims"),
+            CartridgeError::UnsupportedMapper(n) => write!(f, "mapper {n} is not supported"),
+            CartridgeError::UnsupportedBoard(name) => write!(f, "board {name} is not supported"),
+            CartridgeError::Disk(e) => write!(f, "{e}"),
+            CartridgeError::InvalidBios => write!(f, "FDS BIOS images must be 8KB"),
+            CartridgeError::Patch(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
+
+impl From<io::Error> for CartridgeError {
+    fn from(e: io::Error) -> Self {
+        CartridgeError::Io(e)
+    }
+}
+
+impl From<DiskError> for CartridgeError {
+    fn from(e: DiskError) -> Self {
+        CartridgeError::Disk(e)
+    }
+}
+
+impl From<PatchError> for CartridgeError {
+    fn from(e: PatchError) -> Self {
+        CartridgeError::Patch(e)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Header {
+    pub mapper: u16,
+    pub submapper: u8,
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub mirroring: Mirroring,
+    pub battery: bool,
+    pub trainer: bool,
+    pub nes2: bool,
+    pub region: Region,
+    // Empty when the image doesn't say
+    pub input_devices: InputDevices,
+}
+
+impl Header {
+    pub const SIZE: usize = 16;
+
+    // NES 2.0 reserves mapper 20 for the FDS, which has no iNES header of its
+    // own; the BIOS takes the place of PRG ROM
+    pub fn fds() -> Self {
+        Header {
+            mapper: 20,
+            submapper: 0,
+            prg_rom_size: 0x2000,
+            chr_rom_size: 0,
+            prg_ram_size: 0x8000,
+            prg_nvram_size: 0,
+            chr_ram_size: 0x2000,
+            mirroring: Mirroring::Horizontal,
+            battery: false,
+            trainer: false,
+            nes2: true,
+            region: Region::Ntsc,
+            input_devices: InputDevices::empty(),
+        }
+    }
+
+    // NSF rips aren't cartridges, but the player drives them through one.
+    // NES 2.0 mapper 31 is the NSF-style bank switching board.
+    pub fn nsf(nsf: &Nsf) -> Self {
+        let fds = nsf.chips.contains(SoundChips::FDS);
+        Header {
+            mapper: 31,
+            submapper: 0,
+            prg_rom_size: nsf.data.len(),
+            chr_rom_size: 0,
+            prg_ram_size: if fds { 0xA000 } else { 0x2000 },
+            prg_nvram_size: 0,
+            chr_ram_size: 0x2000,
+            mirroring: Mirroring::Horizontal,
+            battery: false,
+            trainer: false,
+            nes2: true,
+            region: Region::Ntsc,
+            input_devices: InputDevices::empty(),
+        }
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, CartridgeError> {
+        if bytes.len() < Self::SIZE || &bytes[0..4] != b"NES\x1A" {
+            return Err(CartridgeError::InvalidHeader);
+        }
+
+        let flags6 = bytes[6];
+        let flags7 = bytes[7];
+        let nes2 = flags7 & 0x0C == 0x08;
+
+        let mirroring = if flags6 & 0x08 != 0 {
+            Mirroring::FourScreen
+        } else if flags6 & 0x01 != 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        };
+
+        let mut header = Header {
+            mapper: ((flags7 & 0xF0) | (flags6 >> 4)) as u16,
+            submapper: 0,
+            prg_rom_size: bytes[4] as usize * 0x4000,
+            chr_rom_size: bytes[5] as usize * 0x2000,
+            prg_ram_size: 0,
+            prg_nvram_size: 0,
+            chr_ram_size: 0,
+            mirroring,
+            battery: flags6 & 0x02 != 0,
+            trainer: flags6 & 0x04 != 0,
+            nes2,
+            // iNES 1.0 has a TV system bit in byte 9, but hardly any dumps
+            // set it, so it's only trusted from NES 2.0 headers
+            region: Region::Ntsc,
+            input_devices: InputDevices::empty(),
+        };
+
+        if nes2 {
+            header.mapper |= ((bytes[8] & 0x0F) as u16) << 8;
+            header.submapper = bytes[8] >> 4;
+            header.prg_rom_size = nes2_rom_size(bytes[4], bytes[9] & 0x0F, 0x4000);
+            header.chr_rom_size = nes2_rom_size(bytes[5], bytes[9] >> 4, 0x2000);
+            header.prg_ram_size = nes2_ram_size(bytes[10] & 0x0F);
+            header.prg_nvram_size = nes2_ram_size(bytes[10] >> 4);
+            header.chr_ram_size = nes2_ram_size(bytes[11] & 0x0F);
+            header.region = Region::from_timing(bytes[12]);
+            header.input_devices = InputDevices::from_expansion(bytes[15] & 0x3F);
+        } else {
+            // iNES 1.0 has no reliable RAM fields, so assume the common 8KB of
+            // work RAM and give CHR-less boards 8KB of CHR RAM
+            if header.battery {
+                header.prg_nvram_size = 0x2000;
+            } else {
+                header.prg_ram_size = 0x2000;
+            }
+            if header.chr_rom_size == 0 {
+                header.chr_ram_size = 0x2000;
+            }
+        }
+
+        Ok(header)
+    }
+
+    // The PRG and CHR ROM of an iNES image this header was parsed from
+    pub fn rom_data<'a>(&self, bytes: &'a [u8]) -> Result<(&'a [u8], &'a [u8]), CartridgeError> {
+        let mut offset = Self::SIZE;
+        if self.trainer {
+            offset += 512;
+        }
+
+        let prg_end = offset + self.prg_rom_size;
+        let chr_end = prg_end + self.chr_rom_size;
+        if bytes.len() < chr_end {
+            return Err(CartridgeError::Truncated);
+        }
+        Ok((&bytes[offset..prg_end], &bytes[prg_end..chr_end]))
+    }
+}
+
+fn nes2_rom_size(lsb: u8, msb: u8, unit: usize) -> usize {
+    if msb == 0x0F {
+        // Exponent-multiplier notation: 2^E * (MM * 2 + 1)
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = (lsb & 0x03) as usize * 2 + 1;
+        (1usize << exponent) * multiplier
+    } else {
+        (((msb as usize) << 8) | lsb as usize) * unit
+    }
+}
+
+fn nes2_ram_size(shift: u8) -> usize {
+    if shift == 0 { 0 } else { 64 << shift }
+}
+
+pub struct Cartridge {
+    header: Header,
+    mapper: Box<dyn Mapper>,
+
+    // Where battery-backed RAM is persisted, and what was last written there
+    save_path: Option<PathBuf>,
+    saved: Vec<u8>,
+    // The board as it came out of the box, registers and RAM both
+    power_on: Vec<u8>,
+}
+
+impl Cartridge {
+    // Loads an iNES or UNIF image. Boards with a battery get their RAM restored
+    // from a .sav file next to the ROM, which flush_save writes back to.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, CartridgeError> {
+        let path = path.as_ref();
+        let bytes = fs::read(path)?;
+        let mut cart = Self::from_bytes(&bytes)?;
+        if cart.has_battery() {
+            cart.attach_save_file(path.with_extension("sav"))?;
+        }
+        Ok(cart)
+    }
+
+    // Loads an image with IPS, UPS or BPS patches applied over it in order.
+    // The battery save goes next to the last patch rather than the ROM, so a
+    // hack's saves don't clobber the original game's.
+    pub fn load_patched<P: AsRef<Path>, Q: AsRef<Path>>(
+        path: P,
+        patches: &[Q],
+    ) -> Result<Self, CartridgeError> {
+        let data = patches
+            .iter()
+            .map(fs::read)
+            .collect::<Result<Vec<_>, _>>()?;
+        let bytes = patch::apply_all(&fs::read(path.as_ref())?, &data)?;
+
+        let mut cart = Self::from_bytes(&bytes)?;
+        if cart.has_battery() {
+            let save_path = patches.last().map_or(path.as_ref(), |patch| patch.as_ref());
+            cart.attach_save_file(save_path.with_extension("sav"))?;
+        }
+        Ok(cart)
+    }
+
+    // Loads a .fds or .qd disk image, which needs the RAM adapter's BIOS
+    // supplied separately. What the game writes to the disk goes to a .ips
+    // patch next to the image, leaving the image itself untouched.
+    pub fn load_fds<P: AsRef<Path>, B: AsRef<Path>>(
+        path: P,
+        bios: B,
+    ) -> Result<Self, CartridgeError> {
+        let path = path.as_ref();
+        let disk = fs::read(path)?;
+        let bios = fs::read(bios)?;
+        let mut cart = Self::from_fds_bytes(&disk, bios)?;
+        cart.attach_save_file(path.with_extension("ips"))?;
+        Ok(cart)
+    }
+
+    pub fn from_fds_bytes(disk: &[u8], bios: Vec<u8>) -> Result<Self, CartridgeError> {
+        if bios.len() != 0x2000 {
+            return Err(CartridgeError::InvalidBios);
+        }
+
+        let header = Header::fds();
+        let disk = DiskImage::parse(disk)?;
+        let memory = CartridgeMemory::new(&header, bios, Vec::new());
+        Ok(Self::new(header, Box::new(Fds::new(memory, disk))))
+    }
+
+    pub fn from_nsf(nsf: &Nsf) -> Self {
+        Self::new(Header::nsf(nsf), Box::new(NsfMapper::new(nsf)))
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CartridgeError> {
+        if bytes.starts_with(b"UNIF") {
+            let image = unif::parse(bytes)?;
+            return Self::from_parts(image.header, image.prg_rom, image.chr_rom);
+        }
+
+        let mut header = Header::parse(bytes)?;
+        let (prg_rom, chr_rom) = header.rom_data(bytes)?;
+
+        // Plenty of dumps in circulation have wrong or incomplete headers, so
+        // known games get theirs from the database instead
+        if let Some(entry) = database::builtin().lookup(prg_rom, chr_rom) {
+            entry.apply(&mut header);
+        }
+
+        Self::from_parts(header, prg_rom.to_vec(), chr_rom.to_vec())
+    }
+
+    fn from_parts(
+        header: Header,
+        prg_rom: Vec<u8>,
+        chr_rom: Vec<u8>,
+    ) -> Result<Self, CartridgeError> {
+        let mapper = mapper::create(&header, prg_rom, chr_rom)?;
+        Ok(Self::new(header, mapper))
+    }
+
+    fn new(header: Header, mapper: Box<dyn Mapper>) -> Self {
+        let mut power_on = StateWriter::bare();
+        mapper.save_state(&mut power_on);
+        Cartridge {
+            header,
+            mapper,
+            save_path: None,
+            saved: Vec::new(),
+            power_on: power_on.finish(),
+        }
+    }
+
+    // Puts the board back as it was when made, except for what a battery
+    // or disk keeps
+    pub fn power_cycle(&mut self) {
+        let save = self.export_save();
+        self.mapper
+            .load_state(&mut StateReader::new(&self.power_on))
+            .expect("state just written");
+        if let Some(save) = save {
+            self.import_save(&save);
+        }
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn mapper(&self) -> &dyn Mapper {
+        self.mapper.as_ref()
+    }
+
+    pub fn mapper_mut(&mut self) -> &mut dyn Mapper {
+        self.mapper.as_mut()
+    }
+
+    pub fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        self.mapper.cpu_read(addr)
+    }
+
+    pub fn cpu_write(&mut self, addr: u16, data: u8) -> bool {
+        self.mapper.cpu_write(addr, data)
+    }
+
+    pub fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        self.mapper.cpu_peek(addr)
+    }
+
+    pub fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        self.mapper.prg_rom_offset(addr)
+    }
+
+    pub fn prg_ram_offset(&self, addr: u16) -> Option<usize> {
+        self.mapper.prg_ram_offset(addr)
+    }
+
+    pub fn chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        self.mapper.chr_rom_offset(addr)
+    }
+
+    pub fn ppu_read(&mut self, addr: u16) -> Option<u8> {
+        let data = self.mapper.ppu_read(addr);
+        self.mapper.on_ppu_read(addr);
+        data
+    }
+
+    pub fn ppu_write(&mut self, addr: u16, data: u8) -> bool {
+        self.mapper.ppu_write(addr, data)
+    }
+
+    pub fn mirroring(&self) -> Mirroring {
+        self.mapper.mirroring()
+    }
+
+    pub fn clock(&mut self) {
+        self.mapper.clock();
+    }
+
+    pub fn irq_state(&self) -> bool {
+        self.mapper.irq_state()
+    }
+
+    pub fn barcode_reader(&mut self) -> Option<&mut BarcodeReader> {
+        self.mapper.barcode_reader()
+    }
+
+    // The FDS drive, for swapping and ejecting disk sides
+    pub fn disk_drive(&mut self) -> Option<&mut Fds> {
+        self.mapper.disk_drive()
+    }
+
+    // Whether anything on the board survives a power cycle
+    pub fn has_battery(&self) -> bool {
+        self.header.battery || self.mapper.has_nonvolatile_storage()
+    }
+
+    // Battery-backed contents as stored in a .sav file, or None if the board
+    // doesn't keep anything across power cycles
+    pub fn export_save(&self) -> Option<Vec<u8>> {
+        if self.has_battery() {
+            Some(self.mapper.save_data())
+        } else {
+            None
+        }
+    }
+
+    pub fn import_save(&mut self, data: &[u8]) {
+        self.mapper.load_save_data(data);
+    }
+
+    // Persists battery RAM to path from now on, restoring it from there first
+    // if the file already exists
+    pub fn attach_save_file<P: Into<PathBuf>>(&mut self, path: P) -> io::Result<()> {
+        let path = path.into();
+        match fs::read(&path) {
+            Ok(data) => self.import_save(&data),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        self.saved = self.mapper.save_data();
+        self.save_path = Some(path);
+        Ok(())
+    }
+
+    // Writes battery RAM out if it changed since the last flush. The file is
+    // replaced through a rename so a crash mid-write can't truncate a save.
+    pub fn flush_save(&mut self) -> io::Result<()> {
+        let Some(path) = self.save_path.as_ref() else {
+            return Ok(());
+        };
+
+        let data = self.mapper.save_data();
+        if data == self.saved {
+            return Ok(());
+        }
+
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        fs::write(&tmp, &data)?;
+        fs::rename(&tmp, path)?;
+        self.saved = data;
+        Ok(())
+    }
+}
+
+impl Drop for Cartridge {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush_save() {
+            eprintln!("failed to write battery save: {e}");
+        }
+    }
+}
+
+// The board's state, preceded by a checksum of the ROM and the board it's
+// on so a state can't be loaded into the wrong game
+impl Snapshot for Cartridge {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u32(self.rom_crc32());
+        w.u16(self.header.mapper);
+        w.u8(self.header.submapper);
+        self.mapper.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        if r.u32()? != self.rom_crc32()
+            || r.u16()? != self.header.mapper
+            || r.u8()? != self.header.submapper
+        {
+            return Err(StateError::WrongCartridge);
+        }
+        self.mapper.load_state(r)
+    }
+}
+
+impl Cartridge {
+    // PRG and CHR ROM, the latter empty for boards with CHR RAM
+    pub fn rom(&self) -> (&[u8], &[u8]) {
+        let memory = self.mapper.memory();
+        let chr: &[u8] = if memory.chr_is_ram { &[] } else { &memory.chr };
+        (&memory.prg_rom, chr)
+    }
+
+    fn rom_crc32(&self) -> u32 {
+        let (prg, chr) = self.rom();
+        let mut crc = Crc32::new();
+        crc.update(prg);
+        crc.update(chr);
+        crc.finish()
+    }
+}
+
+#[cfg(test)]
+impl Cartridge {
+    // An NROM-128 cartridge that runs program from $8000 on reset
+    pub fn nrom(program: &[u8]) -> Self {
+        Self::from_bytes(&Self::nrom_image(program)).unwrap()
+    }
+
+    pub fn nrom_image(program: &[u8]) -> Vec<u8> {
+        let mut prg = vec![0xEA; 0x4000];
+        prg[..program.len()].copy_from_slice(program);
+        prg[0x3FFC..].copy_from_slice(&[0x00, 0x80, 0x00, 0x80]);
+
+        let mut bytes = b"NES\x1A\x01\x01".to_vec();
+        bytes.resize(16, 0);
+        bytes.extend(prg);
+        bytes.resize(16 + 0x4000 + 0x2000, 0);
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An FME-7 board with each 8KB PRG bank filled with its own number
+    fn fme7(battery: bool) -> Cartridge {
+        let mut bytes = b"NES\x1A\x02\x01\x50\x40".to_vec();
+        bytes[6] |= (battery as u8) << 1;
+        bytes.resize(16, 0);
+        for bank in 0..4 {
+            bytes.extend([bank; 0x2000]);
+        }
+        bytes.resize(16 + 0x8000 + 0x2000, 0);
+        Cartridge::from_bytes(&bytes).unwrap()
+    }
+
+    #[test]
+    fn power_cycle_resets_the_board_and_keeps_the_battery() {
+        for battery in [false, true] {
+            let mut cart = fme7(battery);
+            let bank = cart.cpu_peek(0x8000);
+            cart.cpu_write(0x8000, 0x09);
+            cart.cpu_write(0xA000, 0x02);
+            cart.cpu_write(0x8000, 0x08);
+            cart.cpu_write(0xA000, 0xC0);
+            cart.cpu_write(0x6000, 0x55);
+            assert_eq!(cart.cpu_peek(0x8000), Some(2));
+
+            cart.power_cycle();
+            assert_eq!(cart.cpu_peek(0x8000), bank);
+            cart.cpu_write(0x8000, 0x08);
+            cart.cpu_write(0xA000, 0xC0);
+            let kept = if battery { 0x55 } else { 0x00 };
+            assert_eq!(cart.cpu_peek(0x6000), Some(kept), "battery {battery}");
+        }
+    }
+}
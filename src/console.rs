@@ -0,0 +1,145 @@
+use crate::bus::Bus;
+use crate::cartridge::Cartridge;
+use crate::cpu::Cpu;
+use crate::state::{Snapshot, State, StateError, StateWriter};
+
+// CPU cycles in an NTSC frame (29780.67, rounded). There's no PPU yet to say
+// where frames end, so run_frame goes by this.
+pub const CYCLES_PER_FRAME: u64 = 29781;
+
+// The whole machine. It owns each part outright and lends the CPU the bus
+// for every clock, so nothing is shared and nothing needs borrowing at run
+// time. The PPU and APU will sit alongside the CPU once there are ones.
+pub struct Console {
+    pub cpu: Cpu,
+    pub bus: Bus,
+}
+
+impl Console {
+    pub fn new() -> Self {
+        Console {
+            cpu: Cpu::new(),
+            bus: Bus::new(),
+        }
+    }
+
+    pub fn with_cartridge(cart: Cartridge) -> Self {
+        let mut console = Console::new();
+        console.bus.insert_cartridge(cart);
+        console
+    }
+
+    // Advances the system by one CPU cycle
+    pub fn clock(&mut self) {
+        self.cpu.clock(&mut self.bus);
+
+        // The bus ORs every IRQ source into the one line. The PPU will drive
+        // NMI the same way.
+        let irq = self.bus.clock();
+        self.cpu.set_irq(irq);
+    }
+
+    pub fn run_frame(&mut self) {
+        for _ in 0..CYCLES_PER_FRAME {
+            self.clock();
+        }
+    }
+
+    // The reset button
+    pub fn reset(&mut self) {
+        self.cpu.reset(&mut self.bus);
+    }
+
+    // Switching the console off and on again
+    pub fn power_cycle(&mut self) {
+        self.bus.power_cycle();
+        self.cpu.power_on();
+        self.reset();
+    }
+
+    // The whole machine as a save state. Restoring it resumes exactly where
+    // it was taken, mid-instruction included.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        self.write_state(&mut w);
+        w.finish()
+    }
+
+    // For wrappers that add chunks of their own
+    pub fn write_state(&self, w: &mut StateWriter) {
+        w.chunk(*b"CPU ", |w| self.cpu.save_state(w));
+        self.bus.write_state(w);
+    }
+
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        self.read_state(&State::parse(data)?)
+    }
+
+    // A state that fails to load leaves the machine as it was, so the CPU
+    // is read into a copy and only kept once the bus has loaded too
+    pub fn read_state(&mut self, state: &State) -> Result<(), StateError> {
+        let mut cpu = self.cpu.clone();
+        cpu.load_state(&mut state.chunk(*b"CPU ")?)?;
+        self.bus.read_state(state)?;
+        self.cpu = cpu;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Fills a page with a counter, through zero page and indexed stores
+    const PROGRAM: &[u8] = &[
+        0xE6, 0x10, //       INC $10
+        0xA5, 0x10, //       LDA $10
+        0x9D, 0x00, 0x02, // STA $0200,X
+        0xE8, //             INX
+        0x4C, 0x00, 0x80, // JMP $8000
+    ];
+
+    fn console() -> Console {
+        let mut console = Console::with_cartridge(Cartridge::nrom(PROGRAM));
+        console.reset();
+        console
+    }
+
+    fn run(console: &mut Console, cycles: u64) {
+        for _ in 0..cycles {
+            console.clock();
+        }
+    }
+
+    #[test]
+    fn restoring_a_state_replays_exactly() {
+        let mut console = console();
+        // Both stopping points fall mid-instruction
+        run(&mut console, 1001);
+        let state = console.save_state();
+        run(&mut console, 5003);
+        let expected = console.save_state();
+
+        console.load_state(&state).unwrap();
+        run(&mut console, 5003);
+        assert_eq!(console.save_state(), expected);
+    }
+
+    #[test]
+    fn a_bad_chunk_changes_nothing() {
+        let mut console = console();
+        run(&mut console, 1001);
+        let mut state = console.save_state();
+        run(&mut console, 5003);
+        let before = console.save_state();
+
+        // The CPU's interrupt byte, after its 15 bytes of registers and
+        // instruction state, following the header and chunk header
+        state[6 + 8 + 15] = 0xFF;
+        assert!(matches!(
+            console.load_state(&state),
+            Err(StateError::Invalid(_))
+        ));
+        assert_eq!(console.save_state(), before);
+    }
+}
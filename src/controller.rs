@@ -0,0 +1,73 @@
+use bitflags::bitflags;
+
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+bitflags! {
+    // In the order the pad shifts them out, first in the top bit
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct Buttons: u8 {
+        const A      = (1 << 7);
+        const B      = (1 << 6);
+        const SELECT = (1 << 5);
+        const START  = (1 << 4);
+        const UP     = (1 << 3);
+        const DOWN   = (1 << 2);
+        const LEFT   = (1 << 1);
+        const RIGHT  = (1 << 0);
+    }
+}
+
+// A standard pad on $4016 or $4017. Writing 1 to bit 0 of $4016 holds both
+// pads' shift registers loaded with the buttons; once it's cleared, each read
+// shifts one button out, then 1s once all eight are gone.
+#[derive(Clone)]
+pub struct Controller {
+    pub buttons: Buttons,
+    state: u8,
+    strobe: bool,
+}
+
+impl Controller {
+    pub fn new() -> Self {
+        Controller {
+            buttons: Buttons::empty(),
+            state: 0,
+            strobe: false,
+        }
+    }
+
+    pub fn write(&mut self, data: u8) {
+        self.strobe = data & 0x01 != 0;
+        if self.strobe {
+            self.state = self.buttons.bits();
+        }
+    }
+
+    pub fn read(&mut self) -> u8 {
+        if self.strobe {
+            self.state = self.buttons.bits();
+        }
+        let data = self.peek();
+        self.state = (self.state << 1) | 0x01;
+        data
+    }
+
+    pub fn peek(&self) -> u8 {
+        (self.state & 0x80 != 0) as u8
+    }
+}
+
+impl Snapshot for Controller {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.buttons.bits());
+        w.u8(self.state);
+        w.bool(self.strobe);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.buttons = Buttons::from_bits_retain(r.u8()?);
+        self.state = r.u8()?;
+        self.strobe = r.bool()?;
+        Ok(())
+    }
+}
@@ -1,11 +1,10 @@
-use crate::instructions::Instruction;
-use crate::{bus::Bus, instructions::LOOKUP};
+use crate::instructions::{Instruction, LOOKUP, LOOKUP_65C02, LOOKUP_R65C02, LOOKUP_W65C02};
+use crate::memory::Memory;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
 use bitflags::bitflags;
-use std::ops::Not;
-use std::{cell::RefCell, rc::Rc};
 
 bitflags! {
-    #[derive(Clone, Copy)]
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
     pub struct StatusFlags: u8 {
         const CARRY             = (1 << 0);
         const ZERO              = (1 << 1);
@@ -18,63 +17,153 @@ bitflags! {
     }
 }
 
-pub struct Cpu {
-    bus: Option<Rc<RefCell<Bus>>>,
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interrupt {
+    Nmi,
+    Irq,
+    Brk,
+}
 
-    // Registers
-    a: u8,               // Accumulator Register
-    x: u8,               // X Register
-    y: u8,               // Y Register
-    stkp: u8,            // Stack Pointer (points to location on bus)
-    pc: u16,             // Program Counter
-    status: StatusFlags, // Status Register
+// Which chip the core behaves as
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Variant {
+    // The NES's Ricoh 2A03, an NMOS 6502 with decimal mode cut out. D can
+    // still be set and pushed, but ADC and SBC ignore it.
+    Ricoh2A03,
+    // The original NMOS 6502, for anything else
+    Nmos6502,
+    // The CMOS redesign. It adds instructions, fixes JMP ($xxFF), and
+    // spends an extra cycle on decimal arithmetic to get valid flags.
+    Cmos65C02,
+    // With Rockwell's bit instructions, RMB, SMB, BBR and BBS
+    Rockwell65C02,
+    // With those and WDC's WAI and STP
+    Wdc65C02,
+}
 
-    fetched: u8,
-    addr_abs: u16,
-    addr_rel: u16,
-    opcode: u8,
-    cycles: u8,
+impl Variant {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "2a03" | "nes" => Some(Variant::Ricoh2A03),
+            "6502" | "nmos" => Some(Variant::Nmos6502),
+            "65c02" | "cmos" => Some(Variant::Cmos65C02),
+            "r65c02" | "rockwell" => Some(Variant::Rockwell65C02),
+            "w65c02" | "wdc" => Some(Variant::Wdc65C02),
+            _ => None,
+        }
+    }
+
+    pub fn has_decimal_mode(self) -> bool {
+        self != Variant::Ricoh2A03
+    }
+
+    pub fn is_cmos(self) -> bool {
+        matches!(
+            self,
+            Variant::Cmos65C02 | Variant::Rockwell65C02 | Variant::Wdc65C02
+        )
+    }
 
-    lookup: [Instruction; 256],
+    pub fn lookup(self) -> &'static [Instruction; 256] {
+        match self {
+            Variant::Ricoh2A03 | Variant::Nmos6502 => &LOOKUP,
+            Variant::Cmos65C02 => &LOOKUP_65C02,
+            Variant::Rockwell65C02 => &LOOKUP_R65C02,
+            Variant::Wdc65C02 => &LOOKUP_W65C02,
+        }
+    }
+}
+
+// A 6502 core. It keeps no memory of its own: whatever it's wired to is
+// passed in as a Memory on every call, NES bus or otherwise.
+//
+// Instructions run a cycle per clock(), and each cycle makes the one bus
+// access the real chip makes on it: the dummy reads of implied instructions
+// and of indexing before the carry is fixed, and the old value written back
+// by read-modify-write instructions ahead of the new one.
+#[derive(Clone)]
+pub struct Cpu {
+    // Registers
+    pub a: u8,               // Accumulator Register
+    pub x: u8,               // X Register
+    pub y: u8,               // Y Register
+    pub stkp: u8,            // Stack Pointer (points to location on bus)
+    pub pc: u16,             // Program Counter
+    pub status: StatusFlags, // Status Register
+
+    // The instruction in progress and its next cycle. Cycle 0 fetches the
+    // next opcode.
+    opcode: u8,
+    step: u8,
+    // Latched by earlier cycles: the operand's address, the pointer it was
+    // read through and the last value read
+    addr: u16,
+    ptr: u16,
+    fetched: u8,
+    // Idle cycles left of the reset sequence
+    stall: u8,
+    // Whether this cycle's access was one the instruction ignores
+    dummy: bool,
+
+    // The interrupt lines as last set, the NMI line as last polled, and
+    // an NMI edge not yet serviced
+    nmi_line: bool,
+    irq_line: bool,
+    nmi_edge: bool,
+    nmi_detected: bool,
+    // Whether an interrupt was wanted as of the end of the last cycle and
+    // the one before. The next instruction is an interrupt if it was on the
+    // penultimate cycle of the one before.
+    polled: bool,
+    polled_before: bool,
+    // The interrupt whose sequence is running. Interrupts borrow BRK's.
+    servicing: Option<Interrupt>,
+
+    // The last interrupt taken, until a debugger collects it
+    interrupt: Option<Interrupt>,
+
+    variant: Variant,
+    lookup: &'static [Instruction; 256],
 }
 
 impl Cpu {
+    // The NES's CPU
     pub fn new() -> Self {
-        Cpu {
-            bus: None,
-            status: StatusFlags::all(),
+        Cpu::with_variant(Variant::Ricoh2A03)
+    }
 
+    pub fn with_variant(variant: Variant) -> Self {
+        let mut cpu = Cpu {
             a: 0x00,
             x: 0x00,
             y: 0x00,
             stkp: 0x00,
             pc: 0x0000,
+            status: StatusFlags::empty(),
 
-            fetched: 0x00,
-            addr_abs: 0x0000,
-            addr_rel: 0x0000,
             opcode: 0x00,
-            cycles: 0,
+            step: 0,
+            addr: 0x0000,
+            ptr: 0x0000,
+            fetched: 0x00,
+            stall: 0,
+            dummy: false,
 
-            lookup: LOOKUP,
-        }
-    }
+            nmi_line: false,
+            irq_line: false,
+            nmi_edge: false,
+            nmi_detected: false,
+            polled: false,
+            polled_before: false,
+            servicing: None,
 
-    pub fn connect_bus(&mut self, bus: Rc<RefCell<Bus>>) {
-        self.bus = Some(bus);
-    }
+            interrupt: None,
 
-    pub fn read(&self, a: u16) -> u8 {
-        let bus_ref = self.bus.as_ref().unwrap();
-        let bus = bus_ref.borrow();
-        bus.read(a, false)
-    }
-
-    pub fn write(&mut self, a: u16, d: u8) {
-        if let Some(bus_ref) = self.bus.as_mut() {
-            let mut bus = bus_ref.borrow_mut();
-            bus.write(a, d);
-        }
+            variant,
+            lookup: variant.lookup(),
+        };
+        cpu.power_on();
+        cpu
     }
 
     pub fn get_flag(&self, f: StatusFlags) -> bool {
@@ -89,93 +178,228 @@ impl Cpu {
         }
     }
 
-    pub fn clock(&mut self) {
-        if self.cycles == 0 {
-            self.opcode = self.read(self.pc);
-            self.pc += 1;
+    // Called every cycle by whatever drives the chip, so it's worth
+    // inlining into those loops
+    #[inline]
+    pub fn clock(&mut self, mem: &mut dyn Memory) {
+        self.dummy = false;
+        self.poll();
+        if self.stall > 0 {
+            self.stall -= 1;
+            return;
+        }
+        if self.step == 0 {
+            self.fetch_opcode(mem);
+            // The 65C02's one byte NOPs are over with the fetch
+            self.step = (self.lookup[self.opcode as usize].cycles > 1) as u8;
+            return;
+        }
 
-            self.cycles = self.lookup[self.opcode as usize].cycles;
-            let add_cycles1 = self.get_operand_address(self.lookup[self.opcode as usize].mode);
-            let add_cycles2 = 0 as u8; // additional cycles for operation
-            self.cycles += add_cycles1 & add_cycles2;
+        let instruction = self.lookup[self.opcode as usize];
+        if (instruction.exec)(self, mem) {
+            debug_assert!(self.step + 1 >= instruction.cycles);
+            self.step = 0;
+        } else {
+            self.step += 1;
         }
-        self.cycles -= 1;
     }
 
-    pub fn reset(&mut self) {
+    // Between instructions
+    pub fn complete(&self) -> bool {
+        self.step == 0 && self.stall == 0
+    }
+
+    pub fn take_interrupt(&mut self) -> Option<Interrupt> {
+        self.interrupt.take()
+    }
+
+    // For debuggers, which care what a read was for rather than that it
+    // happened
+    pub fn dummy_access(&self) -> bool {
+        self.dummy
+    }
+
+    // The registers as the chip powers up, ready for reset
+    pub fn power_on(&mut self) {
         self.a = 0;
         self.x = 0;
         self.y = 0;
-        self.stkp = 0xFD;
-        self.status = StatusFlags::empty();
-
-        self.addr_abs = 0xFFFC;
-        let lo = self.read(self.addr_abs + 0) as u16;
-        let hi = self.read(self.addr_abs + 1) as u16;
+        self.stkp = 0x00;
+        self.status = StatusFlags::UNUSED;
+        self.step = 0;
+        self.stall = 0;
+        self.nmi_line = false;
+        self.irq_line = false;
+        self.nmi_edge = false;
+        self.forget_interrupts();
+    }
+
+    // The reset sequence is an interrupt whose pushes are turned into reads,
+    // so the vector fetch is the only access that matters. It's made at
+    // once, and the rest of the sequence's seven cycles are idle.
+    pub fn reset(&mut self, mem: &mut dyn Memory) {
+        self.stkp = self.stkp.wrapping_sub(3);
+        self.set_flag(StatusFlags::INTERRUPT_DISABLE, true);
 
+        let lo = mem.read(0xFFFC) as u16;
+        let hi = mem.read(0xFFFD) as u16;
         self.pc = (hi << 8) | lo;
 
-        self.addr_rel = 0x0000;
-        self.addr_abs = 0x0000;
-        self.fetched = 0x00;
-
-        self.cycles = 8;
+        self.step = 0;
+        self.stall = 7;
+        self.forget_interrupts();
     }
 
-    pub fn irq(&mut self) {
-        if self.get_flag(StatusFlags::INTERRUPT_DISABLE) == false {
-            self.write(0x0100 + self.stkp as u16, ((self.pc >> 8) & 0x00FF) as u8);
-            self.stkp -= 1;
-            self.write(0x0100 + self.stkp as u16, (self.pc & 0x00FF) as u8);
-            self.stkp -= 1;
+    fn forget_interrupts(&mut self) {
+        self.nmi_detected = false;
+        self.polled = false;
+        self.polled_before = false;
+        self.servicing = None;
+    }
 
-            self.set_flag(StatusFlags::BREAK, false);
-            self.set_flag(StatusFlags::UNUSED, true);
-            self.set_flag(StatusFlags::INTERRUPT_DISABLE, true);
-            self.write(0x0100 + self.stkp as u16, self.status.bits());
-            self.stkp -= 1;
+    // The /NMI and /IRQ inputs, true while pulled low. A change between
+    // clocks counts as made during the cycle just run, and is polled at the
+    // start of the next. Only the PPU pulls /NMI, so nothing calls set_nmi
+    // until there's one.
+    #[allow(dead_code)]
+    pub fn set_nmi(&mut self, asserted: bool) {
+        self.nmi_line = asserted;
+    }
 
-            self.addr_abs = 0xFFFE;
-            let lo = self.read(self.addr_abs + 0) as u16;
-            let hi = self.read(self.addr_abs + 1) as u16;
-            self.pc = (hi << 8) | lo;
+    pub fn set_irq(&mut self, asserted: bool) {
+        self.irq_line = asserted;
+    }
 
-            self.cycles = 7;
+    // What the chip samples at the end of every cycle, done here as the
+    // next one starts so lines set in between are seen. NMI goes by edges,
+    // and one is remembered until it's serviced; IRQ is a level, and only
+    // counts while it's held and the I flag is clear.
+    fn poll(&mut self) {
+        if self.nmi_line && !self.nmi_edge {
+            self.nmi_detected = true;
+        }
+        self.nmi_edge = self.nmi_line;
+
+        self.polled_before = self.polled;
+        self.polled =
+            self.nmi_detected || (self.irq_line && !self.get_flag(StatusFlags::INTERRUPT_DISABLE));
+    }
+
+    // An instruction starts by fetching its opcode. If an interrupt was due
+    // on the penultimate cycle of the last one, the fetch is a dummy read
+    // instead, and BRK's sequence runs. That's why CLI, SEI and PLP only
+    // take effect after the instruction that follows them.
+    fn fetch_opcode(&mut self, mem: &mut dyn Memory) {
+        self.servicing = match (self.polled_before, self.nmi_detected) {
+            (false, _) => None,
+            (true, true) => Some(Interrupt::Nmi),
+            (true, false) => Some(Interrupt::Irq),
+        };
+
+        if self.servicing.is_some() {
+            self.dummy_read(mem, self.pc);
+            self.opcode = 0x00;
+        } else {
+            self.opcode = self.fetch_pc(mem);
         }
     }
 
-    pub fn nmi(&mut self) {
-        self.write(0x0100 + self.stkp as u16, ((self.pc >> 8) & 0x00FF) as u8);
-        self.stkp -= 1;
-        self.write(0x0100 + self.stkp as u16, (self.pc & 0x00FF) as u8);
-        self.stkp -= 1;
+    fn dummy_read(&mut self, mem: &mut dyn Memory, addr: u16) {
+        mem.read(addr);
+        self.dummy = true;
+    }
 
-        self.set_flag(StatusFlags::BREAK, false);
-        self.set_flag(StatusFlags::UNUSED, true);
-        self.set_flag(StatusFlags::INTERRUPT_DISABLE, true);
-        self.write(0x0100 + self.stkp as u16, self.status.bits());
-        self.stkp -= 1;
+    fn fetch_pc(&mut self, mem: &mut dyn Memory) -> u8 {
+        let data = mem.read(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+        data
+    }
 
-        self.addr_abs = 0xFFFA;
-        let lo = self.read(self.addr_abs + 0) as u16;
-        let hi = self.read(self.addr_abs + 1) as u16;
-        self.pc = (hi << 8) | lo;
+    fn push(&mut self, mem: &mut dyn Memory, data: u8) {
+        mem.write(0x0100 | self.stkp as u16, data);
+        self.stkp = self.stkp.wrapping_sub(1);
+    }
 
-        self.cycles = 8;
+    // Pulls take two cycles: a read at the stack pointer before it's
+    // incremented, then the real one
+    fn read_stack(&mut self, mem: &mut dyn Memory) -> u8 {
+        mem.read(0x0100 | self.stkp as u16)
     }
 
-    fn fetch(&mut self) -> u8 {
-        if !matches!(
-            self.lookup[self.opcode as usize].mode,
-            AddressingMode::Implied
-        ) {
-            self.fetched = self.read(self.addr_abs);
-        }
-        self.fetched
+    fn set_nz(&mut self, value: u8) {
+        self.set_flag(StatusFlags::ZERO, value == 0);
+        self.set_flag(StatusFlags::NEGATIVE, value & 0x80 != 0);
+    }
+
+    // P as pulled by PLP and RTI. B and the unused bit aren't really
+    // stored, so they read back as 0 and 1.
+    fn set_status(&mut self, value: u8) {
+        self.status = StatusFlags::from_bits_retain(value);
+        self.set_flag(StatusFlags::BREAK, false);
+        self.set_flag(StatusFlags::UNUSED, true);
+    }
+}
+
+// Includes the state an instruction leaves behind between clock() calls,
+// so a state saved mid-instruction resumes exactly
+impl Snapshot for Cpu {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.a);
+        w.u8(self.x);
+        w.u8(self.y);
+        w.u8(self.stkp);
+        w.u16(self.pc);
+        w.u8(self.status.bits());
+        w.u8(self.opcode);
+        w.u8(self.step);
+        w.u16(self.addr);
+        w.u16(self.ptr);
+        w.u8(self.fetched);
+        w.u8(self.stall);
+        w.u8(match self.servicing {
+            None | Some(Interrupt::Brk) => 0,
+            Some(Interrupt::Nmi) => 1,
+            Some(Interrupt::Irq) => 2,
+        });
+        w.bool(self.nmi_line);
+        w.bool(self.irq_line);
+        w.bool(self.nmi_edge);
+        w.bool(self.nmi_detected);
+        w.bool(self.polled);
+        w.bool(self.polled_before);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.a = r.u8()?;
+        self.x = r.u8()?;
+        self.y = r.u8()?;
+        self.stkp = r.u8()?;
+        self.pc = r.u16()?;
+        self.status = StatusFlags::from_bits_retain(r.u8()?);
+        self.opcode = r.u8()?;
+        self.step = r.u8()?;
+        self.addr = r.u16()?;
+        self.ptr = r.u16()?;
+        self.fetched = r.u8()?;
+        self.stall = r.u8()?;
+        self.servicing = match r.u8()? {
+            0 => None,
+            1 => Some(Interrupt::Nmi),
+            2 => Some(Interrupt::Irq),
+            _ => return Err(StateError::Invalid("CPU interrupt")),
+        };
+        self.nmi_line = r.bool()?;
+        self.irq_line = r.bool()?;
+        self.nmi_edge = r.bool()?;
+        self.nmi_detected = r.bool()?;
+        self.polled = r.bool()?;
+        self.polled_before = r.bool()?;
+        self.interrupt = None;
+        Ok(())
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum AddressingMode {
     Immediate,
     ZeroPage,
@@ -190,698 +414,1816 @@ pub enum AddressingMode {
     Accumulator,
     Implied,
     Relative,
+    // 65C02 only: (zp), JMP (abs,X), and BBR and BBS's zp,rel
+    ZeroPageIndirect,
+    AbsoluteIndirectX,
+    ZeroPageRelative,
+}
+
+impl AddressingMode {
+    // In the order declared, so a mode survives being made a u8 for a const
+    // generic parameter
+    const fn from_index(index: u8) -> Self {
+        use AddressingMode::*;
+        const MODES: [AddressingMode; 16] = [
+            Immediate,
+            ZeroPage,
+            ZeroPageX,
+            ZeroPageY,
+            Absolute,
+            AbsoluteX,
+            AbsoluteY,
+            Indirect,
+            IndirectX,
+            IndirectY,
+            Accumulator,
+            Implied,
+            Relative,
+            ZeroPageIndirect,
+            AbsoluteIndirectX,
+            ZeroPageRelative,
+        ];
+        MODES[index as usize]
+    }
+}
+
+// The addressing mode a handler was specialised for
+const fn mode<const MODE: u8>() -> AddressingMode {
+    const { AddressingMode::from_index(MODE) }
 }
 
-// Addressing modes
+// Addressing modes. The operand's address takes address_cycles() cycles
+// to work out, from cycle 1, and the operand itself is accessed after.
 impl Cpu {
-    #[rustfmt::skip]
-    pub fn get_operand_address(&mut self, mode: AddressingMode) -> u8 {
-        match mode {
-            AddressingMode::Immediate     => self.addr_imm(),
-            AddressingMode::ZeroPage      => self.addr_zp0(),
-            AddressingMode::ZeroPageX     => self.addr_zpx(),
-            AddressingMode::ZeroPageY     => self.addr_zpy(),
-            AddressingMode::Absolute      => self.addr_abs(),
-            AddressingMode::AbsoluteX     => self.addr_abx(),
-            AddressingMode::AbsoluteY     => self.addr_aby(),
-            AddressingMode::Indirect      => self.addr_ind(),
-            AddressingMode::IndirectX     => self.addr_izx(),
-            AddressingMode::IndirectY     => self.addr_izy(),
-            AddressingMode::Relative      => self.addr_rel(),
-            AddressingMode::Accumulator   => 0, // special case — doesn't use memory
-            AddressingMode::Implied       => 0, // also special — operand implied
+    fn address_cycles<const MODE: u8>(&self) -> u8 {
+        match mode::<MODE>() {
+            AddressingMode::ZeroPage => 1,
+            AddressingMode::ZeroPageX | AddressingMode::ZeroPageY | AddressingMode::Absolute => 2,
+            AddressingMode::AbsoluteX
+            | AddressingMode::AbsoluteY
+            | AddressingMode::ZeroPageIndirect => 3,
+            AddressingMode::IndirectX | AddressingMode::IndirectY => 4,
+            _ => 0,
         }
-
     }
 
-    pub fn addr_imp(&mut self) -> u8 {
-        self.fetched = self.a;
-        0
+    #[rustfmt::skip]
+    fn address_step<const MODE: u8>(&mut self, mem: &mut dyn Memory) {
+        use AddressingMode::*;
+        match (mode::<MODE>(), self.step) {
+            (ZeroPage | ZeroPageX | ZeroPageY | Absolute | AbsoluteX | AbsoluteY, 1) => {
+                self.addr = self.fetch_pc(mem) as u16;
+            }
+            (ZeroPageX, 2) => {
+                self.dummy_read(mem, self.addr);
+                self.addr = (self.addr as u8).wrapping_add(self.x) as u16;
+            }
+            (ZeroPageY, 2) => {
+                self.dummy_read(mem, self.addr);
+                self.addr = (self.addr as u8).wrapping_add(self.y) as u16;
+            }
+            (Absolute | AbsoluteX | AbsoluteY, 2) => {
+                self.addr |= (self.fetch_pc(mem) as u16) << 8;
+            }
+            (AbsoluteX, 3)  => self.index(mem, self.x),
+            (AbsoluteY, 3)  => self.index(mem, self.y),
+
+            (IndirectX | IndirectY | ZeroPageIndirect, 1) => self.ptr = self.fetch_pc(mem) as u16,
+            (IndirectX, 2) => {
+                self.dummy_read(mem, self.ptr);
+                self.ptr = (self.ptr as u8).wrapping_add(self.x) as u16;
+            }
+            (IndirectX, 3) | (IndirectY | ZeroPageIndirect, 2) => self.addr = mem.read(self.ptr) as u16,
+            (IndirectX, 4) | (IndirectY | ZeroPageIndirect, 3) => {
+                self.addr |= (mem.read((self.ptr as u8).wrapping_add(1) as u16) as u16) << 8;
+            }
+            (IndirectY, 4)  => self.index(mem, self.y),
+            _ => {}
+        }
     }
 
-    pub fn addr_imm(&mut self) -> u8 {
-        self.pc += 1;
-        self.addr_abs = self.pc;
-        0
+    // The index is added to the low byte a cycle before any carry reaches
+    // the high byte, and the read on that cycle goes to the unfixed address.
+    // It's kept in ptr, and what was read there in fetched. The read is a
+    // dummy unless the index stayed in the page and an operand was wanted.
+    // The 65C02 reads the last operand byte again instead when it carries.
+    fn index(&mut self, mem: &mut dyn Memory, index: u8) {
+        let fixed = self.addr.wrapping_add(index as u16);
+        self.ptr = (self.addr & 0xFF00) | (fixed & 0x00FF);
+        if self.variant.is_cmos() && self.ptr != fixed {
+            self.dummy_read(mem, self.pc.wrapping_sub(1));
+        } else {
+            self.fetched = mem.read(self.ptr);
+            self.dummy = true;
+        }
+        self.addr = fixed;
     }
 
-    pub fn addr_zp0(&mut self) -> u8 {
-        self.addr_abs = self.read(self.pc) as u16;
-        self.pc += 1;
-        self.addr_abs &= 0x00FF;
-        0
+    fn indexed<const MODE: u8>(&self) -> bool {
+        matches!(
+            mode::<MODE>(),
+            AddressingMode::AbsoluteX | AddressingMode::AbsoluteY | AddressingMode::IndirectY
+        )
     }
 
-    pub fn addr_zpx(&mut self) -> u8 {
-        self.addr_abs = (self.read(self.pc) + self.x) as u16;
-        self.pc += 1;
-        self.addr_abs &= 0x00FF;
-        0
+    fn page_crossed<const MODE: u8>(&self) -> bool {
+        self.indexed::<MODE>() && self.ptr != self.addr
     }
 
-    pub fn addr_zpy(&mut self) -> u8 {
-        self.addr_abs = (self.read(self.pc) + self.y) as u16;
-        self.pc += 1;
-        self.addr_abs &= 0x00FF;
-        0
+    // The cycle an operand is read on. Only meaningful once any indexing
+    // is done.
+    fn operand_step<const MODE: u8>(&self) -> u8 {
+        match mode::<MODE>() {
+            AddressingMode::Immediate => 1,
+            _ if self.indexed::<MODE>() && !self.page_crossed::<MODE>() => {
+                self.address_cycles::<MODE>()
+            }
+            _ => self.address_cycles::<MODE>() + 1,
+        }
     }
 
-    pub fn addr_rel(&mut self) -> u8 {
-        self.addr_rel = self.read(self.pc) as u16;
-        self.pc += 1;
-        if self.addr_rel & 0x80 != 0 {
-            self.addr_rel |= 0xFF00;
+    // For instructions that read their operand; op is given it on the cycle
+    // it's read. An indexed read that stays in its page was made from the
+    // right address while indexing, and the fix-up cycle is skipped.
+    fn read_operand<const MODE: u8>(&mut self, mem: &mut dyn Memory, op: fn(&mut Cpu, u8)) -> bool {
+        if mode::<MODE>() == AddressingMode::Immediate {
+            let value = self.fetch_pc(mem);
+            op(self, value);
+            return true;
+        }
+
+        let cycles = self.address_cycles::<MODE>();
+        if self.step < cycles {
+            self.address_step::<MODE>(mem);
+            return false;
+        }
+        if self.step == cycles {
+            self.address_step::<MODE>(mem);
+            if !self.indexed::<MODE>() || self.page_crossed::<MODE>() {
+                return false;
+            }
+            self.dummy = false;
+        } else {
+            self.fetched = mem.read(self.addr);
         }
-        0
+        op(self, self.fetched);
+        true
     }
 
-    pub fn addr_abs(&mut self) -> u8 {
-        let lo = self.read(self.pc) as u16;
-        self.pc += 1;
+    // Stores always take the fix-up cycle
+    fn write_operand<const MODE: u8>(&mut self, mem: &mut dyn Memory, value: u8) -> bool {
+        if self.step <= self.address_cycles::<MODE>() {
+            self.address_step::<MODE>(mem);
+            return false;
+        }
+        mem.write(self.addr, value);
+        true
+    }
+
+    // Read-modify-write instructions read the operand, write it back
+    // unchanged while op works out the new value, then write that. The
+    // 65C02 reads it again instead of writing it back.
+    fn modify_operand<const MODE: u8>(
+        &mut self,
+        mem: &mut dyn Memory,
+        op: fn(&mut Cpu, u8) -> u8,
+    ) -> bool {
+        if mode::<MODE>() == AddressingMode::Accumulator {
+            self.dummy_read(mem, self.pc);
+            self.a = op(self, self.a);
+            return true;
+        }
 
-        let hi = self.read(self.pc) as u16;
-        self.pc += 1;
+        let cycles = self.address_cycles::<MODE>();
+        if self.step <= cycles {
+            self.address_step::<MODE>(mem);
+            if self.step == cycles && self.skips_fix_up::<MODE>() {
+                self.dummy = false;
+            }
+            return false;
+        }
+        match self.step - cycles + self.skips_fix_up::<MODE>() as u8 {
+            1 => {
+                self.fetched = mem.read(self.addr);
+                false
+            }
+            2 => {
+                if self.variant.is_cmos() {
+                    self.dummy_read(mem, self.addr);
+                } else {
+                    mem.write(self.addr, self.fetched);
+                }
+                self.fetched = op(self, self.fetched);
+                false
+            }
+            _ => {
+                mem.write(self.addr, self.fetched);
+                true
+            }
+        }
+    }
 
-        self.addr_abs = (hi << 8) | lo;
+    // The 65C02's indexed shifts and rotates take the operand from the
+    // indexing read when it stays in the page, as other reads do. The table
+    // gives them a cycle fewer than INC and DEC, which always fix up.
+    fn skips_fix_up<const MODE: u8>(&self) -> bool {
+        self.indexed::<MODE>()
+            && !self.page_crossed::<MODE>()
+            && self.lookup[self.opcode as usize].cycles == self.address_cycles::<MODE>() + 3
+    }
+
+    // Decimal ADC and SBC take a cycle more on the 65C02, rereading the
+    // operand while the flags are fixed
+    fn arithmetic<const MODE: u8>(&mut self, mem: &mut dyn Memory, op: fn(&mut Cpu, u8)) -> bool {
+        let extra = self.variant.is_cmos() && self.decimal_mode();
+        if extra && self.step > self.operand_step::<MODE>() {
+            let addr = match mode::<MODE>() {
+                AddressingMode::Immediate => self.pc.wrapping_sub(1),
+                _ => self.addr,
+            };
+            self.dummy_read(mem, addr);
+            return true;
+        }
+        self.read_operand::<MODE>(mem, op) && !extra
+    }
 
-        0
+    // SHA, SHX, SHY and TAS store a value ANDed with the high byte of the
+    // address before indexing, plus one. When the index crosses a page
+    // the value also replaces the high byte of the address written to.
+    fn write_high_and<const MODE: u8>(&mut self, mem: &mut dyn Memory, value: u8) -> bool {
+        if self.step <= self.address_cycles::<MODE>() {
+            self.address_step::<MODE>(mem);
+            return false;
+        }
+        let data = value & ((self.ptr >> 8) as u8).wrapping_add(1);
+        if self.page_crossed::<MODE>() {
+            self.addr = ((data as u16) << 8) | (self.addr & 0x00FF);
+        }
+        mem.write(self.addr, data);
+        true
     }
 
-    pub fn addr_abx(&mut self) -> u8 {
-        let lo = self.read(self.pc) as u16;
-        self.pc += 1;
-        let hi = self.read(self.pc) as u16;
-        self.pc += 1;
+    // Single byte instructions read the byte after the opcode and ignore it
+    fn implied(&mut self, mem: &mut dyn Memory, op: fn(&mut Cpu)) -> bool {
+        self.dummy_read(mem, self.pc);
+        op(self);
+        true
+    }
 
-        self.addr_abs = (hi << 8) | lo;
-        self.addr_abs += self.x as u16;
+    fn push_register(&mut self, mem: &mut dyn Memory, value: u8) -> bool {
+        if self.step == 1 {
+            self.dummy_read(mem, self.pc);
+            return false;
+        }
+        self.push(mem, value);
+        true
+    }
 
-        if (self.addr_abs & 0xFF00) != (hi << 8) {
-            1
-        } else {
-            0
+    fn pull_register(&mut self, mem: &mut dyn Memory, op: fn(&mut Cpu, u8)) -> bool {
+        match self.step {
+            1 => {
+                self.dummy_read(mem, self.pc);
+                false
+            }
+            2 => {
+                self.dummy_read(mem, 0x0100 | self.stkp as u16);
+                self.stkp = self.stkp.wrapping_add(1);
+                false
+            }
+            _ => {
+                let value = self.read_stack(mem);
+                op(self, value);
+                true
+            }
         }
     }
 
-    pub fn addr_aby(&mut self) -> u8 {
-        let lo = self.read(self.pc) as u16;
-        self.pc += 1;
-        let hi = self.read(self.pc) as u16;
-        self.pc += 1;
+    // A taken branch adds a cycle to move PC, and another if the low byte
+    // carried into the high one, reading from the wrong page meanwhile.
+    // Interrupts are polled after the opcode fetch and before the fix-up,
+    // but not before the cycle that moves PC, so one that arrives then
+    // waits for the instruction after a branch that stays in its page.
+    fn branch<const MODE: u8>(&mut self, mem: &mut dyn Memory, taken: bool) -> bool {
+        // BBR and BBS test their bit first
+        let step = match mode::<MODE>() {
+            AddressingMode::ZeroPageRelative => self.step - 3,
+            _ => self.step,
+        };
+        match step {
+            1 => {
+                self.fetched = self.fetch_pc(mem);
+                !taken
+            }
+            2 => {
+                self.dummy_read(mem, self.pc);
+                self.addr = self.pc.wrapping_add(self.fetched as i8 as u16);
+                self.pc = (self.pc & 0xFF00) | (self.addr & 0x00FF);
+                if self.pc != self.addr {
+                    return false;
+                }
+                self.polled = self.polled_before;
+                true
+            }
+            _ => {
+                self.dummy_read(mem, self.pc);
+                self.pc = self.addr;
+                true
+            }
+        }
+    }
+}
 
-        self.addr_abs = (hi << 8) | lo;
-        self.addr_abs += self.y as u16;
+// Arithmetic shared between instructions
+impl Cpu {
+    fn decimal_mode(&self) -> bool {
+        self.variant.has_decimal_mode() && self.get_flag(StatusFlags::DECIMAL_MODE)
+    }
 
-        if (self.addr_abs & 0xFF00) != (hi << 8) {
-            1
+    fn add(&mut self, value: u8) {
+        if self.decimal_mode() {
+            self.add_decimal(value);
         } else {
-            0
+            self.add_binary(value);
         }
     }
 
-    pub fn addr_ind(&mut self) -> u8 {
-        let ptr_lo = self.read(self.pc) as u16;
-        self.pc += 1;
-        let ptr_hi = self.read(self.pc) as u16;
-        self.pc += 1;
+    fn add_binary(&mut self, value: u8) {
+        let sum = self.a as u16 + value as u16 + self.get_flag(StatusFlags::CARRY) as u16;
+        let result = sum as u8;
+        self.set_flag(StatusFlags::CARRY, sum > 0xFF);
+        self.set_flag(
+            StatusFlags::OVERFLOW,
+            (!(self.a ^ value) & (self.a ^ result)) & 0x80 != 0,
+        );
+        self.a = result;
+        self.set_nz(result);
+    }
 
-        let ptr = (ptr_hi << 8) | ptr_lo;
+    // Each digit is corrected as it's added. The NMOS chip takes Z from the
+    // binary sum, and N and V from the sum before the high digit is
+    // corrected, so they mean little with BCD operands. The 65C02 fixes N
+    // and Z.
+    fn add_decimal(&mut self, value: u8) {
+        let carry = self.get_flag(StatusFlags::CARRY) as u16;
+        let binary = (self.a as u16 + value as u16 + carry) as u8;
 
-        if ptr_lo == 0x00FF {
-            self.addr_abs = ((self.read(ptr & 0xFF00) << 8) | self.read(ptr + 0)) as u16;
-        } else {
-            self.addr_abs = ((self.read(ptr + 1) << 8) | self.read(ptr + 0)) as u16;
+        let mut low = (self.a & 0x0F) as u16 + (value & 0x0F) as u16 + carry;
+        if low >= 0x0A {
+            low = ((low + 0x06) & 0x0F) + 0x10;
         }
+        let mut sum = (self.a & 0xF0) as u16 + (value & 0xF0) as u16 + low;
 
-        0
+        self.set_flag(StatusFlags::ZERO, binary == 0);
+        self.set_flag(StatusFlags::NEGATIVE, sum & 0x80 != 0);
+        self.set_flag(
+            StatusFlags::OVERFLOW,
+            (!(self.a ^ value) & (self.a ^ sum as u8)) & 0x80 != 0,
+        );
+        if sum >= 0xA0 {
+            sum += 0x60;
+        }
+        self.set_flag(StatusFlags::CARRY, sum > 0xFF);
+        self.a = sum as u8;
+        if self.variant.is_cmos() {
+            self.set_nz(self.a);
+        }
     }
 
-    pub fn addr_izx(&mut self) -> u8 {
-        let t = self.read(self.pc) as u16;
-        self.pc += 1;
+    // In decimal mode C and V are still the binary subtraction's, and only
+    // the result is corrected. The 65C02 corrects it a little differently,
+    // and takes N and Z from it.
+    fn subtract(&mut self, value: u8) {
+        let a = self.a;
+        let borrow = !self.get_flag(StatusFlags::CARRY) as i16;
+        self.add_binary(!value);
+        if !self.decimal_mode() {
+            return;
+        }
 
-        let lo = self.read((t + self.x as u16) & 0x00FF) as u16;
-        let hi = self.read((t + self.x as u16 + 1) & 0x00FF) as u16;
+        if self.variant.is_cmos() {
+            let low = (a & 0x0F) as i16 - (value & 0x0F) as i16 - borrow;
+            let mut difference = a as i16 - value as i16 - borrow;
+            if difference < 0 {
+                difference -= 0x60;
+            }
+            if low < 0 {
+                difference -= 0x06;
+            }
+            self.a = difference as u8;
+            self.set_nz(self.a);
+            return;
+        }
 
-        self.addr_abs = (hi << 8) | lo;
+        let mut low = (a & 0x0F) as i16 - (value & 0x0F) as i16 - borrow;
+        if low < 0 {
+            low = ((low - 0x06) & 0x0F) - 0x10;
+        }
+        let mut difference = (a & 0xF0) as i16 - (value & 0xF0) as i16 + low;
+        if difference < 0 {
+            difference -= 0x60;
+        }
+        self.a = difference as u8;
+    }
 
-        0
+    fn compare(&mut self, register: u8, value: u8) {
+        self.set_flag(StatusFlags::CARRY, register >= value);
+        self.set_nz(register.wrapping_sub(value));
     }
 
-    pub fn addr_izy(&mut self) -> u8 {
-        let t = self.read(self.pc) as u16;
-        self.pc += 1;
+    fn shift_left(&mut self, value: u8) -> u8 {
+        self.set_flag(StatusFlags::CARRY, value & 0x80 != 0);
+        let result = value << 1;
+        self.set_nz(result);
+        result
+    }
 
-        let lo = self.read(t & 0x00FF) as u16;
-        let hi = self.read((t + 1) & 0x00FF) as u16;
+    fn shift_right(&mut self, value: u8) -> u8 {
+        self.set_flag(StatusFlags::CARRY, value & 0x01 != 0);
+        let result = value >> 1;
+        self.set_nz(result);
+        result
+    }
 
-        self.addr_abs = (hi << 8) | lo;
-        self.addr_abs += self.y as u16;
+    fn rotate_left(&mut self, value: u8) -> u8 {
+        let result = (value << 1) | self.get_flag(StatusFlags::CARRY) as u8;
+        self.set_flag(StatusFlags::CARRY, value & 0x80 != 0);
+        self.set_nz(result);
+        result
+    }
 
-        if (self.addr_abs & 0xFF00) != (hi << 8) {
-            1
-        } else {
-            0
-        }
+    fn rotate_right(&mut self, value: u8) -> u8 {
+        let result = (value >> 1) | ((self.get_flag(StatusFlags::CARRY) as u8) << 7);
+        self.set_flag(StatusFlags::CARRY, value & 0x01 != 0);
+        self.set_nz(result);
+        result
     }
 }
 
 // OpCodes
 impl Cpu {
-    pub fn adc(&mut self) -> u8 {
-        self.fetch();
-        let temp =
-            (self.a as u16) + (self.fetched as u16) + (self.get_flag(StatusFlags::CARRY) as u16);
-        self.set_flag(StatusFlags::CARRY, temp > 255);
-        self.set_flag(StatusFlags::ZERO, (temp & 0x00FF) == 0);
-        self.set_flag(
-            StatusFlags::OVERFLOW,
-            ((!((self.a as u16) ^ (self.fetched as u16)) & ((self.a as u16) ^ (temp as u16)))
-                & 0x0080)
-                != 0,
-        );
-        self.set_flag(StatusFlags::NEGATIVE, (temp & 0x80) != 0);
-        self.a = (temp & 0x00FF) as u8;
-        return 1;
+    pub fn adc<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.arithmetic::<MODE>(mem, |cpu, value| cpu.add(value))
     }
 
-    pub fn sbc(&mut self) -> u8 {
-        self.fetch();
-        let value = (self.fetched as u16) ^ 0x00FF;
+    pub fn and<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.read_operand::<MODE>(mem, |cpu, value| {
+            cpu.a &= value;
+            cpu.set_nz(cpu.a);
+        })
+    }
 
-        let temp = (self.a as u16) + value + (self.get_flag(StatusFlags::CARRY) as u16);
-        self.set_flag(StatusFlags::CARRY, (temp & 0xFF00) != 0);
-        self.set_flag(StatusFlags::ZERO, (temp & 0x00FF) == 0);
-        self.set_flag(
-            StatusFlags::OVERFLOW,
-            ((temp ^ (self.a as u16)) & (temp ^ value) & 0x0080) != 0,
-        );
-        self.set_flag(StatusFlags::NEGATIVE, (temp & 0x0080) != 0);
-        self.a = (temp & 0x00FF) as u8;
-        return 1;
-    }
-
-    pub fn and(&mut self) -> u8 {
-        self.fetch();
-        self.a = self.a & self.fetched;
-        self.set_flag(StatusFlags::ZERO, self.a == 0x00);
-        self.set_flag(StatusFlags::NEGATIVE, (self.a & 0x80) != 0);
-        return 1;
-    }
-
-    pub fn asl(&mut self) -> u8 {
-        self.fetch();
-        let temp = (self.fetched as u16) << 1;
-        self.set_flag(StatusFlags::CARRY, (temp & 0xFF00) > 0);
-        self.set_flag(StatusFlags::ZERO, (temp & 0x00FF) == 0x00);
-        self.set_flag(StatusFlags::NEGATIVE, (temp & 0x80) != 0);
-        if matches!(
-            self.lookup[self.opcode as usize].mode,
-            AddressingMode::Implied
-        ) {
-            self.a = (temp & 0x00FF) as u8;
-        } else {
-            self.write(self.addr_abs, (temp & 0x00FF) as u8);
-        }
-        return 0;
+    pub fn asl<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.modify_operand::<MODE>(mem, Cpu::shift_left)
     }
 
-    pub fn bcc(&mut self) -> u8 {
-        if self.get_flag(StatusFlags::CARRY) == false {
-            self.cycles += 1;
-            self.addr_abs = self.pc + self.addr_rel;
+    pub fn bcc<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.branch::<MODE>(mem, !self.get_flag(StatusFlags::CARRY))
+    }
 
-            if (self.addr_abs & 0xFF00) != (self.pc & 0xFF00) {
-                self.cycles += 1;
+    pub fn bcs<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.branch::<MODE>(mem, self.get_flag(StatusFlags::CARRY))
+    }
+
+    pub fn beq<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.branch::<MODE>(mem, self.get_flag(StatusFlags::ZERO))
+    }
+
+    // The 65C02's BIT #imm only sets Z
+    pub fn bit<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.read_operand::<MODE>(mem, |cpu, value| {
+            cpu.set_flag(StatusFlags::ZERO, cpu.a & value == 0);
+            if mode::<MODE>() != AddressingMode::Immediate {
+                cpu.set_flag(StatusFlags::OVERFLOW, value & 0x40 != 0);
+                cpu.set_flag(StatusFlags::NEGATIVE, value & 0x80 != 0);
             }
+        })
+    }
 
-            self.pc = self.addr_abs;
-        }
-        return 0;
+    pub fn bmi<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.branch::<MODE>(mem, self.get_flag(StatusFlags::NEGATIVE))
     }
 
-    pub fn bcs(&mut self) -> u8 {
-        if self.get_flag(StatusFlags::CARRY) == true {
-            self.cycles += 1;
-            self.addr_abs = self.pc + self.addr_rel;
+    pub fn bne<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.branch::<MODE>(mem, !self.get_flag(StatusFlags::ZERO))
+    }
 
-            if (self.addr_abs & 0xFF00) != (self.pc & 0xFF00) {
-                self.cycles += 1;
-            }
+    pub fn bpl<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.branch::<MODE>(mem, !self.get_flag(StatusFlags::NEGATIVE))
+    }
 
-            self.pc = self.addr_abs;
+    // Also runs interrupts, which leave PC alone and push B clear. Which
+    // vector is used is only decided as P is pushed, so an NMI seen by then
+    // takes over the sequence, even BRK's. None of the sequence polls, so
+    // the first instruction of the handler always runs. The 65C02 also
+    // clears D.
+    pub fn brk<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        let software = self.servicing.is_none();
+        match self.step {
+            1 => {
+                self.dummy_read(mem, self.pc);
+                if software {
+                    self.pc = self.pc.wrapping_add(1);
+                }
+                false
+            }
+            2 => {
+                self.push(mem, (self.pc >> 8) as u8);
+                false
+            }
+            3 => {
+                self.push(mem, self.pc as u8);
+                false
+            }
+            4 => {
+                let mut status = self.status | StatusFlags::UNUSED;
+                status.set(StatusFlags::BREAK, software);
+                self.push(mem, status.bits());
+                self.ptr = if std::mem::take(&mut self.nmi_detected) {
+                    self.servicing = Some(Interrupt::Nmi);
+                    0xFFFA
+                } else {
+                    0xFFFE
+                };
+                false
+            }
+            5 => {
+                self.addr = mem.read(self.ptr) as u16;
+                self.set_flag(StatusFlags::INTERRUPT_DISABLE, true);
+                if self.variant.is_cmos() {
+                    self.set_flag(StatusFlags::DECIMAL_MODE, false);
+                }
+                false
+            }
+            _ => {
+                self.pc = self.addr | ((mem.read(self.ptr + 1) as u16) << 8);
+                self.interrupt = Some(self.servicing.take().unwrap_or(Interrupt::Brk));
+                self.polled = false;
+                true
+            }
         }
-        return 0;
     }
 
-    pub fn beq(&mut self) -> u8 {
-        if self.get_flag(StatusFlags::ZERO) == true {
-            self.cycles += 1;
-            self.addr_abs = self.pc + self.addr_rel;
+    pub fn bvc<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.branch::<MODE>(mem, !self.get_flag(StatusFlags::OVERFLOW))
+    }
 
-            if (self.addr_abs & 0xFF00) != (self.pc & 0xFF00) {
-                self.cycles += 1;
-            }
+    pub fn bvs<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.branch::<MODE>(mem, self.get_flag(StatusFlags::OVERFLOW))
+    }
 
-            self.pc = self.addr_abs;
-        }
-        return 0;
+    pub fn clc(&mut self, mem: &mut dyn Memory) -> bool {
+        self.implied(mem, |cpu| cpu.set_flag(StatusFlags::CARRY, false))
     }
 
-    pub fn bit(&mut self) -> u8 {
-        self.fetch();
-        let temp = self.a & self.fetched;
-        self.set_flag(StatusFlags::ZERO, (temp & 0x00FF) == 0x00);
-        self.set_flag(StatusFlags::NEGATIVE, (self.fetched & (1 << 7)) != 0);
-        self.set_flag(StatusFlags::OVERFLOW, (self.fetched & (1 << 6)) != 0);
-        return 0;
+    pub fn cld(&mut self, mem: &mut dyn Memory) -> bool {
+        self.implied(mem, |cpu| cpu.set_flag(StatusFlags::DECIMAL_MODE, false))
     }
 
-    pub fn bmi(&mut self) -> u8 {
-        if self.get_flag(StatusFlags::NEGATIVE) == true {
-            self.cycles += 1;
-            self.addr_abs = self.pc + self.addr_rel;
+    pub fn cli(&mut self, mem: &mut dyn Memory) -> bool {
+        self.implied(mem, |cpu| {
+            cpu.set_flag(StatusFlags::INTERRUPT_DISABLE, false)
+        })
+    }
 
-            if (self.addr_abs & 0xFF00) != (self.pc & 0xFF00) {
-                self.cycles += 1;
-            }
+    pub fn clv(&mut self, mem: &mut dyn Memory) -> bool {
+        self.implied(mem, |cpu| cpu.set_flag(StatusFlags::OVERFLOW, false))
+    }
 
-            self.pc = self.addr_abs;
-        }
-        return 0;
+    pub fn cmp<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.read_operand::<MODE>(mem, |cpu, value| cpu.compare(cpu.a, value))
+    }
+
+    pub fn cpx<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.read_operand::<MODE>(mem, |cpu, value| cpu.compare(cpu.x, value))
+    }
+
+    pub fn cpy<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.read_operand::<MODE>(mem, |cpu, value| cpu.compare(cpu.y, value))
     }
 
-    pub fn bne(&mut self) -> u8 {
-        if self.get_flag(StatusFlags::ZERO) == false {
-            self.cycles += 1;
-            self.addr_abs = self.pc + self.addr_rel;
+    pub fn dec<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.modify_operand::<MODE>(mem, |cpu, value| {
+            let result = value.wrapping_sub(1);
+            cpu.set_nz(result);
+            result
+        })
+    }
+
+    pub fn dex(&mut self, mem: &mut dyn Memory) -> bool {
+        self.implied(mem, |cpu| {
+            cpu.x = cpu.x.wrapping_sub(1);
+            cpu.set_nz(cpu.x);
+        })
+    }
+
+    pub fn dey(&mut self, mem: &mut dyn Memory) -> bool {
+        self.implied(mem, |cpu| {
+            cpu.y = cpu.y.wrapping_sub(1);
+            cpu.set_nz(cpu.y);
+        })
+    }
+
+    pub fn eor<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.read_operand::<MODE>(mem, |cpu, value| {
+            cpu.a ^= value;
+            cpu.set_nz(cpu.a);
+        })
+    }
+
+    pub fn inc<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.modify_operand::<MODE>(mem, |cpu, value| {
+            let result = value.wrapping_add(1);
+            cpu.set_nz(result);
+            result
+        })
+    }
+
+    pub fn inx(&mut self, mem: &mut dyn Memory) -> bool {
+        self.implied(mem, |cpu| {
+            cpu.x = cpu.x.wrapping_add(1);
+            cpu.set_nz(cpu.x);
+        })
+    }
 
-            if (self.addr_abs & 0xFF00) != (self.pc & 0xFF00) {
-                self.cycles += 1;
+    pub fn iny(&mut self, mem: &mut dyn Memory) -> bool {
+        self.implied(mem, |cpu| {
+            cpu.y = cpu.y.wrapping_add(1);
+            cpu.set_nz(cpu.y);
+        })
+    }
+
+    // The 65C02 takes a cycle between fetching the pointer and reading
+    // through it, adding X for JMP (abs,X)
+    pub fn jmp<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        let cmos = self.variant.is_cmos();
+        match (mode::<MODE>(), self.step) {
+            (_, 1) => {
+                self.ptr = self.fetch_pc(mem) as u16;
+                false
+            }
+            (AddressingMode::Absolute, _) => {
+                self.pc = self.ptr | ((mem.read(self.pc) as u16) << 8);
+                true
+            }
+            (_, 2) => {
+                self.ptr |= (self.fetch_pc(mem) as u16) << 8;
+                false
+            }
+            (mode, 3) if cmos => {
+                self.dummy_read(mem, self.pc.wrapping_sub(1));
+                if mode == AddressingMode::AbsoluteIndirectX {
+                    self.ptr = self.ptr.wrapping_add(self.x as u16);
+                }
+                false
+            }
+            (_, step) if step == 3 + cmos as u8 => {
+                self.addr = mem.read(self.ptr) as u16;
+                false
             }
+            _ if cmos => {
+                self.pc = self.addr | ((mem.read(self.ptr.wrapping_add(1)) as u16) << 8);
+                true
+            }
+            // The pointer's high byte comes from the same page as its low
+            // byte, so JMP ($10FF) reads $10FF and $1000
+            _ => {
+                let ptr = (self.ptr & 0xFF00) | (self.ptr.wrapping_add(1) & 0x00FF);
+                self.pc = self.addr | ((mem.read(ptr) as u16) << 8);
+                true
+            }
+        }
+    }
 
-            self.pc = self.addr_abs;
+    // The return address pushed is that of JSR's last byte
+    pub fn jsr<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        match self.step {
+            1 => {
+                self.addr = self.fetch_pc(mem) as u16;
+                false
+            }
+            2 => {
+                self.dummy_read(mem, 0x0100 | self.stkp as u16);
+                false
+            }
+            3 => {
+                self.push(mem, (self.pc >> 8) as u8);
+                false
+            }
+            4 => {
+                self.push(mem, self.pc as u8);
+                false
+            }
+            _ => {
+                self.pc = self.addr | ((mem.read(self.pc) as u16) << 8);
+                true
+            }
         }
-        return 0;
     }
 
-    pub fn bpl(&mut self) -> u8 {
-        if self.get_flag(StatusFlags::NEGATIVE) == false {
-            self.cycles += 1;
-            self.addr_abs = self.pc + self.addr_rel;
+    pub fn lda<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.read_operand::<MODE>(mem, |cpu, value| {
+            cpu.a = value;
+            cpu.set_nz(value);
+        })
+    }
 
-            if (self.addr_abs & 0xFF00) != (self.pc & 0xFF00) {
-                self.cycles += 1;
-            }
+    pub fn ldx<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.read_operand::<MODE>(mem, |cpu, value| {
+            cpu.x = value;
+            cpu.set_nz(value);
+        })
+    }
+
+    pub fn ldy<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.read_operand::<MODE>(mem, |cpu, value| {
+            cpu.y = value;
+            cpu.set_nz(value);
+        })
+    }
+
+    pub fn lsr<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.modify_operand::<MODE>(mem, Cpu::shift_right)
+    }
 
-            self.pc = self.addr_abs;
+    // The unofficial NOPs still read their operand. The 65C02's $5C goes
+    // on reading it for as long as the table says.
+    pub fn nop<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        if mode::<MODE>() == AddressingMode::Implied {
+            return self.implied(mem, |_| {});
         }
-        return 0;
+        self.read_operand::<MODE>(mem, |_, _| {})
+            && self.step + 1 >= self.lookup[self.opcode as usize].cycles
     }
 
-    pub fn brk(&mut self) -> u8 {
-        self.pc += 1;
+    pub fn ora<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.read_operand::<MODE>(mem, |cpu, value| {
+            cpu.a |= value;
+            cpu.set_nz(cpu.a);
+        })
+    }
 
-        self.set_flag(StatusFlags::INTERRUPT_DISABLE, true);
-        self.write(0x0100 + (self.stkp as u16), ((self.pc >> 8) & 0x00FF) as u8);
-        self.stkp -= 1;
-        self.write(0x0100 + (self.stkp as u16), (self.pc & 0x00FF) as u8);
-        self.stkp -= 1;
-
-        self.set_flag(StatusFlags::BREAK, true);
-        self.write(0x0100 + (self.stkp as u16), self.status.bits());
-        self.stkp -= 1;
-        self.set_flag(StatusFlags::BREAK, false);
+    pub fn pha(&mut self, mem: &mut dyn Memory) -> bool {
+        self.push_register(mem, self.a)
+    }
 
-        self.pc = (self.read(0xFFFE) as u16) | ((self.read(0xFFFF) as u16) << 8);
-        return 0;
+    pub fn php(&mut self, mem: &mut dyn Memory) -> bool {
+        let status = self.status | StatusFlags::BREAK | StatusFlags::UNUSED;
+        self.push_register(mem, status.bits())
     }
 
-    pub fn bvc(&mut self) -> u8 {
-        if self.get_flag(StatusFlags::OVERFLOW) == false {
-            self.cycles += 1;
-            self.addr_abs = self.pc + self.addr_rel;
+    pub fn pla(&mut self, mem: &mut dyn Memory) -> bool {
+        self.pull_register(mem, |cpu, value| {
+            cpu.a = value;
+            cpu.set_nz(value);
+        })
+    }
 
-            if (self.addr_abs & 0xFF00) != (self.pc & 0xFF00) {
-                self.cycles += 1;
-            }
+    pub fn plp(&mut self, mem: &mut dyn Memory) -> bool {
+        self.pull_register(mem, Cpu::set_status)
+    }
 
-            self.pc = self.addr_abs;
-        }
-        return 0;
+    pub fn rol<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.modify_operand::<MODE>(mem, Cpu::rotate_left)
     }
 
-    pub fn bvs(&mut self) -> u8 {
-        if self.get_flag(StatusFlags::OVERFLOW) == true {
-            self.cycles += 1;
-            self.addr_abs = self.pc + self.addr_rel;
+    pub fn ror<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.modify_operand::<MODE>(mem, Cpu::rotate_right)
+    }
 
-            if (self.addr_abs & 0xFF00) != (self.pc & 0xFF00) {
-                self.cycles += 1;
+    pub fn rti(&mut self, mem: &mut dyn Memory) -> bool {
+        match self.step {
+            1 => {
+                self.dummy_read(mem, self.pc);
+                false
+            }
+            2 => {
+                self.dummy_read(mem, 0x0100 | self.stkp as u16);
+                self.stkp = self.stkp.wrapping_add(1);
+                false
+            }
+            3 => {
+                let status = self.read_stack(mem);
+                self.set_status(status);
+                self.stkp = self.stkp.wrapping_add(1);
+                false
             }
+            4 => {
+                self.addr = self.read_stack(mem) as u16;
+                self.stkp = self.stkp.wrapping_add(1);
+                false
+            }
+            _ => {
+                self.pc = self.addr | ((self.read_stack(mem) as u16) << 8);
+                true
+            }
+        }
+    }
 
-            self.pc = self.addr_abs;
+    // The address pulled is one short of the return address, and PC is
+    // moved on from it in a cycle of its own
+    pub fn rts(&mut self, mem: &mut dyn Memory) -> bool {
+        match self.step {
+            1 => {
+                self.dummy_read(mem, self.pc);
+                false
+            }
+            2 => {
+                self.dummy_read(mem, 0x0100 | self.stkp as u16);
+                self.stkp = self.stkp.wrapping_add(1);
+                false
+            }
+            3 => {
+                self.addr = self.read_stack(mem) as u16;
+                self.stkp = self.stkp.wrapping_add(1);
+                false
+            }
+            4 => {
+                self.addr |= (self.read_stack(mem) as u16) << 8;
+                false
+            }
+            _ => {
+                self.dummy_read(mem, self.addr);
+                self.pc = self.addr.wrapping_add(1);
+                true
+            }
         }
-        return 0;
     }
 
-    pub fn clc(&mut self) -> u8 {
-        self.set_flag(StatusFlags::CARRY, false);
-        return 0;
+    pub fn sbc<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.arithmetic::<MODE>(mem, |cpu, value| cpu.subtract(value))
+    }
+
+    pub fn sec(&mut self, mem: &mut dyn Memory) -> bool {
+        self.implied(mem, |cpu| cpu.set_flag(StatusFlags::CARRY, true))
     }
 
-    pub fn cld(&mut self) -> u8 {
-        self.set_flag(StatusFlags::DECIMAL_MODE, false);
-        return 0;
+    pub fn sed(&mut self, mem: &mut dyn Memory) -> bool {
+        self.implied(mem, |cpu| cpu.set_flag(StatusFlags::DECIMAL_MODE, true))
     }
 
-    pub fn cli(&mut self) -> u8 {
-        self.set_flag(StatusFlags::INTERRUPT_DISABLE, false);
-        return 0;
+    pub fn sei(&mut self, mem: &mut dyn Memory) -> bool {
+        self.implied(mem, |cpu| {
+            cpu.set_flag(StatusFlags::INTERRUPT_DISABLE, true)
+        })
     }
 
-    pub fn clv(&mut self) -> u8 {
-        self.set_flag(StatusFlags::OVERFLOW, false);
-        return 0;
+    pub fn sta<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.write_operand::<MODE>(mem, self.a)
     }
 
-    pub fn cmp(&mut self) -> u8 {
-        self.fetch();
-        let temp = (self.a as u16) - (self.fetched as u16);
-        self.set_flag(StatusFlags::CARRY, self.a >= self.fetched);
-        self.set_flag(StatusFlags::ZERO, (temp & 0x00FF) == 0x0000);
-        self.set_flag(StatusFlags::NEGATIVE, (temp & 0x0080) != 0);
-        return 1;
+    pub fn stx<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.write_operand::<MODE>(mem, self.x)
     }
 
-    pub fn cpx(&mut self) -> u8 {
-        self.fetch();
-        let temp = (self.x as u16) - (self.fetched as u16);
-        self.set_flag(StatusFlags::CARRY, self.x >= self.fetched);
-        self.set_flag(StatusFlags::ZERO, (temp & 0x00FF) == 0x0000);
-        self.set_flag(StatusFlags::NEGATIVE, (temp & 0x0080) != 0);
-        return 0;
+    pub fn sty<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.write_operand::<MODE>(mem, self.y)
     }
 
-    pub fn cpy(&mut self) -> u8 {
-        self.fetch();
-        let temp = (self.y as u16) - (self.fetched as u16);
-        self.set_flag(StatusFlags::CARRY, self.y >= self.fetched);
-        self.set_flag(StatusFlags::ZERO, (temp & 0x00FF) == 0x0000);
-        self.set_flag(StatusFlags::NEGATIVE, (temp & 0x0080) != 0);
-        return 0;
+    pub fn tax(&mut self, mem: &mut dyn Memory) -> bool {
+        self.implied(mem, |cpu| {
+            cpu.x = cpu.a;
+            cpu.set_nz(cpu.x);
+        })
     }
 
-    pub fn dec(&mut self) -> u8 {
-        self.fetch();
-        let temp = self.fetched - 1;
-        self.write(self.addr_abs, (temp & 0x00FF) as u8);
-        self.set_flag(StatusFlags::ZERO, (temp & 0x00FF) == 0x0000);
-        self.set_flag(StatusFlags::NEGATIVE, (temp & 0x0080) != 0);
-        return 0;
+    pub fn tay(&mut self, mem: &mut dyn Memory) -> bool {
+        self.implied(mem, |cpu| {
+            cpu.y = cpu.a;
+            cpu.set_nz(cpu.y);
+        })
     }
 
-    pub fn dex(&mut self) -> u8 {
-        self.x -= 1;
-        self.set_flag(StatusFlags::ZERO, self.x == 0x00);
-        self.set_flag(StatusFlags::NEGATIVE, (self.x & 0x80) != 0);
-        return 0;
+    pub fn tsx(&mut self, mem: &mut dyn Memory) -> bool {
+        self.implied(mem, |cpu| {
+            cpu.x = cpu.stkp;
+            cpu.set_nz(cpu.x);
+        })
     }
 
-    pub fn dey(&mut self) -> u8 {
-        self.y -= 1;
-        self.set_flag(StatusFlags::ZERO, self.y == 0x00);
-        self.set_flag(StatusFlags::NEGATIVE, (self.y & 0x80) != 0);
-        return 0;
+    pub fn txa(&mut self, mem: &mut dyn Memory) -> bool {
+        self.implied(mem, |cpu| {
+            cpu.a = cpu.x;
+            cpu.set_nz(cpu.a);
+        })
     }
 
-    pub fn eor(&mut self) -> u8 {
-        self.fetch();
-        self.a = self.a ^ self.fetched;
-        self.set_flag(StatusFlags::ZERO, self.a == 0x00);
-        self.set_flag(StatusFlags::NEGATIVE, (self.a & 0x80) != 0);
-        return 1;
+    pub fn txs(&mut self, mem: &mut dyn Memory) -> bool {
+        self.implied(mem, |cpu| cpu.stkp = cpu.x)
     }
 
-    pub fn inc(&mut self) -> u8 {
-        self.fetch();
-        let temp = self.fetched + 1;
-        self.write(self.addr_abs, (temp & 0x00FF) as u8);
-        self.set_flag(StatusFlags::ZERO, (temp & 0x00FF) == 0x0000);
-        self.set_flag(StatusFlags::NEGATIVE, (temp & 0x0080) != 0);
-        return 0;
+    pub fn tya(&mut self, mem: &mut dyn Memory) -> bool {
+        self.implied(mem, |cpu| {
+            cpu.a = cpu.y;
+            cpu.set_nz(cpu.a);
+        })
     }
+}
 
-    pub fn inx(&mut self) -> u8 {
-        self.x += 1;
-        self.set_flag(StatusFlags::ZERO, self.x == 0x00);
-        self.set_flag(StatusFlags::NEGATIVE, (self.x & 0x80) != 0);
-        return 0;
+// Unofficial opcodes. The stable ones are combinations of official
+// instructions; the rest behave as measured on most NES consoles.
+impl Cpu {
+    pub fn alr<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.read_operand::<MODE>(mem, |cpu, value| cpu.a = cpu.shift_right(cpu.a & value))
     }
 
-    pub fn iny(&mut self) -> u8 {
-        self.y += 1;
-        self.set_flag(StatusFlags::ZERO, self.y == 0x00);
-        self.set_flag(StatusFlags::NEGATIVE, (self.y & 0x80) != 0);
-        return 0;
+    pub fn anc<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.read_operand::<MODE>(mem, |cpu, value| {
+            cpu.a &= value;
+            cpu.set_nz(cpu.a);
+            cpu.set_flag(StatusFlags::CARRY, cpu.a & 0x80 != 0);
+        })
     }
 
-    pub fn jmp(&mut self) -> u8 {
-        self.pc = self.addr_abs;
-        return 0;
+    pub fn ane<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.read_operand::<MODE>(mem, |cpu, value| {
+            cpu.a = (cpu.a | 0xEE) & cpu.x & value;
+            cpu.set_nz(cpu.a);
+        })
     }
 
-    pub fn jsr(&mut self) -> u8 {
-        self.pc -= 1;
+    pub fn arr<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.read_operand::<MODE>(mem, |cpu, value| {
+            cpu.a = cpu.rotate_right(cpu.a & value);
+            cpu.set_flag(StatusFlags::CARRY, cpu.a & 0x40 != 0);
+            cpu.set_flag(
+                StatusFlags::OVERFLOW,
+                ((cpu.a >> 6) ^ (cpu.a >> 5)) & 1 != 0,
+            );
+        })
+    }
 
-        self.write(0x0100 + self.stkp as u16, ((self.pc >> 8) & 0x00FF) as u8);
-        self.stkp -= 1;
-        self.write(0x0100 + self.stkp as u16, (self.pc & 0x00FF) as u8);
-        self.stkp -= 1;
+    pub fn dcp<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.modify_operand::<MODE>(mem, |cpu, value| {
+            let result = value.wrapping_sub(1);
+            cpu.compare(cpu.a, result);
+            result
+        })
+    }
 
-        self.pc = self.addr_abs;
-        return 0;
+    pub fn isc<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.modify_operand::<MODE>(mem, |cpu, value| {
+            let result = value.wrapping_add(1);
+            cpu.subtract(result);
+            result
+        })
     }
 
-    pub fn lda(&mut self) -> u8 {
-        self.fetch();
-        self.a = self.fetched;
-        self.set_flag(StatusFlags::ZERO, self.a == 0x00);
-        self.set_flag(StatusFlags::NEGATIVE, (self.a & 0x80) != 0);
-        return 1;
+    // Locks up the real chip until reset. Here it stays on its own opcode,
+    // fetching it again each time, so a debugger can still step.
+    pub fn jam(&mut self, mem: &mut dyn Memory) -> bool {
+        self.dummy_read(mem, self.pc);
+        self.pc = self.pc.wrapping_sub(1);
+        true
     }
 
-    pub fn ldx(&mut self) -> u8 {
-        self.fetch();
-        self.x = self.fetched;
-        self.set_flag(StatusFlags::ZERO, self.x == 0x00);
-        self.set_flag(StatusFlags::NEGATIVE, (self.x & 0x80) != 0);
-        return 1;
+    pub fn las<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.read_operand::<MODE>(mem, |cpu, value| {
+            let result = value & cpu.stkp;
+            cpu.a = result;
+            cpu.x = result;
+            cpu.stkp = result;
+            cpu.set_nz(result);
+        })
     }
 
-    pub fn ldy(&mut self) -> u8 {
-        self.fetch();
-        self.y = self.fetched;
-        self.set_flag(StatusFlags::ZERO, self.y == 0x00);
-        self.set_flag(StatusFlags::NEGATIVE, (self.y & 0x80) != 0);
-        return 1;
+    pub fn lax<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.read_operand::<MODE>(mem, |cpu, value| {
+            cpu.a = value;
+            cpu.x = value;
+            cpu.set_nz(value);
+        })
     }
 
-    pub fn lsr(&mut self) -> u8 {
-        self.fetch();
-        self.set_flag(StatusFlags::CARRY, (self.fetched & 0x0001) != 0);
-        let temp = self.fetched >> 1;
-        self.set_flag(StatusFlags::ZERO, (temp & 0x00FF) == 0x0000);
-        self.set_flag(StatusFlags::NEGATIVE, (temp & 0x0080) != 0);
-        if matches!(
-            self.lookup[self.opcode as usize].mode,
-            AddressingMode::Implied
-        ) {
-            self.a = temp & 0x00FF;
-        } else {
-            self.write(self.addr_abs, temp & 0x00FF);
+    pub fn lxa<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.read_operand::<MODE>(mem, |cpu, value| {
+            let result = (cpu.a | 0xEE) & value;
+            cpu.a = result;
+            cpu.x = result;
+            cpu.set_nz(result);
+        })
+    }
+
+    pub fn rla<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.modify_operand::<MODE>(mem, |cpu, value| {
+            let result = cpu.rotate_left(value);
+            cpu.a &= result;
+            cpu.set_nz(cpu.a);
+            result
+        })
+    }
+
+    pub fn rra<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.modify_operand::<MODE>(mem, |cpu, value| {
+            let result = cpu.rotate_right(value);
+            cpu.add(result);
+            result
+        })
+    }
+
+    pub fn sax<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.write_operand::<MODE>(mem, self.a & self.x)
+    }
+
+    pub fn sbx<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.read_operand::<MODE>(mem, |cpu, value| {
+            let ax = cpu.a & cpu.x;
+            cpu.x = ax.wrapping_sub(value);
+            cpu.set_flag(StatusFlags::CARRY, ax >= value);
+            cpu.set_nz(cpu.x);
+        })
+    }
+
+    pub fn sha<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.write_high_and::<MODE>(mem, self.a & self.x)
+    }
+
+    pub fn shx<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.write_high_and::<MODE>(mem, self.x)
+    }
+
+    pub fn shy<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.write_high_and::<MODE>(mem, self.y)
+    }
+
+    pub fn slo<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.modify_operand::<MODE>(mem, |cpu, value| {
+            let result = cpu.shift_left(value);
+            cpu.a |= result;
+            cpu.set_nz(cpu.a);
+            result
+        })
+    }
+
+    pub fn sre<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.modify_operand::<MODE>(mem, |cpu, value| {
+            let result = cpu.shift_right(value);
+            cpu.a ^= result;
+            cpu.set_nz(cpu.a);
+            result
+        })
+    }
+
+    pub fn tas<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.stkp = self.a & self.x;
+        self.write_high_and::<MODE>(mem, self.stkp)
+    }
+}
+
+// Instructions new to the 65C02, and Rockwell's and WDC's additions
+impl Cpu {
+    // RMB, SMB, BBR and BBS take their bit from the opcode
+    fn opcode_bit(&self) -> u8 {
+        (self.opcode >> 4) & 0x07
+    }
+
+    // Reads zero page and throws in a dummy read of it before branching
+    fn branch_on_bit<const MODE: u8>(&mut self, mem: &mut dyn Memory, set: bool) -> bool {
+        match self.step {
+            1 => {
+                self.addr = self.fetch_pc(mem) as u16;
+                false
+            }
+            2 => {
+                self.ptr = mem.read(self.addr) as u16;
+                false
+            }
+            3 => {
+                self.dummy_read(mem, self.addr);
+                false
+            }
+            _ => {
+                let bit = self.ptr & (1 << self.opcode_bit()) != 0;
+                self.branch::<MODE>(mem, bit == set)
+            }
         }
-        return 0;
     }
 
-    pub fn nop(&mut self) -> u8 {
-        match self.opcode {
-            0x1C | 0x3C | 0x5C | 0x7C | 0xDC | 0xFC => 1,
-            _ => 0,
+    pub fn bbr<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.branch_on_bit::<MODE>(mem, false)
+    }
+
+    pub fn bbs<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.branch_on_bit::<MODE>(mem, true)
+    }
+
+    pub fn bra<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.branch::<MODE>(mem, true)
+    }
+
+    pub fn phx(&mut self, mem: &mut dyn Memory) -> bool {
+        self.push_register(mem, self.x)
+    }
+
+    pub fn phy(&mut self, mem: &mut dyn Memory) -> bool {
+        self.push_register(mem, self.y)
+    }
+
+    pub fn plx(&mut self, mem: &mut dyn Memory) -> bool {
+        self.pull_register(mem, |cpu, value| {
+            cpu.x = value;
+            cpu.set_nz(value);
+        })
+    }
+
+    pub fn ply(&mut self, mem: &mut dyn Memory) -> bool {
+        self.pull_register(mem, |cpu, value| {
+            cpu.y = value;
+            cpu.set_nz(value);
+        })
+    }
+
+    pub fn rmb<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.modify_operand::<MODE>(mem, |cpu, value| value & !(1 << cpu.opcode_bit()))
+    }
+
+    pub fn smb<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.modify_operand::<MODE>(mem, |cpu, value| value | (1 << cpu.opcode_bit()))
+    }
+
+    // Stops the clock until reset. Like JAM, it's fetched again and again,
+    // and with the clock stopped no interrupt gets in between.
+    pub fn stp(&mut self, mem: &mut dyn Memory) -> bool {
+        self.dummy_read(mem, self.pc);
+        if self.step < 2 {
+            return false;
         }
+        self.pc = self.pc.wrapping_sub(1);
+        self.polled = false;
+        true
     }
 
-    pub fn ora(&mut self) -> u8 {
-        self.fetch();
-        self.a = self.a | self.fetched;
-        self.set_flag(StatusFlags::ZERO, self.a == 0x00);
-        self.set_flag(StatusFlags::NEGATIVE, (self.a & 0x80) != 0);
-        return 1;
+    pub fn stz<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.write_operand::<MODE>(mem, 0x00)
     }
 
-    pub fn pha(&mut self) -> u8 {
-        self.write(0x0100 + self.stkp as u16, self.a);
-        self.stkp -= 1;
-        return 0;
+    // TRB and TSB set Z as BIT would
+    pub fn trb<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.modify_operand::<MODE>(mem, |cpu, value| {
+            cpu.set_flag(StatusFlags::ZERO, cpu.a & value == 0);
+            value & !cpu.a
+        })
     }
 
-    pub fn php(&mut self) -> u8 {
-        self.write(
-            0x0100 + self.stkp as u16,
-            self.status
-                .clone()
-                .union(StatusFlags::BREAK)
-                .union(StatusFlags::UNUSED)
-                .bits(),
-        );
-        self.set_flag(StatusFlags::BREAK, false);
-        self.set_flag(StatusFlags::UNUSED, false);
-        self.stkp -= 1;
-        return 0;
+    pub fn tsb<const MODE: u8>(&mut self, mem: &mut dyn Memory) -> bool {
+        self.modify_operand::<MODE>(mem, |cpu, value| {
+            cpu.set_flag(StatusFlags::ZERO, cpu.a & value == 0);
+            value | cpu.a
+        })
     }
 
-    pub fn pla(&mut self) -> u8 {
-        self.stkp += 1;
-        self.a = self.read(0x0100 + self.stkp as u16);
-        self.set_flag(StatusFlags::ZERO, self.a == 0x00);
-        self.set_flag(StatusFlags::NEGATIVE, (self.a & 0x80) != 0);
-        return 0;
+    // Waits for an interrupt, running again and again like STP until one
+    // is wanted. An IRQ ends the wait even with I set, and then the
+    // program just carries on.
+    pub fn wai(&mut self, mem: &mut dyn Memory) -> bool {
+        self.dummy_read(mem, self.pc);
+        if self.step < 2 {
+            return false;
+        }
+        if !self.irq_line && !self.nmi_detected {
+            self.pc = self.pc.wrapping_sub(1);
+        }
+        true
     }
+}
 
-    pub fn plp(&mut self) -> u8 {
-        self.stkp += 1;
-        self.status = StatusFlags::from_bits_retain(self.read(0x0100 + self.stkp as u16));
-        self.set_flag(StatusFlags::UNUSED, true);
-        return 0;
-    }
-
-    pub fn rol(&mut self) -> u8 {
-        self.fetch();
-        let temp = ((self.fetched as u16) << 1) | (self.get_flag(StatusFlags::CARRY) as u16);
-        self.set_flag(StatusFlags::CARRY, (temp & 0xFF00) != 0);
-        self.set_flag(StatusFlags::ZERO, (temp & 0x00FF) == 0x0000);
-        self.set_flag(StatusFlags::NEGATIVE, (temp & 0x0080) != 0);
-        if matches!(
-            self.lookup[self.opcode as usize].mode,
-            AddressingMode::Implied
-        ) {
-            self.a = (temp & 0x00FF) as u8;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A CPU with program at $0200, vectors pointing NMI at $0300 and
+    // IRQ/BRK at $0400, and NOPs at both
+    fn setup(variant: Variant, program: &[u8]) -> (Cpu, Box<[u8; 0x10000]>) {
+        let mut ram = Box::new([0xEA; 0x10000]);
+        ram[0x0200..0x0200 + program.len()].copy_from_slice(program);
+        ram[0xFFFA..].copy_from_slice(&[0x00, 0x03, 0x00, 0x02, 0x00, 0x04]);
+        let mut cpu = Cpu::with_variant(variant);
+        cpu.pc = 0x0200;
+        cpu.stkp = 0xFF;
+        (cpu, ram)
+    }
+
+    // Runs one instruction, returning the cycles it took
+    fn step(cpu: &mut Cpu, ram: &mut [u8; 0x10000]) -> u32 {
+        let mut cycles = 0;
+        loop {
+            cpu.clock(ram);
+            cycles += 1;
+            if cpu.complete() {
+                return cycles;
+            }
+        }
+    }
+
+    #[test]
+    fn nmi_is_taken_once_per_edge() {
+        let (mut cpu, mut ram) = setup(Variant::Ricoh2A03, &[]);
+        cpu.set_nmi(true);
+        step(&mut cpu, &mut ram);
+        assert_eq!(step(&mut cpu, &mut ram), 7);
+        assert_eq!(cpu.pc, 0x0300);
+        assert_eq!(cpu.take_interrupt(), Some(Interrupt::Nmi));
+        assert_eq!(ram[0x01FD] & StatusFlags::BREAK.bits(), 0);
+
+        // Held low, the line doesn't fire again
+        for _ in 0..4 {
+            step(&mut cpu, &mut ram);
+        }
+        assert_eq!(cpu.take_interrupt(), None);
+
+        cpu.set_nmi(false);
+        step(&mut cpu, &mut ram);
+        cpu.set_nmi(true);
+        step(&mut cpu, &mut ram);
+        step(&mut cpu, &mut ram);
+        assert_eq!(cpu.take_interrupt(), Some(Interrupt::Nmi));
+        assert_eq!(cpu.pc, 0x0300);
+    }
+
+    #[test]
+    fn nmi_hijacks_brk() {
+        let (mut cpu, mut ram) = setup(Variant::Ricoh2A03, &[0x00, 0x00]);
+        for _ in 0..3 {
+            cpu.clock(&mut *ram);
+        }
+        // Before BRK pushes P, so the NMI vector is fetched instead
+        cpu.set_nmi(true);
+        step(&mut cpu, &mut ram);
+        assert_eq!(cpu.pc, 0x0300);
+        assert_eq!(cpu.take_interrupt(), Some(Interrupt::Nmi));
+        assert_eq!([ram[0x01FF], ram[0x01FE]], [0x02, 0x02]);
+        assert_ne!(ram[0x01FD] & StatusFlags::BREAK.bits(), 0);
+
+        // The NMI was spent on the BRK
+        step(&mut cpu, &mut ram);
+        assert_eq!(cpu.take_interrupt(), None);
+        assert_eq!(cpu.pc, 0x0301);
+    }
+
+    // Runs SED, CLC or SEC, LDA #a, then ADC #b or SBC #b, returning A and
+    // P. The RAM is reused, as every input gets run.
+    fn decimal(
+        cpu: &mut Cpu,
+        ram: &mut [u8; 0x10000],
+        op: u8,
+        a: u8,
+        b: u8,
+        carry: bool,
+    ) -> (u8, StatusFlags) {
+        let carry_op = if carry { 0x38 } else { 0x18 };
+        ram[0x0200..0x0206].copy_from_slice(&[0xF8, carry_op, 0xA9, a, op, b]);
+        cpu.pc = 0x0200;
+        for _ in 0..4 {
+            step(cpu, ram);
+        }
+        (cpu.a, cpu.status)
+    }
+
+    // What Bruce Clark's decimal mode note, which Klaus Dormann's
+    // 6502_decimal_test checks against, says ADC and SBC leave in A, N, V, Z
+    // and C. Sequences 1 and 2 are ADC's result and its NMOS N and V;
+    // sequence 3 is NMOS SBC's result and sequence 4 the 65C02's.
+    fn decimal_model(variant: Variant, op: u8, a: u8, b: u8, carry: bool) -> (u8, [bool; 4]) {
+        let c = carry as i16;
+        let binary_b = if op == 0x69 { b } else { !b };
+        let sum = a as i16 + binary_b as i16 + c;
+        let binary_v = (!(a ^ binary_b) & (a ^ sum as u8)) & 0x80 != 0;
+        let binary = [sum & 0x80 != 0, binary_v, sum as u8 == 0, sum > 0xFF];
+
+        let (a, b) = (a as i16, b as i16);
+        let (result, n, v, z, carry) = if op == 0x69 {
+            let mut low = (a & 0x0F) + (b & 0x0F) + c;
+            if low >= 0x0A {
+                low = ((low + 0x06) & 0x0F) + 0x10;
+            }
+            let mut seq1 = (a & 0xF0) + (b & 0xF0) + low;
+            if seq1 >= 0xA0 {
+                seq1 += 0x60;
+            }
+            let seq2 = (a & 0xF0) as u8 as i8 as i16 + (b & 0xF0) as u8 as i8 as i16 + low;
+            let v = !(-128..=127).contains(&seq2);
+            (seq1 as u8, seq2 & 0x80 != 0, v, binary[2], seq1 >= 0x100)
+        } else if variant.is_cmos() {
+            let low = (a & 0x0F) - (b & 0x0F) + c - 1;
+            let mut seq4 = a - b + c - 1;
+            if seq4 < 0 {
+                seq4 -= 0x60;
+            }
+            if low < 0 {
+                seq4 -= 0x06;
+            }
+            (seq4 as u8, binary[0], binary[1], binary[2], binary[3])
         } else {
-            self.write(self.addr_abs, (temp & 0x00FF) as u8);
-        }
-        return 0;
-    }
-
-    pub fn ror(&mut self) -> u8 {
-        self.fetch();
-        let temp = ((self.get_flag(StatusFlags::CARRY) as u16) << 7) | ((self.fetched as u16) >> 1);
-        self.set_flag(StatusFlags::CARRY, (self.fetched & 0x01) != 0);
-        self.set_flag(StatusFlags::ZERO, (temp & 0x00FF) == 0x0000);
-        self.set_flag(StatusFlags::NEGATIVE, (temp & 0x0080) != 0);
-        if matches!(
-            self.lookup[self.opcode as usize].mode,
-            AddressingMode::Implied
-        ) {
-            self.a = (temp & 0x00FF) as u8;
+            let mut low = (a & 0x0F) - (b & 0x0F) + c - 1;
+            if low < 0 {
+                low = ((low - 0x06) & 0x0F) - 0x10;
+            }
+            let mut seq3 = (a & 0xF0) - (b & 0xF0) + low;
+            if seq3 < 0 {
+                seq3 -= 0x60;
+            }
+            (seq3 as u8, binary[0], binary[1], binary[2], binary[3])
+        };
+
+        if variant.is_cmos() {
+            (result, [result & 0x80 != 0, v, result == 0, carry])
         } else {
-            self.write(self.addr_abs, (temp & 0x00FF) as u8);
+            (result, [n, v, z, carry])
         }
-        return 0;
     }
 
-    pub fn rti(&mut self) -> u8 {
-        self.stkp += 1;
-        self.status = StatusFlags::from_bits_retain(self.read(0x0100 + self.stkp as u16));
-        self.status &= StatusFlags::BREAK.not();
-        self.status &= StatusFlags::UNUSED.not();
-
-        self.stkp += 1;
-        self.pc = self.read(0x0100 + self.stkp as u16) as u16;
-        self.stkp += 1;
-        self.pc |= (self.read(0x0100 + self.stkp as u16) as u16) << 8;
-        return 0;
+    #[test]
+    fn decimal_mode_matches_every_input() {
+        for variant in [Variant::Nmos6502, Variant::Cmos65C02] {
+            let (mut cpu, mut ram) = setup(variant, &[]);
+            for op in [0x69, 0xE9] {
+                for a in 0..=0xFF {
+                    for b in 0..=0xFF {
+                        for carry in [false, true] {
+                            let (result, p) = decimal(&mut cpu, &mut ram, op, a, b, carry);
+                            let flags = [
+                                StatusFlags::NEGATIVE,
+                                StatusFlags::OVERFLOW,
+                                StatusFlags::ZERO,
+                                StatusFlags::CARRY,
+                            ]
+                            .map(|flag| p.contains(flag));
+                            assert_eq!(
+                                (result, flags),
+                                decimal_model(variant, op, a, b, carry),
+                                "{variant:?} {op:02X} {a:02X} {b:02X} carry {carry}"
+                            );
+                        }
+                    }
+                }
+            }
+        }
     }
 
-    pub fn rts(&mut self) -> u8 {
-        self.stkp += 1;
-        self.pc = self.read(0x0100 + self.stkp as u16) as u16;
-        self.stkp += 1;
-        self.pc |= (self.read(0x0100 + self.stkp as u16) as u16) << 8;
-        self.pc += 1;
-        return 0;
+    // The NMOS chip's N, V and Z in decimal mode aren't those of the
+    // result: Z is the binary sum's, and N and V are taken before the high
+    // digit is corrected
+    #[test]
+    fn nmos_decimal_flags() {
+        use StatusFlags as F;
+        let cases = [
+            // op, A, operand, C in, A out, NVZC out
+            (0x69, 0x99, 0x01, false, 0x00, F::NEGATIVE | F::CARRY),
+            (0x69, 0x79, 0x00, true, 0x80, F::NEGATIVE | F::OVERFLOW),
+            (
+                0x69,
+                0x50,
+                0x50,
+                false,
+                0x00,
+                F::NEGATIVE | F::OVERFLOW | F::CARRY,
+            ),
+            (0x69, 0x0F, 0x01, false, 0x16, F::empty()),
+            (0x69, 0x00, 0x00, false, 0x00, F::ZERO),
+            (0xE9, 0x00, 0x01, true, 0x99, F::NEGATIVE),
+            (0xE9, 0x80, 0x01, true, 0x79, F::OVERFLOW | F::CARRY),
+            (0xE9, 0x12, 0x12, true, 0x00, F::ZERO | F::CARRY),
+        ];
+        let nvzc = F::NEGATIVE | F::OVERFLOW | F::ZERO | F::CARRY;
+
+        let (mut cpu, mut ram) = setup(Variant::Nmos6502, &[]);
+        for (op, a, b, carry, result, flags) in cases {
+            let (actual, p) = decimal(&mut cpu, &mut ram, op, a, b, carry);
+            assert_eq!(
+                (actual, p & nvzc),
+                (result, flags),
+                "{op:02X} {a:02X} {b:02X}"
+            );
+        }
+
+        // The same sums on the 65C02 take N and Z from the result
+        let (mut cpu, mut ram) = setup(Variant::Cmos65C02, &[]);
+        let (actual, p) = decimal(&mut cpu, &mut ram, 0x69, 0x99, 0x01, false);
+        assert_eq!((actual, p & nvzc), (0x00, F::ZERO | F::CARRY));
+        let (actual, p) = decimal(&mut cpu, &mut ram, 0x69, 0x50, 0x50, false);
+        assert_eq!((actual, p & nvzc), (0x00, F::OVERFLOW | F::ZERO | F::CARRY));
+    }
+
+    const CMOS_VARIANTS: [Variant; 3] = [
+        Variant::Cmos65C02,
+        Variant::Rockwell65C02,
+        Variant::Wdc65C02,
+    ];
+
+    #[test]
+    fn cmos_tables_differ_only_in_the_maker_additions() {
+        for opcode in 0..=0xFF {
+            let [cmos, rockwell, wdc] = CMOS_VARIANTS.map(|v| v.lookup()[opcode]);
+            let bit_op = opcode & 0x07 == 0x07;
+            if bit_op {
+                assert_eq!((cmos.name, cmos.cycles), ("NOP", 1), "{opcode:02X}");
+                let bit = (opcode >> 4) & 0x07;
+                let name =
+                    ["RMB", "BBR", "SMB", "BBS"][(opcode >> 3) & 0x01 | (opcode >> 6) & 0x02];
+                assert_eq!(rockwell.name, format!("{name}{bit}"), "{opcode:02X}");
+            } else {
+                assert_eq!((rockwell.name, rockwell.cycles), (cmos.name, cmos.cycles));
+            }
+            match opcode {
+                0xCB => assert_eq!((wdc.name, cmos.name), ("WAI", "NOP")),
+                0xDB => assert_eq!((wdc.name, cmos.name), ("STP", "NOP")),
+                _ => assert_eq!((wdc.name, wdc.cycles), (rockwell.name, rockwell.cycles)),
+            }
+        }
     }
 
-    pub fn sec(&mut self) -> u8 {
-        self.set_flag(StatusFlags::CARRY, true);
-        return 0;
+    #[test]
+    fn bit_instructions() {
+        for variant in CMOS_VARIANTS {
+            let bits = variant != Variant::Cmos65C02;
+            for bit in 0..8 {
+                let mask = 1 << bit;
+                let row = bit << 4;
+
+                // RMB and SMB, or one byte NOPs on the plain 65C02
+                for (opcode, before, after) in [(0x07, 0xFF, !mask), (0x87, 0x00, mask)] {
+                    let (mut cpu, mut ram) = setup(variant, &[opcode | row, 0x10]);
+                    ram[0x10] = before;
+                    let cycles = step(&mut cpu, &mut ram);
+                    if bits {
+                        assert_eq!((ram[0x10], cycles, cpu.pc), (after, 5, 0x0202));
+                    } else {
+                        assert_eq!((ram[0x10], cycles, cpu.pc), (before, 1, 0x0201));
+                    }
+                }
+
+                // BBR and BBS, taken and not
+                for (opcode, branch_on) in [(0x0F, 0x00), (0x8F, mask)] {
+                    for value in [0x00, mask, !mask, 0xFF] {
+                        let (mut cpu, mut ram) = setup(variant, &[opcode | row, 0x10, 0x04]);
+                        ram[0x10] = value;
+                        let cycles = step(&mut cpu, &mut ram);
+                        let taken = value & mask == branch_on;
+                        let expected = match (bits, taken) {
+                            (false, _) => (0x0201, 1),
+                            (true, false) => (0x0203, 5),
+                            (true, true) => (0x0207, 6),
+                        };
+                        assert_eq!(
+                            (cpu.pc, cycles),
+                            expected,
+                            "{variant:?} {:02X} {value:02X}",
+                            opcode | row
+                        );
+                    }
+                }
+            }
+        }
     }
 
-    pub fn sed(&mut self) -> u8 {
-        self.set_flag(StatusFlags::DECIMAL_MODE, true);
-        return 0;
+    #[test]
+    fn trb_and_tsb() {
+        for variant in CMOS_VARIANTS {
+            // opcode, A, memory before and after, Z, cycles
+            let cases = [
+                (&[0x04, 0x10][..], 0x0F, 0x3C, 0x3F, false, 5),
+                (&[0x0C, 0x10, 0x00], 0x0F, 0x3C, 0x3F, false, 6),
+                (&[0x04, 0x10], 0x03, 0x3C, 0x3F, true, 5),
+                (&[0x14, 0x10], 0x0F, 0x3C, 0x30, false, 5),
+                (&[0x1C, 0x10, 0x00], 0x0F, 0x3C, 0x30, false, 6),
+                (&[0x14, 0x10], 0xC3, 0x3C, 0x3C, true, 5),
+            ];
+            for (program, a, before, after, zero, cycles) in cases {
+                let (mut cpu, mut ram) = setup(variant, program);
+                cpu.a = a;
+                ram[0x10] = before;
+                assert_eq!(step(&mut cpu, &mut ram), cycles);
+                assert_eq!((ram[0x10], cpu.a), (after, a));
+                assert_eq!(
+                    cpu.get_flag(StatusFlags::ZERO),
+                    zero,
+                    "{:02X} {a:02X}",
+                    program[0]
+                );
+            }
+        }
     }
 
-    pub fn sei(&mut self) -> u8 {
-        self.set_flag(StatusFlags::INTERRUPT_DISABLE, true);
-        return 0;
+    #[test]
+    fn jmp_indirect_crosses_pages_on_cmos() {
+        for variant in [Variant::Nmos6502, Variant::Ricoh2A03]
+            .into_iter()
+            .chain(CMOS_VARIANTS)
+        {
+            let (mut cpu, mut ram) = setup(variant, &[0x6C, 0xFF, 0x10]);
+            ram[0x10FF] = 0x34;
+            ram[0x1100] = 0x12;
+            ram[0x1000] = 0x56;
+            let cycles = step(&mut cpu, &mut ram);
+            if variant.is_cmos() {
+                assert_eq!((cpu.pc, cycles), (0x1234, 6), "{variant:?}");
+            } else {
+                assert_eq!((cpu.pc, cycles), (0x5634, 5), "{variant:?}");
+            }
+        }
+
+        for variant in CMOS_VARIANTS {
+            let (mut cpu, mut ram) = setup(variant, &[0x7C, 0xFF, 0x10]);
+            cpu.x = 0x02;
+            ram[0x1101] = 0x78;
+            ram[0x1102] = 0x56;
+            assert_eq!(step(&mut cpu, &mut ram), 6);
+            assert_eq!(cpu.pc, 0x5678);
+        }
     }
 
-    pub fn sta(&mut self) -> u8 {
-        self.write(self.addr_abs, self.a);
-        return 0;
+    #[test]
+    fn stp_waits_for_reset() {
+        let (mut cpu, mut ram) = setup(Variant::Wdc65C02, &[0xDB]);
+        ram[0xFFFC] = 0x00;
+        ram[0xFFFD] = 0x05;
+        for _ in 0..3 {
+            assert_eq!(step(&mut cpu, &mut ram), 3);
+            assert_eq!(cpu.pc, 0x0200);
+        }
+        cpu.set_irq(true);
+        cpu.set_nmi(true);
+        for _ in 0..3 {
+            step(&mut cpu, &mut ram);
+        }
+        assert_eq!((cpu.pc, cpu.take_interrupt()), (0x0200, None));
+
+        cpu.set_irq(false);
+        cpu.set_nmi(false);
+        cpu.reset(&mut *ram);
+        assert_eq!(step(&mut cpu, &mut ram), 7);
+        step(&mut cpu, &mut ram);
+        assert_eq!(cpu.pc, 0x0501);
+
+        // Elsewhere it's a one byte NOP
+        let (mut cpu, mut ram) = setup(Variant::Rockwell65C02, &[0xDB]);
+        assert_eq!((step(&mut cpu, &mut ram), cpu.pc), (1, 0x0201));
+    }
+
+    #[test]
+    fn wai_waits_for_an_interrupt() {
+        // With I set, the IRQ ends the wait and the program carries on
+        let (mut cpu, mut ram) = setup(Variant::Wdc65C02, &[0x78, 0xCB, 0xEA]);
+        step(&mut cpu, &mut ram);
+        for _ in 0..3 {
+            assert_eq!(step(&mut cpu, &mut ram), 3);
+            assert_eq!(cpu.pc, 0x0201);
+        }
+        cpu.set_irq(true);
+        step(&mut cpu, &mut ram);
+        assert_eq!(cpu.pc, 0x0202);
+        step(&mut cpu, &mut ram);
+        assert_eq!((cpu.pc, cpu.take_interrupt()), (0x0203, None));
+
+        // With I clear, it's taken
+        let (mut cpu, mut ram) = setup(Variant::Wdc65C02, &[0x58, 0xCB, 0xEA]);
+        step(&mut cpu, &mut ram);
+        step(&mut cpu, &mut ram);
+        cpu.set_irq(true);
+        step(&mut cpu, &mut ram);
+        step(&mut cpu, &mut ram);
+        assert_eq!(
+            (cpu.pc, cpu.take_interrupt()),
+            (0x0400, Some(Interrupt::Irq))
+        );
+        assert_eq!([ram[0x01FF], ram[0x01FE]], [0x02, 0x02]);
+
+        // As does an NMI
+        let (mut cpu, mut ram) = setup(Variant::Wdc65C02, &[0xCB, 0xEA]);
+        step(&mut cpu, &mut ram);
+        cpu.set_nmi(true);
+        step(&mut cpu, &mut ram);
+        step(&mut cpu, &mut ram);
+        assert_eq!(
+            (cpu.pc, cpu.take_interrupt()),
+            (0x0300, Some(Interrupt::Nmi))
+        );
+
+        let (mut cpu, mut ram) = setup(Variant::Cmos65C02, &[0xCB]);
+        assert_eq!((step(&mut cpu, &mut ram), cpu.pc), (1, 0x0201));
+    }
+
+    #[test]
+    fn cmos_cycle_counts() {
+        // Program, X, D, cycles on the NMOS chip and on the 65C02s
+        let cases = [
+            (&[0x1E, 0x00, 0x10][..], 0x01, false, 7, 6), // ASL $1000,X
+            (&[0x1E, 0xFF, 0x10], 0x01, false, 7, 7),     // ASL $10FF,X
+            (&[0x5E, 0x00, 0x10], 0x01, false, 7, 6),     // LSR $1000,X
+            (&[0x3E, 0x00, 0x10], 0x01, false, 7, 6),     // ROL $1000,X
+            (&[0x7E, 0x00, 0x10], 0x01, false, 7, 6),     // ROR $1000,X
+            (&[0xFE, 0x00, 0x10], 0x01, false, 7, 7),     // INC $1000,X
+            (&[0xDE, 0x00, 0x10], 0x01, false, 7, 7),     // DEC $1000,X
+            (&[0x69, 0x01], 0x00, false, 2, 2),           // ADC #$01
+            (&[0x69, 0x01], 0x00, true, 2, 3),            // ADC #$01, decimal
+            (&[0xE9, 0x01], 0x00, true, 2, 3),            // SBC #$01, decimal
+            (&[0x6D, 0x00, 0x10], 0x00, true, 4, 5),      // ADC $1000, decimal
+            (&[0xBD, 0xFF, 0x10], 0x01, false, 5, 5),     // LDA $10FF,X
+            (&[0x9D, 0x00, 0x10], 0x01, false, 5, 5),     // STA $1000,X
+            (&[0x6C, 0x00, 0x10], 0x00, false, 5, 6),     // JMP ($1000)
+            (&[0xEA], 0x00, false, 2, 2),                 // NOP
+        ];
+        for (program, x, decimal, nmos, cmos) in cases {
+            for variant in [Variant::Nmos6502].into_iter().chain(CMOS_VARIANTS) {
+                let (mut cpu, mut ram) = setup(variant, program);
+                cpu.x = x;
+                cpu.set_flag(StatusFlags::DECIMAL_MODE, decimal);
+                let expected = if variant.is_cmos() { cmos } else { nmos };
+                assert_eq!(
+                    step(&mut cpu, &mut ram),
+                    expected,
+                    "{variant:?} {program:02X?}"
+                );
+            }
+        }
+
+        // The 65C02's new instructions and modes
+        let cases = [
+            (&[0x80, 0x04][..], 3),   // BRA +4
+            (&[0x80, 0xF0], 4),       // BRA -16, to the page before
+            (&[0xB2, 0x10], 5),       // LDA ($10)
+            (&[0x64, 0x10], 3),       // STZ $10
+            (&[0x9E, 0x00, 0x10], 5), // STZ $1000,X
+            (&[0xDA], 3),             // PHX
+            (&[0xFA], 4),             // PLX
+            (&[0x1A], 2),             // INC A
+            (&[0x5C, 0x00, 0x10], 8), // NOP, the 8 cycle one
+            (&[0x02, 0x00], 2),       // NOP #
+            (&[0x03], 1),             // NOP
+        ];
+        for variant in CMOS_VARIANTS {
+            for (program, cycles) in cases {
+                let (mut cpu, mut ram) = setup(variant, program);
+                assert_eq!(
+                    step(&mut cpu, &mut ram),
+                    cycles,
+                    "{variant:?} {program:02X?}"
+                );
+            }
+        }
     }
 
-    pub fn stx(&mut self) -> u8 {
-        self.write(self.addr_abs, self.x);
-        return 0;
+    #[test]
+    fn cmos_interrupts_clear_decimal_mode() {
+        for variant in [Variant::Nmos6502].into_iter().chain(CMOS_VARIANTS) {
+            let (mut cpu, mut ram) = setup(variant, &[0xF8, 0x00, 0x00]);
+            step(&mut cpu, &mut ram);
+            step(&mut cpu, &mut ram);
+            assert_eq!(cpu.pc, 0x0400);
+            let decimal = cpu.get_flag(StatusFlags::DECIMAL_MODE);
+            assert_eq!(decimal, !variant.is_cmos(), "{variant:?}");
+            assert_ne!(ram[0x01FD] & StatusFlags::DECIMAL_MODE.bits(), 0);
+        }
     }
 
-    pub fn sty(&mut self) -> u8 {
-        self.write(self.addr_abs, self.y);
-        return 0;
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    enum Access {
+        Read(u16),
+        Write(u16, u8),
     }
+    use Access::{Read, Write};
 
-    pub fn tax(&mut self) -> u8 {
-        self.x = self.a;
-        self.set_flag(StatusFlags::ZERO, self.x == 0x00);
-        self.set_flag(StatusFlags::NEGATIVE, (self.x & 0x80) != 0);
-        return 0;
+    // RAM that logs every access made through it
+    struct Logged {
+        ram: Box<[u8; 0x10000]>,
+        log: Vec<Access>,
     }
 
-    pub fn tay(&mut self) -> u8 {
-        self.y = self.a;
-        self.set_flag(StatusFlags::ZERO, self.y == 0x00);
-        self.set_flag(StatusFlags::NEGATIVE, (self.y & 0x80) != 0);
-        return 0;
+    impl Memory for Logged {
+        fn read(&mut self, addr: u16) -> u8 {
+            self.log.push(Read(addr));
+            self.ram[addr as usize]
+        }
+
+        fn write(&mut self, addr: u16, data: u8) {
+            self.log.push(Write(addr, data));
+            self.ram[addr as usize] = data;
+        }
+
+        fn peek(&self, addr: u16) -> u8 {
+            self.ram[addr as usize]
+        }
     }
 
-    pub fn tsx(&mut self) -> u8 {
-        self.x = self.stkp;
-        self.set_flag(StatusFlags::ZERO, self.x == 0x00);
-        self.set_flag(StatusFlags::NEGATIVE, (self.x & 0x80) != 0);
-        return 0;
+    // Runs one instruction, checking it made exactly one access a cycle,
+    // and returns them
+    fn accesses(cpu: &mut Cpu, ram: Box<[u8; 0x10000]>) -> Vec<Access> {
+        let mut mem = Logged {
+            ram,
+            log: Vec::new(),
+        };
+        let mut cycles = 0;
+        loop {
+            cpu.clock(&mut mem);
+            cycles += 1;
+            assert_eq!(mem.log.len(), cycles, "{:?}", mem.log);
+            if cpu.complete() {
+                return mem.log;
+            }
+        }
     }
 
-    pub fn txa(&mut self) -> u8 {
-        self.a = self.x;
-        self.set_flag(StatusFlags::ZERO, self.a == 0x00);
-        self.set_flag(StatusFlags::NEGATIVE, (self.a & 0x80) != 0);
-        return 0;
+    #[test]
+    fn read_modify_write_writes_the_old_value_back() {
+        let (mut cpu, mut ram) = setup(Variant::Ricoh2A03, &[0xEE, 0x34, 0x12]);
+        ram[0x1234] = 0x05;
+        assert_eq!(
+            accesses(&mut cpu, ram),
+            [
+                Read(0x0200),
+                Read(0x0201),
+                Read(0x0202),
+                Read(0x1234),
+                Write(0x1234, 0x05),
+                Write(0x1234, 0x06),
+            ]
+        );
     }
 
-    pub fn txs(&mut self) -> u8 {
-        self.stkp = self.x;
-        return 0;
+    #[test]
+    fn indexed_accesses_read_the_unfixed_address_first() {
+        // LDA abs,X only reads it when the page is crossed
+        let (mut cpu, ram) = setup(Variant::Ricoh2A03, &[0xBD, 0xF0, 0x12]);
+        cpu.x = 0x20;
+        assert_eq!(
+            accesses(&mut cpu, ram),
+            [
+                Read(0x0200),
+                Read(0x0201),
+                Read(0x0202),
+                Read(0x1210),
+                Read(0x1310)
+            ]
+        );
+        let (mut cpu, ram) = setup(Variant::Ricoh2A03, &[0xBD, 0x00, 0x12]);
+        cpu.x = 0x20;
+        assert_eq!(
+            accesses(&mut cpu, ram),
+            [Read(0x0200), Read(0x0201), Read(0x0202), Read(0x1220)]
+        );
+
+        // STA abs,X always does
+        for (lo, unfixed, fixed) in [(0x00, 0x1220, 0x1220), (0xF0, 0x1210, 0x1310)] {
+            let (mut cpu, ram) = setup(Variant::Ricoh2A03, &[0x9D, lo, 0x12]);
+            cpu.x = 0x20;
+            cpu.a = 0x42;
+            assert_eq!(
+                accesses(&mut cpu, ram),
+                [
+                    Read(0x0200),
+                    Read(0x0201),
+                    Read(0x0202),
+                    Read(unfixed),
+                    Write(fixed, 0x42),
+                ]
+            );
+        }
     }
 
-    pub fn tya(&mut self) -> u8 {
-        self.a = self.y;
-        self.set_flag(StatusFlags::ZERO, self.a == 0x00);
-        self.set_flag(StatusFlags::NEGATIVE, (self.a & 0x80) != 0);
-        return 0;
+    #[test]
+    fn implied_instructions_read_the_next_byte() {
+        for opcode in [0xEA, 0xAA, 0xE8, 0x18] {
+            let (mut cpu, ram) = setup(Variant::Ricoh2A03, &[opcode]);
+            assert_eq!(accesses(&mut cpu, ram), [Read(0x0200), Read(0x0201)]);
+        }
     }
 
-    pub fn xxx(&mut self) -> u8 {
-        return 0;
+    #[test]
+    fn returns_make_dummy_stack_reads() {
+        // RTS reads the stack before pulling, and the byte it returns to
+        // before moving past it
+        let (mut cpu, mut ram) = setup(Variant::Ricoh2A03, &[0x60]);
+        cpu.stkp = 0xFD;
+        ram[0x01FE] = 0x34;
+        ram[0x01FF] = 0x12;
+        assert_eq!(
+            accesses(&mut cpu, ram),
+            [
+                Read(0x0200),
+                Read(0x0201),
+                Read(0x01FD),
+                Read(0x01FE),
+                Read(0x01FF),
+                Read(0x1234),
+            ]
+        );
+        assert_eq!((cpu.pc, cpu.stkp), (0x1235, 0xFF));
+
+        let (mut cpu, mut ram) = setup(Variant::Ricoh2A03, &[0x40]);
+        cpu.stkp = 0xFC;
+        ram[0x01FD] = 0xC3;
+        ram[0x01FE] = 0x34;
+        ram[0x01FF] = 0x12;
+        assert_eq!(
+            accesses(&mut cpu, ram),
+            [
+                Read(0x0200),
+                Read(0x0201),
+                Read(0x01FC),
+                Read(0x01FD),
+                Read(0x01FE),
+                Read(0x01FF),
+            ]
+        );
+        assert_eq!((cpu.pc, cpu.status.bits()), (0x1234, 0xE3));
     }
 }
@@ -0,0 +1,328 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::{fmt, fs, io};
+
+use crate::cartridge::{Header, InputDevices, Mirroring, Region};
+use crate::hash::{self, Crc32, Sha1};
+
+// Game database in the format of the community NES 2.0 database
+// (nes20db.xml). Each <game> is keyed by the CRC-32 and SHA-1 of its PRG and
+// CHR ROM together, and describes the board as a correct NES 2.0 header
+// would:
+//
+//   <game>
+//     <!-- Name of the game -->
+//     <rom size="40960" crc32="..." sha1="..."/>
+//     <pcb mapper="0" submapper="0" mirroring="V" battery="0"/>
+//     <prgram size="0"/> <prgnvram size="0"/> <chrram size="0"/>
+//     <console type="0" region="0"/>
+//     <expansion type="1"/>
+//   </game>
+//
+// Only the tags and attributes above are read; everything else is skipped.
+
+const BUILTIN: &str = include_str!("nes20db.xml");
+
+#[derive(Debug)]
+pub enum DatabaseError {
+    Io(io::Error),
+    // A <game> the database couldn't make sense of, and why
+    Malformed(String),
+}
+
+impl fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DatabaseError::Io(e) => write!(f, "i/o error: {e}"),
+            DatabaseError::Malformed(reason) => write!(f, "malformed database: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for DatabaseError {}
+
+impl From<io::Error> for DatabaseError {
+    fn from(e: io::Error) -> Self {
+        DatabaseError::Io(e)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub name: String,
+    pub crc32: u32,
+    pub sha1: Option<[u8; 20]>,
+
+    pub mapper: u16,
+    pub submapper: u8,
+    // None when the mapper controls it
+    pub mirroring: Option<Mirroring>,
+    pub battery: bool,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub region: Region,
+    pub input_devices: InputDevices,
+}
+
+impl Entry {
+    // Overrides everything the header says about the board. ROM sizes are
+    // left alone, since the image was already split using them.
+    pub fn apply(&self, header: &mut Header) {
+        header.mapper = self.mapper;
+        header.submapper = self.submapper;
+        if let Some(mirroring) = self.mirroring {
+            header.mirroring = mirroring;
+        }
+        header.battery = self.battery;
+        header.prg_ram_size = self.prg_ram_size;
+        header.prg_nvram_size = self.prg_nvram_size;
+        header.chr_ram_size = self.chr_ram_size;
+        header.region = self.region;
+        header.input_devices = self.input_devices;
+    }
+}
+
+#[derive(Default)]
+pub struct Database {
+    entries: Vec<Entry>,
+    by_crc32: HashMap<u32, Vec<usize>>,
+}
+
+impl Database {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, DatabaseError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(xml: &str) -> Result<Self, DatabaseError> {
+        let mut db = Database::default();
+        let mut rest = xml;
+        while let Some(start) = rest.find("<game>") {
+            let end = rest[start..]
+                .find("</game>")
+                .ok_or_else(|| DatabaseError::Malformed("unterminated <game>".into()))?;
+            let entry = parse_game(&rest[start + 6..start + end])?;
+            db.by_crc32
+                .entry(entry.crc32)
+                .or_default()
+                .push(db.entries.len());
+            db.entries.push(entry);
+            rest = &rest[start + end + 7..];
+        }
+        Ok(db)
+    }
+
+    // Finds the game whose ROM is prg followed by chr. CRC-32 narrows it
+    // down, and the SHA-1 settles it where the entry has one.
+    pub fn lookup(&self, prg: &[u8], chr: &[u8]) -> Option<&Entry> {
+        let mut crc = Crc32::new();
+        crc.update(prg);
+        crc.update(chr);
+        let candidates = self.by_crc32.get(&crc.finish())?;
+
+        let mut sha1 = None;
+        candidates.iter().map(|&i| &self.entries[i]).find(|entry| {
+            entry.sha1.is_none_or(|expected| {
+                let digest = *sha1.get_or_insert_with(|| {
+                    let mut sha1 = Sha1::new();
+                    sha1.update(prg);
+                    sha1.update(chr);
+                    sha1.finish()
+                });
+                digest == expected
+            })
+        })
+    }
+}
+
+// The database compiled into the emulator, parsed on first use
+pub fn builtin() -> &'static Database {
+    static DATABASE: OnceLock<Database> = OnceLock::new();
+    DATABASE.get_or_init(|| {
+        Database::parse(BUILTIN).unwrap_or_else(|e| {
+            eprintln!("built-in game database: {e}");
+            Database::default()
+        })
+    })
+}
+
+fn parse_game(game: &str) -> Result<Entry, DatabaseError> {
+    let name = game
+        .find("<!--")
+        .and_then(|start| {
+            let comment = &game[start + 4..];
+            comment.find("-->").map(|end| comment[..end].trim())
+        })
+        .unwrap_or("")
+        .to_string();
+    let malformed = |what: &str| DatabaseError::Malformed(format!("{what} in \"{name}\""));
+
+    let rom = tag(game, "rom").ok_or_else(|| malformed("no <rom>"))?;
+    let crc32 = attr(rom, "crc32")
+        .and_then(|crc| u32::from_str_radix(crc, 16).ok())
+        .ok_or_else(|| malformed("bad CRC-32"))?;
+    let sha1 = match attr(rom, "sha1") {
+        Some(sha1) => Some(parse_sha1(sha1).ok_or_else(|| malformed("bad SHA-1"))?),
+        None => None,
+    };
+
+    let pcb = tag(game, "pcb").ok_or_else(|| malformed("no <pcb>"))?;
+    let mirroring = match attr(pcb, "mirroring") {
+        Some("H") => Some(Mirroring::Horizontal),
+        Some("V") => Some(Mirroring::Vertical),
+        Some("4") => Some(Mirroring::FourScreen),
+        _ => None,
+    };
+
+    let number = |tag_name: &str, attr_name: &str| -> Result<usize, DatabaseError> {
+        match tag(game, tag_name).and_then(|tag| attr(tag, attr_name)) {
+            Some(value) => value
+                .parse()
+                .map_err(|_| malformed(&format!("bad {tag_name} {attr_name}"))),
+            None => Ok(0),
+        }
+    };
+
+    Ok(Entry {
+        crc32,
+        sha1,
+        mapper: number("pcb", "mapper")? as u16,
+        submapper: number("pcb", "submapper")? as u8,
+        mirroring,
+        battery: number("pcb", "battery")? != 0,
+        prg_ram_size: number("prgram", "size")?,
+        prg_nvram_size: number("prgnvram", "size")?,
+        chr_ram_size: number("chrram", "size")?,
+        region: Region::from_timing(number("console", "region")? as u8),
+        input_devices: InputDevices::from_expansion(number("expansion", "type")? as u8),
+        name,
+    })
+}
+
+// Attributes of the first <name .../> tag
+fn tag<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let open = format!("<{name} ");
+    let start = xml.find(&open)? + open.len();
+    let end = xml[start..].find('>')?;
+    Some(&xml[start..start + end])
+}
+
+fn attr<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let key = format!(" {name}=\"");
+    let start = format!(" {tag}").find(&key)? + key.len() - 1;
+    let end = tag[start..].find('"')?;
+    Some(&tag[start..start + end])
+}
+
+fn parse_sha1(hex: &str) -> Option<[u8; 20]> {
+    if hex.len() != 40 || !hex.is_ascii() {
+        return None;
+    }
+    let mut digest = [0; 20];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(digest)
+}
+
+// CRC-32 and SHA-1 of a ROM as the database keys it, for reporting
+pub fn rom_hashes(prg: &[u8], chr: &[u8]) -> (u32, String) {
+    let mut crc = Crc32::new();
+    let mut sha1 = Sha1::new();
+    for data in [prg, chr] {
+        crc.update(data);
+        sha1.update(data);
+    }
+    (crc.finish(), hash::hex(&sha1.finish()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An NROM image whose header claims horizontal mirroring and nothing else
+    fn image() -> Vec<u8> {
+        let mut bytes = b"NES\x1A\x01\x01".to_vec();
+        bytes.resize(16, 0);
+        bytes.extend((0..0x4000 + 0x2000).map(|i| (i * 7) as u8));
+        bytes
+    }
+
+    // A database describing image() as a CNROM board
+    fn database(crc32: u32, sha1: &str) -> Database {
+        let xml = format!(
+            r#"<nes20db>
+              <game>
+                <!-- Test Game -->
+                <rom size="24576" crc32="{crc32:08X}" sha1="{sha1}"/>
+                <pcb mapper="3" submapper="0" mirroring="V" battery="1"/>
+                <prgnvram size="8192"/>
+                <console type="0" region="1"/>
+                <expansion type="8"/>
+              </game>
+            </nes20db>"#
+        );
+        Database::parse(&xml).unwrap()
+    }
+
+    fn board(header: &Header) -> (u16, Mirroring, bool, usize, usize, Region, InputDevices) {
+        (
+            header.mapper,
+            header.mirroring,
+            header.battery,
+            header.prg_ram_size,
+            header.prg_nvram_size,
+            header.region,
+            header.input_devices,
+        )
+    }
+
+    #[test]
+    fn known_roms_get_the_database_header() {
+        let bytes = image();
+        let mut header = Header::parse(&bytes).unwrap();
+        let (prg, chr) = header.rom_data(&bytes).unwrap();
+        let (crc32, sha1) = rom_hashes(prg, chr);
+
+        let db = database(crc32, &sha1);
+        let entry = db.lookup(prg, chr).unwrap();
+        assert_eq!(entry.name, "Test Game");
+        entry.apply(&mut header);
+        assert_eq!(
+            board(&header),
+            (
+                3,
+                Mirroring::Vertical,
+                true,
+                0,
+                0x2000,
+                Region::Pal,
+                InputDevices::STANDARD | InputDevices::ZAPPER
+            )
+        );
+        assert_eq!((header.prg_rom_size, header.chr_rom_size), (0x4000, 0x2000));
+    }
+
+    #[test]
+    fn unknown_roms_keep_their_header() {
+        let bytes = image();
+        let header = Header::parse(&bytes).unwrap();
+        let (prg, chr) = header.rom_data(&bytes).unwrap();
+        let (crc32, sha1) = rom_hashes(prg, chr);
+        let before = board(&header);
+
+        // A different ROM, and a CRC-32 collision the SHA-1 tells apart
+        let other = database(crc32 ^ 1, &sha1);
+        let collision = database(crc32, &"0".repeat(40));
+        for db in [other, collision] {
+            let mut header = header.clone();
+            if let Some(entry) = db.lookup(prg, chr) {
+                entry.apply(&mut header);
+            }
+            assert_eq!(board(&header), before);
+        }
+        assert_eq!(before.0, 0);
+        assert_eq!(before.1, Mirroring::Horizontal);
+    }
+}
@@ -0,0 +1,269 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use bitflags::bitflags;
+
+use crate::bus::{Access, AccessKind, AddressSpace};
+use crate::cartridge::Cartridge;
+use crate::cpu::AddressingMode;
+use crate::disasm;
+use crate::instructions::LOOKUP;
+
+const JMP_INDIRECT: u8 = 0x6C;
+
+bitflags! {
+    // FCEUX's per-byte PRG flags. Bits 2-3 hold which 8K window at $8000+
+    // the byte was last seen in.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct PrgFlags: u8 {
+        const CODE          = (1 << 0);
+        const DATA          = (1 << 1);
+        const BANK          = (3 << 2);
+        // Jumped to through JMP ($nnnn)
+        const INDIRECT_CODE = (1 << 4);
+        // Read through ($nn,X) or ($nn),Y
+        const INDIRECT_DATA = (1 << 5);
+    }
+}
+
+// The instruction whose accesses are being classified
+#[derive(Clone, Copy)]
+struct Instruction {
+    pc: u16,
+    len: u16,
+    opcode: u8,
+    indirect: bool,
+}
+
+// A Code/Data Logger: marks each PRG ROM byte with how the CPU has used
+// it, saved in FCEUX's .cdl layout (one flag byte per PRG ROM byte, then
+// one per CHR ROM byte). Fed the bus access log by the Debugger. DMC sample
+// fetches and CHR accesses go unlogged until there's an APU and a PPU to
+// make them, but CHR flags in a loaded log are kept.
+pub struct CodeDataLog {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+    instruction: Option<Instruction>,
+}
+
+impl CodeDataLog {
+    // CHR RAM isn't logged, as FCEUX doesn't
+    pub fn new(cart: &Cartridge) -> Self {
+        let memory = cart.mapper().memory();
+        let chr = if memory.chr_is_ram {
+            0
+        } else {
+            memory.chr.len()
+        };
+        CodeDataLog {
+            prg: vec![0; memory.prg_rom.len()],
+            chr: vec![0; chr],
+            instruction: None,
+        }
+    }
+
+    pub fn prg(&self) -> &[u8] {
+        &self.prg
+    }
+
+    pub fn prg_flags(&self, offset: usize) -> PrgFlags {
+        PrgFlags::from_bits_retain(self.prg.get(offset).copied().unwrap_or(0))
+    }
+
+    // Merges in an earlier log of the same ROM, so coverage builds up
+    // across sessions
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let data = fs::read(path)?;
+        if data.len() != self.prg.len() + self.chr.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "code/data log is for a different ROM size",
+            ));
+        }
+        let (prg, chr) = data.split_at(self.prg.len());
+        for (flags, old) in self.prg.iter_mut().zip(prg) {
+            // Keep the bank bits from this session where there are any
+            *flags |= if *flags & PrgFlags::BANK.bits() != 0 {
+                old & !PrgFlags::BANK.bits()
+            } else {
+                *old
+            };
+        }
+        for (flags, old) in self.chr.iter_mut().zip(chr) {
+            *flags |= old;
+        }
+        Ok(())
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, [self.prg.as_slice(), self.chr.as_slice()].concat())
+    }
+
+    // Classifies one cycle's accesses. starting is set when the CPU began a
+    // new instruction (or interrupt) on this cycle, and dummy when its read
+    // was one the instruction threw away. Those aren't logged, and one that
+    // starts an instruction is an interrupt, not an opcode fetch.
+    pub fn log(&mut self, mut starting: bool, dummy: bool, accesses: &[Access]) {
+        for access in accesses {
+            if access.space != AddressSpace::Cpu || access.kind != AccessKind::Read {
+                continue;
+            }
+            if dummy {
+                if starting {
+                    starting = false;
+                    self.instruction = None;
+                }
+                continue;
+            }
+            if starting {
+                starting = false;
+                self.start_instruction(access);
+            }
+            let Some(offset) = access.rom_offset else {
+                continue;
+            };
+
+            let flags = match self.instruction {
+                Some(i) if access.addr.wrapping_sub(i.pc) < i.len => PrgFlags::CODE,
+                Some(i) if i.indirect => PrgFlags::DATA | PrgFlags::INDIRECT_DATA,
+                _ => PrgFlags::DATA,
+            };
+            self.mark_prg(offset, access.addr, flags);
+        }
+    }
+
+    fn start_instruction(&mut self, fetch: &Access) {
+        // The byte a JMP ($nnnn) lands on
+        if let Some(i) = self.instruction
+            && i.opcode == JMP_INDIRECT
+            && let Some(offset) = fetch.rom_offset
+        {
+            self.mark_prg(offset, fetch.addr, PrgFlags::INDIRECT_CODE);
+        }
+
+        let mode = LOOKUP[fetch.data as usize].mode;
+        self.instruction = Some(Instruction {
+            pc: fetch.addr,
+            len: 1 + disasm::operand_len(mode),
+            opcode: fetch.data,
+            indirect: matches!(mode, AddressingMode::IndirectX | AddressingMode::IndirectY),
+        });
+    }
+
+    fn mark_prg(&mut self, offset: usize, addr: u16, flags: PrgFlags) {
+        let Some(byte) = self.prg.get_mut(offset) else {
+            return;
+        };
+        let mut flags = flags.bits();
+        if addr >= 0x8000 {
+            *byte &= !PrgFlags::BANK.bits();
+            flags |= ((addr >> 13) as u8 & 0x03) << 2;
+        }
+        *byte |= flags;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::Console;
+    use crate::debugger::Debugger;
+
+    const PROGRAM: &[u8] = &[
+        0xAD, 0x10, 0xA0, // LDA $A010
+        0xAD, 0x20, 0xC0, // LDA $C020
+        0xA2, 0x10, // LDX #$10
+        0xBD, 0xF8, 0xA0, // LDA $A0F8,X (dummy read of $A008)
+        0xA9, 0x30, // LDA #$30
+        0x85, 0x00, // STA $00
+        0xA9, 0xA0, // LDA #$A0
+        0x85, 0x01, // STA $01
+        0xA0, 0x02, // LDY #$02
+        0xB1, 0x00, // LDA ($00),Y
+        0x6C, 0x40, 0x80, // JMP ($8040)
+    ];
+
+    // Runs PROGRAM up to the NOP the JMP lands on at $8050
+    fn logged_run() -> CodeDataLog {
+        let mut prg = PROGRAM.to_vec();
+        prg.resize(0x40, 0xEA);
+        prg.extend([0x50, 0x80]);
+        let cart = Cartridge::nrom(&prg);
+
+        let mut debugger = Debugger::new(Console::with_cartridge(cart));
+        debugger.console_mut().reset();
+        debugger.cdl = Some(CodeDataLog::new(
+            debugger.console().bus.cartridge().unwrap(),
+        ));
+        // The reset sequence, then twelve instructions
+        for _ in 0..13 {
+            debugger.step();
+        }
+        assert_eq!(debugger.console().cpu.pc, 0x8051);
+        debugger.cdl.take().unwrap()
+    }
+
+    fn bank(window: u8) -> PrgFlags {
+        PrgFlags::from_bits_retain(window << 2)
+    }
+
+    #[test]
+    fn classifies_each_byte_the_program_touches() {
+        let cdl = logged_run();
+        for offset in 0..PROGRAM.len() {
+            assert_eq!(cdl.prg_flags(offset), PrgFlags::CODE, "${offset:04X}");
+        }
+        assert_eq!(
+            cdl.prg_flags(0x50),
+            PrgFlags::CODE | PrgFlags::INDIRECT_CODE
+        );
+
+        // The same 16K seen through different windows
+        assert_eq!(cdl.prg_flags(0x2010), PrgFlags::DATA | bank(1));
+        assert_eq!(cdl.prg_flags(0x0020), PrgFlags::DATA | bank(2));
+        assert_eq!(cdl.prg_flags(0x2108), PrgFlags::DATA | bank(1));
+        assert_eq!(
+            cdl.prg_flags(0x2032),
+            PrgFlags::DATA | PrgFlags::INDIRECT_DATA | bank(1)
+        );
+        assert_eq!(cdl.prg_flags(0x40), PrgFlags::DATA);
+        assert_eq!(cdl.prg_flags(0x41), PrgFlags::DATA);
+
+        // Neither the indexed read's dummy read, the byte after the JMP nor
+        // the one the NOP throws away
+        for offset in [0x2008, PROGRAM.len(), 0x51] {
+            assert_eq!(cdl.prg_flags(offset), PrgFlags::empty(), "${offset:04X}");
+        }
+    }
+
+    #[test]
+    fn load_merges_an_earlier_log() {
+        let path = std::env::temp_dir().join(format!("nes-rs-cdl-{}", std::process::id()));
+        let mut old = vec![0; 0x4000 + 0x2000];
+        old[0x0000] = (PrgFlags::DATA | bank(3)).bits();
+        old[0x2010] = (PrgFlags::CODE | bank(3)).bits();
+        old[0x3000] = (PrgFlags::CODE | bank(2)).bits();
+        old[0x4000] = 0x01;
+        fs::write(&path, &old).unwrap();
+
+        let mut cdl = logged_run();
+        cdl.load(&path).unwrap();
+        // This session's bank bits win where it has any
+        assert_eq!(
+            cdl.prg_flags(0x0000),
+            PrgFlags::CODE | PrgFlags::DATA | bank(3)
+        );
+        assert_eq!(
+            cdl.prg_flags(0x2010),
+            PrgFlags::CODE | PrgFlags::DATA | bank(1)
+        );
+        assert_eq!(cdl.prg_flags(0x3000), PrgFlags::CODE | bank(2));
+        assert_eq!(cdl.chr[0], 0x01);
+
+        fs::write(&path, &old[..0x4000]).unwrap();
+        let err = cdl.load(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,468 @@
+use std::fmt;
+
+use crate::bus::{Access, Bus};
+use crate::cpu::{Cpu, StatusFlags};
+
+// Breakpoint conditions, such as `A == $20 && [$00FF] > 3`. Operands are
+// numbers ($hex, %binary or decimal), registers (A, X, Y, S, P, PC), flags
+// (C, Z, I, D, V, N), [addr] for a byte of CPU memory, {addr} for a
+// little-endian word, and `value` / `address` for the access that triggered
+// a watchpoint. Operators bind as in Rust: unary ! - ~, then + -, & ^ |,
+// comparisons, && and ||. Anything non-zero is true.
+
+#[derive(Clone, Debug)]
+pub struct Expr {
+    source: String,
+    root: Node,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Register {
+    A,
+    X,
+    Y,
+    S,
+    P,
+    Pc,
+    Flag(StatusFlags),
+    Value,
+    Address,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum UnaryOp {
+    Not,
+    Negate,
+    Complement,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BinaryOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Add,
+    Sub,
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    Number(i64),
+    Register(Register),
+    Byte(Box<Node>),
+    Word(Box<Node>),
+    Unary(UnaryOp, Box<Node>),
+    Binary(BinaryOp, Box<Node>, Box<Node>),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ExprError {
+    // Both carry the character offset into the expression
+    UnexpectedEnd(usize),
+    Unexpected(usize),
+    UnknownName(String),
+    InvalidNumber(String),
+}
+
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExprError::UnexpectedEnd(pos) => {
+                write!(f, "expression ends unexpectedly at offset {pos}")
+            }
+            ExprError::Unexpected(pos) => write!(f, "unexpected input at offset {pos}"),
+            ExprError::UnknownName(name) => write!(f, "unknown register or flag {name}"),
+            ExprError::InvalidNumber(text) => write!(f, "invalid number {text}"),
+        }
+    }
+}
+
+impl std::error::Error for ExprError {}
+
+impl Expr {
+    pub fn parse(source: &str) -> Result<Self, ExprError> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser {
+            tokens,
+            pos: 0,
+            end: source.len(),
+        };
+        let root = parser.parse_or()?;
+        if let Some(&(_, offset)) = parser.tokens.get(parser.pos) {
+            return Err(ExprError::Unexpected(offset));
+        }
+        Ok(Expr {
+            source: source.trim().to_string(),
+            root,
+        })
+    }
+
+    // Memory is read with Bus::peek, so evaluating a condition never
+    // disturbs the machine
+    pub fn eval(&self, cpu: &Cpu, bus: &Bus, access: Option<&Access>) -> i64 {
+        eval(&self.root, cpu, bus, access)
+    }
+
+    pub fn is_true(&self, cpu: &Cpu, bus: &Bus, access: Option<&Access>) -> bool {
+        self.eval(cpu, bus, access) != 0
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+fn eval(node: &Node, cpu: &Cpu, bus: &Bus, access: Option<&Access>) -> i64 {
+    let eval = |node: &Node| eval(node, cpu, bus, access);
+    match node {
+        Node::Number(n) => *n,
+        Node::Register(register) => match register {
+            Register::A => cpu.a as i64,
+            Register::X => cpu.x as i64,
+            Register::Y => cpu.y as i64,
+            Register::S => cpu.stkp as i64,
+            Register::P => cpu.status.bits() as i64,
+            Register::Pc => cpu.pc as i64,
+            Register::Flag(flag) => cpu.status.contains(*flag) as i64,
+            Register::Value => access.map_or(0, |access| access.data as i64),
+            Register::Address => access.map_or(0, |access| access.addr as i64),
+        },
+        Node::Byte(addr) => bus.peek(eval(addr) as u16) as i64,
+        Node::Word(addr) => {
+            let addr = eval(addr) as u16;
+            let lo = bus.peek(addr) as i64;
+            let hi = bus.peek(addr.wrapping_add(1)) as i64;
+            (hi << 8) | lo
+        }
+        Node::Unary(op, operand) => {
+            let value = eval(operand);
+            match op {
+                UnaryOp::Not => (value == 0) as i64,
+                UnaryOp::Negate => value.wrapping_neg(),
+                UnaryOp::Complement => !value,
+            }
+        }
+        Node::Binary(BinaryOp::Or, lhs, rhs) => (eval(lhs) != 0 || eval(rhs) != 0) as i64,
+        Node::Binary(BinaryOp::And, lhs, rhs) => (eval(lhs) != 0 && eval(rhs) != 0) as i64,
+        Node::Binary(op, lhs, rhs) => {
+            let (lhs, rhs) = (eval(lhs), eval(rhs));
+            match op {
+                BinaryOp::Eq => (lhs == rhs) as i64,
+                BinaryOp::Ne => (lhs != rhs) as i64,
+                BinaryOp::Lt => (lhs < rhs) as i64,
+                BinaryOp::Le => (lhs <= rhs) as i64,
+                BinaryOp::Gt => (lhs > rhs) as i64,
+                BinaryOp::Ge => (lhs >= rhs) as i64,
+                BinaryOp::BitOr => lhs | rhs,
+                BinaryOp::BitXor => lhs ^ rhs,
+                BinaryOp::BitAnd => lhs & rhs,
+                BinaryOp::Add => lhs.wrapping_add(rhs),
+                BinaryOp::Sub => lhs.wrapping_sub(rhs),
+                BinaryOp::Or | BinaryOp::And => unreachable!(),
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    // Operators and brackets
+    Symbol(&'static str),
+}
+
+const SYMBOLS: [&str; 21] = [
+    "==", "!=", "<=", ">=", "&&", "||", "<", ">", "&", "|", "^", "+", "-", "!", "~", "(", ")", "[",
+    "]", "{", "}",
+];
+
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, ExprError> {
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while pos < source.len() {
+        let rest = &source[pos..];
+        let c = rest.chars().next().unwrap();
+        if c.is_whitespace() {
+            pos += c.len_utf8();
+            continue;
+        }
+
+        let word_len = |skip: usize| {
+            rest[skip..]
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .map_or(rest.len(), |end| end + skip)
+        };
+
+        let (token, len) = if let Some(symbol) = SYMBOLS.iter().find(|s| rest.starts_with(**s)) {
+            (Token::Symbol(symbol), symbol.len())
+        } else if c == '$' || c == '%' || c.is_ascii_digit() {
+            let len = word_len(1);
+            (Token::Number(parse_number(&rest[..len])?), len)
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let len = word_len(0);
+            (Token::Name(rest[..len].to_string()), len)
+        } else {
+            return Err(ExprError::Unexpected(pos));
+        };
+        tokens.push((token, pos));
+        pos += len;
+    }
+    Ok(tokens)
+}
+
+fn parse_number(text: &str) -> Result<i64, ExprError> {
+    let (digits, radix) = if let Some(hex) = text.strip_prefix('$') {
+        (hex, 16)
+    } else if let Some(hex) = text.strip_prefix("0x") {
+        (hex, 16)
+    } else if let Some(bin) = text.strip_prefix('%') {
+        (bin, 2)
+    } else {
+        (text, 10)
+    };
+    i64::from_str_radix(digits, radix).map_err(|_| ExprError::InvalidNumber(text.to_string()))
+}
+
+fn register(name: &str) -> Option<Register> {
+    let register = match name.to_ascii_uppercase().as_str() {
+        "A" => Register::A,
+        "X" => Register::X,
+        "Y" => Register::Y,
+        "S" | "SP" => Register::S,
+        "P" => Register::P,
+        "PC" => Register::Pc,
+        "C" => Register::Flag(StatusFlags::CARRY),
+        "Z" => Register::Flag(StatusFlags::ZERO),
+        "I" => Register::Flag(StatusFlags::INTERRUPT_DISABLE),
+        "D" => Register::Flag(StatusFlags::DECIMAL_MODE),
+        "V" => Register::Flag(StatusFlags::OVERFLOW),
+        "N" => Register::Flag(StatusFlags::NEGATIVE),
+        "VALUE" => Register::Value,
+        "ADDRESS" | "ADDR" => Register::Address,
+        _ => return None,
+    };
+    Some(register)
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(token, _)| token)
+    }
+
+    fn next(&mut self) -> Result<(Token, usize), ExprError> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token.ok_or(ExprError::UnexpectedEnd(self.end))
+    }
+
+    fn eat(&mut self, symbol: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Symbol(s)) if *s == symbol) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), ExprError> {
+        let (token, offset) = self.next()?;
+        if matches!(token, Token::Symbol(s) if s == symbol) {
+            Ok(())
+        } else {
+            Err(ExprError::Unexpected(offset))
+        }
+    }
+
+    // One precedence level of left-associative binary operators
+    fn binary(
+        &mut self,
+        ops: &[(&str, BinaryOp)],
+        operand: fn(&mut Self) -> Result<Node, ExprError>,
+    ) -> Result<Node, ExprError> {
+        let mut lhs = operand(self)?;
+        'outer: loop {
+            for &(symbol, op) in ops {
+                if self.eat(symbol) {
+                    let rhs = operand(self)?;
+                    lhs = Node::Binary(op, Box::new(lhs), Box::new(rhs));
+                    continue 'outer;
+                }
+            }
+            return Ok(lhs);
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Node, ExprError> {
+        self.binary(&[("||", BinaryOp::Or)], Self::parse_and)
+    }
+
+    fn parse_and(&mut self) -> Result<Node, ExprError> {
+        self.binary(&[("&&", BinaryOp::And)], Self::parse_comparison)
+    }
+
+    fn parse_comparison(&mut self) -> Result<Node, ExprError> {
+        self.binary(
+            &[
+                ("==", BinaryOp::Eq),
+                ("!=", BinaryOp::Ne),
+                ("<=", BinaryOp::Le),
+                (">=", BinaryOp::Ge),
+                ("<", BinaryOp::Lt),
+                (">", BinaryOp::Gt),
+            ],
+            Self::parse_bit_or,
+        )
+    }
+
+    fn parse_bit_or(&mut self) -> Result<Node, ExprError> {
+        self.binary(&[("|", BinaryOp::BitOr)], Self::parse_bit_xor)
+    }
+
+    fn parse_bit_xor(&mut self) -> Result<Node, ExprError> {
+        self.binary(&[("^", BinaryOp::BitXor)], Self::parse_bit_and)
+    }
+
+    fn parse_bit_and(&mut self) -> Result<Node, ExprError> {
+        self.binary(&[("&", BinaryOp::BitAnd)], Self::parse_sum)
+    }
+
+    fn parse_sum(&mut self) -> Result<Node, ExprError> {
+        self.binary(
+            &[("+", BinaryOp::Add), ("-", BinaryOp::Sub)],
+            Self::parse_unary,
+        )
+    }
+
+    fn parse_unary(&mut self) -> Result<Node, ExprError> {
+        for (symbol, op) in [
+            ("!", UnaryOp::Not),
+            ("-", UnaryOp::Negate),
+            ("~", UnaryOp::Complement),
+        ] {
+            if self.eat(symbol) {
+                return Ok(Node::Unary(op, Box::new(self.parse_unary()?)));
+            }
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<Node, ExprError> {
+        let (token, offset) = self.next()?;
+        match token {
+            Token::Number(n) => Ok(Node::Number(n)),
+            Token::Name(name) => register(&name)
+                .map(Node::Register)
+                .ok_or(ExprError::UnknownName(name)),
+            Token::Symbol("(") => {
+                let node = self.parse_or()?;
+                self.expect(")")?;
+                Ok(node)
+            }
+            Token::Symbol("[") => {
+                let node = self.parse_or()?;
+                self.expect("]")?;
+                Ok(Node::Byte(Box::new(node)))
+            }
+            Token::Symbol("{") => {
+                let node = self.parse_or()?;
+                self.expect("}")?;
+                Ok(Node::Word(Box::new(node)))
+            }
+            Token::Symbol(_) => Err(ExprError::Unexpected(offset)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{AccessKind, AddressSpace};
+    use crate::console::Console;
+
+    fn eval(source: &str, console: &Console, access: Option<&Access>) -> i64 {
+        Expr::parse(source)
+            .unwrap()
+            .eval(&console.cpu, &console.bus, access)
+    }
+
+    #[test]
+    fn evaluates_registers_and_memory() {
+        let mut console = Console::new();
+        console.cpu.a = 0x20;
+        console.bus.write(0x00FF, 0x04);
+        console.bus.write(0x0010, 0x34);
+        console.bus.write(0x0011, 0x12);
+
+        let condition = Expr::parse("A == $20 && [$00FF] > 3").unwrap();
+        assert!(condition.is_true(&console.cpu, &console.bus, None));
+        assert_eq!(condition.to_string(), "A == $20 && [$00FF] > 3");
+        console.bus.write(0x00FF, 0x03);
+        assert!(!condition.is_true(&console.cpu, &console.bus, None));
+
+        assert_eq!(eval("{$10}", &console, None), 0x1234);
+        assert_eq!(eval("{$0E + 2} - [%10000]", &console, None), 0x1234 - 0x34);
+    }
+
+    #[test]
+    fn operators_bind_as_in_rust() {
+        let mut console = Console::new();
+        // (1 | 2) == 3, where C would read 1 | (2 == 3)
+        assert_eq!(eval("1 | 2 == 3", &console, None), 1);
+        assert_eq!(eval("2 | 1 == 1", &console, None), 0);
+        assert_eq!(eval("2 | (1 == 1)", &console, None), 3);
+        assert_eq!(eval("1 + 2 & 6", &console, None), 2);
+
+        // (!A) && X
+        console.cpu.x = 1;
+        assert_eq!(eval("!A && X", &console, None), 1);
+        console.cpu.a = 1;
+        assert_eq!(eval("!A && X", &console, None), 0);
+        assert_eq!(eval("!(A && X)", &console, None), 0);
+        assert_eq!(eval("-1 < 0 || 0", &console, None), 1);
+    }
+
+    #[test]
+    fn watchpoint_access_operands() {
+        let console = Console::new();
+        let access = Access {
+            space: AddressSpace::Cpu,
+            kind: AccessKind::Write,
+            addr: 0x2007,
+            data: 0x3F,
+            rom_offset: None,
+        };
+        assert_eq!(eval("value", &console, Some(&access)), 0x3F);
+        assert_eq!(eval("address == $2007", &console, Some(&access)), 1);
+        assert_eq!(eval("addr + VALUE", &console, Some(&access)), 0x2007 + 0x3F);
+        assert_eq!(eval("value", &console, None), 0);
+    }
+
+    #[test]
+    fn errors_point_into_the_source() {
+        let parse = |source| Expr::parse(source).unwrap_err();
+        assert_eq!(parse("A =="), ExprError::UnexpectedEnd(4));
+        assert_eq!(parse("[1"), ExprError::UnexpectedEnd(2));
+        assert_eq!(parse("A == )"), ExprError::Unexpected(5));
+        assert_eq!(parse("[1 X"), ExprError::Unexpected(3));
+        assert_eq!(parse("A @ 1"), ExprError::Unexpected(2));
+        assert_eq!(parse("Q == 1"), ExprError::UnknownName("Q".to_string()));
+        assert_eq!(parse("$12G"), ExprError::InvalidNumber("$12G".to_string()));
+    }
+}
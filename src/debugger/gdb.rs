@@ -0,0 +1,509 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::bus::{AccessKind, AddressSpace};
+use crate::cpu::StatusFlags;
+use crate::debugger::{AccessFlags, Breakpoint, Debugger, Stop};
+
+// GDB Remote Serial Protocol server, so GDB-compatible frontends can debug
+// the running game. Registers are A, X, Y, S and P (8 bits each) then PC
+// (16 bits, little-endian), as described to the client by target.xml.
+// Memory reads are peeks and so never disturb the machine; writes are
+// pokes, which reach RAM and nothing else. Breakpoints are kept by the
+// debugger rather than patched into memory, which is mostly ROM.
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.nes-rs.6502">
+    <reg name="a" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="x" bitsize="8" type="uint8"/>
+    <reg name="y" bitsize="8" type="uint8"/>
+    <reg name="s" bitsize="8" type="uint8"/>
+    <reg name="p" bitsize="8" type="uint8"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+// The largest packet the client is told it can send, and so the most
+// memory one m packet reads (two hex digits a byte, plus the $, # and
+// checksum around them)
+const PACKET_SIZE: usize = 0x4000;
+const MAX_READ: usize = (PACKET_SIZE - 4) / 2;
+
+// Cycles run between checks for the client interrupting a continue
+const CONTINUE_SLICE: u64 = 10_000;
+
+// Stop signals
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+pub struct GdbStub {
+    debugger: Debugger,
+    stream: TcpStream,
+    // Breakpoint ids by (Z packet type, address, length)
+    breakpoints: Vec<((u8, u16, u16), u32)>,
+    no_ack: bool,
+    // Bytes that arrived during a continue, waiting to be read as packets
+    unread: VecDeque<u8>,
+}
+
+impl GdbStub {
+    // Waits for a frontend to connect on listener
+    pub fn accept(debugger: Debugger, listener: &TcpListener) -> io::Result<Self> {
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        Ok(GdbStub {
+            debugger,
+            stream,
+            breakpoints: Vec::new(),
+            no_ack: false,
+            unread: VecDeque::new(),
+        })
+    }
+
+    pub fn debugger(&mut self) -> &mut Debugger {
+        &mut self.debugger
+    }
+
+    // Serves requests until the client detaches, kills the session or
+    // disconnects
+    pub fn serve(&mut self) -> io::Result<()> {
+        loop {
+            let Some(packet) = self.read_packet()? else {
+                return Ok(());
+            };
+            let reply = match packet.first() {
+                Some(b'D') => {
+                    self.send("OK")?;
+                    return Ok(());
+                }
+                Some(b'k') => return Ok(()),
+                _ => self.handle(&packet)?,
+            };
+            self.send(&reply)?;
+            // Acks stop from the packet after this one's reply
+            if packet == b"QStartNoAckMode" {
+                self.no_ack = true;
+            }
+        }
+    }
+
+    fn handle(&mut self, packet: &[u8]) -> io::Result<String> {
+        let text = String::from_utf8_lossy(packet);
+        if text.is_empty() || !text.is_char_boundary(1) {
+            return Ok(String::new());
+        }
+        let (command, args) = text.split_at(1);
+        let reply = match command {
+            "?" => format!("S{SIGTRAP:02x}"),
+            "g" => self.read_registers(),
+            "G" => self.write_registers(args),
+            "p" => self.read_register(args),
+            "P" => self.write_register(args),
+            "m" => self.read_memory(args),
+            "M" => self.write_memory(args),
+            "s" => {
+                self.jump(args);
+                let stop = self.debugger.step();
+                self.stop_reply(stop)
+            }
+            "c" => {
+                self.jump(args);
+                self.resume()?
+            }
+            "Z" => self.insert_breakpoint(args),
+            "z" => self.remove_breakpoint(args),
+            "H" => "OK".to_string(),
+            "q" => self.query(args),
+            "Q" if args == "StartNoAckMode" => "OK".to_string(),
+            _ => String::new(),
+        };
+        Ok(reply)
+    }
+
+    fn query(&self, args: &str) -> String {
+        if args.starts_with("Supported") {
+            format!(
+                "PacketSize={PACKET_SIZE:x};qXfer:features:read+;swbreak+;hwbreak+;QStartNoAckMode+"
+            )
+        } else if args == "Attached" {
+            "1".to_string()
+        } else if args == "C" {
+            "QC1".to_string()
+        } else if args == "fThreadInfo" {
+            "m1".to_string()
+        } else if args == "sThreadInfo" {
+            "l".to_string()
+        } else if let Some(range) = args.strip_prefix("Xfer:features:read:target.xml:") {
+            let Some((offset, len)) = parse_pair(range) else {
+                return "E01".to_string();
+            };
+            let offset = (offset as usize).min(TARGET_XML.len());
+            let end = (offset + len as usize).min(TARGET_XML.len());
+            let marker = if end == TARGET_XML.len() { 'l' } else { 'm' };
+            format!("{marker}{}", &TARGET_XML[offset..end])
+        } else {
+            String::new()
+        }
+    }
+
+    fn registers(&self) -> [u8; 7] {
+        let cpu = &self.debugger.console().cpu;
+        let [pc_lo, pc_hi] = cpu.pc.to_le_bytes();
+        [
+            cpu.a,
+            cpu.x,
+            cpu.y,
+            cpu.stkp,
+            cpu.status.bits(),
+            pc_lo,
+            pc_hi,
+        ]
+    }
+
+    fn set_registers(&mut self, regs: [u8; 7]) {
+        let cpu = &mut self.debugger.console_mut().cpu;
+        cpu.a = regs[0];
+        cpu.x = regs[1];
+        cpu.y = regs[2];
+        cpu.stkp = regs[3];
+        cpu.status = StatusFlags::from_bits_retain(regs[4]);
+        cpu.pc = u16::from_le_bytes([regs[5], regs[6]]);
+    }
+
+    fn read_registers(&self) -> String {
+        hex(&self.registers())
+    }
+
+    fn write_registers(&mut self, args: &str) -> String {
+        match unhex(args).and_then(|bytes| <[u8; 7]>::try_from(bytes).ok()) {
+            Some(regs) => {
+                self.set_registers(regs);
+                "OK".to_string()
+            }
+            None => "E01".to_string(),
+        }
+    }
+
+    fn read_register(&self, args: &str) -> String {
+        let regs = self.registers();
+        match u8::from_str_radix(args, 16) {
+            Ok(n @ 0..=4) => hex(&regs[n as usize..n as usize + 1]),
+            Ok(5) => hex(&regs[5..7]),
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let mut regs = self.registers();
+        let Some((n, value)) = args.split_once('=') else {
+            return "E01".to_string();
+        };
+        let (Ok(n), Some(value)) = (usize::from_str_radix(n, 16), unhex(value)) else {
+            return "E01".to_string();
+        };
+        match (n, value.as_slice()) {
+            (0..=4, &[byte]) => regs[n] = byte,
+            (5, &[lo, hi]) => regs[5..7].copy_from_slice(&[lo, hi]),
+            _ => return "E01".to_string(),
+        }
+        self.set_registers(regs);
+        "OK".to_string()
+    }
+
+    fn read_memory(&self, args: &str) -> String {
+        let Some((addr, len)) = parse_pair(args) else {
+            return "E01".to_string();
+        };
+        // Replies may be short, and the client asks again for the rest
+        let len = (len as usize).min(MAX_READ);
+        let bus = &self.debugger.console().bus;
+        let data: Vec<u8> = (0..len)
+            .map(|i| bus.peek((addr as u16).wrapping_add(i as u16)))
+            .collect();
+        hex(&data)
+    }
+
+    fn write_memory(&mut self, args: &str) -> String {
+        let Some((range, data)) = args.split_once(':') else {
+            return "E01".to_string();
+        };
+        let (Some((addr, len)), Some(data)) = (parse_pair(range), unhex(data)) else {
+            return "E01".to_string();
+        };
+        if data.len() != len as usize {
+            return "E01".to_string();
+        }
+        // Writing through the bus would set off mapper registers, so ROM and
+        // I/O addresses are refused rather than written
+        let bus = &mut self.debugger.console_mut().bus;
+        let written = data
+            .into_iter()
+            .enumerate()
+            .all(|(i, byte)| bus.poke((addr as u16).wrapping_add(i as u16), byte));
+        if written { "OK" } else { "E01" }.to_string()
+    }
+
+    // s and c can carry an address to resume from
+    fn jump(&mut self, args: &str) {
+        if let Ok(addr) = u16::from_str_radix(args, 16) {
+            self.debugger.console_mut().cpu.pc = addr;
+        }
+    }
+
+    // Runs until a breakpoint fires or the client sends an interrupt (^C)
+    fn resume(&mut self) -> io::Result<String> {
+        loop {
+            match self.debugger.run(CONTINUE_SLICE) {
+                Stop::CycleLimit => {}
+                stop => return Ok(self.stop_reply(stop)),
+            }
+
+            if self.poll_interrupt()? {
+                return Ok(format!("S{SIGINT:02x}"));
+            }
+        }
+    }
+
+    // Takes whatever the client has sent without waiting for more, keeping
+    // all but a ^C for read_packet
+    fn poll_interrupt(&mut self) -> io::Result<bool> {
+        let mut buf = [0; 64];
+        self.stream.set_nonblocking(true)?;
+        let read = self.stream.read(&mut buf);
+        self.stream.set_nonblocking(false)?;
+        match read {
+            Ok(0) => return Err(io::ErrorKind::ConnectionAborted.into()),
+            Ok(n) => self.unread.extend(&buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+
+        let interrupt = interrupt_position(&self.unread);
+        if let Some(index) = interrupt {
+            self.unread.remove(index);
+        }
+        Ok(interrupt.is_some())
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        if let Some(byte) = self.unread.pop_front() {
+            return Ok(Some(byte));
+        }
+        let mut byte = [0];
+        Ok((self.stream.read(&mut byte)? != 0).then_some(byte[0]))
+    }
+
+    fn stop_reply(&self, stop: Stop) -> String {
+        match stop {
+            Stop::Breakpoint { access, .. } => {
+                let reason = match access.kind {
+                    AccessKind::Execute => "swbreak:".to_string(),
+                    AccessKind::Write => format!("watch:{:x}", access.addr),
+                    AccessKind::Read => format!("rwatch:{:x}", access.addr),
+                };
+                format!("T{SIGTRAP:02x}{reason};")
+            }
+            _ => format!("S{SIGTRAP:02x}"),
+        }
+    }
+
+    // Z0/Z1 are breakpoints, Z2/Z3/Z4 write, read and access watchpoints
+    fn insert_breakpoint(&mut self, args: &str) -> String {
+        let Some((kind, addr, len)) = parse_breakpoint(args) else {
+            return "E01".to_string();
+        };
+        let access = match kind {
+            0 | 1 => AccessFlags::EXECUTE,
+            2 => AccessFlags::WRITE,
+            3 => AccessFlags::READ,
+            4 => AccessFlags::READ | AccessFlags::WRITE,
+            _ => return String::new(),
+        };
+        let end = addr.saturating_add(len.max(1) - 1);
+        let breakpoint = Breakpoint::watch(AddressSpace::Cpu, addr..=end, access);
+        let id = self.debugger.add_breakpoint(breakpoint);
+        self.breakpoints.push(((kind, addr, len), id));
+        "OK".to_string()
+    }
+
+    fn remove_breakpoint(&mut self, args: &str) -> String {
+        let Some(key) = parse_breakpoint(args) else {
+            return "E01".to_string();
+        };
+        if let Some(index) = self.breakpoints.iter().position(|(k, _)| *k == key) {
+            let (_, id) = self.breakpoints.remove(index);
+            self.debugger.remove_breakpoint(id);
+        }
+        "OK".to_string()
+    }
+
+    // Packets are $<data>#<checksum>, acknowledged with + unless no-ack mode
+    // was negotiated. Returns None once the client disconnects.
+    fn read_packet(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => break,
+                // Acks, and interrupts that arrived while already stopped
+                Some(_) => {}
+            }
+        }
+
+        let mut data = Vec::new();
+        let mut sum: u8 = 0;
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'#') => break,
+                Some(byte) => {
+                    sum = sum.wrapping_add(byte);
+                    data.push(byte);
+                }
+            }
+        }
+        let mut checksum = [0; 2];
+        for digit in &mut checksum {
+            *digit = self.read_byte()?.ok_or(io::ErrorKind::UnexpectedEof)?;
+        }
+
+        let valid = std::str::from_utf8(&checksum)
+            .ok()
+            .and_then(|c| u8::from_str_radix(c, 16).ok())
+            == Some(sum);
+        if !self.no_ack {
+            self.stream.write_all(if valid { b"+" } else { b"-" })?;
+        }
+        if !valid {
+            return self.read_packet();
+        }
+        Ok(Some(unescape(&data)))
+    }
+
+    fn send(&mut self, reply: &str) -> io::Result<()> {
+        let sum = reply.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        write!(self.stream, "${reply}#{sum:02x}")?;
+        self.stream.flush()?;
+        if !self.no_ack {
+            // The client's ack; retransmission on - isn't worth handling on
+            // a local TCP connection
+            self.read_byte()?.ok_or(io::ErrorKind::UnexpectedEof)?;
+        }
+        Ok(())
+    }
+}
+
+// Where a ^C is among bytes from the client. One inside a packet is just
+// data.
+fn interrupt_position(bytes: &VecDeque<u8>) -> Option<usize> {
+    let mut in_packet = false;
+    let mut checksum = 0;
+    for (i, &byte) in bytes.iter().enumerate() {
+        match byte {
+            _ if checksum > 0 => checksum -= 1,
+            b'$' => in_packet = true,
+            b'#' if in_packet => {
+                in_packet = false;
+                checksum = 2;
+            }
+            0x03 if !in_packet => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+// } escapes the next byte, XORed with $20
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut bytes = data.iter();
+    while let Some(&byte) = bytes.next() {
+        if byte == b'}' {
+            if let Some(&next) = bytes.next() {
+                out.push(next ^ 0x20);
+            }
+        } else {
+            out.push(byte);
+        }
+    }
+    out
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+// "addr,len" in hex
+fn parse_pair(text: &str) -> Option<(u32, u32)> {
+    let (a, b) = text.split_once(',')?;
+    Some((
+        u32::from_str_radix(a, 16).ok()?,
+        u32::from_str_radix(b, 16).ok()?,
+    ))
+}
+
+// "type,addr,kind", where kind is the length for watchpoints
+fn parse_breakpoint(text: &str) -> Option<(u8, u16, u16)> {
+    let (kind, rest) = text.split_once(',')?;
+    let rest = rest.split(';').next()?;
+    let (addr, len) = parse_pair(rest)?;
+    Some((kind.parse().ok()?, addr as u16, len as u16))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::Cartridge;
+    use crate::console::Console;
+
+    // A stub serving JMP $8000, and the client's end of its connection
+    fn connect() -> (GdbStub, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut console = Console::with_cartridge(Cartridge::nrom(&[0x4C, 0x00, 0x80]));
+        console.reset();
+        let mut stub = GdbStub::accept(Debugger::new(console), &listener).unwrap();
+        stub.no_ack = true;
+        (stub, client)
+    }
+
+    #[test]
+    fn memory_reads_are_capped_at_the_packet_size() {
+        let (stub, _client) = connect();
+        assert_eq!(stub.read_memory("8000,3"), "4c0080");
+        assert_eq!(stub.read_memory("0,ffffffff").len(), MAX_READ * 2);
+    }
+
+    #[test]
+    fn memory_writes_only_reach_ram() {
+        let (mut stub, _client) = connect();
+        assert_eq!(stub.write_memory("10,2:abcd"), "OK");
+        assert_eq!(stub.write_memory("6000,1:ef"), "OK");
+        assert_eq!(stub.write_memory("8000,1:ea"), "E01");
+        assert_eq!(stub.read_memory("10,2"), "abcd");
+        assert_eq!(stub.read_memory("6000,1"), "ef");
+        assert_eq!(stub.read_memory("8000,1"), "4c");
+    }
+
+    #[test]
+    fn continuing_keeps_what_the_client_sends_besides_a_break() {
+        let (mut stub, mut client) = connect();
+        client.write_all(b"$g#67\x03").unwrap();
+        let reply = stub.resume().unwrap();
+        assert_eq!(reply, format!("S{SIGINT:02x}"));
+        assert_eq!(stub.read_packet().unwrap().as_deref(), Some(&b"g"[..]));
+    }
+}
@@ -0,0 +1,331 @@
+mod cdl;
+mod expr;
+mod gdb;
+mod tui;
+
+pub use cdl::{CodeDataLog, PrgFlags};
+pub use expr::{Expr, ExprError};
+pub use gdb::GdbStub;
+pub use tui::Tui;
+
+use std::ops::RangeInclusive;
+
+use bitflags::bitflags;
+
+use crate::bus::{Access, AccessKind, AddressSpace};
+use crate::console::Console;
+use crate::cpu::Interrupt;
+
+bitflags! {
+    // Which accesses a breakpoint watches for
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct AccessFlags: u8 {
+        const READ    = (1 << 0);
+        const WRITE   = (1 << 1);
+        const EXECUTE = (1 << 2);
+    }
+}
+
+bitflags! {
+    // Interrupts the debugger stops on as the CPU enters their handler
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct InterruptFlags: u8 {
+        const NMI = (1 << 0);
+        const IRQ = (1 << 1);
+        const BRK = (1 << 2);
+    }
+}
+
+impl AccessFlags {
+    fn matches(self, kind: AccessKind) -> bool {
+        match kind {
+            AccessKind::Read => self.contains(AccessFlags::READ),
+            AccessKind::Write => self.contains(AccessFlags::WRITE),
+            AccessKind::Execute => self.contains(AccessFlags::EXECUTE),
+        }
+    }
+}
+
+impl InterruptFlags {
+    fn matches(self, interrupt: Interrupt) -> bool {
+        match interrupt {
+            Interrupt::Nmi => self.contains(InterruptFlags::NMI),
+            Interrupt::Irq => self.contains(InterruptFlags::IRQ),
+            Interrupt::Brk => self.contains(InterruptFlags::BRK),
+        }
+    }
+}
+
+// An execution breakpoint or a watchpoint: accesses of the given kinds to
+// an address range, optionally only when a condition holds
+#[derive(Clone, Debug)]
+pub struct Breakpoint {
+    pub space: AddressSpace,
+    pub range: RangeInclusive<u16>,
+    pub access: AccessFlags,
+    pub condition: Option<Expr>,
+    pub enabled: bool,
+
+    // Matching accesses so far (for which the condition held)
+    pub hits: u64,
+    // Hits to let through before stopping, as with gdb's ignore counts
+    pub ignore: u64,
+}
+
+impl Breakpoint {
+    pub fn execute(addr: u16) -> Self {
+        Self::watch(AddressSpace::Cpu, addr..=addr, AccessFlags::EXECUTE)
+    }
+
+    // PPU watchpoints can be set but never fire yet: the bus only sees PPU
+    // accesses once there's a PPU to make them
+    pub fn watch(space: AddressSpace, range: RangeInclusive<u16>, access: AccessFlags) -> Self {
+        Breakpoint {
+            space,
+            range,
+            access,
+            condition: None,
+            enabled: true,
+            hits: 0,
+            ignore: 0,
+        }
+    }
+
+    pub fn with_condition(mut self, condition: &str) -> Result<Self, ExprError> {
+        self.condition = Some(Expr::parse(condition)?);
+        Ok(self)
+    }
+
+    fn matches(&self, access: &Access) -> bool {
+        self.enabled
+            && self.space == access.space
+            && self.access.matches(access.kind)
+            && self.range.contains(&access.addr)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stop {
+    // Execution breakpoints stop before the instruction runs; watchpoints
+    // stop once the instruction that made the access has finished
+    Breakpoint { id: u32, access: Access },
+    Interrupt(Interrupt),
+    // A step finished
+    Step,
+    // run() used up its cycle budget, and then finished the instruction
+    CycleLimit,
+}
+
+// Runs the machine under a set of breakpoints, stopping as soon as one
+// fires. The console it's given logs every bus access for it to check.
+pub struct Debugger {
+    console: Console,
+    breakpoints: Vec<(u32, Breakpoint)>,
+    next_id: u32,
+    // Set on stopping at an execution breakpoint, so resuming from there
+    // runs the instruction instead of stopping again
+    resume_at: Option<u16>,
+    pub break_on: InterruptFlags,
+    pub cdl: Option<CodeDataLog>,
+}
+
+impl Debugger {
+    pub fn new(mut console: Console) -> Self {
+        console.bus.set_access_logging(true);
+        Debugger {
+            console,
+            breakpoints: Vec::new(),
+            next_id: 1,
+            resume_at: None,
+            break_on: InterruptFlags::empty(),
+            cdl: None,
+        }
+    }
+
+    pub fn console(&self) -> &Console {
+        &self.console
+    }
+
+    pub fn console_mut(&mut self) -> &mut Console {
+        &mut self.console
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.breakpoints.push((id, breakpoint));
+        id
+    }
+
+    pub fn remove_breakpoint(&mut self, id: u32) -> Option<Breakpoint> {
+        let index = self.breakpoints.iter().position(|(i, _)| *i == id)?;
+        Some(self.breakpoints.remove(index).1)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = (u32, &Breakpoint)> {
+        self.breakpoints.iter().map(|(id, bp)| (*id, bp))
+    }
+
+    // Runs until something stops it or max_cycles have gone by. Resuming
+    // from an execution breakpoint runs the instruction it stopped at.
+    pub fn run(&mut self, max_cycles: u64) -> Stop {
+        for _ in 0..max_cycles {
+            if let Some(stop) = self.clock() {
+                return stop;
+            }
+        }
+        self.finish_instruction();
+        Stop::CycleLimit
+    }
+
+    // Runs one whole instruction (or interrupt sequence)
+    pub fn step(&mut self) -> Stop {
+        loop {
+            if let Some(stop) = self.clock() {
+                return stop;
+            }
+            if self.console.cpu.complete() {
+                return Stop::Step;
+            }
+        }
+    }
+
+    // Advances one CPU cycle, checking breakpoints against what it did
+    fn clock(&mut self) -> Option<Stop> {
+        // About to fetch an opcode
+        let resume_at = self.resume_at.take();
+        if self.console.cpu.complete() && resume_at != Some(self.console.cpu.pc) {
+            let pc = self.console.cpu.pc;
+            let bus = &self.console.bus;
+            let access = Access {
+                space: AddressSpace::Cpu,
+                kind: AccessKind::Execute,
+                addr: pc,
+                data: bus.peek(pc),
+                rom_offset: bus.rom_offset(AddressSpace::Cpu, pc),
+            };
+            if let Some(stop) = self.check(&[access]) {
+                self.resume_at = Some(pc);
+                return Some(stop);
+            }
+        }
+
+        let stop = self.clock_logged();
+        let interrupt = self.console.cpu.take_interrupt();
+        if stop.is_some() {
+            // Stopping halfway through would leave the registers torn for
+            // anything that reads or changes them, so the instruction that
+            // hit a watchpoint runs to its end first
+            self.finish_instruction();
+            return stop;
+        }
+        match interrupt {
+            Some(interrupt) if self.break_on.matches(interrupt) => Some(Stop::Interrupt(interrupt)),
+            _ => None,
+        }
+    }
+
+    // One cycle with its accesses logged and checked, but no stopping
+    fn clock_logged(&mut self) -> Option<Stop> {
+        let starting = self.console.cpu.complete();
+        self.console.clock();
+
+        let accesses = self.console.bus.take_accesses();
+        if let Some(cdl) = self.cdl.as_mut() {
+            cdl.log(starting, self.console.cpu.dummy_access(), &accesses);
+        }
+        self.check(&accesses)
+    }
+
+    // Breakpoints hit on the way still count their hits
+    fn finish_instruction(&mut self) {
+        while !self.console.cpu.complete() {
+            self.clock_logged();
+            self.console.cpu.take_interrupt();
+        }
+    }
+
+    // Every matching breakpoint counts a hit; the first to stop is reported
+    fn check(&mut self, accesses: &[Access]) -> Option<Stop> {
+        if self.breakpoints.is_empty() {
+            return None;
+        }
+
+        let Console { cpu, bus } = &self.console;
+        let mut stop = None;
+        for access in accesses {
+            for (id, bp) in self.breakpoints.iter_mut() {
+                if !bp.matches(access) {
+                    continue;
+                }
+                if let Some(condition) = &bp.condition
+                    && !condition.is_true(cpu, bus, Some(access))
+                {
+                    continue;
+                }
+                bp.hits += 1;
+                if bp.hits > bp.ignore && stop.is_none() {
+                    stop = Some(Stop::Breakpoint {
+                        id: *id,
+                        access: *access,
+                    });
+                }
+            }
+        }
+        stop
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::Cartridge;
+
+    // INC $0200, then LDA #$01 at $8003
+    fn debugger() -> Debugger {
+        let mut console = Console::with_cartridge(Cartridge::nrom(&[0xEE, 0x00, 0x02, 0xA9, 0x01]));
+        console.reset();
+        Debugger::new(console)
+    }
+
+    #[test]
+    fn watchpoints_stop_after_the_instruction() {
+        let mut debugger = debugger();
+        debugger.add_breakpoint(Breakpoint::watch(
+            AddressSpace::Cpu,
+            0x0200..=0x0200,
+            AccessFlags::WRITE,
+        ));
+
+        // The read-modify-write's dummy write is what trips it, a cycle
+        // before the real one
+        let stop = debugger.run(100);
+        assert!(matches!(stop, Stop::Breakpoint { access, .. } if access.data == 0x00));
+        assert!(debugger.console().cpu.complete());
+        assert_eq!(debugger.console().cpu.pc, 0x8003);
+        assert_eq!(debugger.console().bus.peek(0x0200), 0x01);
+    }
+
+    #[test]
+    fn resuming_only_skips_the_breakpoint_it_stopped_at() {
+        let mut debugger = debugger();
+        let first = debugger.add_breakpoint(Breakpoint::execute(0x8000));
+        debugger.add_breakpoint(Breakpoint::execute(0x8003));
+
+        assert!(
+            matches!(debugger.run(100), Stop::Breakpoint { access, .. } if access.addr == 0x8000)
+        );
+        assert!(
+            matches!(debugger.run(100), Stop::Breakpoint { access, .. } if access.addr == 0x8003)
+        );
+
+        // Nor does a cycle limit hit mid-instruction skip the next one
+        debugger.remove_breakpoint(first);
+        debugger.console_mut().cpu.pc = 0x8000;
+        assert_eq!(debugger.run(1), Stop::CycleLimit);
+        assert!(debugger.console().cpu.complete());
+        assert!(
+            matches!(debugger.run(100), Stop::Breakpoint { access, .. } if access.addr == 0x8003)
+        );
+    }
+}
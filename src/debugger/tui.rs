@@ -0,0 +1,727 @@
+use std::io::{self, Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use crate::bus::Bus;
+use crate::console::{CYCLES_PER_FRAME, Console};
+use crate::cpu::StatusFlags;
+use crate::debugger::{AccessFlags, Breakpoint, Debugger, InterruptFlags, Stop};
+use crate::disasm;
+use crate::mapper::Fds;
+use crate::rewind::Rewind;
+use crate::symbols::Symbols;
+
+// Full-screen terminal debugger. It only needs a terminal that understands
+// ANSI escapes and `stty`, so it works over plain SSH sessions.
+//
+//   s step       n step over    o step out     c continue    t run to cursor
+//   up/down (or k/j) move the cursor           g go to address
+//   b toggle breakpoint at cursor    B add breakpoint    d delete breakpoint
+//   m memory view address            i toggle break on NMI/IRQ/BRK
+//   w save state   r restore it      z rewind to the previous frame
+//   x scan a Datach barcode
+//   e eject the FDS disk             f insert a disk side
+//   q quit
+//
+// B takes `addr` or `addr if condition` and only sets execution breakpoints;
+// there are no PPU watchpoints, as nothing makes PPU accesses yet.
+
+const HELP: &str = "s step  n over  o out  c continue  t to cursor  b/B/d breakpoints  \
+                    g goto  m memory  i interrupts  w/r state  z rewind  x barcode  e/f disk  q quit";
+
+const DISASM_LINES: usize = 20;
+const MEMORY_ROWS: usize = 8;
+const LEFT_WIDTH: usize = 56;
+
+// Instructions run between checks for a key press while running
+const RUN_SLICE: usize = 1000;
+
+// Memory the rewind history may use, with a snapshot every frame
+const REWIND_BUDGET: usize = 16 << 20;
+
+// How often battery RAM is written out while the game runs
+const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy)]
+enum Goal {
+    // Until a breakpoint or a key press
+    Continue,
+    // Until PC reaches an address, optionally only at a given stack depth
+    Until(u16, Option<u8>),
+    // Until an RTS or RTI pops the current stack frame
+    StepOut(u8),
+}
+
+enum Key {
+    Char(char),
+    Up,
+    Down,
+    Enter,
+    Backspace,
+    Other,
+}
+
+pub struct Tui {
+    debugger: Debugger,
+    keys: Receiver<u8>,
+    symbols: Symbols,
+
+    // Top of the disassembly pane and the cursor within it
+    top: u16,
+    cursor: u16,
+    memory: u16,
+    status: String,
+    // Quick save slot
+    state: Option<Vec<u8>>,
+    // Snapshots taken on entering each frame, counted from rewind_origin
+    rewind: Rewind,
+    rewind_origin: u64,
+    last_autosave: Instant,
+}
+
+impl Tui {
+    pub fn new(console: Console, symbols: Symbols) -> Self {
+        let (sender, keys) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut stdin = io::stdin().lock();
+            let mut buf = [0; 64];
+            while let Ok(n @ 1..) = stdin.read(&mut buf) {
+                if buf[..n].iter().any(|&byte| sender.send(byte).is_err()) {
+                    break;
+                }
+            }
+        });
+
+        let pc = console.cpu.pc;
+        let rewind_origin = console.bus.system_clock_counter();
+        Tui {
+            debugger: Debugger::new(console),
+            keys,
+            symbols,
+            top: pc,
+            cursor: pc,
+            memory: 0x0000,
+            status: "stopped".to_string(),
+            state: None,
+            rewind: Rewind::new(1, REWIND_BUDGET),
+            rewind_origin,
+            last_autosave: Instant::now(),
+        }
+    }
+
+    pub fn run(&mut self) -> io::Result<()> {
+        let _terminal = RawTerminal::enable()?;
+        loop {
+            self.draw()?;
+            let key = self.read_key();
+            match key {
+                Key::Char('q') => return self.debugger.console_mut().bus.flush_save(),
+                Key::Char('s') => {
+                    let stop = self.debugger.step();
+                    self.stopped(stop);
+                }
+                Key::Char('n') => {
+                    let cpu = self.cpu();
+                    if self.bus().peek(cpu.pc) == 0x20 {
+                        self.run_until(Goal::Until(cpu.pc.wrapping_add(3), Some(cpu.stkp)));
+                    } else {
+                        let stop = self.debugger.step();
+                        self.stopped(stop);
+                    }
+                }
+                Key::Char('o') => {
+                    let stkp = self.cpu().stkp;
+                    self.run_until(Goal::StepOut(stkp));
+                }
+                Key::Char('c') => self.run_until(Goal::Continue),
+                Key::Char('t') => self.run_until(Goal::Until(self.cursor, None)),
+                Key::Up | Key::Char('k') => self.move_cursor(-1),
+                Key::Down | Key::Char('j') => self.move_cursor(1),
+                Key::Char('g') => {
+                    if let Some(addr) = self.prompt("go to: ")?.and_then(|a| self.parse_addr(&a)) {
+                        self.top = addr;
+                        self.cursor = addr;
+                    }
+                }
+                Key::Char('m') => {
+                    if let Some(addr) = self
+                        .prompt("memory at: ")?
+                        .and_then(|a| self.parse_addr(&a))
+                    {
+                        self.memory = addr & 0xFFF0;
+                    }
+                }
+                Key::Char('b') => self.toggle_breakpoint(self.cursor),
+                Key::Char('B') => {
+                    if let Some(text) = self.prompt("breakpoint <addr> [if <condition>]: ")? {
+                        self.add_breakpoint(&text);
+                    }
+                }
+                Key::Char('d') => {
+                    if let Some(id) = self.prompt("delete breakpoint #")? {
+                        match id.trim().parse() {
+                            Ok(id) if self.debugger.remove_breakpoint(id).is_some() => {
+                                self.status = format!("deleted breakpoint #{id}");
+                            }
+                            _ => self.status = format!("no breakpoint #{}", id.trim()),
+                        }
+                    }
+                }
+                Key::Char('w') => {
+                    let state = self.debugger.console().save_state();
+                    self.status = format!("saved state ({} bytes)", state.len());
+                    self.state = Some(state);
+                }
+                Key::Char('r') => {
+                    let result = match &self.state {
+                        Some(state) => self.debugger.console_mut().load_state(state),
+                        None => {
+                            self.status = "no saved state".to_string();
+                            continue;
+                        }
+                    };
+                    // The history is of the timeline just left
+                    self.rewind = Rewind::new(1, REWIND_BUDGET);
+                    self.rewind_origin = self.bus().system_clock_counter();
+                    self.stopped(Stop::Step);
+                    if let Err(e) = result {
+                        self.status = e.to_string();
+                    } else {
+                        self.status = "restored state".to_string();
+                    }
+                }
+                Key::Char('z') => self.rewind_frame(),
+                Key::Char('x') => {
+                    if let Some(code) = self.prompt("barcode: ")? {
+                        self.scan_barcode(code.trim());
+                    }
+                }
+                Key::Char('e') => self.eject_disk(),
+                Key::Char('f') => {
+                    if let Some(side) = self.prompt("insert side (1A, 1B, 2A...): ")? {
+                        self.insert_disk(side.trim());
+                    }
+                }
+                Key::Char('i') => {
+                    self.debugger.break_on = if self.debugger.break_on.is_empty() {
+                        InterruptFlags::all()
+                    } else {
+                        InterruptFlags::empty()
+                    };
+                    self.status = format!("break on interrupts: {:?}", self.debugger.break_on);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn bus(&self) -> &Bus {
+        &self.debugger.console().bus
+    }
+
+    // A copy of the registers
+    fn cpu(&self) -> Registers {
+        let cpu = &self.debugger.console().cpu;
+        Registers {
+            a: cpu.a,
+            x: cpu.x,
+            y: cpu.y,
+            stkp: cpu.stkp,
+            pc: cpu.pc,
+            status: cpu.status,
+        }
+    }
+
+    fn run_until(&mut self, goal: Goal) {
+        self.status = "running (any key stops)".to_string();
+        let _ = self.draw();
+        loop {
+            for _ in 0..RUN_SLICE {
+                let before = self.cpu();
+                let opcode = self.bus().peek(before.pc);
+                let stop = self.debugger.step();
+                if stop != Stop::Step {
+                    self.stopped(stop);
+                    return;
+                }
+                self.record_frame();
+
+                let after = self.cpu();
+                let done = match goal {
+                    Goal::Continue => false,
+                    Goal::Until(addr, stkp) => {
+                        after.pc == addr && stkp.is_none_or(|s| s == after.stkp)
+                    }
+                    // RTS and RTI
+                    Goal::StepOut(stkp) => matches!(opcode, 0x60 | 0x40) && before.stkp >= stkp,
+                };
+                if done {
+                    self.stopped(Stop::Step);
+                    return;
+                }
+            }
+            self.autosave();
+            if self.keys.try_recv().is_ok() {
+                self.stopped(Stop::Step);
+                self.status = "interrupted".to_string();
+                return;
+            }
+        }
+    }
+
+    // Snapshots the machine on the first stop after it passes into a new
+    // frame
+    fn record_frame(&mut self) {
+        let cycles = self
+            .bus()
+            .system_clock_counter()
+            .saturating_sub(self.rewind_origin);
+        if cycles / CYCLES_PER_FRAME > self.rewind.frame() {
+            self.rewind.push_frame(self.debugger.console());
+        }
+    }
+
+    // Back to where the previous frame was entered
+    fn rewind_frame(&mut self) {
+        let result = self.rewind.step_back(self.debugger.console_mut());
+        self.stopped(Stop::Step);
+        self.status = match result {
+            Ok(true) => format!(
+                "rewound to frame {} ({} snapshots back to frame {}, {} KiB)",
+                self.rewind.frame(),
+                self.rewind.len(),
+                self.rewind.oldest_frame().unwrap_or_default(),
+                self.rewind.memory_used() / 1024
+            ),
+            Ok(false) => "no earlier frame to rewind to".to_string(),
+            Err(e) => e.to_string(),
+        };
+    }
+
+    fn autosave(&mut self) {
+        if self.last_autosave.elapsed() < AUTOSAVE_INTERVAL {
+            return;
+        }
+        self.last_autosave = Instant::now();
+        if let Err(e) = self.debugger.console_mut().bus.flush_save() {
+            self.status = format!("failed to write battery save: {e}");
+        }
+    }
+
+    fn stopped(&mut self, stop: Stop) {
+        self.record_frame();
+        self.status = match stop {
+            Stop::Breakpoint { id, access } => format!(
+                "breakpoint #{id}: {:?} {:?} ${:04X} = ${:02X}",
+                access.space, access.kind, access.addr, access.data
+            ),
+            Stop::Interrupt(interrupt) => format!("{interrupt:?}"),
+            Stop::Step => "stopped".to_string(),
+            Stop::CycleLimit => "cycle limit".to_string(),
+        };
+
+        // Keep PC on screen
+        let pc = self.cpu().pc;
+        let mut addr = self.top;
+        let visible = (0..DISASM_LINES).any(|_| {
+            let line = disasm::disassemble(self.bus(), &self.symbols, addr);
+            addr = line.next();
+            line.addr == pc
+        });
+        if !visible {
+            self.top = pc;
+        }
+        self.cursor = pc;
+    }
+
+    fn move_cursor(&mut self, lines: i32) {
+        let bus = &self.debugger.console().bus;
+        if lines > 0 {
+            self.cursor = disasm::disassemble(bus, &self.symbols, self.cursor).next();
+        } else if self.cursor == self.top {
+            // There's no telling where the previous instruction starts, so
+            // step back a byte and let the listing resync
+            self.top = self.top.wrapping_sub(1);
+            self.cursor = self.top;
+        } else {
+            let mut addr = self.top;
+            loop {
+                let next = disasm::disassemble(bus, &self.symbols, addr).next();
+                if next == self.cursor || next <= addr {
+                    break;
+                }
+                addr = next;
+            }
+            self.cursor = addr;
+        }
+
+        let mut bottom = self.top;
+        for _ in 0..DISASM_LINES - 1 {
+            bottom = disasm::disassemble(bus, &self.symbols, bottom).next();
+        }
+        if self.cursor > bottom {
+            self.top = disasm::disassemble(bus, &self.symbols, self.top).next();
+        }
+    }
+
+    // A hex address, with or without $, or a label
+    fn parse_addr(&self, text: &str) -> Option<u16> {
+        let text = text.trim();
+        let hex = text.strip_prefix('$').unwrap_or(text);
+        u16::from_str_radix(hex, 16)
+            .ok()
+            .or_else(|| self.symbols.address(self.bus(), text))
+    }
+
+    fn execute_breakpoint(&self, addr: u16) -> Option<u32> {
+        self.debugger
+            .breakpoints()
+            .find(|(_, bp)| bp.range == (addr..=addr) && bp.condition.is_none())
+            .map(|(id, _)| id)
+    }
+
+    fn toggle_breakpoint(&mut self, addr: u16) {
+        match self.execute_breakpoint(addr) {
+            Some(id) => {
+                self.debugger.remove_breakpoint(id);
+                self.status = format!("deleted breakpoint #{id}");
+            }
+            None => {
+                let id = self.debugger.add_breakpoint(Breakpoint::execute(addr));
+                self.status = format!("breakpoint #{id} at ${addr:04X}");
+            }
+        }
+    }
+
+    fn add_breakpoint(&mut self, text: &str) {
+        let (addr, condition) = match text.split_once(" if ") {
+            Some((addr, condition)) => (addr, Some(condition)),
+            None => (text, None),
+        };
+        let Some(addr) = self.parse_addr(addr) else {
+            self.status = format!("bad address {}", addr.trim());
+            return;
+        };
+
+        let mut breakpoint = Breakpoint::execute(addr);
+        if let Some(condition) = condition {
+            breakpoint = match breakpoint.with_condition(condition) {
+                Ok(breakpoint) => breakpoint,
+                Err(e) => {
+                    self.status = e.to_string();
+                    return;
+                }
+            };
+        }
+        let id = self.debugger.add_breakpoint(breakpoint);
+        self.status = format!("breakpoint #{id} at ${addr:04X}");
+    }
+
+    fn scan_barcode(&mut self, code: &str) {
+        let reader = self
+            .debugger
+            .console_mut()
+            .bus
+            .cartridge_mut()
+            .and_then(|cart| cart.barcode_reader());
+        self.status = match reader.map(|reader| reader.scan(code)) {
+            Some(Ok(())) => format!("scanning {code}"),
+            Some(Err(e)) => e.to_string(),
+            None => "no barcode reader on this cartridge".to_string(),
+        };
+    }
+
+    fn disk_drive(&mut self) -> Option<&mut Fds> {
+        self.debugger
+            .console_mut()
+            .bus
+            .cartridge_mut()
+            .and_then(|cart| cart.disk_drive())
+    }
+
+    fn eject_disk(&mut self) {
+        self.status = match self.disk_drive() {
+            Some(drive) => match drive.inserted_side() {
+                Some(side) => {
+                    drive.eject();
+                    format!("ejected disk {}", side_name(side))
+                }
+                None => "the drive is already empty".to_string(),
+            },
+            None => "no disk drive".to_string(),
+        };
+    }
+
+    // Sides are named as on the disk labels, disk 1 side A being the first
+    fn insert_disk(&mut self, name: &str) {
+        let side = parse_side(name);
+        self.status = match (self.disk_drive(), side) {
+            (None, _) => "no disk drive".to_string(),
+            (Some(drive), Some(side)) if side < drive.side_count() => {
+                drive.insert_side(side);
+                format!("inserted disk {}", side_name(side))
+            }
+            (Some(drive), _) => {
+                format!("no side {name}; the disk has {} sides", drive.side_count())
+            }
+        };
+    }
+
+    fn read_key(&self) -> Key {
+        let Ok(byte) = self.keys.recv() else {
+            return Key::Char('q');
+        };
+        match byte {
+            b'\r' | b'\n' => Key::Enter,
+            0x7F | 0x08 => Key::Backspace,
+            // Ctrl-C and Ctrl-D quit, as raw mode stops the terminal
+            // turning them into signals
+            0x03 | 0x04 => Key::Char('q'),
+            0x1B => {
+                let timeout = Duration::from_millis(20);
+                match (
+                    self.keys.recv_timeout(timeout),
+                    self.keys.recv_timeout(timeout),
+                ) {
+                    (Ok(b'['), Ok(b'A')) => Key::Up,
+                    (Ok(b'['), Ok(b'B')) => Key::Down,
+                    _ => Key::Other,
+                }
+            }
+            byte if byte.is_ascii() => Key::Char(byte as char),
+            _ => Key::Other,
+        }
+    }
+
+    // Reads a line on the status row. None if cancelled with Escape.
+    fn prompt(&mut self, label: &str) -> io::Result<Option<String>> {
+        let mut text = String::new();
+        loop {
+            self.status = format!("{label}{text}");
+            self.draw()?;
+            match self.keys.recv_timeout(Duration::from_secs(3600)) {
+                Ok(b'\r' | b'\n') => break,
+                Ok(0x1B) | Err(RecvTimeoutError::Disconnected) => {
+                    self.status.clear();
+                    return Ok(None);
+                }
+                Ok(0x7F | 0x08) => {
+                    text.pop();
+                }
+                Ok(byte) if byte.is_ascii() && !byte.is_ascii_control() => text.push(byte as char),
+                _ => {}
+            }
+        }
+        self.status.clear();
+        Ok(Some(text))
+    }
+
+    fn draw(&self) -> io::Result<()> {
+        let left = self.left_pane();
+        let right = self.disassembly_pane();
+
+        let mut out = String::from("\x1b[H\x1b[2J");
+        for i in 0..left.len().max(right.len()) {
+            let l = left.get(i).map_or("", String::as_str);
+            let r = right.get(i).map_or("", String::as_str);
+            out.push_str(&format!("{l:<LEFT_WIDTH$}│ {r}\r\n"));
+        }
+        out.push_str(&format!("\r\n{}\r\n\x1b[7m{HELP}\x1b[0m", self.status));
+
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(out.as_bytes())?;
+        stdout.flush()
+    }
+
+    fn left_pane(&self) -> Vec<String> {
+        let cpu = self.cpu();
+        let bus = self.bus();
+        let mut lines = Vec::new();
+
+        lines.push("Registers".to_string());
+        lines.push(format!(
+            "  A=${:02X}  X=${:02X}  Y=${:02X}  S=${:02X}  PC=${:04X}",
+            cpu.a, cpu.x, cpu.y, cpu.stkp, cpu.pc
+        ));
+        let flags: String = [
+            (StatusFlags::NEGATIVE, 'N'),
+            (StatusFlags::OVERFLOW, 'V'),
+            (StatusFlags::UNUSED, 'U'),
+            (StatusFlags::BREAK, 'B'),
+            (StatusFlags::DECIMAL_MODE, 'D'),
+            (StatusFlags::INTERRUPT_DISABLE, 'I'),
+            (StatusFlags::ZERO, 'Z'),
+            (StatusFlags::CARRY, 'C'),
+        ]
+        .iter()
+        .map(|&(flag, c)| if cpu.status.contains(flag) { c } else { '.' })
+        .collect();
+        lines.push(format!(
+            "  P=${:02X} [{flags}]  cycle {}",
+            cpu.status.bits(),
+            bus.system_clock_counter()
+        ));
+
+        lines.push(String::new());
+        lines.push("Stack".to_string());
+        let top = 0x0100 | cpu.stkp.wrapping_add(1) as u16;
+        let stack: Vec<String> = (0..8)
+            .map(|i| top + i)
+            .take_while(|&addr| addr <= 0x01FF)
+            .map(|addr| format!("{:02X}", bus.peek(addr)))
+            .collect();
+        lines.push(format!("  ${top:04X}: {}", stack.join(" ")));
+
+        lines.push(String::new());
+        lines.push("Memory".to_string());
+        for row in 0..MEMORY_ROWS {
+            let addr = self.memory.wrapping_add(row as u16 * 16);
+            let bytes: Vec<String> = (0..16)
+                .map(|i| format!("{:02X}", bus.peek(addr.wrapping_add(i))))
+                .collect();
+            lines.push(format!("  {addr:04X}: {}", bytes.join(" ")));
+        }
+
+        lines.push(String::new());
+        lines.push("PPU".to_string());
+        match bus.cartridge() {
+            Some(cart) => {
+                let header = cart.header();
+                lines.push(format!(
+                    "  mapper {}.{}  mirroring {:?}",
+                    header.mapper,
+                    header.submapper,
+                    cart.mirroring()
+                ));
+                lines.push(format!(
+                    "  CHR {} bytes {}  IRQ line {}",
+                    cart.mapper().memory().chr.len(),
+                    if cart.mapper().memory().chr_is_ram {
+                        "RAM"
+                    } else {
+                        "ROM"
+                    },
+                    if cart.irq_state() { "low" } else { "high" }
+                ));
+            }
+            None => lines.push("  no cartridge".to_string()),
+        }
+        lines.push("  (no PPU core yet: registers unavailable)".to_string());
+
+        lines.push(String::new());
+        lines.push("Breakpoints".to_string());
+        for (id, bp) in self.debugger.breakpoints() {
+            let condition = bp
+                .condition
+                .as_ref()
+                .map(|c| format!(" if {c}"))
+                .unwrap_or_default();
+            let access: String = [
+                (AccessFlags::READ, 'r'),
+                (AccessFlags::WRITE, 'w'),
+                (AccessFlags::EXECUTE, 'x'),
+            ]
+            .iter()
+            .map(|&(flag, c)| if bp.access.contains(flag) { c } else { '-' })
+            .collect();
+            lines.push(format!(
+                "  #{id} ${:04X}-${:04X} {access} hits {}{condition}",
+                bp.range.start(),
+                bp.range.end(),
+                bp.hits
+            ));
+        }
+        lines
+    }
+
+    fn disassembly_pane(&self) -> Vec<String> {
+        let pc = self.cpu().pc;
+        let bus = self.bus();
+        let mut lines = vec!["Disassembly".to_string()];
+        let mut addr = self.top;
+        for _ in 0..DISASM_LINES {
+            let line = disasm::disassemble(bus, &self.symbols, addr);
+            let marker = if line.addr == pc { '>' } else { ' ' };
+            let breakpoint = if self.execute_breakpoint(line.addr).is_some() {
+                '*'
+            } else {
+                ' '
+            };
+            if let Some(comment) = &line.comment {
+                lines.extend(comment.lines().map(|text| format!("  ; {text}")));
+            }
+            if let Some(label) = &line.label {
+                lines.push(format!("  {label}:"));
+            }
+            let text = format!("{marker}{breakpoint}{line}");
+            lines.push(if line.addr == self.cursor {
+                format!("\x1b[7m{text}\x1b[0m")
+            } else {
+                text
+            });
+            addr = line.next();
+        }
+        lines
+    }
+}
+
+struct Registers {
+    a: u8,
+    x: u8,
+    y: u8,
+    stkp: u8,
+    pc: u16,
+    status: StatusFlags,
+}
+
+// Puts the terminal into raw mode on the alternate screen for as long as it
+// lives
+struct RawTerminal;
+
+impl RawTerminal {
+    fn enable() -> io::Result<Self> {
+        stty(&["raw", "-echo"])?;
+        print!("\x1b[?1049h\x1b[?25l");
+        io::stdout().flush()?;
+        Ok(RawTerminal)
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        print!("\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+        let _ = stty(&["sane"]);
+    }
+}
+
+// "1A" is side 0, "1B" side 1, "2A" side 2 and so on
+fn parse_side(name: &str) -> Option<usize> {
+    let name = name.to_ascii_uppercase();
+    let (disk, face) = name.split_at_checked(name.len().checked_sub(1)?)?;
+    let disk: usize = disk.parse().ok()?;
+    let face = match face {
+        "A" => 0,
+        "B" => 1,
+        _ => return None,
+    };
+    Some((disk.checked_sub(1)? * 2) + face)
+}
+
+fn side_name(side: usize) -> String {
+    let face = if side.is_multiple_of(2) { 'A' } else { 'B' };
+    format!("{}{face}", side / 2 + 1)
+}
+
+fn stty(args: &[&str]) -> io::Result<()> {
+    let status = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other("stty failed; is stdin a terminal?"))
+    }
+}
@@ -0,0 +1,327 @@
+use std::fmt;
+
+// DEFLATE streams (RFC 1951), as found inside zip archives and PNG images.
+// Decompression is complete; compression only goes as far as stored blocks.
+// Huffman codes are decoded a bit at a time from the code length counts,
+// which is slow but needs no lookup tables.
+
+const MAX_BITS: usize = 15;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+// The order a dynamic block lists the code length code's lengths in
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DeflateError {
+    Truncated,
+    InvalidBlockType,
+    // A stored block whose length and its complement disagree
+    InvalidStoredLength,
+    InvalidCode,
+    // A back reference to before the start of the output
+    InvalidDistance,
+    InvalidHeader,
+    Checksum,
+}
+
+impl fmt::Display for DeflateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeflateError::Truncated => write!(f, "compressed data ends early"),
+            DeflateError::InvalidBlockType => write!(f, "invalid deflate block type"),
+            DeflateError::InvalidStoredLength => write!(f, "corrupt stored block length"),
+            DeflateError::InvalidCode => write!(f, "invalid Huffman code"),
+            DeflateError::InvalidDistance => write!(f, "back reference out of range"),
+            DeflateError::InvalidHeader => write!(f, "invalid zlib header"),
+            DeflateError::Checksum => write!(f, "checksum mismatch"),
+        }
+    }
+}
+
+impl std::error::Error for DeflateError {}
+
+// Bits are taken from each byte starting at the lowest
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bits: u32,
+    count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader {
+            data,
+            pos: 0,
+            bits: 0,
+            count: 0,
+        }
+    }
+
+    fn bits(&mut self, n: u32) -> Result<u32, DeflateError> {
+        while self.count < n {
+            let byte = *self.data.get(self.pos).ok_or(DeflateError::Truncated)?;
+            self.pos += 1;
+            self.bits |= (byte as u32) << self.count;
+            self.count += 8;
+        }
+        let value = self.bits & ((1 << n) - 1);
+        self.bits >>= n;
+        self.count -= n;
+        Ok(value)
+    }
+
+    // Drops what's left of the current byte, which is all that can be
+    // buffered as bytes are only read when needed
+    fn align(&mut self) {
+        self.bits = 0;
+        self.count = 0;
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], DeflateError> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or(DeflateError::Truncated)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+}
+
+// A canonical Huffman code: how many codes there are of each length, and
+// the symbols in code order
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0; MAX_BITS + 1];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut offsets = [0; MAX_BITS + 2];
+        for len in 1..=MAX_BITS {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0; offsets[MAX_BITS + 1] as usize];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Huffman { counts, symbols }
+    }
+
+    fn decode(&self, r: &mut BitReader) -> Result<u16, DeflateError> {
+        // The first code of each length, and where its symbols start
+        let mut code = 0;
+        let mut first = 0;
+        let mut index = 0;
+        for len in 1..=MAX_BITS {
+            code |= r.bits(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(DeflateError::InvalidCode)
+    }
+}
+
+pub fn inflate(data: &[u8]) -> Result<Vec<u8>, DeflateError> {
+    let mut r = BitReader::new(data);
+    let mut out = Vec::new();
+    loop {
+        let last = r.bits(1)? == 1;
+        match r.bits(2)? {
+            0 => stored(&mut r, &mut out)?,
+            1 => {
+                let (lengths, distances) = fixed_codes();
+                codes(&mut r, &mut out, &lengths, &distances)?;
+            }
+            2 => {
+                let (lengths, distances) = dynamic_codes(&mut r)?;
+                codes(&mut r, &mut out, &lengths, &distances)?;
+            }
+            _ => return Err(DeflateError::InvalidBlockType),
+        }
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+fn stored(r: &mut BitReader, out: &mut Vec<u8>) -> Result<(), DeflateError> {
+    r.align();
+    let header = r.bytes(4)?;
+    let len = u16::from_le_bytes([header[0], header[1]]);
+    let complement = u16::from_le_bytes([header[2], header[3]]);
+    if len != !complement {
+        return Err(DeflateError::InvalidStoredLength);
+    }
+    out.extend_from_slice(r.bytes(len as usize)?);
+    Ok(())
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+}
+
+fn dynamic_codes(r: &mut BitReader) -> Result<(Huffman, Huffman), DeflateError> {
+    let literal_count = r.bits(5)? as usize + 257;
+    let distance_count = r.bits(5)? as usize + 1;
+    let code_length_count = r.bits(4)? as usize + 4;
+
+    let mut code_lengths = [0; 19];
+    for &i in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_lengths[i] = r.bits(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&code_lengths);
+
+    // Literal/length and distance code lengths run together, and repeats
+    // can cross from one to the other
+    let mut lengths = vec![0; literal_count + distance_count];
+    let mut i = 0;
+    while i < lengths.len() {
+        let (len, repeat) = match code_length_code.decode(r)? {
+            len @ 0..=15 => (len as u8, 1),
+            16 => {
+                let previous = *i
+                    .checked_sub(1)
+                    .and_then(|prev| lengths.get(prev))
+                    .ok_or(DeflateError::InvalidCode)?;
+                (previous, 3 + r.bits(2)? as usize)
+            }
+            17 => (0, 3 + r.bits(3)? as usize),
+            _ => (0, 11 + r.bits(7)? as usize),
+        };
+        let run = lengths
+            .get_mut(i..i + repeat)
+            .ok_or(DeflateError::InvalidCode)?;
+        run.fill(len);
+        i += repeat;
+    }
+
+    let (literals, distances) = lengths.split_at(literal_count);
+    Ok((Huffman::new(literals), Huffman::new(distances)))
+}
+
+fn codes(
+    r: &mut BitReader,
+    out: &mut Vec<u8>,
+    lengths: &Huffman,
+    distances: &Huffman,
+) -> Result<(), DeflateError> {
+    loop {
+        let symbol = lengths.decode(r)? as usize;
+        match symbol {
+            0..=255 => out.push(symbol as u8),
+            256 => return Ok(()),
+            _ => {
+                let i = symbol - 257;
+                if i >= LENGTH_BASE.len() {
+                    return Err(DeflateError::InvalidCode);
+                }
+                let len = LENGTH_BASE[i] as usize + r.bits(LENGTH_EXTRA[i] as u32)? as usize;
+
+                let i = distances.decode(r)? as usize;
+                if i >= DISTANCE_BASE.len() {
+                    return Err(DeflateError::InvalidCode);
+                }
+                let distance =
+                    DISTANCE_BASE[i] as usize + r.bits(DISTANCE_EXTRA[i] as u32)? as usize;
+                if distance > out.len() {
+                    return Err(DeflateError::InvalidDistance);
+                }
+
+                // The copy can overlap what it's producing
+                let start = out.len() - distance;
+                for j in 0..len {
+                    out.push(out[start + j]);
+                }
+            }
+        }
+    }
+}
+
+// Raw DEFLATE stream of stored blocks, for formats that require deflate
+// but whose output isn't worth compressing here
+pub fn store(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut blocks = data.chunks(0xFFFF).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        out.push(blocks.peek().is_none() as u8);
+        out.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out
+}
+
+// The zlib wrapper (RFC 1950) PNG puts around DEFLATE: a two byte header
+// and an Adler-32 of the uncompressed data
+pub fn zlib_decompress(data: &[u8]) -> Result<Vec<u8>, DeflateError> {
+    let (&[cmf, flg], rest) = data.split_first_chunk().ok_or(DeflateError::Truncated)?;
+    // Deflate, no preset dictionary
+    if cmf & 0x0F != 8 || !(cmf as u16 * 256 + flg as u16).is_multiple_of(31) || flg & 0x20 != 0 {
+        return Err(DeflateError::InvalidHeader);
+    }
+    let out = inflate(rest)?;
+    let checksum = rest
+        .last_chunk()
+        .map(|&bytes| u32::from_be_bytes(bytes))
+        .ok_or(DeflateError::Truncated)?;
+    if checksum != adler32(&out) {
+        return Err(DeflateError::Checksum);
+    }
+    Ok(out)
+}
+
+pub fn zlib_compress(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    out.extend(store(data));
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
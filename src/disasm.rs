@@ -0,0 +1,151 @@
+use crate::bus::Bus;
+use crate::console::Console;
+use crate::cpu::AddressingMode;
+use crate::instructions::{Instruction, LOOKUP};
+use crate::memory::Memory;
+use crate::symbols::Symbols;
+
+// One decoded instruction, read with Bus::peek so listing code never
+// disturbs the machine
+pub struct Line {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    pub text: String,
+    // Label starting at addr, if any, and the comment that came with it
+    pub label: Option<String>,
+    pub comment: Option<String>,
+}
+
+impl Line {
+    pub fn len(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+
+    pub fn next(&self) -> u16 {
+        self.addr.wrapping_add(self.len())
+    }
+}
+
+pub fn operand_len(mode: AddressingMode) -> u16 {
+    match mode {
+        AddressingMode::Implied | AddressingMode::Accumulator => 0,
+        AddressingMode::Immediate
+        | AddressingMode::ZeroPage
+        | AddressingMode::ZeroPageX
+        | AddressingMode::ZeroPageY
+        | AddressingMode::IndirectX
+        | AddressingMode::IndirectY
+        | AddressingMode::ZeroPageIndirect
+        | AddressingMode::Relative => 1,
+        AddressingMode::Absolute
+        | AddressingMode::AbsoluteX
+        | AddressingMode::AbsoluteY
+        | AddressingMode::Indirect
+        | AddressingMode::AbsoluteIndirectX
+        | AddressingMode::ZeroPageRelative => 2,
+    }
+}
+
+// Operand addresses that have labels are shown by name
+pub fn disassemble(bus: &Bus, symbols: &Symbols, addr: u16) -> Line {
+    let mut line = decode(bus, &LOOKUP, addr, |target| symbols.name(bus, target));
+    if let Some(label) = symbols.label(bus, addr) {
+        line.label = Some(label.name.clone());
+        line.comment = label.comment.clone();
+    }
+    line
+}
+
+// Decodes from any 6502 memory map, as the chip with the given instruction
+// table, with name giving the names of operand addresses that have one
+pub fn decode(
+    mem: &dyn Memory,
+    lookup: &[Instruction; 256],
+    addr: u16,
+    name: impl Fn(u16) -> Option<String>,
+) -> Line {
+    let opcode = mem.peek(addr);
+    let instruction = &lookup[opcode as usize];
+    let len = 1 + operand_len(instruction.mode);
+
+    let bytes: Vec<u8> = (0..len).map(|i| mem.peek(addr.wrapping_add(i))).collect();
+    let byte = bytes.get(1).copied().unwrap_or(0);
+    let word = u16::from_le_bytes([byte, bytes.get(2).copied().unwrap_or(0)]);
+
+    let zp = name(byte as u16).unwrap_or_else(|| format!("${byte:02X}"));
+    let abs = |target: u16| name(target).unwrap_or_else(|| format!("${target:04X}"));
+
+    let operand = match instruction.mode {
+        AddressingMode::Implied => String::new(),
+        AddressingMode::Accumulator => "A".to_string(),
+        AddressingMode::Immediate => format!("#${byte:02X}"),
+        AddressingMode::ZeroPage => zp,
+        AddressingMode::ZeroPageX => format!("{zp},X"),
+        AddressingMode::ZeroPageY => format!("{zp},Y"),
+        AddressingMode::IndirectX => format!("({zp},X)"),
+        AddressingMode::IndirectY => format!("({zp}),Y"),
+        AddressingMode::Relative => abs(addr.wrapping_add(2).wrapping_add(byte as i8 as u16)),
+        AddressingMode::Absolute => abs(word),
+        AddressingMode::AbsoluteX => format!("{},X", abs(word)),
+        AddressingMode::AbsoluteY => format!("{},Y", abs(word)),
+        AddressingMode::Indirect => format!("({})", abs(word)),
+        AddressingMode::ZeroPageIndirect => format!("({zp})"),
+        AddressingMode::AbsoluteIndirectX => format!("({},X)", abs(word)),
+        AddressingMode::ZeroPageRelative => {
+            let offset = bytes.get(2).copied().unwrap_or(0);
+            format!(
+                "{zp},{}",
+                abs(addr.wrapping_add(3).wrapping_add(offset as i8 as u16))
+            )
+        }
+    };
+
+    let text = if operand.is_empty() {
+        instruction.name.to_string()
+    } else {
+        format!("{} {operand}", instruction.name)
+    };
+    Line {
+        addr,
+        bytes,
+        text,
+        label: None,
+        comment: None,
+    }
+}
+
+// One line of a trace log for the instruction about to run at PC, in the
+// layout of Nintendulator's nestest.log
+pub fn trace(console: &Console, symbols: &Symbols) -> String {
+    let Console { cpu, bus } = console;
+    let line = disassemble(bus, symbols, cpu.pc);
+    let label = line
+        .label
+        .as_ref()
+        .map(|l| format!("{l}:"))
+        .unwrap_or_default();
+    format!(
+        "{line:<38}  A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}  {label}",
+        cpu.a,
+        cpu.x,
+        cpu.y,
+        cpu.status.bits(),
+        cpu.stkp,
+        bus.system_clock_counter()
+    )
+    .trim_end()
+    .to_string()
+}
+
+impl std::fmt::Display for Line {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{b:02X}")).collect();
+        // pad() so the line can be aligned as a whole
+        f.pad(&format!(
+            "{:04X}  {:<8}  {}",
+            self.addr,
+            bytes.join(" "),
+            self.text
+        ))
+    }
+}
@@ -0,0 +1,341 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::cartridge::{Cartridge, CartridgeError};
+use crate::console::Console;
+use crate::framebuffer::Framebuffer;
+use crate::movie::{Frame, Movie, MovieError, Session};
+use crate::png::{self, PngError};
+
+// Golden-frame regression tests: a ROM is run from power on with scripted
+// input, and the screen at chosen frames is compared with PNGs kept from a
+// run known to be good. Setting this variable writes the screens as the new
+// golden images instead.
+pub const UPDATE_VAR: &str = "NES_UPDATE_GOLDEN";
+
+// How a mismatched pixel is shown in diff images. Matching ones are the
+// golden image dimmed to grey.
+const DIFF_COLOUR: [u8; 3] = [0xFF, 0x00, 0x00];
+
+#[derive(Debug)]
+pub enum GoldenError {
+    Io(io::Error),
+    Cartridge(CartridgeError),
+    Movie(MovieError),
+    // A golden image that couldn't be read
+    Png(PathBuf, PngError),
+}
+
+impl fmt::Display for GoldenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GoldenError::Io(e) => write!(f, "i/o error: {e}"),
+            GoldenError::Cartridge(e) => write!(f, "{e}"),
+            GoldenError::Movie(e) => write!(f, "{e}"),
+            GoldenError::Png(path, e) => write!(f, "{}: {e}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for GoldenError {}
+
+impl From<io::Error> for GoldenError {
+    fn from(e: io::Error) -> Self {
+        GoldenError::Io(e)
+    }
+}
+
+impl From<CartridgeError> for GoldenError {
+    fn from(e: CartridgeError) -> Self {
+        GoldenError::Cartridge(e)
+    }
+}
+
+impl From<MovieError> for GoldenError {
+    fn from(e: MovieError) -> Self {
+        GoldenError::Movie(e)
+    }
+}
+
+#[derive(Debug)]
+pub enum Difference {
+    // There's no golden image for the frame yet
+    Missing,
+    Size {
+        golden: (usize, usize),
+        actual: (usize, usize),
+    },
+    // How many pixels differ
+    Pixels(usize),
+}
+
+// A captured frame that doesn't match its golden image. What was captured
+// is written next to it, along with a diff image where the sizes agree.
+#[derive(Debug)]
+pub struct Mismatch {
+    pub frame: u64,
+    pub golden: PathBuf,
+    pub actual: PathBuf,
+    pub diff: Option<PathBuf>,
+    pub difference: Difference,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "frame {}: ", self.frame)?;
+        match &self.difference {
+            Difference::Missing => write!(f, "no golden image {}", self.golden.display())?,
+            Difference::Size { golden, actual } => write!(
+                f,
+                "{}x{} instead of {}x{}",
+                actual.0, actual.1, golden.0, golden.1
+            )?,
+            Difference::Pixels(n) => write!(f, "{n} pixels differ")?,
+        }
+        write!(f, " (got {}", self.actual.display())?;
+        if let Some(diff) = &self.diff {
+            write!(f, ", diff {}", diff.display())?;
+        }
+        write!(f, ")")
+    }
+}
+
+pub struct GoldenTest {
+    name: String,
+    rom: PathBuf,
+    dir: PathBuf,
+    input: Vec<Frame>,
+    captures: Vec<u64>,
+}
+
+impl GoldenTest {
+    // Golden images are kept in dir, named after the test and frame
+    pub fn new<P: AsRef<Path>, Q: AsRef<Path>>(name: &str, rom: P, dir: Q) -> Self {
+        GoldenTest {
+            name: name.to_string(),
+            rom: rom.as_ref().to_path_buf(),
+            dir: dir.as_ref().to_path_buf(),
+            input: Vec::new(),
+            captures: Vec::new(),
+        }
+    }
+
+    // Input for each frame from power on. Nothing is held on frames past
+    // the end of it.
+    pub fn input(mut self, input: &[Frame]) -> Self {
+        self.input = input.to_vec();
+        self
+    }
+
+    pub fn movie(self, movie: &Movie) -> Self {
+        self.input(&movie.frames)
+    }
+
+    // Captures the screen once this many frames have run
+    pub fn capture_at(mut self, frame: u64) -> Self {
+        if let Err(i) = self.captures.binary_search(&frame) {
+            self.captures.insert(i, frame);
+        }
+        self
+    }
+
+    #[cfg(test)]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn golden_path(&self, frame: u64) -> PathBuf {
+        self.dir.join(format!("{}-{frame}.png", self.name))
+    }
+
+    // Runs the ROM, returning the screen at each capture. The cartridge
+    // isn't given its battery save, so every run starts the same.
+    pub fn run(&self) -> Result<Vec<(u64, Framebuffer)>, GoldenError> {
+        let cart = Cartridge::from_bytes(&fs::read(&self.rom)?)?;
+        let movie = Movie::new(&cart, &self.name);
+        let mut console = Console::with_cartridge(cart);
+        let mut session = Session::record(&mut console, movie)?;
+
+        let mut screens = Vec::new();
+        for &capture in &self.captures {
+            while session.frame() < capture {
+                let input = self.input.get(session.frame() as usize);
+                session.run_frame(&mut console, input.copied().unwrap_or_default())?;
+            }
+            screens.push((capture, console.bus.screen().clone()));
+        }
+        Ok(screens)
+    }
+
+    // For tests that keep hashes in the source rather than images
+    #[cfg(test)]
+    pub fn hashes(&self) -> Result<Vec<(u64, u32)>, GoldenError> {
+        Ok(self
+            .run()?
+            .into_iter()
+            .map(|(frame, screen)| (frame, screen.hash()))
+            .collect())
+    }
+
+    // Compares each capture with its golden image, or replaces the golden
+    // images when updating
+    pub fn check(&self, update: bool) -> Result<Vec<Mismatch>, GoldenError> {
+        fs::create_dir_all(&self.dir)?;
+        let mut mismatches = Vec::new();
+        for (frame, screen) in self.run()? {
+            let golden = self.golden_path(frame);
+            let actual = golden.with_extension("actual.png");
+            let diff = golden.with_extension("diff.png");
+            // Left over from an earlier failure
+            let _ = fs::remove_file(&actual);
+            let _ = fs::remove_file(&diff);
+
+            if update {
+                fs::write(&golden, png::encode(&screen))?;
+                continue;
+            }
+
+            let image = if golden.is_file() {
+                let image = png::decode(&fs::read(&golden)?)
+                    .map_err(|e| GoldenError::Png(golden.clone(), e))?;
+                Some(image)
+            } else {
+                None
+            };
+            let difference = match &image {
+                Some(image) => compare(image, &screen),
+                None => Some(Difference::Missing),
+            };
+            let Some(difference) = difference else {
+                continue;
+            };
+
+            fs::write(&actual, png::encode(&screen))?;
+            let diff = match (&image, &difference) {
+                (Some(image), Difference::Pixels(_)) => {
+                    fs::write(&diff, png::encode(&diff_image(image, &screen)))?;
+                    Some(diff)
+                }
+                _ => None,
+            };
+            mismatches.push(Mismatch {
+                frame,
+                golden,
+                actual,
+                diff,
+                difference,
+            });
+        }
+        Ok(mismatches)
+    }
+}
+
+fn compare(golden: &Framebuffer, actual: &Framebuffer) -> Option<Difference> {
+    let size = |image: &Framebuffer| (image.width(), image.height());
+    if size(golden) != size(actual) {
+        return Some(Difference::Size {
+            golden: size(golden),
+            actual: size(actual),
+        });
+    }
+    let pixels = golden
+        .pixels()
+        .chunks_exact(3)
+        .zip(actual.pixels().chunks_exact(3))
+        .filter(|(a, b)| a != b)
+        .count();
+    (pixels > 0).then_some(Difference::Pixels(pixels))
+}
+
+fn diff_image(golden: &Framebuffer, actual: &Framebuffer) -> Framebuffer {
+    let mut diff = Framebuffer::new(golden.width(), golden.height());
+    for y in 0..golden.height() {
+        for x in 0..golden.width() {
+            let expected = golden.pixel(x, y);
+            let rgb = if expected == actual.pixel(x, y) {
+                let [r, g, b] = expected.map(|c| c as u16);
+                [((r * 77 + g * 150 + b * 29) >> 10) as u8; 3]
+            } else {
+                DIFF_COLOUR
+            };
+            diff.set_pixel(x, y, rgb);
+        }
+    }
+    diff
+}
+
+// For #[test] functions: panics listing the frames that don't match their
+// golden images, or updates them when UPDATE_VAR is set
+#[cfg(test)]
+pub fn assert_golden(test: &GoldenTest) {
+    let update = std::env::var_os(UPDATE_VAR).is_some();
+    let mismatches = match test.check(update) {
+        Ok(mismatches) => mismatches,
+        Err(e) => panic!("golden test {} failed to run: {e}", test.name()),
+    };
+    if !mismatches.is_empty() {
+        let list: Vec<String> = mismatches.iter().map(|m| format!("  {m}")).collect();
+        panic!(
+            "golden test {} doesn't match:\n{}\nrun with {UPDATE_VAR}=1 to accept the new frames",
+            test.name(),
+            list.join("\n")
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn golden_images_are_written_compared_and_diffed() {
+        let dir = std::env::temp_dir().join(format!("nes-rs-golden-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let rom = dir.join("loop.nes");
+        fs::write(&rom, Cartridge::nrom_image(&[0x4C, 0x00, 0x80])).unwrap();
+        let test = GoldenTest::new("loop", &rom, &dir)
+            .capture_at(2)
+            .capture_at(1);
+
+        let screens = test.run().unwrap();
+        let hashes: Vec<_> = screens.iter().map(|(f, s)| (*f, s.hash())).collect();
+        assert_eq!(test.hashes().unwrap(), hashes);
+        assert_eq!(hashes.iter().map(|(f, _)| *f).collect::<Vec<_>>(), [1, 2]);
+
+        let missing = test.check(false).unwrap();
+        assert!(matches!(
+            missing[..],
+            [
+                Mismatch {
+                    difference: Difference::Missing,
+                    ..
+                },
+                _
+            ]
+        ));
+        assert!(missing[0].actual.is_file() && missing[0].diff.is_none());
+        assert!(test.check(true).unwrap().is_empty());
+        assert!(!missing[0].actual.exists());
+        assert_golden(&test);
+
+        // Knock one pixel of the first golden image out
+        let golden = test.golden_path(1);
+        let mut image = png::decode(&fs::read(&golden).unwrap()).unwrap();
+        let [r, g, b] = image.pixel(3, 4);
+        image.set_pixel(3, 4, [!r, g, b]);
+        fs::write(&golden, png::encode(&image)).unwrap();
+
+        let mismatches = test.check(false).unwrap();
+        assert_eq!(mismatches.len(), 1);
+        let mismatch = &mismatches[0];
+        assert!(matches!(mismatch.difference, Difference::Pixels(1)));
+        let diff = png::decode(&fs::read(mismatch.diff.as_ref().unwrap()).unwrap()).unwrap();
+        assert_eq!(diff.pixel(3, 4), DIFF_COLOUR);
+        assert_ne!(diff.pixel(0, 0), DIFF_COLOUR);
+        assert!(std::panic::catch_unwind(|| assert_golden(&test)).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,45 +1,113 @@
-use std::vec;
-
 use crate::cpu::*;
+use crate::memory::Memory;
 
 #[derive(Clone, Copy)]
 pub struct Instruction {
     pub name: &'static str,
     pub mode: AddressingMode,
-    pub exec: fn(&mut Cpu) -> u8,
+    // Runs one cycle of the instruction, returning true on its last
+    pub exec: fn(&mut Cpu, &mut dyn Memory) -> bool,
+    // Without page crossings or branches taken
     pub cycles: u8,
 }
 
+// Handlers that work on an operand are specialised for its addressing mode
+// at compile time, taking it as a const generic, so they never look it up
+// as they run
+macro_rules! op {
+    ($name:literal, $exec:ident, $mode:ident, $cycles:literal) => {
+        Instruction {
+            name: $name,
+            exec: Cpu::$exec::<{ AddressingMode::$mode as u8 }>,
+            mode: AddressingMode::$mode,
+            cycles: $cycles,
+        }
+    };
+}
+
+macro_rules! implied {
+    ($name:literal, $exec:ident, $cycles:literal) => {
+        Instruction {
+            name: $name,
+            exec: Cpu::$exec,
+            mode: AddressingMode::Implied,
+            cycles: $cycles,
+        }
+    };
+}
+
+// Unofficial opcodes go by the names in the NESdev wiki
+#[rustfmt::skip]
+pub static LOOKUP: [Instruction; 256] = [
+    op!("BRK", brk, Immediate,        7), op!("ORA", ora, IndirectX,        6), implied!("JAM", jam, 2),              op!("SLO", slo, IndirectX,        8), op!("NOP", nop, ZeroPage,         3), op!("ORA", ora, ZeroPage,         3), op!("ASL", asl, ZeroPage,         5), op!("SLO", slo, ZeroPage,         5), implied!("PHP", php, 3),              op!("ORA", ora, Immediate,        2), op!("ASL", asl, Accumulator,      2), op!("ANC", anc, Immediate,        2), op!("NOP", nop, Absolute,         4), op!("ORA", ora, Absolute,         4), op!("ASL", asl, Absolute,         6), op!("SLO", slo, Absolute,         6),
+    op!("BPL", bpl, Relative,         2), op!("ORA", ora, IndirectY,        5), implied!("JAM", jam, 2),              op!("SLO", slo, IndirectY,        8), op!("NOP", nop, ZeroPageX,        4), op!("ORA", ora, ZeroPageX,        4), op!("ASL", asl, ZeroPageX,        6), op!("SLO", slo, ZeroPageX,        6), implied!("CLC", clc, 2),              op!("ORA", ora, AbsoluteY,        4), op!("NOP", nop, Implied,          2), op!("SLO", slo, AbsoluteY,        7), op!("NOP", nop, AbsoluteX,        4), op!("ORA", ora, AbsoluteX,        4), op!("ASL", asl, AbsoluteX,        7), op!("SLO", slo, AbsoluteX,        7),
+    op!("JSR", jsr, Absolute,         6), op!("AND", and, IndirectX,        6), implied!("JAM", jam, 2),              op!("RLA", rla, IndirectX,        8), op!("BIT", bit, ZeroPage,         3), op!("AND", and, ZeroPage,         3), op!("ROL", rol, ZeroPage,         5), op!("RLA", rla, ZeroPage,         5), implied!("PLP", plp, 4),              op!("AND", and, Immediate,        2), op!("ROL", rol, Accumulator,      2), op!("ANC", anc, Immediate,        2), op!("BIT", bit, Absolute,         4), op!("AND", and, Absolute,         4), op!("ROL", rol, Absolute,         6), op!("RLA", rla, Absolute,         6),
+    op!("BMI", bmi, Relative,         2), op!("AND", and, IndirectY,        5), implied!("JAM", jam, 2),              op!("RLA", rla, IndirectY,        8), op!("NOP", nop, ZeroPageX,        4), op!("AND", and, ZeroPageX,        4), op!("ROL", rol, ZeroPageX,        6), op!("RLA", rla, ZeroPageX,        6), implied!("SEC", sec, 2),              op!("AND", and, AbsoluteY,        4), op!("NOP", nop, Implied,          2), op!("RLA", rla, AbsoluteY,        7), op!("NOP", nop, AbsoluteX,        4), op!("AND", and, AbsoluteX,        4), op!("ROL", rol, AbsoluteX,        7), op!("RLA", rla, AbsoluteX,        7),
+    implied!("RTI", rti, 6),              op!("EOR", eor, IndirectX,        6), implied!("JAM", jam, 2),              op!("SRE", sre, IndirectX,        8), op!("NOP", nop, ZeroPage,         3), op!("EOR", eor, ZeroPage,         3), op!("LSR", lsr, ZeroPage,         5), op!("SRE", sre, ZeroPage,         5), implied!("PHA", pha, 3),              op!("EOR", eor, Immediate,        2), op!("LSR", lsr, Accumulator,      2), op!("ALR", alr, Immediate,        2), op!("JMP", jmp, Absolute,         3), op!("EOR", eor, Absolute,         4), op!("LSR", lsr, Absolute,         6), op!("SRE", sre, Absolute,         6),
+    op!("BVC", bvc, Relative,         2), op!("EOR", eor, IndirectY,        5), implied!("JAM", jam, 2),              op!("SRE", sre, IndirectY,        8), op!("NOP", nop, ZeroPageX,        4), op!("EOR", eor, ZeroPageX,        4), op!("LSR", lsr, ZeroPageX,        6), op!("SRE", sre, ZeroPageX,        6), implied!("CLI", cli, 2),              op!("EOR", eor, AbsoluteY,        4), op!("NOP", nop, Implied,          2), op!("SRE", sre, AbsoluteY,        7), op!("NOP", nop, AbsoluteX,        4), op!("EOR", eor, AbsoluteX,        4), op!("LSR", lsr, AbsoluteX,        7), op!("SRE", sre, AbsoluteX,        7),
+    implied!("RTS", rts, 6),              op!("ADC", adc, IndirectX,        6), implied!("JAM", jam, 2),              op!("RRA", rra, IndirectX,        8), op!("NOP", nop, ZeroPage,         3), op!("ADC", adc, ZeroPage,         3), op!("ROR", ror, ZeroPage,         5), op!("RRA", rra, ZeroPage,         5), implied!("PLA", pla, 4),              op!("ADC", adc, Immediate,        2), op!("ROR", ror, Accumulator,      2), op!("ARR", arr, Immediate,        2), op!("JMP", jmp, Indirect,         5), op!("ADC", adc, Absolute,         4), op!("ROR", ror, Absolute,         6), op!("RRA", rra, Absolute,         6),
+    op!("BVS", bvs, Relative,         2), op!("ADC", adc, IndirectY,        5), implied!("JAM", jam, 2),              op!("RRA", rra, IndirectY,        8), op!("NOP", nop, ZeroPageX,        4), op!("ADC", adc, ZeroPageX,        4), op!("ROR", ror, ZeroPageX,        6), op!("RRA", rra, ZeroPageX,        6), implied!("SEI", sei, 2),              op!("ADC", adc, AbsoluteY,        4), op!("NOP", nop, Implied,          2), op!("RRA", rra, AbsoluteY,        7), op!("NOP", nop, AbsoluteX,        4), op!("ADC", adc, AbsoluteX,        4), op!("ROR", ror, AbsoluteX,        7), op!("RRA", rra, AbsoluteX,        7),
+    op!("NOP", nop, Immediate,        2), op!("STA", sta, IndirectX,        6), op!("NOP", nop, Immediate,        2), op!("SAX", sax, IndirectX,        6), op!("STY", sty, ZeroPage,         3), op!("STA", sta, ZeroPage,         3), op!("STX", stx, ZeroPage,         3), op!("SAX", sax, ZeroPage,         3), implied!("DEY", dey, 2),              op!("NOP", nop, Immediate,        2), implied!("TXA", txa, 2),              op!("ANE", ane, Immediate,        2), op!("STY", sty, Absolute,         4), op!("STA", sta, Absolute,         4), op!("STX", stx, Absolute,         4), op!("SAX", sax, Absolute,         4),
+    op!("BCC", bcc, Relative,         2), op!("STA", sta, IndirectY,        6), implied!("JAM", jam, 2),              op!("SHA", sha, IndirectY,        6), op!("STY", sty, ZeroPageX,        4), op!("STA", sta, ZeroPageX,        4), op!("STX", stx, ZeroPageY,        4), op!("SAX", sax, ZeroPageY,        4), implied!("TYA", tya, 2),              op!("STA", sta, AbsoluteY,        5), implied!("TXS", txs, 2),              op!("TAS", tas, AbsoluteY,        5), op!("SHY", shy, AbsoluteX,        5), op!("STA", sta, AbsoluteX,        5), op!("SHX", shx, AbsoluteY,        5), op!("SHA", sha, AbsoluteY,        5),
+    op!("LDY", ldy, Immediate,        2), op!("LDA", lda, IndirectX,        6), op!("LDX", ldx, Immediate,        2), op!("LAX", lax, IndirectX,        6), op!("LDY", ldy, ZeroPage,         3), op!("LDA", lda, ZeroPage,         3), op!("LDX", ldx, ZeroPage,         3), op!("LAX", lax, ZeroPage,         3), implied!("TAY", tay, 2),              op!("LDA", lda, Immediate,        2), implied!("TAX", tax, 2),              op!("LXA", lxa, Immediate,        2), op!("LDY", ldy, Absolute,         4), op!("LDA", lda, Absolute,         4), op!("LDX", ldx, Absolute,         4), op!("LAX", lax, Absolute,         4),
+    op!("BCS", bcs, Relative,         2), op!("LDA", lda, IndirectY,        5), implied!("JAM", jam, 2),              op!("LAX", lax, IndirectY,        5), op!("LDY", ldy, ZeroPageX,        4), op!("LDA", lda, ZeroPageX,        4), op!("LDX", ldx, ZeroPageY,        4), op!("LAX", lax, ZeroPageY,        4), implied!("CLV", clv, 2),              op!("LDA", lda, AbsoluteY,        4), implied!("TSX", tsx, 2),              op!("LAS", las, AbsoluteY,        4), op!("LDY", ldy, AbsoluteX,        4), op!("LDA", lda, AbsoluteX,        4), op!("LDX", ldx, AbsoluteY,        4), op!("LAX", lax, AbsoluteY,        4),
+    op!("CPY", cpy, Immediate,        2), op!("CMP", cmp, IndirectX,        6), op!("NOP", nop, Immediate,        2), op!("DCP", dcp, IndirectX,        8), op!("CPY", cpy, ZeroPage,         3), op!("CMP", cmp, ZeroPage,         3), op!("DEC", dec, ZeroPage,         5), op!("DCP", dcp, ZeroPage,         5), implied!("INY", iny, 2),              op!("CMP", cmp, Immediate,        2), implied!("DEX", dex, 2),              op!("SBX", sbx, Immediate,        2), op!("CPY", cpy, Absolute,         4), op!("CMP", cmp, Absolute,         4), op!("DEC", dec, Absolute,         6), op!("DCP", dcp, Absolute,         6),
+    op!("BNE", bne, Relative,         2), op!("CMP", cmp, IndirectY,        5), implied!("JAM", jam, 2),              op!("DCP", dcp, IndirectY,        8), op!("NOP", nop, ZeroPageX,        4), op!("CMP", cmp, ZeroPageX,        4), op!("DEC", dec, ZeroPageX,        6), op!("DCP", dcp, ZeroPageX,        6), implied!("CLD", cld, 2),              op!("CMP", cmp, AbsoluteY,        4), op!("NOP", nop, Implied,          2), op!("DCP", dcp, AbsoluteY,        7), op!("NOP", nop, AbsoluteX,        4), op!("CMP", cmp, AbsoluteX,        4), op!("DEC", dec, AbsoluteX,        7), op!("DCP", dcp, AbsoluteX,        7),
+    op!("CPX", cpx, Immediate,        2), op!("SBC", sbc, IndirectX,        6), op!("NOP", nop, Immediate,        2), op!("ISC", isc, IndirectX,        8), op!("CPX", cpx, ZeroPage,         3), op!("SBC", sbc, ZeroPage,         3), op!("INC", inc, ZeroPage,         5), op!("ISC", isc, ZeroPage,         5), implied!("INX", inx, 2),              op!("SBC", sbc, Immediate,        2), op!("NOP", nop, Implied,          2), op!("SBC", sbc, Immediate,        2), op!("CPX", cpx, Absolute,         4), op!("SBC", sbc, Absolute,         4), op!("INC", inc, Absolute,         6), op!("ISC", isc, Absolute,         6),
+    op!("BEQ", beq, Relative,         2), op!("SBC", sbc, IndirectY,        5), implied!("JAM", jam, 2),              op!("ISC", isc, IndirectY,        8), op!("NOP", nop, ZeroPageX,        4), op!("SBC", sbc, ZeroPageX,        4), op!("INC", inc, ZeroPageX,        6), op!("ISC", isc, ZeroPageX,        6), implied!("SED", sed, 2),              op!("SBC", sbc, AbsoluteY,        4), op!("NOP", nop, Implied,          2), op!("ISC", isc, AbsoluteY,        7), op!("NOP", nop, AbsoluteX,        4), op!("SBC", sbc, AbsoluteX,        4), op!("INC", inc, AbsoluteX,        7), op!("ISC", isc, AbsoluteX,        7),
+];
+
+// The 65C02 as most makers built it. Every opcode the NMOS chip left
+// undefined is a NOP here, of one of a handful of lengths and timings.
 #[rustfmt::skip]
-pub static LOOKUP: [Instruction; 256] = {
-    use AddressingMode::*;
-
-    let mut table: [Instruction; 256] = [Instruction {
-        name: "???",
-        exec: Cpu::lda,
-        mode: Implied,
-        cycles: 2,
-    }; 256];
-
-    table = [
-        Instruction { name:"BRK", exec: Cpu::brk, mode: Immediate, cycles: 7 }, Instruction { name:"ORA", exec: Cpu::ora, mode: IndirectX, cycles: 6 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied,   cycles: 2 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 8 }, Instruction { name:"???", exec: Cpu::nop, mode: Implied,   cycles: 3 }, Instruction { name:"ORA", exec: Cpu::ora, mode: ZeroPage,  cycles: 3 }, Instruction { name:"ASL", exec: Cpu::asl, mode: ZeroPage,  cycles: 5 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 5 }, Instruction { name:"PHP", exec: Cpu::php, mode: Implied, cycles: 3 }, Instruction { name:"ORA", exec: Cpu::ora, mode: Immediate, cycles: 2 }, Instruction { name:"ASL", exec: Cpu::asl, mode: Implied, cycles: 2 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 2 }, Instruction { name:"???", exec: Cpu::nop, mode: Implied,   cycles: 4 }, Instruction { name:"ORA", exec: Cpu::ora, mode: Absolute,  cycles: 4 }, Instruction { name:"ASL", exec: Cpu::asl, mode: Absolute,  cycles: 6 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 6 }, 
-        Instruction { name:"BPL", exec: Cpu::bpl, mode: Relative,  cycles: 2 }, Instruction { name:"ORA", exec: Cpu::ora, mode: IndirectY, cycles: 5 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied,   cycles: 2 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 8 }, Instruction { name:"???", exec: Cpu::nop, mode: Implied,   cycles: 4 }, Instruction { name:"ORA", exec: Cpu::ora, mode: ZeroPageX, cycles: 4 }, Instruction { name:"ASL", exec: Cpu::asl, mode: ZeroPageX, cycles: 6 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 6 }, Instruction { name:"CLC", exec: Cpu::clc, mode: Implied, cycles: 2 }, Instruction { name:"ORA", exec: Cpu::ora, mode: AbsoluteY, cycles: 4 }, Instruction { name:"???", exec: Cpu::nop, mode: Implied, cycles: 2 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 7 }, Instruction { name:"???", exec: Cpu::nop, mode: Implied,   cycles: 4 }, Instruction { name:"ORA", exec: Cpu::ora, mode: AbsoluteX, cycles: 4 }, Instruction { name:"ASL", exec: Cpu::asl, mode: AbsoluteX, cycles: 7 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 7 }, 
-        Instruction { name:"JSR", exec: Cpu::jsr, mode: Absolute,  cycles: 6 }, Instruction { name:"AND", exec: Cpu::and, mode: IndirectX, cycles: 6 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied,   cycles: 2 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 8 }, Instruction { name:"BIT", exec: Cpu::bit, mode: ZeroPage,  cycles: 3 }, Instruction { name:"AND", exec: Cpu::and, mode: ZeroPage,  cycles: 3 }, Instruction { name:"ROL", exec: Cpu::rol, mode: ZeroPage,  cycles: 5 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 5 }, Instruction { name:"PLP", exec: Cpu::plp, mode: Implied, cycles: 4 }, Instruction { name:"AND", exec: Cpu::and, mode: Immediate, cycles: 2 }, Instruction { name:"ROL", exec: Cpu::rol, mode: Implied, cycles: 2 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 2 }, Instruction { name:"BIT", exec: Cpu::bit, mode: Absolute,  cycles: 4 }, Instruction { name:"AND", exec: Cpu::and, mode: Absolute,  cycles: 4 }, Instruction { name:"ROL", exec: Cpu::rol, mode: Absolute,  cycles: 6 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 6 }, 
-        Instruction { name:"BMI", exec: Cpu::bmi, mode: Relative,  cycles: 2 }, Instruction { name:"AND", exec: Cpu::and, mode: IndirectY, cycles: 5 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied,   cycles: 2 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 8 }, Instruction { name:"???", exec: Cpu::nop, mode: Implied,   cycles: 4 }, Instruction { name:"AND", exec: Cpu::and, mode: ZeroPageX, cycles: 4 }, Instruction { name:"ROL", exec: Cpu::rol, mode: ZeroPageX, cycles: 6 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 6 }, Instruction { name:"SEC", exec: Cpu::sec, mode: Implied, cycles: 2 }, Instruction { name:"AND", exec: Cpu::and, mode: AbsoluteY, cycles: 4 }, Instruction { name:"???", exec: Cpu::nop, mode: Implied, cycles: 2 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 7 }, Instruction { name:"???", exec: Cpu::nop, mode: Implied,   cycles: 4 }, Instruction { name:"AND", exec: Cpu::and, mode: AbsoluteX, cycles: 4 }, Instruction { name:"ROL", exec: Cpu::rol, mode: AbsoluteX, cycles: 7 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 7 }, 
-        Instruction { name:"RTI", exec: Cpu::rti, mode: Implied,   cycles: 6 }, Instruction { name:"EOR", exec: Cpu::eor, mode: IndirectX, cycles: 6 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied,   cycles: 2 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 8 }, Instruction { name:"???", exec: Cpu::nop, mode: Implied,   cycles: 3 }, Instruction { name:"EOR", exec: Cpu::eor, mode: ZeroPage,  cycles: 3 }, Instruction { name:"LSR", exec: Cpu::lsr, mode: ZeroPage,  cycles: 5 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 5 }, Instruction { name:"PHA", exec: Cpu::pha, mode: Implied, cycles: 3 }, Instruction { name:"EOR", exec: Cpu::eor, mode: Immediate, cycles: 2 }, Instruction { name:"LSR", exec: Cpu::lsr, mode: Implied, cycles: 2 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 2 }, Instruction { name:"JMP", exec: Cpu::jmp, mode: Absolute,  cycles: 3 }, Instruction { name:"EOR", exec: Cpu::eor, mode: Absolute,  cycles: 4 }, Instruction { name:"LSR", exec: Cpu::lsr, mode: Absolute,  cycles: 6 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 6 }, 
-        Instruction { name:"BVC", exec: Cpu::bvc, mode: Relative,  cycles: 2 }, Instruction { name:"EOR", exec: Cpu::eor, mode: IndirectY, cycles: 5 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied,   cycles: 2 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 8 }, Instruction { name:"???", exec: Cpu::nop, mode: Implied,   cycles: 4 }, Instruction { name:"EOR", exec: Cpu::eor, mode: ZeroPageX, cycles: 4 }, Instruction { name:"LSR", exec: Cpu::lsr, mode: ZeroPageX, cycles: 6 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 6 }, Instruction { name:"CLI", exec: Cpu::cli, mode: Implied, cycles: 2 }, Instruction { name:"EOR", exec: Cpu::eor, mode: AbsoluteY, cycles: 4 }, Instruction { name:"???", exec: Cpu::nop, mode: Implied, cycles: 2 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 7 }, Instruction { name:"???", exec: Cpu::nop, mode: Implied,   cycles: 4 }, Instruction { name:"EOR", exec: Cpu::eor, mode: AbsoluteX, cycles: 4 }, Instruction { name:"LSR", exec: Cpu::lsr, mode: AbsoluteX, cycles: 7 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 7 }, 
-        Instruction { name:"RTS", exec: Cpu::rts, mode: Implied,   cycles: 6 }, Instruction { name:"ADC", exec: Cpu::adc, mode: IndirectX, cycles: 6 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied,   cycles: 2 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 8 }, Instruction { name:"???", exec: Cpu::nop, mode: Implied,   cycles: 3 }, Instruction { name:"ADC", exec: Cpu::adc, mode: ZeroPage,  cycles: 3 }, Instruction { name:"ROR", exec: Cpu::ror, mode: ZeroPage,  cycles: 5 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 5 }, Instruction { name:"PLA", exec: Cpu::pla, mode: Implied, cycles: 4 }, Instruction { name:"ADC", exec: Cpu::adc, mode: Immediate, cycles: 2 }, Instruction { name:"ROR", exec: Cpu::ror, mode: Implied, cycles: 2 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 2 }, Instruction { name:"JMP", exec: Cpu::jmp, mode: Indirect,  cycles: 5 }, Instruction { name:"ADC", exec: Cpu::adc, mode: Absolute,  cycles: 4 }, Instruction { name:"ROR", exec: Cpu::ror, mode: Absolute,  cycles: 6 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 6 }, 
-        Instruction { name:"BVS", exec: Cpu::bvs, mode: Relative,  cycles: 2 }, Instruction { name:"ADC", exec: Cpu::adc, mode: IndirectY, cycles: 5 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied,   cycles: 2 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 8 }, Instruction { name:"???", exec: Cpu::nop, mode: Implied,   cycles: 4 }, Instruction { name:"ADC", exec: Cpu::adc, mode: ZeroPageX, cycles: 4 }, Instruction { name:"ROR", exec: Cpu::ror, mode: ZeroPageX, cycles: 6 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 6 }, Instruction { name:"SEI", exec: Cpu::sei, mode: Implied, cycles: 2 }, Instruction { name:"ADC", exec: Cpu::adc, mode: AbsoluteY, cycles: 4 }, Instruction { name:"???", exec: Cpu::nop, mode: Implied, cycles: 2 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 7 }, Instruction { name:"???", exec: Cpu::nop, mode: Implied,   cycles: 4 }, Instruction { name:"ADC", exec: Cpu::adc, mode: AbsoluteX, cycles: 4 }, Instruction { name:"ROR", exec: Cpu::ror, mode: AbsoluteX, cycles: 7 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 7 }, 
-        Instruction { name:"???", exec: Cpu::nop, mode: Implied,   cycles: 2 }, Instruction { name:"STA", exec: Cpu::sta, mode: IndirectX, cycles: 6 }, Instruction { name:"???", exec: Cpu::nop, mode: Implied,   cycles: 2 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 6 }, Instruction { name:"STY", exec: Cpu::sty, mode: ZeroPage,  cycles: 3 }, Instruction { name:"STA", exec: Cpu::sta, mode: ZeroPage,  cycles: 3 }, Instruction { name:"STX", exec: Cpu::stx, mode: ZeroPage,  cycles: 3 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 3 }, Instruction { name:"DEY", exec: Cpu::dey, mode: Implied, cycles: 2 }, Instruction { name:"???", exec: Cpu::nop, mode: Implied,   cycles: 2 }, Instruction { name:"TXA", exec: Cpu::txa, mode: Implied, cycles: 2 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 2 }, Instruction { name:"STY", exec: Cpu::sty, mode: Absolute,  cycles: 4 }, Instruction { name:"STA", exec: Cpu::sta, mode: Absolute,  cycles: 4 }, Instruction { name:"STX", exec: Cpu::stx, mode: Absolute,  cycles: 4 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 4 }, 
-        Instruction { name:"BCC", exec: Cpu::bcc, mode: Relative,  cycles: 2 }, Instruction { name:"STA", exec: Cpu::sta, mode: IndirectY, cycles: 6 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied,   cycles: 2 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 6 }, Instruction { name:"STY", exec: Cpu::sty, mode: ZeroPageX, cycles: 4 }, Instruction { name:"STA", exec: Cpu::sta, mode: ZeroPageX, cycles: 4 }, Instruction { name:"STX", exec: Cpu::stx, mode: ZeroPageY, cycles: 4 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 4 }, Instruction { name:"TYA", exec: Cpu::tya, mode: Implied, cycles: 2 }, Instruction { name:"STA", exec: Cpu::sta, mode: AbsoluteY, cycles: 5 }, Instruction { name:"TXS", exec: Cpu::txs, mode: Implied, cycles: 2 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 5 }, Instruction { name:"???", exec: Cpu::nop, mode: Implied,   cycles: 5 }, Instruction { name:"STA", exec: Cpu::sta, mode: AbsoluteX, cycles: 5 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied,   cycles: 5 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 5 }, 
-        Instruction { name:"LDY", exec: Cpu::ldy, mode: Immediate, cycles: 2 }, Instruction { name:"LDA", exec: Cpu::lda, mode: IndirectX, cycles: 6 }, Instruction { name:"LDX", exec: Cpu::ldx, mode: Immediate, cycles: 2 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 6 }, Instruction { name:"LDY", exec: Cpu::ldy, mode: ZeroPage,  cycles: 3 }, Instruction { name:"LDA", exec: Cpu::lda, mode: ZeroPage,  cycles: 3 }, Instruction { name:"LDX", exec: Cpu::ldx, mode: ZeroPage,  cycles: 3 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 3 }, Instruction { name:"TAY", exec: Cpu::tay, mode: Implied, cycles: 2 }, Instruction { name:"LDA", exec: Cpu::lda, mode: Immediate, cycles: 2 }, Instruction { name:"TAX", exec: Cpu::tax, mode: Implied, cycles: 2 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 2 }, Instruction { name:"LDY", exec: Cpu::ldy, mode: Absolute,  cycles: 4 }, Instruction { name:"LDA", exec: Cpu::lda, mode: Absolute,  cycles: 4 }, Instruction { name:"LDX", exec: Cpu::ldx, mode: Absolute,  cycles: 4 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 4 }, 
-        Instruction { name:"BCS", exec: Cpu::bcs, mode: Relative,  cycles: 2 }, Instruction { name:"LDA", exec: Cpu::lda, mode: IndirectY, cycles: 5 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied,   cycles: 2 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 5 }, Instruction { name:"LDY", exec: Cpu::ldy, mode: ZeroPageX, cycles: 4 }, Instruction { name:"LDA", exec: Cpu::lda, mode: ZeroPageX, cycles: 4 }, Instruction { name:"LDX", exec: Cpu::ldx, mode: ZeroPageY, cycles: 4 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 4 }, Instruction { name:"CLV", exec: Cpu::clv, mode: Implied, cycles: 2 }, Instruction { name:"LDA", exec: Cpu::lda, mode: AbsoluteY, cycles: 4 }, Instruction { name:"TSX", exec: Cpu::tsx, mode: Implied, cycles: 2 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 4 }, Instruction { name:"LDY", exec: Cpu::ldy, mode: AbsoluteX, cycles: 4 }, Instruction { name:"LDA", exec: Cpu::lda, mode: AbsoluteX, cycles: 4 }, Instruction { name:"LDX", exec: Cpu::ldx, mode: AbsoluteY, cycles: 4 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 4 }, 
-        Instruction { name:"CPY", exec: Cpu::cpy, mode: Immediate, cycles: 2 }, Instruction { name:"CMP", exec: Cpu::cmp, mode: IndirectX, cycles: 6 }, Instruction { name:"???", exec: Cpu::nop, mode: Implied,   cycles: 2 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 8 }, Instruction { name:"CPY", exec: Cpu::cpy, mode: ZeroPage,  cycles: 3 }, Instruction { name:"CMP", exec: Cpu::cmp, mode: ZeroPage,  cycles: 3 }, Instruction { name:"DEC", exec: Cpu::dec, mode: ZeroPage,  cycles: 5 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 5 }, Instruction { name:"INY", exec: Cpu::iny, mode: Implied, cycles: 2 }, Instruction { name:"CMP", exec: Cpu::cmp, mode: Immediate, cycles: 2 }, Instruction { name:"DEX", exec: Cpu::dex, mode: Implied, cycles: 2 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 2 }, Instruction { name:"CPY", exec: Cpu::cpy, mode: Absolute,  cycles: 4 }, Instruction { name:"CMP", exec: Cpu::cmp, mode: Absolute,  cycles: 4 }, Instruction { name:"DEC", exec: Cpu::dec, mode: Absolute,  cycles: 6 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 6 }, 
-        Instruction { name:"BNE", exec: Cpu::bne, mode: Relative,  cycles: 2 }, Instruction { name:"CMP", exec: Cpu::cmp, mode: IndirectY, cycles: 5 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied,   cycles: 2 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 8 }, Instruction { name:"???", exec: Cpu::nop, mode: Implied,   cycles: 4 }, Instruction { name:"CMP", exec: Cpu::cmp, mode: ZeroPageX, cycles: 4 }, Instruction { name:"DEC", exec: Cpu::dec, mode: ZeroPageX, cycles: 6 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 6 }, Instruction { name:"CLD", exec: Cpu::cld, mode: Implied, cycles: 2 }, Instruction { name:"CMP", exec: Cpu::cmp, mode: AbsoluteY, cycles: 4 }, Instruction { name:"NOP", exec: Cpu::nop, mode: Implied, cycles: 2 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 7 }, Instruction { name:"???", exec: Cpu::nop, mode: Implied,   cycles: 4 }, Instruction { name:"CMP", exec: Cpu::cmp, mode: AbsoluteX, cycles: 4 }, Instruction { name:"DEC", exec: Cpu::dec, mode: AbsoluteX, cycles: 7 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 7 }, 
-        Instruction { name:"CPX", exec: Cpu::cpx, mode: Immediate, cycles: 2 }, Instruction { name:"SBC", exec: Cpu::sbc, mode: IndirectX, cycles: 6 }, Instruction { name:"???", exec: Cpu::nop, mode: Implied,   cycles: 2 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 8 }, Instruction { name:"CPX", exec: Cpu::cpx, mode: ZeroPage,  cycles: 3 }, Instruction { name:"SBC", exec: Cpu::sbc, mode: ZeroPage,  cycles: 3 }, Instruction { name:"INC", exec: Cpu::inc, mode: ZeroPage,  cycles: 5 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 5 }, Instruction { name:"INX", exec: Cpu::inx, mode: Implied, cycles: 2 }, Instruction { name:"SBC", exec: Cpu::sbc, mode: Immediate, cycles: 2 }, Instruction { name:"NOP", exec: Cpu::nop, mode: Implied, cycles: 2 }, Instruction { name:"???", exec: Cpu::sbc, mode: Implied, cycles: 2 }, Instruction { name:"CPX", exec: Cpu::cpx, mode: Absolute,  cycles: 4 }, Instruction { name:"SBC", exec: Cpu::sbc, mode: Absolute,  cycles: 4 }, Instruction { name:"INC", exec: Cpu::inc, mode: Absolute,  cycles: 6 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 6 }, 
-        Instruction { name:"BEQ", exec: Cpu::beq, mode: Relative,  cycles: 2 }, Instruction { name:"SBC", exec: Cpu::sbc, mode: IndirectY, cycles: 5 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied,   cycles: 2 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 8 }, Instruction { name:"???", exec: Cpu::nop, mode: Implied,   cycles: 4 }, Instruction { name:"SBC", exec: Cpu::sbc, mode: ZeroPageX, cycles: 4 }, Instruction { name:"INC", exec: Cpu::inc, mode: ZeroPageX, cycles: 6 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 6 }, Instruction { name:"SED", exec: Cpu::sed, mode: Implied, cycles: 2 }, Instruction { name:"SBC", exec: Cpu::sbc, mode: AbsoluteY, cycles: 4 }, Instruction { name:"NOP", exec: Cpu::nop, mode: Implied, cycles: 2 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 7 }, Instruction { name:"???", exec: Cpu::nop, mode: Implied,   cycles: 4 }, Instruction { name:"SBC", exec: Cpu::sbc, mode: AbsoluteX, cycles: 4 }, Instruction { name:"INC", exec: Cpu::inc, mode: AbsoluteX, cycles: 7 }, Instruction { name:"???", exec: Cpu::xxx, mode: Implied, cycles: 7 }, 
-    
-    ];
+const CMOS: [Instruction; 256] = [
+    op!("BRK", brk, Immediate,        7),  op!("ORA", ora, IndirectX,        6),  op!("NOP", nop, Immediate,        2),  op!("NOP", nop, Implied,          1),  op!("TSB", tsb, ZeroPage,         5),  op!("ORA", ora, ZeroPage,         3),  op!("ASL", asl, ZeroPage,         5),  op!("NOP", nop, Implied,          1),  implied!("PHP", php, 3),               op!("ORA", ora, Immediate,        2),  op!("ASL", asl, Accumulator,      2),  op!("NOP", nop, Implied,          1),  op!("TSB", tsb, Absolute,         6),  op!("ORA", ora, Absolute,         4),  op!("ASL", asl, Absolute,         6),  op!("NOP", nop, Implied,          1),
+    op!("BPL", bpl, Relative,         2),  op!("ORA", ora, IndirectY,        5),  op!("ORA", ora, ZeroPageIndirect, 5),  op!("NOP", nop, Implied,          1),  op!("TRB", trb, ZeroPage,         5),  op!("ORA", ora, ZeroPageX,        4),  op!("ASL", asl, ZeroPageX,        6),  op!("NOP", nop, Implied,          1),  implied!("CLC", clc, 2),               op!("ORA", ora, AbsoluteY,        4),  op!("INC", inc, Accumulator,      2),  op!("NOP", nop, Implied,          1),  op!("TRB", trb, Absolute,         6),  op!("ORA", ora, AbsoluteX,        4),  op!("ASL", asl, AbsoluteX,        6),  op!("NOP", nop, Implied,          1),
+    op!("JSR", jsr, Absolute,         6),  op!("AND", and, IndirectX,        6),  op!("NOP", nop, Immediate,        2),  op!("NOP", nop, Implied,          1),  op!("BIT", bit, ZeroPage,         3),  op!("AND", and, ZeroPage,         3),  op!("ROL", rol, ZeroPage,         5),  op!("NOP", nop, Implied,          1),  implied!("PLP", plp, 4),               op!("AND", and, Immediate,        2),  op!("ROL", rol, Accumulator,      2),  op!("NOP", nop, Implied,          1),  op!("BIT", bit, Absolute,         4),  op!("AND", and, Absolute,         4),  op!("ROL", rol, Absolute,         6),  op!("NOP", nop, Implied,          1),
+    op!("BMI", bmi, Relative,         2),  op!("AND", and, IndirectY,        5),  op!("AND", and, ZeroPageIndirect, 5),  op!("NOP", nop, Implied,          1),  op!("BIT", bit, ZeroPageX,        4),  op!("AND", and, ZeroPageX,        4),  op!("ROL", rol, ZeroPageX,        6),  op!("NOP", nop, Implied,          1),  implied!("SEC", sec, 2),               op!("AND", and, AbsoluteY,        4),  op!("DEC", dec, Accumulator,      2),  op!("NOP", nop, Implied,          1),  op!("BIT", bit, AbsoluteX,        4),  op!("AND", and, AbsoluteX,        4),  op!("ROL", rol, AbsoluteX,        6),  op!("NOP", nop, Implied,          1),
+    implied!("RTI", rti, 6),               op!("EOR", eor, IndirectX,        6),  op!("NOP", nop, Immediate,        2),  op!("NOP", nop, Implied,          1),  op!("NOP", nop, ZeroPage,         3),  op!("EOR", eor, ZeroPage,         3),  op!("LSR", lsr, ZeroPage,         5),  op!("NOP", nop, Implied,          1),  implied!("PHA", pha, 3),               op!("EOR", eor, Immediate,        2),  op!("LSR", lsr, Accumulator,      2),  op!("NOP", nop, Implied,          1),  op!("JMP", jmp, Absolute,         3),  op!("EOR", eor, Absolute,         4),  op!("LSR", lsr, Absolute,         6),  op!("NOP", nop, Implied,          1),
+    op!("BVC", bvc, Relative,         2),  op!("EOR", eor, IndirectY,        5),  op!("EOR", eor, ZeroPageIndirect, 5),  op!("NOP", nop, Implied,          1),  op!("NOP", nop, ZeroPageX,        4),  op!("EOR", eor, ZeroPageX,        4),  op!("LSR", lsr, ZeroPageX,        6),  op!("NOP", nop, Implied,          1),  implied!("CLI", cli, 2),               op!("EOR", eor, AbsoluteY,        4),  implied!("PHY", phy, 3),               op!("NOP", nop, Implied,          1),  op!("NOP", nop, Absolute,         8),  op!("EOR", eor, AbsoluteX,        4),  op!("LSR", lsr, AbsoluteX,        6),  op!("NOP", nop, Implied,          1),
+    implied!("RTS", rts, 6),               op!("ADC", adc, IndirectX,        6),  op!("NOP", nop, Immediate,        2),  op!("NOP", nop, Implied,          1),  op!("STZ", stz, ZeroPage,         3),  op!("ADC", adc, ZeroPage,         3),  op!("ROR", ror, ZeroPage,         5),  op!("NOP", nop, Implied,          1),  implied!("PLA", pla, 4),               op!("ADC", adc, Immediate,        2),  op!("ROR", ror, Accumulator,      2),  op!("NOP", nop, Implied,          1),  op!("JMP", jmp, Indirect,         6),  op!("ADC", adc, Absolute,         4),  op!("ROR", ror, Absolute,         6),  op!("NOP", nop, Implied,          1),
+    op!("BVS", bvs, Relative,         2),  op!("ADC", adc, IndirectY,        5),  op!("ADC", adc, ZeroPageIndirect, 5),  op!("NOP", nop, Implied,          1),  op!("STZ", stz, ZeroPageX,        4),  op!("ADC", adc, ZeroPageX,        4),  op!("ROR", ror, ZeroPageX,        6),  op!("NOP", nop, Implied,          1),  implied!("SEI", sei, 2),               op!("ADC", adc, AbsoluteY,        4),  implied!("PLY", ply, 4),               op!("NOP", nop, Implied,          1),  op!("JMP", jmp, AbsoluteIndirectX, 6), op!("ADC", adc, AbsoluteX,        4),  op!("ROR", ror, AbsoluteX,        6),  op!("NOP", nop, Implied,          1),
+    op!("BRA", bra, Relative,         3),  op!("STA", sta, IndirectX,        6),  op!("NOP", nop, Immediate,        2),  op!("NOP", nop, Implied,          1),  op!("STY", sty, ZeroPage,         3),  op!("STA", sta, ZeroPage,         3),  op!("STX", stx, ZeroPage,         3),  op!("NOP", nop, Implied,          1),  implied!("DEY", dey, 2),               op!("BIT", bit, Immediate,        2),  implied!("TXA", txa, 2),               op!("NOP", nop, Implied,          1),  op!("STY", sty, Absolute,         4),  op!("STA", sta, Absolute,         4),  op!("STX", stx, Absolute,         4),  op!("NOP", nop, Implied,          1),
+    op!("BCC", bcc, Relative,         2),  op!("STA", sta, IndirectY,        6),  op!("STA", sta, ZeroPageIndirect, 5),  op!("NOP", nop, Implied,          1),  op!("STY", sty, ZeroPageX,        4),  op!("STA", sta, ZeroPageX,        4),  op!("STX", stx, ZeroPageY,        4),  op!("NOP", nop, Implied,          1),  implied!("TYA", tya, 2),               op!("STA", sta, AbsoluteY,        5),  implied!("TXS", txs, 2),               op!("NOP", nop, Implied,          1),  op!("STZ", stz, Absolute,         4),  op!("STA", sta, AbsoluteX,        5),  op!("STZ", stz, AbsoluteX,        5),  op!("NOP", nop, Implied,          1),
+    op!("LDY", ldy, Immediate,        2),  op!("LDA", lda, IndirectX,        6),  op!("LDX", ldx, Immediate,        2),  op!("NOP", nop, Implied,          1),  op!("LDY", ldy, ZeroPage,         3),  op!("LDA", lda, ZeroPage,         3),  op!("LDX", ldx, ZeroPage,         3),  op!("NOP", nop, Implied,          1),  implied!("TAY", tay, 2),               op!("LDA", lda, Immediate,        2),  implied!("TAX", tax, 2),               op!("NOP", nop, Implied,          1),  op!("LDY", ldy, Absolute,         4),  op!("LDA", lda, Absolute,         4),  op!("LDX", ldx, Absolute,         4),  op!("NOP", nop, Implied,          1),
+    op!("BCS", bcs, Relative,         2),  op!("LDA", lda, IndirectY,        5),  op!("LDA", lda, ZeroPageIndirect, 5),  op!("NOP", nop, Implied,          1),  op!("LDY", ldy, ZeroPageX,        4),  op!("LDA", lda, ZeroPageX,        4),  op!("LDX", ldx, ZeroPageY,        4),  op!("NOP", nop, Implied,          1),  implied!("CLV", clv, 2),               op!("LDA", lda, AbsoluteY,        4),  implied!("TSX", tsx, 2),               op!("NOP", nop, Implied,          1),  op!("LDY", ldy, AbsoluteX,        4),  op!("LDA", lda, AbsoluteX,        4),  op!("LDX", ldx, AbsoluteY,        4),  op!("NOP", nop, Implied,          1),
+    op!("CPY", cpy, Immediate,        2),  op!("CMP", cmp, IndirectX,        6),  op!("NOP", nop, Immediate,        2),  op!("NOP", nop, Implied,          1),  op!("CPY", cpy, ZeroPage,         3),  op!("CMP", cmp, ZeroPage,         3),  op!("DEC", dec, ZeroPage,         5),  op!("NOP", nop, Implied,          1),  implied!("INY", iny, 2),               op!("CMP", cmp, Immediate,        2),  implied!("DEX", dex, 2),               op!("NOP", nop, Implied,          1),  op!("CPY", cpy, Absolute,         4),  op!("CMP", cmp, Absolute,         4),  op!("DEC", dec, Absolute,         6),  op!("NOP", nop, Implied,          1),
+    op!("BNE", bne, Relative,         2),  op!("CMP", cmp, IndirectY,        5),  op!("CMP", cmp, ZeroPageIndirect, 5),  op!("NOP", nop, Implied,          1),  op!("NOP", nop, ZeroPageX,        4),  op!("CMP", cmp, ZeroPageX,        4),  op!("DEC", dec, ZeroPageX,        6),  op!("NOP", nop, Implied,          1),  implied!("CLD", cld, 2),               op!("CMP", cmp, AbsoluteY,        4),  implied!("PHX", phx, 3),               op!("NOP", nop, Implied,          1),  op!("NOP", nop, Absolute,         4),  op!("CMP", cmp, AbsoluteX,        4),  op!("DEC", dec, AbsoluteX,        7),  op!("NOP", nop, Implied,          1),
+    op!("CPX", cpx, Immediate,        2),  op!("SBC", sbc, IndirectX,        6),  op!("NOP", nop, Immediate,        2),  op!("NOP", nop, Implied,          1),  op!("CPX", cpx, ZeroPage,         3),  op!("SBC", sbc, ZeroPage,         3),  op!("INC", inc, ZeroPage,         5),  op!("NOP", nop, Implied,          1),  implied!("INX", inx, 2),               op!("SBC", sbc, Immediate,        2),  op!("NOP", nop, Implied,          2),  op!("NOP", nop, Implied,          1),  op!("CPX", cpx, Absolute,         4),  op!("SBC", sbc, Absolute,         4),  op!("INC", inc, Absolute,         6),  op!("NOP", nop, Implied,          1),
+    op!("BEQ", beq, Relative,         2),  op!("SBC", sbc, IndirectY,        5),  op!("SBC", sbc, ZeroPageIndirect, 5),  op!("NOP", nop, Implied,          1),  op!("NOP", nop, ZeroPageX,        4),  op!("SBC", sbc, ZeroPageX,        4),  op!("INC", inc, ZeroPageX,        6),  op!("NOP", nop, Implied,          1),  implied!("SED", sed, 2),               op!("SBC", sbc, AbsoluteY,        4),  implied!("PLX", plx, 4),               op!("NOP", nop, Implied,          1),  op!("NOP", nop, Absolute,         4),  op!("SBC", sbc, AbsoluteX,        4),  op!("INC", inc, AbsoluteX,        7),  op!("NOP", nop, Implied,          1),
+];
 
+pub static LOOKUP_65C02: [Instruction; 256] = CMOS;
+
+// Rockwell's and later WDC's chips put bit instructions in the NOPs ending
+// in 7 and F. The bit is the opcode's high nibble, less 8 for SMB and BBS.
+pub static LOOKUP_R65C02: [Instruction; 256] = with_bit_instructions(CMOS);
+
+// WDC's also wait for an interrupt or stop the clock
+pub static LOOKUP_W65C02: [Instruction; 256] = {
+    let mut table = with_bit_instructions(CMOS);
+    table[0xCB] = implied!("WAI", wai, 3);
+    table[0xDB] = implied!("STP", stp, 3);
     table
 };
+
+#[rustfmt::skip]
+const fn with_bit_instructions(mut table: [Instruction; 256]) -> [Instruction; 256] {
+    let rmb = [op!("RMB0", rmb, ZeroPage, 5), op!("RMB1", rmb, ZeroPage, 5), op!("RMB2", rmb, ZeroPage, 5), op!("RMB3", rmb, ZeroPage, 5), op!("RMB4", rmb, ZeroPage, 5), op!("RMB5", rmb, ZeroPage, 5), op!("RMB6", rmb, ZeroPage, 5), op!("RMB7", rmb, ZeroPage, 5)];
+    let smb = [op!("SMB0", smb, ZeroPage, 5), op!("SMB1", smb, ZeroPage, 5), op!("SMB2", smb, ZeroPage, 5), op!("SMB3", smb, ZeroPage, 5), op!("SMB4", smb, ZeroPage, 5), op!("SMB5", smb, ZeroPage, 5), op!("SMB6", smb, ZeroPage, 5), op!("SMB7", smb, ZeroPage, 5)];
+    let bbr = [op!("BBR0", bbr, ZeroPageRelative, 5), op!("BBR1", bbr, ZeroPageRelative, 5), op!("BBR2", bbr, ZeroPageRelative, 5), op!("BBR3", bbr, ZeroPageRelative, 5), op!("BBR4", bbr, ZeroPageRelative, 5), op!("BBR5", bbr, ZeroPageRelative, 5), op!("BBR6", bbr, ZeroPageRelative, 5), op!("BBR7", bbr, ZeroPageRelative, 5)];
+    let bbs = [op!("BBS0", bbs, ZeroPageRelative, 5), op!("BBS1", bbs, ZeroPageRelative, 5), op!("BBS2", bbs, ZeroPageRelative, 5), op!("BBS3", bbs, ZeroPageRelative, 5), op!("BBS4", bbs, ZeroPageRelative, 5), op!("BBS5", bbs, ZeroPageRelative, 5), op!("BBS6", bbs, ZeroPageRelative, 5), op!("BBS7", bbs, ZeroPageRelative, 5)];
+
+    let mut bit = 0;
+    while bit < 8 {
+        let row = bit << 4;
+        table[0x07 | row] = rmb[bit];
+        table[0x87 | row] = smb[bit];
+        table[0x0F | row] = bbr[bit];
+        table[0x8F | row] = bbs[bit];
+        bit += 1;
+    }
+    table
+}
@@ -1,7 +1,561 @@
+mod audio;
+mod bench;
 mod bus;
+mod cartridge;
+mod console;
+mod controller;
 mod cpu;
+mod database;
+mod debugger;
+mod deflate;
+mod disasm;
+mod framebuffer;
+mod golden;
+mod hash;
 mod instructions;
+mod mapper;
+mod memory;
+mod movie;
+mod nsf;
+mod patch;
+mod png;
+mod rewind;
+mod state;
+mod symbols;
+mod unif;
 
-fn main() {
-    println!("Hello, world!");
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::process::ExitCode;
+use std::time::Duration;
+
+use std::net::TcpListener;
+
+use audio::ExpansionChip;
+use cartridge::{Cartridge, Header};
+use console::Console;
+use cpu::{Cpu, Interrupt, Variant};
+use database::Database;
+use debugger::{CodeDataLog, Debugger, GdbStub, PrgFlags, Tui};
+use golden::GoldenTest;
+use movie::{Frame, Movie, MovieError, Session};
+use nsf::{Nsf, Player};
+use symbols::Symbols;
+
+const USAGE: &str = "usage: nes-rs nsf <file> [track] [out.wav] [--mute apu,5b,n163,fds]
+       nes-rs info <rom> [database.xml]
+       nes-rs gdb <rom> [port] [--bios <file>] [--patch <file>...]
+       nes-rs debug <rom> [symbols...] [--bios <file>] [--patch <file>...]
+       nes-rs trace <rom> [instructions] [--bios <file>] [--patch <file>...]
+       nes-rs cdl <rom> [cycles] [out.cdl] [--bios <file>] [--patch <file>...]
+       nes-rs play <rom> <movie>
+       nes-rs record <rom> <movie> <out.fm2|out.bk2>
+       nes-rs golden <rom> <golden dir> <movie> <frame>...
+       nes-rs 6502 <binary> <load address> <start address> [variant]
+       nes-rs bench [frames] [rom...]";
+
+const GDB_PORT: u16 = 6502;
+
+const TRACE_INSTRUCTIONS: u64 = 10000;
+
+// One second of NTSC CPU time
+const CDL_CYCLES: u64 = 1_789_773;
+
+const SAMPLE_RATE: u32 = 48000;
+
+// Ten seconds of real time
+const BENCH_FRAMES: u64 = 600;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        Some("nsf") if args.len() >= 2 => render_nsf(&args[1..]),
+        Some("info") if args.len() >= 2 => rom_info(&args[1..]),
+        Some("gdb") if args.len() >= 2 => serve_gdb(&args[1..]),
+        Some("debug") if args.len() >= 2 => debug(&args[1..]),
+        Some("trace") if args.len() >= 2 => trace(&args[1..]),
+        Some("cdl") if args.len() >= 2 => log_code_data(&args[1..]),
+        Some("play") if args.len() >= 3 => play_movie(&args[1..]),
+        Some("record") if args.len() >= 4 => record_movie(&args[1..]),
+        Some("golden") if args.len() >= 5 => check_golden(&args[1..]),
+        Some("6502") if args.len() >= 4 => run_6502(&args[1..]),
+        Some("bench") => run_benchmarks(&args[1..]),
+        _ => {
+            eprintln!("{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// Prints an NSF's metadata, then renders one track (numbered from 1) or
+// every track to WAV files next to it, leaving out any chips muted
+fn render_nsf(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut args = args.to_vec();
+    // None stands for the 2A03's own channels
+    let mut muted = Vec::new();
+    for name in take_option(&mut args, "--mute")?
+        .iter()
+        .flat_map(|list| list.split(','))
+    {
+        if name.eq_ignore_ascii_case("apu") {
+            muted.push(None);
+        } else {
+            let chip =
+                ExpansionChip::from_name(name).ok_or(format!("unknown sound chip {name}"))?;
+            muted.push(Some(chip));
+        }
+    }
+    let path = Path::new(args.first().ok_or(USAGE)?);
+    let nsf = Nsf::load(path)?;
+
+    println!("{} - {} ({})", nsf.artist, nsf.title, nsf.copyright);
+    for (i, track) in nsf.tracks.iter().enumerate() {
+        let name = track.name.as_deref().unwrap_or("");
+        match track.duration {
+            Some(duration) => println!("{:3}: {name} [{:.1}s]", i + 1, duration.as_secs_f32()),
+            None => println!("{:3}: {name}", i + 1),
+        }
+    }
+
+    let tracks = match args.get(1) {
+        Some(track) => {
+            let track: u8 = track.parse()?;
+            vec![track.saturating_sub(1)]
+        }
+        None => (0..nsf.track_count).collect(),
+    };
+
+    let mut player = Player::new(nsf, SAMPLE_RATE);
+    for chip in muted {
+        match chip {
+            Some(chip) => player.mixer_mut().set_muted(chip, true),
+            None => player.mixer_mut().set_apu_muted(true),
+        }
+    }
+    if !player.unsupported_chips().is_empty() {
+        eprintln!("warning: no emulation of {:?}", player.unsupported_chips());
+    }
+
+    for track in tracks {
+        let out = match args.get(2) {
+            Some(out) => out.into(),
+            None => path.with_extension(format!("{}.wav", track + 1)),
+        };
+        player.record(track, BufWriter::new(File::create(&out)?), None)?;
+        println!("wrote {}", out.display());
+    }
+    Ok(())
+}
+
+// Shows what an iNES or UNIF image's header says next to what the game
+// database (the built-in one, or the file given) says about the same ROM
+fn rom_info(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let bytes = std::fs::read(&args[0])?;
+    let image;
+    let (header, prg, chr) = if bytes.starts_with(b"UNIF") {
+        image = unif::parse(&bytes)?;
+        println!("UNIF board: {}", image.board);
+        (image.header.clone(), &image.prg_rom[..], &image.chr_rom[..])
+    } else {
+        let header = Header::parse(&bytes)?;
+        let (prg, chr) = header.rom_data(&bytes)?;
+        (header, prg, chr)
+    };
+
+    let loaded;
+    let db = match args.get(1) {
+        Some(path) => {
+            loaded = Database::load(path)?;
+            &loaded
+        }
+        None => database::builtin(),
+    };
+
+    let (crc32, sha1) = database::rom_hashes(prg, chr);
+    println!("CRC-32: {crc32:08X}");
+    println!("SHA-1:  {sha1}");
+
+    let Some(entry) = db.lookup(prg, chr) else {
+        println!("not in the database; using the header as it is");
+        print_header("header", &header, None);
+        return Ok(());
+    };
+
+    println!("database: {}", entry.name);
+    let mut corrected = header.clone();
+    entry.apply(&mut corrected);
+    print_header("header", &header, None);
+    print_header("database", &corrected, Some(&header));
+    Ok(())
+}
+
+// Marks with a * the fields that differ from other
+fn print_header(label: &str, header: &Header, other: Option<&Header>) {
+    let mark = |differs: bool| if differs { "*" } else { " " };
+    let other = other.unwrap_or(header);
+    println!("{label}:");
+    println!(
+        "  {}mapper      {}.{}",
+        mark(header.mapper != other.mapper || header.submapper != other.submapper),
+        header.mapper,
+        header.submapper
+    );
+    println!(
+        "  {}mirroring   {:?}",
+        mark(header.mirroring != other.mirroring),
+        header.mirroring
+    );
+    println!(
+        "  {}battery     {}",
+        mark(header.battery != other.battery),
+        header.battery
+    );
+    println!(
+        "  {}PRG RAM     {} + {} battery-backed",
+        mark(
+            header.prg_ram_size != other.prg_ram_size
+                || header.prg_nvram_size != other.prg_nvram_size
+        ),
+        header.prg_ram_size,
+        header.prg_nvram_size
+    );
+    println!(
+        "  {}CHR RAM     {}",
+        mark(header.chr_ram_size != other.chr_ram_size),
+        header.chr_ram_size
+    );
+    println!(
+        "  {}region      {:?}",
+        mark(header.region != other.region),
+        header.region
+    );
+    println!(
+        "  {}input       {:?}",
+        mark(header.input_devices != other.input_devices),
+        header.input_devices
+    );
+}
+
+// Powers on with the ROM inserted and waits, stopped at the reset vector,
+// for a GDB frontend to connect on localhost
+fn serve_gdb(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut args = args.to_vec();
+    let cart = load_cartridge(&mut args)?;
+    let port = match args.get(1) {
+        Some(port) => port.parse()?,
+        None => GDB_PORT,
+    };
+
+    let mut console = Console::with_cartridge(cart);
+    console.reset();
+
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    println!("waiting for gdb on 127.0.0.1:{port}");
+    let mut stub = GdbStub::accept(Debugger::new(console), &listener)?;
+    stub.serve()?;
+    stub.debugger().console_mut().bus.flush_save()?;
+    Ok(())
+}
+
+// Powers on with the ROM inserted and opens the terminal debugger, stopped
+// at the reset vector. Symbol files next to the ROM are picked up along
+// with any given.
+fn debug(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut args = args.to_vec();
+    let cart = load_cartridge(&mut args)?;
+    let mut symbols = Symbols::discover(&args[0])?;
+    for path in &args[1..] {
+        symbols.load(path)?;
+    }
+
+    let mut console = Console::with_cartridge(cart);
+    console.reset();
+
+    Tui::new(console, symbols).run()?;
+    Ok(())
+}
+
+// Logs each instruction from power on to stdout
+fn trace(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut args = args.to_vec();
+    let cart = load_cartridge(&mut args)?;
+    let instructions = match args.get(1) {
+        Some(n) => n.parse()?,
+        None => TRACE_INSTRUCTIONS,
+    };
+    let symbols = Symbols::discover(&args[0])?;
+
+    let mut console = Console::with_cartridge(cart);
+    console.reset();
+
+    let mut out = BufWriter::new(std::io::stdout().lock());
+    let mut traced = 0;
+    while traced < instructions {
+        if console.cpu.complete() {
+            writeln!(out, "{}", disasm::trace(&console, &symbols))?;
+            traced += 1;
+        }
+        console.clock();
+    }
+    console.bus.flush_save()?;
+    Ok(())
+}
+
+// Runs from power on under the Code/Data Logger and writes an FCEUX .cdl
+// file, merged with the one already there
+fn log_code_data(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut args = args.to_vec();
+    let cart = load_cartridge(&mut args)?;
+    let cycles = match args.get(1) {
+        Some(n) => n.parse()?,
+        None => CDL_CYCLES,
+    };
+    let out = match args.get(2) {
+        Some(out) => Path::new(out).to_path_buf(),
+        None => Path::new(&args[0]).with_extension("cdl"),
+    };
+
+    let mut cdl = CodeDataLog::new(&cart);
+    if out.is_file() {
+        cdl.load(&out)?;
+    }
+
+    let mut console = Console::with_cartridge(cart);
+    console.reset();
+
+    let mut debugger = Debugger::new(console);
+    debugger.cdl = Some(cdl);
+    debugger.run(cycles);
+    debugger.console_mut().bus.flush_save()?;
+    let cdl = debugger.cdl.take().expect("cdl enabled above");
+    cdl.save(&out)?;
+
+    let count = |flag| {
+        (0..cdl.prg().len())
+            .filter(|&i| cdl.prg_flags(i).contains(flag))
+            .count()
+    };
+    let unused = cdl.prg().iter().filter(|&&flags| flags == 0).count();
+    println!(
+        "PRG: {} code, {} data, {} unused of {} bytes",
+        count(PrgFlags::CODE),
+        count(PrgFlags::DATA),
+        unused,
+        cdl.prg().len()
+    );
+    println!("wrote {}", out.display());
+    Ok(())
+}
+
+// Plays an FM2 or BK2 movie back to the end, failing if it desyncs. The
+// cartridge isn't given its battery save, so playback always starts from
+// the same power-on state.
+fn play_movie(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let movie = Movie::load(&args[1])?;
+    let cart = Cartridge::from_bytes(&std::fs::read(&args[0])?)?;
+    let mut console = Console::with_cartridge(cart);
+
+    let mut session = Session::play(&mut console, movie, true)?;
+    while session.run_frame(&mut console, Frame::default())? {}
+    println!(
+        "played {} frames, {} RAM hashes matched",
+        session.frame(),
+        session.movie.ram_hashes.len()
+    );
+    Ok(())
+}
+
+// Records a new movie from another's input, with RAM hashes taken from
+// this emulator, in the format the output's extension names. Doubles as a
+// converter between FM2 and BK2.
+fn record_movie(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let rom = Path::new(&args[0]);
+    let input = Movie::load(&args[1])?;
+    let cart = Cartridge::from_bytes(&std::fs::read(rom)?)?;
+    if !input.matches(&cart) {
+        return Err(MovieError::WrongRom.into());
+    }
+
+    let name = rom.file_stem().unwrap_or_default().to_string_lossy();
+    let movie = Movie {
+        author: input.author.clone(),
+        rerecords: input.rerecords,
+        comments: input.comments.clone(),
+        start_state: input.start_state.clone(),
+        ..Movie::new(&cart, &name)
+    };
+    let mut console = Console::with_cartridge(cart);
+    let mut session = Session::record(&mut console, movie)?;
+    for frame in &input.frames {
+        session.run_frame(&mut console, *frame)?;
+    }
+
+    let movie = session.finish(&console);
+    movie.save(&args[2])?;
+    println!(
+        "recorded {} frames, {} RAM hashes",
+        movie.len(),
+        movie.ram_hashes.len()
+    );
+    Ok(())
+}
+
+// Checks the screen at each frame given against the golden images in a
+// directory, playing the movie's input meanwhile
+fn check_golden(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let rom = Path::new(&args[0]);
+    let name = rom.file_stem().unwrap_or_default().to_string_lossy();
+    let mut test = GoldenTest::new(&name, rom, &args[1]).movie(&Movie::load(&args[2])?);
+    for frame in &args[3..] {
+        test = test.capture_at(frame.parse()?);
+    }
+
+    let update = std::env::var_os(golden::UPDATE_VAR).is_some();
+    let mismatches = test.check(update)?;
+    for mismatch in &mismatches {
+        println!("{mismatch}");
+    }
+    if !mismatches.is_empty() {
+        return Err(format!("{} of {} frames differ", mismatches.len(), args.len() - 3).into());
+    }
+    Ok(())
+}
+
+// Runs a bare 6502 program, such as Klaus Dormann's test suites, on 64K of
+// RAM until it traps by jumping or branching to itself, or hits BRK. Which
+// trap means success is up to the program. Addresses are in hex, and the
+// CPU is an NMOS 6502 unless another variant is named.
+fn run_6502(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let image = std::fs::read(&args[0])?;
+    let hex = |arg: &str| u16::from_str_radix(arg.trim_start_matches('$'), 16);
+    let load = hex(&args[1])? as usize;
+    let start = hex(&args[2])?;
+    let variant = match args.get(3) {
+        Some(name) => Variant::from_name(name).ok_or(format!("unknown CPU variant {name}"))?,
+        None => Variant::Nmos6502,
+    };
+
+    let mut ram = Box::new([0x00; 0x10000]);
+    ram.get_mut(load..load + image.len())
+        .ok_or("program doesn't fit in 64K")?
+        .copy_from_slice(&image);
+
+    let mut cpu = Cpu::with_variant(variant);
+    cpu.pc = start;
+    let mut cycles: u64 = 0;
+    loop {
+        let pc = cpu.pc;
+        loop {
+            cpu.clock(&mut *ram);
+            cycles += 1;
+            if cpu.complete() {
+                break;
+            }
+        }
+
+        let trap = if cpu.take_interrupt() == Some(Interrupt::Brk) {
+            "BRK"
+        } else if cpu.pc == pc {
+            "trapped"
+        } else {
+            continue;
+        };
+        println!(
+            "{trap} at ${pc:04X} after {cycles} cycles  A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
+            cpu.a,
+            cpu.x,
+            cpu.y,
+            cpu.status.bits(),
+            cpu.stkp
+        );
+        return Ok(());
+    }
+}
+
+// Times the built-in workloads on the bare CPU as each variant and on the
+// whole console, then each ROM given, headless from power on. A ROM that
+// won't load is reported and skipped, so a whole directory can be run.
+fn run_benchmarks(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let (frames, roms) = match args.first().and_then(|n| n.parse().ok()) {
+        Some(frames) => (frames, &args[1..]),
+        None => (BENCH_FRAMES, args),
+    };
+
+    for workload in &bench::WORKLOADS {
+        for variant in [Variant::Ricoh2A03, Variant::Nmos6502, Variant::Cmos65C02] {
+            let report = bench::run_cpu(workload, variant, frames);
+            println!(
+                "{:<8} {:<15} {report}",
+                workload.name,
+                format!("{variant:?}")
+            );
+        }
+        let report = bench::run_console(workload, frames);
+        println!("{:<8} {:<15} {report}", workload.name, "console");
+    }
+
+    let mut total = Duration::ZERO;
+    let mut ran = 0;
+    for rom in roms {
+        match bench::run_rom(rom, frames) {
+            Ok(report) => {
+                println!("{rom}: {report}");
+                total += report.elapsed;
+                ran += 1;
+            }
+            Err(e) => println!("{rom}: {e}"),
+        }
+    }
+    if ran > 1 {
+        let report = bench::Report {
+            frames: frames * ran,
+            elapsed: total,
+        };
+        println!("{ran} ROMs: {report}");
+    }
+    Ok(())
+}
+
+// Loads the game named by the first argument. A disk image is inserted in
+// the FDS RAM adapter when a BIOS is given with --bios. Each --patch is
+// applied over the ROM in the order given.
+fn load_cartridge(args: &mut Vec<String>) -> Result<Cartridge, Box<dyn Error>> {
+    let bios = take_option(args, "--bios")?;
+    let patches = take_option(args, "--patch")?;
+    let path = args.first().ok_or(USAGE)?;
+    let disk = [".fds", ".qd"]
+        .iter()
+        .any(|ext| path.to_ascii_lowercase().ends_with(ext));
+    let cart = match bios.last() {
+        Some(_) if !patches.is_empty() => return Err("disk images can't be patched".into()),
+        Some(bios) => Cartridge::load_fds(path, bios)?,
+        None if disk => return Err("disk images need the FDS BIOS, given with --bios".into()),
+        None if patches.is_empty() => Cartridge::load(path)?,
+        None => Cartridge::load_patched(path, &patches)?,
+    };
+    Ok(cart)
+}
+
+// Takes every `--name value` pair out of args, keeping the values in the
+// order they were given
+fn take_option(args: &mut Vec<String>, name: &str) -> Result<Vec<String>, String> {
+    let mut values = Vec::new();
+    while let Some(i) = args.iter().position(|arg| arg == name) {
+        if i + 1 == args.len() {
+            return Err(format!("{name} needs a value"));
+        }
+        values.push(args.remove(i + 1));
+        args.remove(i);
+    }
+    Ok(values)
 }
@@ -0,0 +1,176 @@
+use std::fmt;
+
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+// Datach Joint ROM System barcode reader. A scanned EAN-13 or EAN-8 code is
+// turned into a stream of bar modules that the game samples through bit 3 of
+// $6000, one module every 1000 CPU cycles.
+pub struct BarcodeReader {
+    stream: Vec<u8>,
+    cycle: usize,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct InvalidBarcode;
+
+impl fmt::Display for InvalidBarcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "barcodes must be 8 or 13 digits")
+    }
+}
+
+impl std::error::Error for InvalidBarcode {}
+
+const CYCLES_PER_MODULE: usize = 1000;
+
+// Module patterns for each digit, 1 being a dark bar
+const LEFT_ODD: [u8; 10] = [
+    0b0001101, 0b0011001, 0b0010011, 0b0111101, 0b0100011, 0b0110001, 0b0101111, 0b0111011,
+    0b0110111, 0b0001011,
+];
+const LEFT_EVEN: [u8; 10] = [
+    0b0100111, 0b0110011, 0b0011011, 0b0100001, 0b0011101, 0b0111001, 0b0000101, 0b0010001,
+    0b0001001, 0b0010111,
+];
+const RIGHT: [u8; 10] = [
+    0b1110010, 0b1100110, 0b1101100, 0b1000010, 0b1011100, 0b1001110, 0b1010000, 0b1000100,
+    0b1001000, 0b1110100,
+];
+
+// EAN-13 encodes its first digit in the odd/even parity of the next six,
+// bit 5 being the leftmost and set meaning even
+const FIRST_DIGIT_PARITY: [u8; 10] = [
+    0b000000, 0b001011, 0b001101, 0b001110, 0b010011, 0b011001, 0b011100, 0b010101, 0b010110,
+    0b011010,
+];
+
+impl BarcodeReader {
+    pub fn new() -> Self {
+        BarcodeReader {
+            stream: Vec::new(),
+            cycle: 0,
+        }
+    }
+
+    // Starts feeding a new barcode to the game, as if swiped through the reader
+    pub fn scan(&mut self, code: &str) -> Result<(), InvalidBarcode> {
+        let digits: Vec<u8> = code
+            .chars()
+            .map(|c| c.to_digit(10).map(|d| d as u8))
+            .collect::<Option<_>>()
+            .ok_or(InvalidBarcode)?;
+
+        let mut modules = Vec::new();
+        match digits.len() {
+            13 => {
+                let parity = FIRST_DIGIT_PARITY[digits[0] as usize];
+                push_pattern(&mut modules, 0b101, 3);
+                for (i, &d) in digits[1..7].iter().enumerate() {
+                    let even = parity & (0x20 >> i) != 0;
+                    let table = if even { &LEFT_EVEN } else { &LEFT_ODD };
+                    push_pattern(&mut modules, table[d as usize], 7);
+                }
+                push_pattern(&mut modules, 0b01010, 5);
+                for &d in &digits[7..13] {
+                    push_pattern(&mut modules, RIGHT[d as usize], 7);
+                }
+                push_pattern(&mut modules, 0b101, 3);
+            }
+            8 => {
+                push_pattern(&mut modules, 0b101, 3);
+                for &d in &digits[0..4] {
+                    push_pattern(&mut modules, LEFT_ODD[d as usize], 7);
+                }
+                push_pattern(&mut modules, 0b01010, 5);
+                for &d in &digits[4..8] {
+                    push_pattern(&mut modules, RIGHT[d as usize], 7);
+                }
+                push_pattern(&mut modules, 0b101, 3);
+            }
+            _ => return Err(InvalidBarcode),
+        }
+
+        // The reader sees white margins either side of the code, and reports
+        // dark bars as a low bit
+        self.stream = vec![0x08; 33];
+        self.stream
+            .extend(modules.iter().map(|&bar| if bar { 0x00 } else { 0x08 }));
+        self.stream.extend([0x08; 32]);
+        self.cycle = 0;
+        Ok(())
+    }
+
+    pub fn clock(&mut self) {
+        if self.cycle < self.stream.len() * CYCLES_PER_MODULE {
+            self.cycle += 1;
+        }
+    }
+
+    // Bit 3 of $6000-$7FFF reads
+    pub fn output(&self) -> u8 {
+        self.stream
+            .get(self.cycle / CYCLES_PER_MODULE)
+            .copied()
+            .unwrap_or(0x00)
+    }
+}
+
+fn push_pattern(modules: &mut Vec<bool>, pattern: u8, width: u8) {
+    for i in (0..width).rev() {
+        modules.push(pattern & (1 << i) != 0);
+    }
+}
+
+impl Snapshot for BarcodeReader {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.stream);
+        w.u64(self.cycle as u64);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.stream = r.bytes()?.to_vec();
+        self.cycle = r.u64()? as usize;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Bit 3 of each module the game would sample, from the first bar on
+    fn modules(reader: &mut BarcodeReader) -> Vec<bool> {
+        let mut modules = Vec::new();
+        while modules.len() < 33 + 95 + 32 {
+            modules.push(reader.output() == 0x00);
+            for _ in 0..CYCLES_PER_MODULE {
+                reader.clock();
+            }
+        }
+        modules
+    }
+
+    #[test]
+    fn scans_ean13() {
+        let mut reader = BarcodeReader::new();
+        reader.scan("4902425123457").unwrap();
+        let modules = modules(&mut reader);
+
+        assert!(modules[..33].iter().all(|&bar| !bar));
+        let code = &modules[33..33 + 95];
+        assert_eq!(code[..3], [true, false, true]);
+        assert_eq!(code[45..50], [false, true, false, true, false]);
+        assert_eq!(code[92..], [true, false, true]);
+        // 9 after a leading 4 is odd parity, 0001011
+        assert_eq!(code[3..10], [false, false, false, true, false, true, true]);
+        assert!(modules[33 + 95..].iter().all(|&bar| !bar));
+    }
+
+    #[test]
+    fn rejects_bad_codes() {
+        let mut reader = BarcodeReader::new();
+        assert_eq!(reader.scan("1234567"), Err(InvalidBarcode));
+        assert_eq!(reader.scan("4902425l23457"), Err(InvalidBarcode));
+        assert_eq!(reader.scan("96385074"), Ok(()));
+    }
+}
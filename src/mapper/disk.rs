@@ -0,0 +1,364 @@
+use std::fmt;
+
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+// Famicom Disk System disk images. Both file formats store the blocks on a
+// side back to back, without the gaps between them:
+//
+// - .fds: 65500 bytes per side, no CRCs, optionally preceded by a 16-byte
+//   fwNES header ("FDS\x1A" and the side count)
+// - .qd: 65536 bytes per side, each block followed by its CRC
+//
+// The drive needs to see what is physically on the disk, so sides are kept
+// as a raw stream with the lead-in gap, gap end marks, CRCs and inter-block
+// gaps put back in, and stripped again when the image is written out.
+
+const FWNES_HEADER: usize = 16;
+const FDS_SIDE_SIZE: usize = 65500;
+const QD_SIDE_SIZE: usize = 0x10000;
+
+const LEAD_IN_GAP: usize = 28300 / 8;
+const BLOCK_GAP: usize = 976 / 8;
+const GAP_END: u8 = 0x80;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiskFormat {
+    Fds { header: bool },
+    Qd,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DiskError {
+    UnknownFormat,
+    Truncated,
+    LayoutMismatch,
+}
+
+impl fmt::Display for DiskError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiskError::UnknownFormat => write!(f, "not an FDS or QD disk image"),
+            DiskError::Truncated => write!(f, "disk image ends partway through a side"),
+            DiskError::LayoutMismatch => write!(f, "disk image doesn't match the loaded disk"),
+        }
+    }
+}
+
+impl std::error::Error for DiskError {}
+
+pub struct DiskImage {
+    format: DiskFormat,
+    // The file as loaded. Rebuilt sides are laid over it, so the header and
+    // anything past the last block survive unchanged.
+    original: Vec<u8>,
+    sides: Vec<Vec<u8>>,
+}
+
+impl DiskImage {
+    pub fn parse(bytes: &[u8]) -> Result<Self, DiskError> {
+        let (format, data) = if bytes.starts_with(b"FDS\x1A") {
+            if bytes.len() < FWNES_HEADER {
+                return Err(DiskError::Truncated);
+            }
+            (DiskFormat::Fds { header: true }, &bytes[FWNES_HEADER..])
+        } else if bytes.starts_with(b"\x01*NINTENDO-HVC*") {
+            // The two headerless formats only differ in side size
+            let format = if bytes.len().is_multiple_of(QD_SIDE_SIZE) {
+                DiskFormat::Qd
+            } else {
+                DiskFormat::Fds { header: false }
+            };
+            (format, bytes)
+        } else {
+            return Err(DiskError::UnknownFormat);
+        };
+
+        let side_size = format.side_size();
+        if data.is_empty() || !data.len().is_multiple_of(side_size) {
+            return Err(DiskError::Truncated);
+        }
+
+        let sides = data
+            .chunks(side_size)
+            .map(|side| add_gaps(side, format == DiskFormat::Qd))
+            .collect();
+
+        Ok(DiskImage {
+            format,
+            original: bytes.to_vec(),
+            sides,
+        })
+    }
+
+    pub fn side_count(&self) -> usize {
+        self.sides.len()
+    }
+
+    // Raw stream the drive head passes over
+    pub fn side(&self, side: usize) -> &[u8] {
+        &self.sides[side]
+    }
+
+    pub fn side_mut(&mut self, side: usize) -> &mut [u8] {
+        &mut self.sides[side]
+    }
+
+    // Takes the sides from another copy of the same disk (the original with
+    // a save patch applied), keeping this one's original to diff against
+    pub fn load_sides(&mut self, bytes: &[u8]) -> Result<(), DiskError> {
+        let image = DiskImage::parse(bytes)?;
+        if image.format != self.format || image.sides.len() != self.sides.len() {
+            return Err(DiskError::LayoutMismatch);
+        }
+        self.sides = image.sides;
+        Ok(())
+    }
+
+    pub fn original(&self) -> &[u8] {
+        &self.original
+    }
+
+    // The image in the format it was loaded from, with whatever has been
+    // written to the disk since
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let side_size = self.format.side_size();
+        let start = match self.format {
+            DiskFormat::Fds { header: true } => FWNES_HEADER,
+            _ => 0,
+        };
+
+        let mut bytes = self.original.clone();
+        for (i, side) in self.sides.iter().enumerate() {
+            let mut data = strip_gaps(side, self.format == DiskFormat::Qd);
+            data.truncate(side_size);
+            let offset = start + i * side_size;
+            bytes[offset..offset + data.len()].copy_from_slice(&data);
+        }
+        bytes
+    }
+}
+
+impl DiskFormat {
+    fn side_size(self) -> usize {
+        match self {
+            DiskFormat::Fds { .. } => FDS_SIDE_SIZE,
+            DiskFormat::Qd => QD_SIDE_SIZE,
+        }
+    }
+}
+
+// Length of the block starting at data[0], not counting a CRC. File data
+// blocks take their size from the header block before them.
+fn block_len(data: &[u8], file_size: usize) -> Option<usize> {
+    match data.first()? {
+        1 => Some(56),
+        2 => Some(2),
+        3 => Some(16),
+        4 => Some(1 + file_size),
+        _ => None,
+    }
+}
+
+fn file_size(block: &[u8]) -> usize {
+    block[13] as usize | (block[14] as usize) << 8
+}
+
+// The CRC as the RAM adapter computes it: CRC-16 with polynomial $8408 over
+// the gap end mark and the block, flushed with two zero bytes
+pub fn update_crc(crc: &mut u16, data: u8) {
+    for bit in 0..8 {
+        let carry = *crc & 0x0001 != 0;
+        *crc >>= 1;
+        if carry {
+            *crc ^= 0x8408;
+        }
+        if data & (1 << bit) != 0 {
+            *crc ^= 0x8000;
+        }
+    }
+}
+
+fn block_crc(block: &[u8]) -> u16 {
+    let mut value = 0;
+    for &data in [GAP_END].iter().chain(block).chain(&[0x00, 0x00]) {
+        update_crc(&mut value, data);
+    }
+    value
+}
+
+fn add_gaps(side: &[u8], has_crc: bool) -> Vec<u8> {
+    let mut raw = vec![0x00; LEAD_IN_GAP];
+    let mut pos = 0;
+    let mut size = 0;
+
+    while let Some(len) = block_len(side.get(pos..).unwrap_or(&[]), size) {
+        let Some(block) = side.get(pos..pos + len) else {
+            break;
+        };
+        if block[0] == 3 {
+            size = file_size(block);
+        }
+        pos += len;
+
+        raw.push(GAP_END);
+        raw.extend_from_slice(block);
+        if has_crc {
+            raw.extend_from_slice(side.get(pos..pos + 2).unwrap_or(&[0x00, 0x00]));
+            pos += 2;
+        } else {
+            raw.extend_from_slice(&block_crc(block).to_le_bytes());
+        }
+        raw.extend_from_slice(&[0x00; BLOCK_GAP]);
+    }
+
+    // Leave the rest of the side blank for games to write files into
+    raw.resize(raw.len().max(LEAD_IN_GAP + FDS_SIDE_SIZE), 0x00);
+    raw
+}
+
+fn strip_gaps(raw: &[u8], keep_crc: bool) -> Vec<u8> {
+    let mut side = Vec::new();
+    let mut pos = 0;
+    let mut size = 0;
+
+    // Skip each gap up to its end mark
+    while let Some(mark) = raw[pos..].iter().position(|&b| b != 0x00) {
+        pos += mark;
+        if raw[pos] != GAP_END {
+            break;
+        }
+        pos += 1;
+
+        let Some(len) = block_len(&raw[pos..], size) else {
+            break;
+        };
+        let Some(block) = raw.get(pos..pos + len) else {
+            break;
+        };
+        if block[0] == 3 {
+            size = file_size(block);
+        }
+        side.extend_from_slice(block);
+        pos += len;
+
+        if keep_crc {
+            side.extend_from_slice(raw.get(pos..pos + 2).unwrap_or(&[0x00, 0x00]));
+        }
+        pos = (pos + 2).min(raw.len());
+    }
+
+    side
+}
+
+// Sides as written to so far; the original file can't change
+impl Snapshot for DiskImage {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u32(self.sides.len() as u32);
+        self.sides.iter().for_each(|side| w.bytes(side));
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        if r.u32()? as usize != self.sides.len() {
+            return Err(StateError::Invalid("disk side count"));
+        }
+        for side in self.sides.iter_mut() {
+            r.bytes_into(side, "disk side size")?;
+        }
+        Ok(())
+    }
+}
+
+// A .fds image with a fwNES header and one four byte file on each side: the
+// side number, then $11, $22 and $33
+#[cfg(test)]
+pub fn test_image(sides: u8) -> Vec<u8> {
+    let mut image = b"FDS\x1A".to_vec();
+    image.push(sides);
+    image.resize(FWNES_HEADER, 0x00);
+    for side in 0..sides {
+        let start = image.len();
+        image.push(0x01);
+        image.extend_from_slice(b"*NINTENDO-HVC*");
+        image.resize(start + 56, 0x00);
+        image.extend_from_slice(&[0x02, 0x01]);
+        image.extend_from_slice(&[0x03, 0x00, 0x00]);
+        image.extend_from_slice(b"TESTFILE");
+        image.extend_from_slice(&[0x00, 0x60, 0x04, 0x00, 0x00]);
+        image.extend_from_slice(&[0x04, side, 0x11, 0x22, 0x33]);
+        image.resize(start + FDS_SIDE_SIZE, 0x00);
+    }
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCKS: [usize; 4] = [56, 2, 16, 5];
+
+    #[test]
+    fn gaps_are_put_back_for_the_drive_and_stripped_for_the_file() {
+        let image = test_image(2);
+        let mut disk = DiskImage::parse(&image).unwrap();
+        assert_eq!(disk.side_count(), 2);
+        assert_eq!(disk.format, DiskFormat::Fds { header: true });
+
+        let side = &image[FWNES_HEADER + FDS_SIDE_SIZE..][..FDS_SIDE_SIZE];
+        let raw = disk.side(1);
+        assert_eq!(raw.len(), LEAD_IN_GAP + FDS_SIDE_SIZE);
+        assert!(raw[..LEAD_IN_GAP].iter().all(|&b| b == 0x00));
+
+        // Each block gets a gap end mark before it, and its CRC and a gap
+        // after
+        let (mut pos, mut offset) = (LEAD_IN_GAP, 0);
+        for len in BLOCKS {
+            let block = &side[offset..offset + len];
+            assert_eq!(raw[pos], GAP_END);
+            assert_eq!(raw[pos + 1..pos + 1 + len], *block);
+            assert_eq!(
+                raw[pos + 1 + len..pos + 3 + len],
+                block_crc(block).to_le_bytes()
+            );
+            pos += 3 + len;
+            assert!(raw[pos..pos + BLOCK_GAP].iter().all(|&b| b == 0x00));
+            pos += BLOCK_GAP;
+            offset += len;
+        }
+        assert!(raw[pos..].iter().all(|&b| b == 0x00));
+        assert_eq!(strip_gaps(raw, false), side[..offset]);
+        assert_eq!(disk.to_bytes(), image);
+
+        // Writes land back in the file at the same place
+        let data = raw
+            .windows(3)
+            .position(|w| w == [0x11, 0x22, 0x33])
+            .unwrap();
+        disk.side_mut(1)[data] = 0x99;
+        let written = disk.to_bytes();
+        let file = FWNES_HEADER + FDS_SIDE_SIZE + offset - 3;
+        assert_eq!(written[file], 0x99);
+        assert_eq!(written[..file], image[..file]);
+        assert_eq!(written[file + 1..], image[file + 1..]);
+    }
+
+    #[test]
+    fn qd_sides_keep_their_own_crcs() {
+        let fds = test_image(1);
+        let mut qd = Vec::new();
+        let mut offset = FWNES_HEADER;
+        for len in BLOCKS {
+            qd.extend_from_slice(&fds[offset..offset + len]);
+            qd.extend_from_slice(&[0xAB, 0xCD]);
+            offset += len;
+        }
+        let blocks = qd.len();
+        qd.resize(QD_SIDE_SIZE, 0x00);
+
+        let disk = DiskImage::parse(&qd).unwrap();
+        assert_eq!(disk.format, DiskFormat::Qd);
+        let raw = disk.side(0);
+        assert_eq!(raw[LEAD_IN_GAP + 1 + 56..][..2], [0xAB, 0xCD]);
+        assert_eq!(strip_gaps(raw, true), qd[..blocks]);
+        assert_eq!(disk.to_bytes(), qd);
+    }
+}
@@ -0,0 +1,277 @@
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+// Serial EEPROMs on Bandai boards, driven by the game bit-banging SCL/SDA
+// through a mapper register.
+//
+// The 24C02 speaks standard I2C: a device address byte, a word address and
+// then data, most significant bit first. The Xicor X24C01 predates that and
+// sends a 7-bit word address plus R/W straight after START, least
+// significant bit first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EepromKind {
+    X24C01,
+    X24C02,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    Idle,
+    DeviceAddress,
+    WordAddress,
+    Read,
+    Write,
+    SendAck,
+    WaitAck,
+}
+
+pub struct Eeprom {
+    kind: EepromKind,
+    data: Vec<u8>,
+
+    mode: Mode,
+    next_mode: Mode,
+    address: u8,
+    shift: u8,
+    bit: u8,
+
+    scl: bool,
+    sda: bool,
+    output: bool,
+}
+
+impl Eeprom {
+    pub fn new(kind: EepromKind) -> Self {
+        let size = match kind {
+            EepromKind::X24C01 => 128,
+            EepromKind::X24C02 => 256,
+        };
+
+        Eeprom {
+            kind,
+            data: vec![0xFF; size],
+            mode: Mode::Idle,
+            next_mode: Mode::Idle,
+            address: 0,
+            shift: 0,
+            bit: 0,
+            scl: false,
+            sda: false,
+            output: true,
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn load(&mut self, data: &[u8]) -> usize {
+        let len = data.len().min(self.data.len());
+        self.data[..len].copy_from_slice(&data[..len]);
+        len
+    }
+
+    // What the chip is driving onto SDA; high when it has let go of the line
+    pub fn output(&self) -> bool {
+        self.output
+    }
+
+    pub fn set_scl(&mut self, scl: bool) {
+        self.set_lines(scl, self.sda);
+    }
+
+    pub fn set_sda(&mut self, sda: bool) {
+        self.set_lines(self.scl, sda);
+    }
+
+    pub fn set_lines(&mut self, scl: bool, sda: bool) {
+        let (old_scl, old_sda) = (self.scl, self.sda);
+        self.scl = scl;
+        self.sda = sda;
+
+        if old_scl && scl {
+            // SDA changing while SCL is high is a START or STOP condition
+            if old_sda && !sda {
+                self.start();
+            } else if !old_sda && sda {
+                self.stop();
+            }
+        } else if !old_scl && scl {
+            self.clock_rising();
+        } else if old_scl && !scl {
+            self.clock_falling();
+        }
+    }
+
+    fn start(&mut self) {
+        self.mode = match self.kind {
+            EepromKind::X24C01 => Mode::WordAddress,
+            EepromKind::X24C02 => Mode::DeviceAddress,
+        };
+        self.shift = 0;
+        self.bit = 0;
+        self.output = true;
+    }
+
+    fn stop(&mut self) {
+        self.mode = Mode::Idle;
+        self.output = true;
+    }
+
+    fn lsb_first(&self) -> bool {
+        self.kind == EepromKind::X24C01
+    }
+
+    // The master drives SDA while SCL is low and the chip samples it on the
+    // rising edge
+    fn clock_rising(&mut self) {
+        match self.mode {
+            Mode::DeviceAddress | Mode::WordAddress | Mode::Write if self.bit < 8 => {
+                let bit = self.sda as u8;
+                if self.lsb_first() {
+                    self.shift |= bit << self.bit;
+                } else {
+                    self.shift = (self.shift << 1) | bit;
+                }
+                self.bit += 1;
+            }
+            Mode::Read => self.bit += 1,
+            // A high SDA here is the master's NACK, which ends a read
+            Mode::WaitAck => {
+                self.next_mode = if self.sda { Mode::Idle } else { Mode::Read };
+            }
+            _ => {}
+        }
+    }
+
+    // ...and the chip changes its own output while SCL is low
+    fn clock_falling(&mut self) {
+        match self.mode {
+            Mode::DeviceAddress | Mode::WordAddress | Mode::Write if self.bit == 8 => {
+                self.receive_byte(self.shift);
+            }
+            Mode::SendAck | Mode::WaitAck => {
+                self.output = true;
+                self.mode = self.next_mode;
+                self.shift = 0;
+                self.bit = 0;
+                if self.mode == Mode::Read {
+                    self.drive_read_bit();
+                }
+            }
+            Mode::Read => {
+                if self.bit == 8 {
+                    self.output = true;
+                    self.address = self.address.wrapping_add(1) & self.address_mask();
+                    self.mode = Mode::WaitAck;
+                } else {
+                    self.drive_read_bit();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn drive_read_bit(&mut self) {
+        let byte = self.data[self.address as usize];
+        let shift = if self.lsb_first() {
+            self.bit
+        } else {
+            7 - self.bit
+        };
+        self.output = (byte >> shift) & 1 != 0;
+    }
+
+    fn address_mask(&self) -> u8 {
+        (self.data.len() - 1) as u8
+    }
+
+    fn receive_byte(&mut self, byte: u8) {
+        self.next_mode = match self.mode {
+            Mode::DeviceAddress => {
+                if byte & 0xF0 != 0xA0 {
+                    // Not for us; stay off the bus until the next START
+                    self.mode = Mode::Idle;
+                    return;
+                }
+                if byte & 0x01 != 0 {
+                    Mode::Read
+                } else {
+                    Mode::WordAddress
+                }
+            }
+            Mode::WordAddress => match self.kind {
+                EepromKind::X24C01 => {
+                    self.address = byte & 0x7F;
+                    if byte & 0x80 != 0 {
+                        Mode::Read
+                    } else {
+                        Mode::Write
+                    }
+                }
+                EepromKind::X24C02 => {
+                    self.address = byte;
+                    Mode::Write
+                }
+            },
+            Mode::Write => {
+                self.data[self.address as usize] = byte;
+                // Sequential writes wrap within the page
+                let page = match self.kind {
+                    EepromKind::X24C01 => 0x03,
+                    EepromKind::X24C02 => 0x07,
+                };
+                self.address = (self.address & !page) | (self.address.wrapping_add(1) & page);
+                Mode::Write
+            }
+            _ => unreachable!(),
+        };
+
+        self.output = false;
+        self.mode = Mode::SendAck;
+    }
+}
+
+const MODES: [Mode; 7] = [
+    Mode::Idle,
+    Mode::DeviceAddress,
+    Mode::WordAddress,
+    Mode::Read,
+    Mode::Write,
+    Mode::SendAck,
+    Mode::WaitAck,
+];
+
+impl Snapshot for Eeprom {
+    fn save_state(&self, w: &mut StateWriter) {
+        let mode = |mode| MODES.iter().position(|&m| m == mode).unwrap_or(0) as u8;
+        w.bytes(&self.data);
+        w.u8(mode(self.mode));
+        w.u8(mode(self.next_mode));
+        w.u8(self.address);
+        w.u8(self.shift);
+        w.u8(self.bit);
+        w.bool(self.scl);
+        w.bool(self.sda);
+        w.bool(self.output);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.bytes_into(&mut self.data, "EEPROM size")?;
+        let mut mode = || {
+            let mode = r.u8()?;
+            MODES
+                .get(mode as usize)
+                .copied()
+                .ok_or(StateError::Invalid("EEPROM mode"))
+        };
+        self.mode = mode()?;
+        self.next_mode = mode()?;
+        self.address = r.u8()?;
+        self.shift = r.u8()?;
+        self.bit = r.u8()?;
+        self.scl = r.bool()?;
+        self.sda = r.bool()?;
+        self.output = r.bool()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,652 @@
+use crate::audio::{ExpansionChip, ExpansionOutput, FdsAudio};
+use crate::cartridge::Mirroring;
+use crate::mapper::disk::{self, DiskImage};
+use crate::mapper::{CartridgeMemory, Mapper};
+use crate::patch::ips;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+// Famicom Disk System RAM adapter: 32KB of PRG RAM at $6000-$DFFF, the BIOS
+// at $E000-$FFFF, 8KB of CHR RAM, a timer IRQ, the disk drive interface and
+// the wavetable sound channel.
+//
+// The drive streams one byte of the inserted side every 150 CPU cycles
+// (about 96.4 kbit/s) while the motor runs, raising the disk IRQ for each,
+// and stops at the end of the side until the BIOS rewinds it.
+pub struct Fds {
+    memory: CartridgeMemory,
+    disk: DiskImage,
+    side: Option<usize>,
+
+    disk_registers_enabled: bool,
+    sound_registers_enabled: bool,
+
+    irq_reload: u16,
+    irq_counter: u16,
+    irq_enabled: bool,
+    irq_repeat: bool,
+    timer_irq: bool,
+
+    motor_on: bool,
+    reset_transfer: bool,
+    read_mode: bool,
+    crc_control: bool,
+    transfer_enabled: bool,
+    disk_irq_enabled: bool,
+    disk_irq: bool,
+    mirroring: Mirroring,
+    ext_output: u8,
+
+    read_data: u8,
+    write_data: u8,
+    transfer_complete: bool,
+
+    position: usize,
+    delay: u32,
+    scanning: bool,
+    end_of_head: bool,
+    gap_ended: bool,
+    previous_crc_control: bool,
+    crc: u16,
+
+    audio: FdsAudio,
+}
+
+// Cycles from the motor starting to the head reaching the disk, and between
+// bytes after that
+const SPIN_UP_DELAY: u32 = 50000;
+const BYTE_DELAY: u32 = 150;
+
+impl Fds {
+    pub fn new(memory: CartridgeMemory, disk: DiskImage) -> Self {
+        Fds {
+            memory,
+            disk,
+            side: Some(0),
+            disk_registers_enabled: true,
+            sound_registers_enabled: true,
+            irq_reload: 0,
+            irq_counter: 0,
+            irq_enabled: false,
+            irq_repeat: false,
+            timer_irq: false,
+            motor_on: false,
+            reset_transfer: false,
+            read_mode: true,
+            crc_control: false,
+            transfer_enabled: false,
+            disk_irq_enabled: false,
+            disk_irq: false,
+            mirroring: Mirroring::Horizontal,
+            ext_output: 0,
+            read_data: 0,
+            write_data: 0,
+            transfer_complete: false,
+            position: 0,
+            delay: 0,
+            scanning: false,
+            end_of_head: true,
+            gap_ended: false,
+            previous_crc_control: false,
+            crc: 0,
+            audio: FdsAudio::new(),
+        }
+    }
+
+    pub fn side_count(&self) -> usize {
+        self.disk.side_count()
+    }
+
+    // Side currently in the drive, or None if it's empty
+    pub fn inserted_side(&self) -> Option<usize> {
+        self.side
+    }
+
+    // Side 0 is disk 1 side A, 1 is side B, 2 is disk 2 side A and so on.
+    // Games check for the disk being taken out before they accept a new one,
+    // so a host swapping sides should eject and wait a second or so first.
+    pub fn insert_side(&mut self, side: usize) {
+        if side < self.disk.side_count() {
+            self.side = Some(side);
+        }
+    }
+
+    pub fn eject(&mut self) {
+        self.side = None;
+    }
+
+    fn clock_timer(&mut self) {
+        if !self.irq_enabled {
+            return;
+        }
+
+        if self.irq_counter == 0 {
+            self.timer_irq = true;
+            self.irq_counter = self.irq_reload;
+            if !self.irq_repeat {
+                self.irq_enabled = false;
+            }
+        } else {
+            self.irq_counter -= 1;
+        }
+    }
+
+    fn clock_drive(&mut self) {
+        let Some(side) = self.side.filter(|_| self.motor_on) else {
+            self.end_of_head = true;
+            self.scanning = false;
+            return;
+        };
+
+        if self.reset_transfer && !self.scanning {
+            return;
+        }
+
+        // Rewinding to the start of the side takes a while before the first
+        // byte comes under the head
+        if self.end_of_head {
+            self.delay = SPIN_UP_DELAY;
+            self.end_of_head = false;
+            self.position = 0;
+            self.gap_ended = false;
+            return;
+        }
+
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+
+        self.scanning = true;
+        let mut irq = self.disk_irq_enabled;
+
+        if self.read_mode {
+            let data = self.disk.side(side)[self.position];
+            if !self.previous_crc_control {
+                disk::update_crc(&mut self.crc, data);
+            }
+
+            // Bytes only start being delivered after the gap end mark, which
+            // itself doesn't raise an IRQ
+            if !self.transfer_enabled {
+                self.gap_ended = false;
+                self.crc = 0;
+            } else if data != 0x00 && !self.gap_ended {
+                self.gap_ended = true;
+                irq = false;
+            }
+
+            if self.gap_ended {
+                self.transfer_complete = true;
+                self.read_data = data;
+                if irq {
+                    self.disk_irq = true;
+                }
+            }
+        } else {
+            let mut data = 0x00;
+            if !self.crc_control {
+                self.transfer_complete = true;
+                data = self.write_data;
+                if irq {
+                    self.disk_irq = true;
+                }
+            }
+            if !self.transfer_enabled {
+                data = 0x00;
+                self.crc = 0;
+            }
+
+            if !self.crc_control {
+                disk::update_crc(&mut self.crc, data);
+            } else {
+                // Shift the CRC out, low byte first
+                if !self.previous_crc_control {
+                    disk::update_crc(&mut self.crc, 0x00);
+                    disk::update_crc(&mut self.crc, 0x00);
+                }
+                data = self.crc as u8;
+                self.crc >>= 8;
+            }
+
+            // The write head sits a little behind the read head
+            if let Some(pos) = self.position.checked_sub(2) {
+                self.disk.side_mut(side)[pos] = data;
+            }
+            self.gap_ended = false;
+        }
+
+        self.previous_crc_control = self.crc_control;
+        self.position += 1;
+        if self.position >= self.disk.side(side).len() {
+            self.motor_on = false;
+        } else {
+            self.delay = BYTE_DELAY;
+        }
+    }
+
+    // Reading $4030 acknowledges both IRQs, and $4031 the disk one
+    fn read_register(&mut self, addr: u16) -> Option<u8> {
+        let data = self.peek_register(addr);
+        match addr {
+            0x4030 => {
+                self.transfer_complete = false;
+                self.timer_irq = false;
+                self.disk_irq = false;
+            }
+            0x4031 => {
+                self.transfer_complete = false;
+                self.disk_irq = false;
+            }
+            _ => {}
+        }
+        data
+    }
+
+    fn peek_register(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4030 => {
+                // CRC errors are never reported, as images converted from
+                // .fds only carry CRCs computed on load
+                let mut data = 0x00;
+                data |= self.timer_irq as u8;
+                data |= (self.transfer_complete as u8) << 1;
+                Some(data)
+            }
+            0x4031 => Some(self.read_data),
+            0x4032 => {
+                let inserted = self.side.is_some();
+                let mut data = 0x40;
+                data |= !inserted as u8;
+                data |= ((!inserted || !self.scanning) as u8) << 1;
+                data |= (!inserted as u8) << 2;
+                Some(data)
+            }
+            // Expansion port inputs aren't connected; bit 7 reports a good
+            // battery in the drive
+            0x4033 => Some(0x80),
+            _ => None,
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, data: u8) {
+        // Everything but the IRQ reload and the master enable ignores
+        // writes while the disk registers are disabled
+        if !self.disk_registers_enabled && !matches!(addr, 0x4020 | 0x4021 | 0x4023) {
+            return;
+        }
+
+        match addr {
+            0x4020 => self.irq_reload = (self.irq_reload & 0xFF00) | data as u16,
+            0x4021 => self.irq_reload = (self.irq_reload & 0x00FF) | ((data as u16) << 8),
+            0x4022 => {
+                self.irq_repeat = data & 0x01 != 0;
+                self.irq_enabled = data & 0x02 != 0;
+                if self.irq_enabled {
+                    self.irq_counter = self.irq_reload;
+                } else {
+                    self.timer_irq = false;
+                }
+            }
+            0x4023 => {
+                self.disk_registers_enabled = data & 0x01 != 0;
+                self.sound_registers_enabled = data & 0x02 != 0;
+                if !self.disk_registers_enabled {
+                    self.irq_enabled = false;
+                    self.timer_irq = false;
+                    self.disk_irq = false;
+                }
+            }
+            0x4024 => {
+                self.write_data = data;
+                self.transfer_complete = false;
+                self.disk_irq = false;
+            }
+            0x4025 => {
+                self.motor_on = data & 0x01 != 0;
+                self.reset_transfer = data & 0x02 != 0;
+                self.read_mode = data & 0x04 != 0;
+                self.mirroring = if data & 0x08 != 0 {
+                    Mirroring::Horizontal
+                } else {
+                    Mirroring::Vertical
+                };
+                self.crc_control = data & 0x10 != 0;
+                self.transfer_enabled = data & 0x40 != 0;
+                self.disk_irq_enabled = data & 0x80 != 0;
+                self.disk_irq = false;
+            }
+            0x4026 => self.ext_output = data,
+            _ => {}
+        }
+    }
+}
+
+impl Mapper for Fds {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4030..=0x4033 => self.read_register(addr),
+            _ => self.cpu_peek(addr),
+        }
+    }
+
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4030..=0x4033 => self.peek_register(addr),
+            0x4040..=0x4097 => self.audio.read(addr),
+            0x6000..=0xDFFF => self.memory.read_prg_ram(0, 0x8000, addr - 0x6000),
+            0xE000..=0xFFFF => Some(self.memory.read_prg(0, 0x2000, addr)),
+            _ => None,
+        }
+    }
+
+    // The BIOS is the only PRG ROM; disk contents load into RAM
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0xE000..=0xFFFF => self.memory.prg_offset(0, 0x2000, addr),
+            _ => None,
+        }
+    }
+
+    fn prg_ram_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x6000..=0xDFFF => self.memory.prg_ram_offset(0, 0x8000, addr - 0x6000),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            0x4020..=0x4026 => {
+                self.write_register(addr, data);
+                true
+            }
+            0x4040..=0x4097 => {
+                if self.sound_registers_enabled {
+                    self.audio.write(addr, data);
+                }
+                true
+            }
+            0x6000..=0xDFFF => self.memory.write_prg_ram(0, 0x8000, addr - 0x6000, data),
+            _ => false,
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x0000..=0x1FFF => Some(self.memory.read_chr(0, 0x2000, addr)),
+            _ => None,
+        }
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            0x0000..=0x1FFF => {
+                self.memory.write_chr(0, 0x2000, addr, data);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn memory(&self) -> &CartridgeMemory {
+        &self.memory
+    }
+
+    fn memory_mut(&mut self) -> &mut CartridgeMemory {
+        &mut self.memory
+    }
+
+    fn has_nonvolatile_storage(&self) -> bool {
+        true
+    }
+
+    // What the game has written to the disk, as an IPS patch against the
+    // image it was loaded from so that file is never modified
+    fn save_data(&self) -> Vec<u8> {
+        ips::create(self.disk.original(), &self.disk.to_bytes())
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        let patched = match ips::apply(self.disk.original(), data) {
+            Ok(patched) => patched,
+            Err(e) => {
+                eprintln!("ignoring disk save: {e}");
+                return;
+            }
+        };
+        if let Err(e) = self.disk.load_sides(&patched) {
+            eprintln!("ignoring disk save: {e}");
+        }
+    }
+
+    fn clock(&mut self) {
+        self.clock_timer();
+        self.clock_drive();
+        self.audio.clock();
+    }
+
+    fn irq_state(&self) -> bool {
+        self.timer_irq || self.disk_irq
+    }
+
+    fn expansion_audio(&self) -> ExpansionOutput {
+        ExpansionOutput::single(ExpansionChip::Fds, self.audio.output())
+    }
+
+    fn disk_drive(&mut self) -> Option<&mut Fds> {
+        Some(self)
+    }
+}
+
+impl Snapshot for Fds {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.memory.save_state(w);
+        self.disk.save_state(w);
+        w.u32(self.side.map_or(u32::MAX, |side| side as u32));
+
+        w.bool(self.disk_registers_enabled);
+        w.bool(self.sound_registers_enabled);
+
+        w.u16(self.irq_reload);
+        w.u16(self.irq_counter);
+        w.bool(self.irq_enabled);
+        w.bool(self.irq_repeat);
+        w.bool(self.timer_irq);
+
+        w.bool(self.motor_on);
+        w.bool(self.reset_transfer);
+        w.bool(self.read_mode);
+        w.bool(self.crc_control);
+        w.bool(self.transfer_enabled);
+        w.bool(self.disk_irq_enabled);
+        w.bool(self.disk_irq);
+        self.mirroring.save_state(w);
+        w.u8(self.ext_output);
+
+        w.u8(self.read_data);
+        w.u8(self.write_data);
+        w.bool(self.transfer_complete);
+
+        w.u64(self.position as u64);
+        w.u32(self.delay);
+        w.bool(self.scanning);
+        w.bool(self.end_of_head);
+        w.bool(self.gap_ended);
+        w.bool(self.previous_crc_control);
+        w.u16(self.crc);
+
+        self.audio.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.memory.load_state(r)?;
+        self.disk.load_state(r)?;
+        self.side = match r.u32()? {
+            u32::MAX => None,
+            side if (side as usize) < self.disk.side_count() => Some(side as usize),
+            _ => return Err(StateError::Invalid("disk side")),
+        };
+
+        self.disk_registers_enabled = r.bool()?;
+        self.sound_registers_enabled = r.bool()?;
+
+        self.irq_reload = r.u16()?;
+        self.irq_counter = r.u16()?;
+        self.irq_enabled = r.bool()?;
+        self.irq_repeat = r.bool()?;
+        self.timer_irq = r.bool()?;
+
+        self.motor_on = r.bool()?;
+        self.reset_transfer = r.bool()?;
+        self.read_mode = r.bool()?;
+        self.crc_control = r.bool()?;
+        self.transfer_enabled = r.bool()?;
+        self.disk_irq_enabled = r.bool()?;
+        self.disk_irq = r.bool()?;
+        self.mirroring.load_state(r)?;
+        self.ext_output = r.u8()?;
+
+        self.read_data = r.u8()?;
+        self.write_data = r.u8()?;
+        self.transfer_complete = r.bool()?;
+
+        self.position = r.u64()? as usize;
+        if let Some(side) = self.side
+            && self.position > self.disk.side(side).len()
+        {
+            return Err(StateError::Invalid("disk position"));
+        }
+        self.delay = r.u32()?;
+        self.scanning = r.bool()?;
+        self.end_of_head = r.bool()?;
+        self.gap_ended = r.bool()?;
+        self.previous_crc_control = r.bool()?;
+        self.crc = r.u16()?;
+
+        self.audio.load_state(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::Cartridge;
+    use crate::mapper::disk::test_image;
+    use std::fs;
+
+    fn cartridge() -> Cartridge {
+        Cartridge::from_fds_bytes(&test_image(2), vec![0x00; 0x2000]).unwrap()
+    }
+
+    // Clocks until the IRQ goes up, returning how many clocks that took
+    fn clock_until_irq(cart: &mut Cartridge, limit: u32) -> Option<u32> {
+        (1..=limit).find(|_| {
+            cart.clock();
+            cart.irq_state()
+        })
+    }
+
+    #[test]
+    fn timer_irq_one_shot_and_repeating() {
+        let mut cart = cartridge();
+        cart.cpu_write(0x4020, 0x03);
+        cart.cpu_write(0x4021, 0x00);
+        cart.cpu_write(0x4022, 0x02);
+        assert_eq!(clock_until_irq(&mut cart, 100), Some(4));
+        assert_eq!(cart.cpu_read(0x4030).map(|data| data & 0x01), Some(0x01));
+        assert!(!cart.irq_state());
+        assert_eq!(clock_until_irq(&mut cart, 100), None);
+
+        // Repeating, it reloads and counts down again
+        cart.cpu_write(0x4022, 0x03);
+        for _ in 0..3 {
+            assert_eq!(clock_until_irq(&mut cart, 100), Some(4));
+            cart.cpu_read(0x4030);
+        }
+
+        // Disabling the timer acknowledges it
+        clock_until_irq(&mut cart, 100);
+        cart.cpu_write(0x4022, 0x00);
+        assert!(!cart.irq_state());
+        assert_eq!(clock_until_irq(&mut cart, 100), None);
+    }
+
+    #[test]
+    fn reads_the_disk_through_4031() {
+        let mut cart = cartridge();
+        // Motor on, reading, transfers and their IRQ enabled
+        cart.cpu_write(0x4025, 0xC5);
+
+        // The gap end mark is read without an IRQ, so the first one is for
+        // the block code that follows it
+        let limit = SPIN_UP_DELAY + 4000 * (BYTE_DELAY + 1);
+        assert!(clock_until_irq(&mut cart, limit).is_some());
+        assert_eq!(cart.cpu_peek(0x4030).map(|data| data & 0x02), Some(0x02));
+        assert_eq!(cart.cpu_read(0x4031), Some(0x01));
+        assert!(!cart.irq_state());
+        assert_eq!(cart.cpu_peek(0x4030).map(|data| data & 0x02), Some(0x00));
+
+        for &expected in b"*NINTENDO" {
+            assert!(clock_until_irq(&mut cart, BYTE_DELAY + 1).is_some());
+            assert_eq!(cart.cpu_read(0x4031), Some(expected));
+        }
+    }
+
+    #[test]
+    fn states_past_the_end_of_the_side_are_refused() {
+        let mut cart = cartridge();
+        let fds = cart.disk_drive().unwrap();
+        let end = fds.disk.side(0).len();
+
+        for (position, ok) in [(end, true), (end + 1, false)] {
+            fds.position = position;
+            let mut w = StateWriter::bare();
+            fds.save_state(&mut w);
+            let data = w.finish();
+
+            let mut other = cartridge();
+            let result = other
+                .disk_drive()
+                .unwrap()
+                .load_state(&mut StateReader::new(&data));
+            if ok {
+                assert!(result.is_ok());
+            } else {
+                assert!(matches!(result, Err(StateError::Invalid("disk position"))));
+            }
+        }
+    }
+
+    #[test]
+    fn disk_writes_are_saved_as_an_ips_patch_beside_the_image() {
+        let dir = std::env::temp_dir().join(format!("nes-rs-fds-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let image = dir.join("game.fds");
+        let bios = dir.join("disksys.rom");
+        fs::write(&image, test_image(2)).unwrap();
+        fs::write(&bios, [0x00; 0x2000]).unwrap();
+
+        let mut cart = Cartridge::load_fds(&image, &bios).unwrap();
+        let fds = cart.disk_drive().unwrap();
+        let raw = fds.disk.side(1);
+        let data = raw
+            .windows(3)
+            .position(|w| w == [0x11, 0x22, 0x33])
+            .unwrap();
+        fds.disk.side_mut(1)[data] = 0x99;
+        let written = fds.disk.to_bytes();
+        cart.flush_save().unwrap();
+        drop(cart);
+
+        assert!(dir.join("game.ips").is_file());
+        assert_eq!(fs::read(&image).unwrap(), test_image(2));
+        let mut cart = Cartridge::load_fds(&image, &bios).unwrap();
+        assert_eq!(cart.disk_drive().unwrap().disk.to_bytes(), written);
+        drop(cart);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,98 @@
+use crate::cartridge::Mirroring;
+use crate::mapper::{CartridgeMemory, Mapper};
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+// NROM: 16KB or 32KB of PRG (16KB mirrored), 8KB of CHR, no registers
+pub struct Mapper000 {
+    memory: CartridgeMemory,
+    mirroring: Mirroring,
+}
+
+impl Mapper000 {
+    pub fn new(memory: CartridgeMemory, mirroring: Mirroring) -> Self {
+        Mapper000 { memory, mirroring }
+    }
+}
+
+impl Mapper for Mapper000 {
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF => self.memory.read_prg_ram(0, 0x2000, addr),
+            0x8000..=0xFFFF => {
+                let bank = ((addr - 0x8000) / 0x4000) as usize;
+                Some(self.memory.read_prg(bank, 0x4000, addr))
+            }
+            _ => None,
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x8000..=0xFFFF => {
+                let bank = ((addr - 0x8000) / 0x4000) as usize;
+                self.memory.prg_offset(bank, 0x4000, addr)
+            }
+            _ => None,
+        }
+    }
+
+    fn prg_ram_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x6000..=0x7FFF => self.memory.prg_ram_offset(0, 0x2000, addr),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            0x6000..=0x7FFF => self.memory.write_prg_ram(0, 0x2000, addr, data),
+            _ => false,
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x0000..=0x1FFF => Some(self.memory.read_chr(0, 0x2000, addr)),
+            _ => None,
+        }
+    }
+
+    fn chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x0000..=0x1FFF => self.memory.chr_offset(0, 0x2000, addr),
+            _ => None,
+        }
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            0x0000..=0x1FFF => {
+                self.memory.write_chr(0, 0x2000, addr, data);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn memory(&self) -> &CartridgeMemory {
+        &self.memory
+    }
+
+    fn memory_mut(&mut self) -> &mut CartridgeMemory {
+        &mut self.memory
+    }
+}
+
+impl Snapshot for Mapper000 {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.memory.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.memory.load_state(r)
+    }
+}
@@ -0,0 +1,244 @@
+use crate::cartridge::Mirroring;
+use crate::mapper::{CartridgeMemory, Mapper};
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+// MMC2 (PxROM): one switchable 8KB PRG bank at $8000 with the rest fixed to
+// the last three, and two 4KB CHR windows that each switch between a pair of
+// banks depending on whether tile $FD or $FE was fetched last.
+pub struct Mapper009 {
+    memory: CartridgeMemory,
+    prg_bank: u8,
+    latches: ChrLatches,
+    mirroring: Mirroring,
+}
+
+impl Mapper009 {
+    pub fn new(memory: CartridgeMemory) -> Self {
+        Mapper009 {
+            memory,
+            prg_bank: 0,
+            latches: ChrLatches::new(),
+            mirroring: Mirroring::Vertical,
+        }
+    }
+}
+
+impl Mapper for Mapper009 {
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF => self.memory.read_prg_ram(0, 0x2000, addr),
+            0x8000..=0x9FFF => Some(self.memory.read_prg(self.prg_bank as usize, 0x2000, addr)),
+            0xA000..=0xFFFF => {
+                let banks = self.memory.prg_banks(0x2000);
+                let bank = banks.saturating_sub(4) + ((addr - 0x8000) / 0x2000) as usize;
+                Some(self.memory.read_prg(bank, 0x2000, addr))
+            }
+            _ => None,
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x8000..=0x9FFF => self.memory.prg_offset(self.prg_bank as usize, 0x2000, addr),
+            0xA000..=0xFFFF => {
+                let banks = self.memory.prg_banks(0x2000);
+                let bank = banks.saturating_sub(4) + ((addr - 0x8000) / 0x2000) as usize;
+                self.memory.prg_offset(bank, 0x2000, addr)
+            }
+            _ => None,
+        }
+    }
+
+    fn prg_ram_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x6000..=0x7FFF => self.memory.prg_ram_offset(0, 0x2000, addr),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            0x6000..=0x7FFF => self.memory.write_prg_ram(0, 0x2000, addr, data),
+            0xA000..=0xAFFF => {
+                self.prg_bank = data & 0x0F;
+                true
+            }
+            0xB000..=0xEFFF => {
+                self.latches.write_bank(addr, data);
+                true
+            }
+            0xF000..=0xFFFF => {
+                self.mirroring = mirroring(data);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x0000..=0x1FFF => Some(self.memory.read_chr(self.latches.bank(addr), 0x1000, addr)),
+            _ => None,
+        }
+    }
+
+    fn chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x0000..=0x1FFF => self
+                .memory
+                .chr_offset(self.latches.bank(addr), 0x1000, addr),
+            _ => None,
+        }
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            0x0000..=0x1FFF => {
+                self.memory
+                    .write_chr(self.latches.bank(addr), 0x1000, addr, data);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn on_ppu_read(&mut self, addr: u16) {
+        // MMC2 only decodes the exact address for the left pattern table
+        match addr {
+            0x0FD8 | 0x0FE8 | 0x1FD8..=0x1FDF | 0x1FE8..=0x1FEF => self.latches.update(addr),
+            _ => {}
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn memory(&self) -> &CartridgeMemory {
+        &self.memory
+    }
+
+    fn memory_mut(&mut self) -> &mut CartridgeMemory {
+        &mut self.memory
+    }
+}
+
+pub(super) fn mirroring(data: u8) -> Mirroring {
+    if data & 0x01 == 0 {
+        Mirroring::Vertical
+    } else {
+        Mirroring::Horizontal
+    }
+}
+
+// The $FD/$FE latch pair shared by MMC2 and MMC4. Registers $B000-$E000 hold
+// the FD and FE bank for each half of the pattern tables.
+pub(super) struct ChrLatches {
+    banks: [[u8; 2]; 2],
+    latch: [usize; 2],
+}
+
+impl ChrLatches {
+    pub(super) fn new() -> Self {
+        ChrLatches {
+            banks: [[0; 2]; 2],
+            latch: [1, 1],
+        }
+    }
+
+    pub(super) fn write_bank(&mut self, addr: u16, data: u8) {
+        let reg = ((addr - 0xB000) / 0x1000) as usize;
+        self.banks[reg >> 1][reg & 1] = data & 0x1F;
+    }
+
+    pub(super) fn bank(&self, addr: u16) -> usize {
+        let half = (addr >> 12) as usize & 1;
+        self.banks[half][self.latch[half]] as usize
+    }
+
+    // The latch flips after the fetch of the tile's upper plane has been
+    // served, so the $FD/$FE tile itself still comes from the old bank
+    pub(super) fn update(&mut self, addr: u16) {
+        let half = (addr >> 12) as usize & 1;
+        match addr & 0x0FF0 {
+            0x0FD0 => self.latch[half] = 0,
+            0x0FE0 => self.latch[half] = 1,
+            _ => {}
+        }
+    }
+}
+
+impl Snapshot for Mapper009 {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.memory.save_state(w);
+        w.u8(self.prg_bank);
+        self.latches.save_state(w);
+        self.mirroring.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.memory.load_state(r)?;
+        self.prg_bank = r.u8()?;
+        self.latches.load_state(r)?;
+        self.mirroring.load_state(r)
+    }
+}
+
+impl Snapshot for ChrLatches {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.banks.iter().flatten().for_each(|&bank| w.u8(bank));
+        self.latch.iter().for_each(|&latch| w.u8(latch as u8));
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        for bank in self.banks.iter_mut().flatten() {
+            *bank = r.u8()?;
+        }
+        for latch in self.latch.iter_mut() {
+            *latch = (r.u8()? & 1) as usize;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cartridge::Cartridge;
+
+    // An MMC2 board with each 4KB CHR bank filled with its own number
+    fn mmc2() -> Cartridge {
+        let mut bytes = b"NES\x1A\x02\x04\x90".to_vec();
+        bytes.resize(16 + 0x8000, 0);
+        for bank in 0..8 {
+            bytes.extend([bank; 0x1000]);
+        }
+        Cartridge::from_bytes(&bytes).unwrap()
+    }
+
+    fn fetch(cart: &mut Cartridge, addrs: &[u16]) -> Vec<u8> {
+        addrs
+            .iter()
+            .map(|&addr| cart.ppu_read(addr).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn latch_switches_after_the_fetch() {
+        let mut cart = mmc2();
+        for (addr, bank) in [(0xB000, 1), (0xC000, 2), (0xD000, 3), (0xE000, 4)] {
+            cart.cpu_write(addr, bank);
+        }
+
+        // The $FD tile is still drawn from the $FE bank the latch starts on
+        assert_eq!(fetch(&mut cart, &[0x0000, 0x0FD8, 0x0000]), [2, 2, 1]);
+        assert_eq!(fetch(&mut cart, &[0x0FE8, 0x0000]), [1, 2]);
+        // The left half only decodes the exact address
+        assert_eq!(fetch(&mut cart, &[0x0FD9, 0x0FDF, 0x0000]), [2, 2, 2]);
+
+        assert_eq!(fetch(&mut cart, &[0x1000, 0x1FDF, 0x1000]), [4, 4, 3]);
+        assert_eq!(fetch(&mut cart, &[0x1FE8, 0x1000]), [3, 4]);
+        assert_eq!(fetch(&mut cart, &[0x1FD8, 0x1FEF, 0x1000]), [4, 3, 4]);
+        // Each half keeps its own latch
+        assert_eq!(fetch(&mut cart, &[0x0000]), [2]);
+    }
+}
@@ -0,0 +1,172 @@
+use crate::cartridge::Mirroring;
+use crate::mapper::mapper_009::{self, ChrLatches};
+use crate::mapper::{CartridgeMemory, Mapper};
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+// MMC4 (FxROM): MMC2's CHR latches with 16KB PRG banking and PRG RAM
+pub struct Mapper010 {
+    memory: CartridgeMemory,
+    prg_bank: u8,
+    latches: ChrLatches,
+    mirroring: Mirroring,
+}
+
+impl Mapper010 {
+    pub fn new(memory: CartridgeMemory) -> Self {
+        Mapper010 {
+            memory,
+            prg_bank: 0,
+            latches: ChrLatches::new(),
+            mirroring: Mirroring::Vertical,
+        }
+    }
+}
+
+impl Mapper for Mapper010 {
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF => self.memory.read_prg_ram(0, 0x2000, addr),
+            0x8000..=0xBFFF => Some(self.memory.read_prg(self.prg_bank as usize, 0x4000, addr)),
+            0xC000..=0xFFFF => {
+                let last = self.memory.prg_banks(0x4000) - 1;
+                Some(self.memory.read_prg(last, 0x4000, addr))
+            }
+            _ => None,
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x8000..=0xBFFF => self.memory.prg_offset(self.prg_bank as usize, 0x4000, addr),
+            0xC000..=0xFFFF => {
+                let last = self.memory.prg_banks(0x4000) - 1;
+                self.memory.prg_offset(last, 0x4000, addr)
+            }
+            _ => None,
+        }
+    }
+
+    fn prg_ram_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x6000..=0x7FFF => self.memory.prg_ram_offset(0, 0x2000, addr),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            0x6000..=0x7FFF => self.memory.write_prg_ram(0, 0x2000, addr, data),
+            0xA000..=0xAFFF => {
+                self.prg_bank = data & 0x0F;
+                true
+            }
+            0xB000..=0xEFFF => {
+                self.latches.write_bank(addr, data);
+                true
+            }
+            0xF000..=0xFFFF => {
+                self.mirroring = mapper_009::mirroring(data);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x0000..=0x1FFF => Some(self.memory.read_chr(self.latches.bank(addr), 0x1000, addr)),
+            _ => None,
+        }
+    }
+
+    fn chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x0000..=0x1FFF => self
+                .memory
+                .chr_offset(self.latches.bank(addr), 0x1000, addr),
+            _ => None,
+        }
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            0x0000..=0x1FFF => {
+                self.memory
+                    .write_chr(self.latches.bank(addr), 0x1000, addr, data);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn on_ppu_read(&mut self, addr: u16) {
+        match addr & 0x0FFF {
+            0x0FD8..=0x0FDF | 0x0FE8..=0x0FEF => self.latches.update(addr),
+            _ => {}
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn memory(&self) -> &CartridgeMemory {
+        &self.memory
+    }
+
+    fn memory_mut(&mut self) -> &mut CartridgeMemory {
+        &mut self.memory
+    }
+}
+
+impl Snapshot for Mapper010 {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.memory.save_state(w);
+        w.u8(self.prg_bank);
+        self.latches.save_state(w);
+        self.mirroring.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.memory.load_state(r)?;
+        self.prg_bank = r.u8()?;
+        self.latches.load_state(r)?;
+        self.mirroring.load_state(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cartridge::Cartridge;
+
+    // An MMC4 board with each 4KB CHR bank filled with its own number
+    fn mmc4() -> Cartridge {
+        let mut bytes = b"NES\x1A\x02\x04\xA0".to_vec();
+        bytes.resize(16 + 0x8000, 0);
+        for bank in 0..8 {
+            bytes.extend([bank; 0x1000]);
+        }
+        Cartridge::from_bytes(&bytes).unwrap()
+    }
+
+    fn fetch(cart: &mut Cartridge, addrs: &[u16]) -> Vec<u8> {
+        addrs
+            .iter()
+            .map(|&addr| cart.ppu_read(addr).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn both_halves_latch_on_the_whole_range() {
+        let mut cart = mmc4();
+        for (addr, bank) in [(0xB000, 1), (0xC000, 2), (0xD000, 3), (0xE000, 4)] {
+            cart.cpu_write(addr, bank);
+        }
+
+        assert_eq!(fetch(&mut cart, &[0x0FDB, 0x0000]), [2, 1]);
+        assert_eq!(fetch(&mut cart, &[0x0FEF, 0x0000]), [1, 2]);
+        assert_eq!(fetch(&mut cart, &[0x1FD8, 0x1000]), [4, 3]);
+        assert_eq!(fetch(&mut cart, &[0x1FEA, 0x1000]), [3, 4]);
+        assert_eq!(fetch(&mut cart, &[0x0FF8, 0x0FC8, 0x0000]), [2, 2, 2]);
+    }
+}
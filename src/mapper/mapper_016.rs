@@ -0,0 +1,604 @@
+use crate::cartridge::{Header, Mirroring};
+use crate::mapper::datach::BarcodeReader;
+use crate::mapper::eeprom::{Eeprom, EepromKind};
+use crate::mapper::{CartridgeMemory, Mapper};
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+// The Bandai FCG family and its variants, which share one register layout
+// (selected by the low four address bits) but differ in where the
+// registers are decoded, how the IRQ counter is loaded and what save
+// storage hangs off register $D.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BandaiBoard {
+    // FCG-1/2: registers at $6000-$7FFF, counter written directly
+    Fcg,
+    // LZ93D50: registers at $8000-$FFFF, counter reloaded from a latch,
+    // optionally with a serial EEPROM
+    Lz93d50(Option<EepromKind>),
+    // Mapper 16 from an iNES 1.0 header: decode both ranges, as the board
+    // can't be told apart
+    Unknown(Option<EepromKind>),
+    // Mapper 153: LZ93D50 with 8KB of battery SRAM and a 512KB outer bank
+    Lz93d50Sram,
+    // Mapper 157: LZ93D50 in the Datach base unit, with a 24C02 there, a
+    // 24C01 in some game cartridges and the barcode reader
+    Datach,
+}
+
+impl BandaiBoard {
+    pub fn for_mapper(header: &Header) -> Self {
+        match header.mapper {
+            153 => BandaiBoard::Lz93d50Sram,
+            157 => BandaiBoard::Datach,
+            159 => BandaiBoard::Lz93d50(Some(EepromKind::X24C01)),
+            _ => {
+                let eeprom = match header.prg_nvram_size {
+                    0 if header.nes2 => None,
+                    128 => Some(EepromKind::X24C01),
+                    _ if header.battery || header.nes2 => Some(EepromKind::X24C02),
+                    _ => None,
+                };
+                match header.submapper {
+                    4 => BandaiBoard::Fcg,
+                    5 => BandaiBoard::Lz93d50(eeprom),
+                    _ => BandaiBoard::Unknown(eeprom),
+                }
+            }
+        }
+    }
+
+    fn registers_at_6000(self) -> bool {
+        matches!(self, BandaiBoard::Fcg | BandaiBoard::Unknown(_))
+    }
+
+    fn registers_at_8000(self) -> bool {
+        !matches!(self, BandaiBoard::Fcg)
+    }
+
+    fn latched_irq(self) -> bool {
+        !matches!(self, BandaiBoard::Fcg | BandaiBoard::Unknown(_))
+    }
+}
+
+pub struct Mapper016 {
+    board: BandaiBoard,
+    memory: CartridgeMemory,
+
+    chr_banks: [u8; 8],
+    prg_bank: u8,
+    outer_bank: u8,
+    prg_ram_enabled: bool,
+    mirroring: Mirroring,
+
+    irq_enabled: bool,
+    irq_counter: u16,
+    irq_latch: u16,
+    irq_pending: bool,
+
+    eeprom: Option<Eeprom>,
+    // The Datach's second chip lives in the game cartridge, sharing SDA with
+    // the base unit's but clocked from bit 3 of the CHR registers
+    game_eeprom: Option<Eeprom>,
+    barcode: Option<BarcodeReader>,
+}
+
+impl Mapper016 {
+    pub fn new(memory: CartridgeMemory, board: BandaiBoard) -> Self {
+        let (eeprom, game_eeprom) = match board {
+            BandaiBoard::Lz93d50(kind) | BandaiBoard::Unknown(kind) => {
+                (kind.map(Eeprom::new), None)
+            }
+            BandaiBoard::Datach => (
+                Some(Eeprom::new(EepromKind::X24C02)),
+                Some(Eeprom::new(EepromKind::X24C01)),
+            ),
+            BandaiBoard::Fcg | BandaiBoard::Lz93d50Sram => (None, None),
+        };
+
+        // Only mapper 153 has PRG RAM; elsewhere the header's NVRAM size
+        // describes the EEPROM
+        let mut memory = memory;
+        if board != BandaiBoard::Lz93d50Sram {
+            memory.prg_ram.clear();
+        }
+
+        Mapper016 {
+            board,
+            memory,
+            chr_banks: [0; 8],
+            prg_bank: 0,
+            outer_bank: 0,
+            prg_ram_enabled: false,
+            mirroring: Mirroring::Vertical,
+            irq_enabled: false,
+            irq_counter: 0,
+            irq_latch: 0,
+            irq_pending: false,
+            eeprom,
+            game_eeprom,
+            barcode: (board == BandaiBoard::Datach).then(BarcodeReader::new),
+        }
+    }
+
+    fn write_register(&mut self, reg: u16, data: u8) {
+        match reg {
+            0x0..=0x7 => {
+                self.chr_banks[reg as usize] = data;
+                match self.board {
+                    BandaiBoard::Lz93d50Sram => self.outer_bank = data & 0x01,
+                    BandaiBoard::Datach => {
+                        if let Some(eeprom) = self.game_eeprom.as_mut() {
+                            eeprom.set_scl(data & 0x08 != 0);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            0x8 => self.prg_bank = data & 0x0F,
+            0x9 => {
+                self.mirroring = match data & 0x03 {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::OneScreenLower,
+                    _ => Mirroring::OneScreenUpper,
+                }
+            }
+            0xA => {
+                self.irq_enabled = data & 0x01 != 0;
+                if self.board.latched_irq() {
+                    self.irq_counter = self.irq_latch;
+                }
+                self.irq_pending = false;
+            }
+            0xB | 0xC => {
+                let shift = if reg == 0xB { 0 } else { 8 };
+                let target = if self.board.latched_irq() {
+                    &mut self.irq_latch
+                } else {
+                    &mut self.irq_counter
+                };
+                *target = (*target & !(0xFF << shift)) | ((data as u16) << shift);
+            }
+            0xD => {
+                if self.board == BandaiBoard::Lz93d50Sram {
+                    self.prg_ram_enabled = data & 0x20 != 0;
+                }
+                let scl = data & 0x20 != 0;
+                let sda = data & 0x40 != 0;
+                if let Some(eeprom) = self.eeprom.as_mut() {
+                    eeprom.set_lines(scl, sda);
+                }
+                if let Some(eeprom) = self.game_eeprom.as_mut() {
+                    eeprom.set_sda(sda);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn eeprom_output(&self) -> Option<u8> {
+        if self.eeprom.is_none() && self.game_eeprom.is_none() {
+            return None;
+        }
+        let high = self.eeprom.as_ref().is_none_or(Eeprom::output)
+            && self.game_eeprom.as_ref().is_none_or(Eeprom::output);
+        Some(if high { 0x10 } else { 0x00 })
+    }
+
+    // Mapper 153 splits its 512KB of PRG into two 256KB halves
+    fn prg_bank_base(&self) -> usize {
+        (self.outer_bank as usize) << 4
+    }
+
+    fn chr_banked(&self) -> bool {
+        !matches!(self.board, BandaiBoard::Lz93d50Sram | BandaiBoard::Datach)
+    }
+}
+
+impl Mapper for Mapper016 {
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF => {
+                if self.board == BandaiBoard::Lz93d50Sram {
+                    if self.prg_ram_enabled {
+                        self.memory.read_prg_ram(0, 0x2000, addr)
+                    } else {
+                        None
+                    }
+                } else {
+                    let barcode = self.barcode.as_ref().map_or(0x00, BarcodeReader::output);
+                    self.eeprom_output().map(|eeprom| eeprom | barcode)
+                }
+            }
+            0x8000..=0xBFFF => {
+                let bank = self.prg_bank_base() | self.prg_bank as usize;
+                Some(self.memory.read_prg(bank, 0x4000, addr))
+            }
+            0xC000..=0xFFFF => {
+                let bank = self.prg_bank_base() | 0x0F;
+                Some(self.memory.read_prg(bank, 0x4000, addr))
+            }
+            _ => None,
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x8000..=0xBFFF => {
+                let bank = self.prg_bank_base() | self.prg_bank as usize;
+                self.memory.prg_offset(bank, 0x4000, addr)
+            }
+            0xC000..=0xFFFF => {
+                let bank = self.prg_bank_base() | 0x0F;
+                self.memory.prg_offset(bank, 0x4000, addr)
+            }
+            _ => None,
+        }
+    }
+
+    // The other boards have an EEPROM or nothing at $6000
+    fn prg_ram_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x6000..=0x7FFF if self.board == BandaiBoard::Lz93d50Sram && self.prg_ram_enabled => {
+                self.memory.prg_ram_offset(0, 0x2000, addr)
+            }
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            0x6000..=0x7FFF if self.board == BandaiBoard::Lz93d50Sram => {
+                self.prg_ram_enabled && self.memory.write_prg_ram(0, 0x2000, addr, data)
+            }
+            0x6000..=0x7FFF if self.board.registers_at_6000() => {
+                self.write_register(addr & 0x0F, data);
+                true
+            }
+            0x8000..=0xFFFF if self.board.registers_at_8000() => {
+                self.write_register(addr & 0x0F, data);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x0000..=0x1FFF if self.chr_banked() => {
+                let bank = self.chr_banks[(addr / 0x0400) as usize] as usize;
+                Some(self.memory.read_chr(bank, 0x0400, addr))
+            }
+            0x0000..=0x1FFF => Some(self.memory.read_chr(0, 0x2000, addr)),
+            _ => None,
+        }
+    }
+
+    fn chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x0000..=0x1FFF if self.chr_banked() => {
+                let bank = self.chr_banks[(addr / 0x0400) as usize] as usize;
+                self.memory.chr_offset(bank, 0x0400, addr)
+            }
+            0x0000..=0x1FFF => self.memory.chr_offset(0, 0x2000, addr),
+            _ => None,
+        }
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            0x0000..=0x1FFF if self.chr_banked() => {
+                let bank = self.chr_banks[(addr / 0x0400) as usize] as usize;
+                self.memory.write_chr(bank, 0x0400, addr, data);
+                true
+            }
+            0x0000..=0x1FFF => {
+                self.memory.write_chr(0, 0x2000, addr, data);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn memory(&self) -> &CartridgeMemory {
+        &self.memory
+    }
+
+    fn memory_mut(&mut self) -> &mut CartridgeMemory {
+        &mut self.memory
+    }
+
+    fn has_nonvolatile_storage(&self) -> bool {
+        self.eeprom.is_some() || self.game_eeprom.is_some()
+    }
+
+    // EEPROM contents follow PRG RAM (which only mapper 153 has): the base
+    // chip first, then the Datach game cartridge's 24C01
+    fn save_data(&self) -> Vec<u8> {
+        let mut data = self.memory.prg_ram.clone();
+        for eeprom in self.eeprom.iter().chain(self.game_eeprom.iter()) {
+            data.extend_from_slice(eeprom.data());
+        }
+        data
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        let mut used = self.memory.load_prg_ram(data);
+        for eeprom in self.eeprom.iter_mut().chain(self.game_eeprom.iter_mut()) {
+            used += eeprom.load(&data[used..]);
+        }
+    }
+
+    fn clock(&mut self) {
+        if self.irq_enabled {
+            if self.irq_counter == 0 {
+                self.irq_pending = true;
+            }
+            self.irq_counter = self.irq_counter.wrapping_sub(1);
+        }
+
+        if let Some(barcode) = self.barcode.as_mut() {
+            barcode.clock();
+        }
+    }
+
+    fn irq_state(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn barcode_reader(&mut self) -> Option<&mut BarcodeReader> {
+        self.barcode.as_mut()
+    }
+}
+
+// Which EEPROMs and barcode reader exist depends only on the board, so
+// they're saved without presence flags
+impl Snapshot for Mapper016 {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.memory.save_state(w);
+        w.bytes(&self.chr_banks);
+        w.u8(self.prg_bank);
+        w.u8(self.outer_bank);
+        w.bool(self.prg_ram_enabled);
+        self.mirroring.save_state(w);
+        w.bool(self.irq_enabled);
+        w.u16(self.irq_counter);
+        w.u16(self.irq_latch);
+        w.bool(self.irq_pending);
+        if let Some(eeprom) = &self.eeprom {
+            eeprom.save_state(w);
+        }
+        if let Some(eeprom) = &self.game_eeprom {
+            eeprom.save_state(w);
+        }
+        if let Some(barcode) = &self.barcode {
+            barcode.save_state(w);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.memory.load_state(r)?;
+        r.bytes_into(&mut self.chr_banks, "CHR banks")?;
+        self.prg_bank = r.u8()?;
+        self.outer_bank = r.u8()?;
+        self.prg_ram_enabled = r.bool()?;
+        self.mirroring.load_state(r)?;
+        self.irq_enabled = r.bool()?;
+        self.irq_counter = r.u16()?;
+        self.irq_latch = r.u16()?;
+        self.irq_pending = r.bool()?;
+        if let Some(eeprom) = &mut self.eeprom {
+            eeprom.load_state(r)?;
+        }
+        if let Some(eeprom) = &mut self.game_eeprom {
+            eeprom.load_state(r)?;
+        }
+        if let Some(barcode) = &mut self.barcode {
+            barcode.load_state(r)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cartridge::Cartridge;
+
+    const SCL: u8 = 0x20;
+    const SDA: u8 = 0x40;
+
+    // An NES 2.0 Bandai board with 32KB of PRG and 8KB of CHR
+    fn bandai(mapper: u8, submapper: u8, nvram_shift: u8) -> Cartridge {
+        let mut bytes = b"NES\x1A\x02\x01".to_vec();
+        bytes.extend([mapper << 4, (mapper & 0xF0) | 0x08, submapper << 4]);
+        bytes.extend([0, nvram_shift << 4]);
+        bytes.resize(16 + 0x8000 + 0x2000, 0);
+        Cartridge::from_bytes(&bytes).unwrap()
+    }
+
+    // The game's side of the I2C bus, bit-banged through $800D
+    struct Bus<'a> {
+        cart: &'a mut Cartridge,
+        lsb_first: bool,
+    }
+
+    impl Bus<'_> {
+        fn lines(&mut self, scl: bool, sda: bool) {
+            let data = if scl { SCL } else { 0 } | if sda { SDA } else { 0 };
+            self.cart.cpu_write(0x800D, data);
+        }
+
+        fn sda(&mut self) -> bool {
+            self.cart.cpu_peek(0x6000).unwrap() & 0x10 != 0
+        }
+
+        fn start(&mut self) {
+            self.lines(false, true);
+            self.lines(true, true);
+            self.lines(true, false);
+            self.lines(false, false);
+        }
+
+        fn stop(&mut self) {
+            self.lines(false, false);
+            self.lines(true, false);
+            self.lines(true, true);
+        }
+
+        // Clocks one bit out and returns what SDA read while SCL was high
+        fn clock_bit(&mut self, bit: bool) -> bool {
+            self.lines(false, bit);
+            self.lines(true, bit);
+            let sda = self.sda();
+            self.lines(false, bit);
+            sda
+        }
+
+        // Sends a byte and returns whether the chip acknowledged it
+        fn send(&mut self, byte: u8) -> bool {
+            for i in 0..8 {
+                let shift = if self.lsb_first { i } else { 7 - i };
+                self.clock_bit(byte >> shift & 1 != 0);
+            }
+            !self.clock_bit(true)
+        }
+
+        // Reads a byte, answering with a NACK to end the read
+        fn receive(&mut self) -> u8 {
+            let mut byte = 0;
+            for i in 0..8 {
+                let shift = if self.lsb_first { i } else { 7 - i };
+                byte |= (self.clock_bit(true) as u8) << shift;
+            }
+            self.clock_bit(true);
+            byte
+        }
+    }
+
+    fn write_24c02(cart: &mut Cartridge, address: u8, data: u8) {
+        let mut bus = Bus {
+            cart,
+            lsb_first: false,
+        };
+        bus.start();
+        assert!(bus.send(0xA0), "device address");
+        assert!(bus.send(address), "word address");
+        assert!(bus.send(data), "data");
+        bus.stop();
+    }
+
+    fn read_24c02(cart: &mut Cartridge, address: u8) -> u8 {
+        let mut bus = Bus {
+            cart,
+            lsb_first: false,
+        };
+        bus.start();
+        assert!(bus.send(0xA0));
+        assert!(bus.send(address));
+        bus.start();
+        assert!(bus.send(0xA1));
+        let data = bus.receive();
+        bus.stop();
+        data
+    }
+
+    #[test]
+    fn writes_and_reads_a_24c02() {
+        let mut cart = bandai(16, 5, 2);
+        write_24c02(&mut cart, 0x42, 0x5A);
+        assert_eq!(read_24c02(&mut cart, 0x42), 0x5A);
+        assert_eq!(read_24c02(&mut cart, 0x43), 0xFF);
+
+        // A byte for another device address is left unacknowledged
+        let mut bus = Bus {
+            cart: &mut cart,
+            lsb_first: false,
+        };
+        bus.start();
+        assert!(!bus.send(0xB0));
+        bus.stop();
+    }
+
+    #[test]
+    fn writes_and_reads_an_x24c01() {
+        let mut cart = bandai(159, 0, 1);
+        let mut bus = Bus {
+            cart: &mut cart,
+            lsb_first: true,
+        };
+        bus.start();
+        assert!(bus.send(0x15), "word address");
+        assert!(bus.send(0xC3), "data");
+        bus.stop();
+
+        bus.start();
+        assert!(bus.send(0x80 | 0x15));
+        assert_eq!(bus.receive(), 0xC3);
+        bus.stop();
+        assert_eq!(cart.export_save().unwrap()[0x15], 0xC3);
+    }
+
+    #[test]
+    fn save_data_round_trips_the_eeprom() {
+        let mut cart = bandai(16, 5, 2);
+        write_24c02(&mut cart, 0x10, 0x99);
+        write_24c02(&mut cart, 0xFF, 0x01);
+        let save = cart.export_save().unwrap();
+        assert_eq!(save.len(), 256);
+
+        let mut cart = bandai(16, 5, 2);
+        cart.import_save(&save);
+        assert_eq!(read_24c02(&mut cart, 0x10), 0x99);
+        assert_eq!(read_24c02(&mut cart, 0xFF), 0x01);
+    }
+
+    // Clocks until the IRQ goes up, returning how many clocks that took
+    fn clock_until_irq(cart: &mut Cartridge, limit: u32) -> Option<u32> {
+        (1..=limit).find(|_| {
+            cart.clock();
+            cart.irq_state()
+        })
+    }
+
+    #[test]
+    fn fcg_counter_is_written_directly() {
+        let mut cart = bandai(16, 4, 0);
+        cart.cpu_write(0x600B, 0x03);
+        cart.cpu_write(0x600C, 0x00);
+        cart.cpu_write(0x600A, 0x01);
+        assert_eq!(clock_until_irq(&mut cart, 10), Some(4));
+
+        // Writing the low byte mid-count takes effect straight away
+        cart.cpu_write(0x600A, 0x01);
+        assert!(!cart.irq_state());
+        cart.cpu_write(0x600B, 0x10);
+        cart.cpu_write(0x600C, 0x00);
+        cart.clock();
+        cart.cpu_write(0x600B, 0x00);
+        assert_eq!(clock_until_irq(&mut cart, 10), Some(1));
+    }
+
+    #[test]
+    fn lz93d50_counter_reloads_from_the_latch() {
+        let mut cart = bandai(16, 5, 0);
+        cart.cpu_write(0x800B, 0x03);
+        cart.cpu_write(0x800C, 0x00);
+        assert_eq!(clock_until_irq(&mut cart, 10), None);
+        cart.cpu_write(0x800A, 0x01);
+        assert_eq!(clock_until_irq(&mut cart, 10), Some(4));
+
+        // Latch writes leave the running counter alone...
+        cart.cpu_write(0x800B, 0x01);
+        cart.cpu_write(0x800A, 0x01);
+        cart.cpu_write(0x800B, 0x40);
+        assert_eq!(clock_until_irq(&mut cart, 10), Some(2));
+
+        // ...until $800A copies the latch in
+        cart.cpu_write(0x800A, 0x01);
+        assert_eq!(clock_until_irq(&mut cart, 0x50), Some(0x41));
+    }
+}
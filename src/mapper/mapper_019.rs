@@ -0,0 +1,356 @@
+use crate::audio::{ExpansionChip, ExpansionOutput, Namco163Audio};
+use crate::cartridge::Mirroring;
+use crate::mapper::{CartridgeMemory, Mapper};
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+// Namco 129 / 163: 8KB PRG banking, 1KB CHR banking with the option of
+// pointing nametables at CHR-ROM, a 15-bit cycle IRQ counter and (on the
+// 163) wavetable sound.
+pub struct Mapper019 {
+    memory: CartridgeMemory,
+
+    chr_banks: [u8; 8],
+    nametable_banks: [u8; 4],
+    prg_banks: [u8; 3],
+    write_protect: u8,
+    sound_disabled: bool,
+
+    irq_enabled: bool,
+    irq_counter: u16,
+    irq_pending: bool,
+
+    audio: Namco163Audio,
+}
+
+impl Mapper019 {
+    pub fn new(memory: CartridgeMemory) -> Self {
+        Mapper019 {
+            memory,
+            chr_banks: [0; 8],
+            nametable_banks: [0xE0, 0xE1, 0xE0, 0xE1],
+            prg_banks: [0; 3],
+            write_protect: 0xFF,
+            sound_disabled: false,
+            irq_enabled: false,
+            irq_counter: 0,
+            irq_pending: false,
+            audio: Namco163Audio::new(),
+        }
+    }
+
+    // Writes to $6000-$7FFF need $F800 to hold 0100xxxx, with each of the
+    // low four bits protecting one 2KB quarter
+    fn ram_writable(&self, addr: u16) -> bool {
+        let quarter = (addr - 0x6000) / 0x0800;
+        self.write_protect & 0xF0 == 0x40 && self.write_protect & (1 << quarter) == 0
+    }
+
+    // Bank values $E0-$FF select CIRAM instead of CHR-ROM
+    fn nametable_in_rom(&self, slot: usize) -> bool {
+        self.nametable_banks[slot] < 0xE0
+    }
+}
+
+impl Mapper for Mapper019 {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4800..=0x4FFF => Some(self.audio.read_data()),
+            _ => self.cpu_peek(addr),
+        }
+    }
+
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4800..=0x4FFF => Some(self.audio.peek_data()),
+            0x5000..=0x57FF => Some(self.irq_counter as u8),
+            0x5800..=0x5FFF => {
+                Some((self.irq_counter >> 8) as u8 | if self.irq_enabled { 0x80 } else { 0x00 })
+            }
+            0x6000..=0x7FFF => self.memory.read_prg_ram(0, 0x2000, addr),
+            0x8000..=0xDFFF => {
+                let bank = self.prg_banks[((addr - 0x8000) / 0x2000) as usize] as usize;
+                Some(self.memory.read_prg(bank, 0x2000, addr))
+            }
+            0xE000..=0xFFFF => {
+                let last = self.memory.prg_banks(0x2000) - 1;
+                Some(self.memory.read_prg(last, 0x2000, addr))
+            }
+            _ => None,
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x8000..=0xDFFF => {
+                let bank = self.prg_banks[((addr - 0x8000) / 0x2000) as usize] as usize;
+                self.memory.prg_offset(bank, 0x2000, addr)
+            }
+            0xE000..=0xFFFF => {
+                let last = self.memory.prg_banks(0x2000) - 1;
+                self.memory.prg_offset(last, 0x2000, addr)
+            }
+            _ => None,
+        }
+    }
+
+    fn prg_ram_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x6000..=0x7FFF => self.memory.prg_ram_offset(0, 0x2000, addr),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            0x4800..=0x4FFF => self.audio.write_data(data),
+            0x5000..=0x57FF => {
+                self.irq_counter = (self.irq_counter & 0x7F00) | data as u16;
+                self.irq_pending = false;
+            }
+            0x5800..=0x5FFF => {
+                self.irq_counter = (self.irq_counter & 0x00FF) | ((data as u16 & 0x7F) << 8);
+                self.irq_enabled = data & 0x80 != 0;
+                self.irq_pending = false;
+            }
+            0x6000..=0x7FFF if self.ram_writable(addr) => {
+                return self.memory.write_prg_ram(0, 0x2000, addr, data);
+            }
+            0x8000..=0xBFFF => self.chr_banks[((addr - 0x8000) / 0x0800) as usize] = data,
+            0xC000..=0xDFFF => self.nametable_banks[((addr - 0xC000) / 0x0800) as usize] = data,
+            0xE000..=0xE7FF => {
+                self.prg_banks[0] = data & 0x3F;
+                self.sound_disabled = data & 0x40 != 0;
+            }
+            0xE800..=0xEFFF => self.prg_banks[1] = data & 0x3F,
+            0xF000..=0xF7FF => self.prg_banks[2] = data & 0x3F,
+            0xF800..=0xFFFF => {
+                self.audio.write_address(data);
+                self.write_protect = data;
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            // CHR bank values $E0-$FF can also put CIRAM in the pattern
+            // tables, which no released game relies on
+            0x0000..=0x1FFF => {
+                let bank = self.chr_banks[(addr / 0x0400) as usize] as usize;
+                Some(self.memory.read_chr(bank, 0x0400, addr))
+            }
+            0x2000..=0x3EFF => {
+                let slot = ((addr >> 10) & 0x03) as usize;
+                if self.nametable_in_rom(slot) {
+                    let bank = self.nametable_banks[slot] as usize;
+                    Some(self.memory.read_chr(bank, 0x0400, addr))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x0000..=0x1FFF => {
+                let bank = self.chr_banks[(addr / 0x0400) as usize] as usize;
+                self.memory.chr_offset(bank, 0x0400, addr)
+            }
+            0x2000..=0x3EFF => {
+                let slot = ((addr >> 10) & 0x03) as usize;
+                if self.nametable_in_rom(slot) {
+                    let bank = self.nametable_banks[slot] as usize;
+                    self.memory.chr_offset(bank, 0x0400, addr)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            0x0000..=0x1FFF => {
+                let bank = self.chr_banks[(addr / 0x0400) as usize] as usize;
+                self.memory.write_chr(bank, 0x0400, addr, data);
+                true
+            }
+            // Writes to ROM-backed nametables go nowhere
+            0x2000..=0x3EFF => self.nametable_in_rom(((addr >> 10) & 0x03) as usize),
+            _ => false,
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        let banks = self.nametable_banks;
+        Mirroring::Mapped([banks[0] & 1, banks[1] & 1, banks[2] & 1, banks[3] & 1])
+    }
+
+    fn memory(&self) -> &CartridgeMemory {
+        &self.memory
+    }
+
+    fn memory_mut(&mut self) -> &mut CartridgeMemory {
+        &mut self.memory
+    }
+
+    // The 163's sound RAM sits on the battery too, and some games keep
+    // their saves in it
+    fn save_data(&self) -> Vec<u8> {
+        let mut data = self.memory.prg_ram.clone();
+        data.extend_from_slice(self.audio.ram());
+        data
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        let used = self.memory.load_prg_ram(data);
+        let rest = &data[used..];
+        let len = rest.len().min(128);
+        self.audio.ram_mut()[..len].copy_from_slice(&rest[..len]);
+    }
+
+    fn clock(&mut self) {
+        if self.irq_enabled && self.irq_counter < 0x7FFF {
+            self.irq_counter += 1;
+            if self.irq_counter == 0x7FFF {
+                self.irq_pending = true;
+            }
+        }
+
+        if !self.sound_disabled {
+            self.audio.clock();
+        }
+    }
+
+    fn irq_state(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn expansion_audio(&self) -> ExpansionOutput {
+        if self.sound_disabled {
+            ExpansionOutput::default()
+        } else {
+            ExpansionOutput::single(ExpansionChip::Namco163, self.audio.output())
+        }
+    }
+}
+
+impl Snapshot for Mapper019 {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.memory.save_state(w);
+        w.bytes(&self.chr_banks);
+        w.bytes(&self.nametable_banks);
+        w.bytes(&self.prg_banks);
+        w.u8(self.write_protect);
+        w.bool(self.sound_disabled);
+        w.bool(self.irq_enabled);
+        w.u16(self.irq_counter);
+        w.bool(self.irq_pending);
+        self.audio.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.memory.load_state(r)?;
+        r.bytes_into(&mut self.chr_banks, "CHR banks")?;
+        r.bytes_into(&mut self.nametable_banks, "nametable banks")?;
+        r.bytes_into(&mut self.prg_banks, "PRG banks")?;
+        self.write_protect = r.u8()?;
+        self.sound_disabled = r.bool()?;
+        self.irq_enabled = r.bool()?;
+        self.irq_counter = r.u16()?;
+        self.irq_pending = r.bool()?;
+        self.audio.load_state(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cartridge::Cartridge;
+
+    // An NES 2.0 Namco 163 board with 8KB of battery-backed PRG RAM
+    fn n163() -> Cartridge {
+        let mut bytes = b"NES\x1A\x02\x01\x32\x18\x00\x00\x70".to_vec();
+        bytes.resize(16 + 0x8000 + 0x2000, 0);
+        Cartridge::from_bytes(&bytes).unwrap()
+    }
+
+    #[test]
+    fn irq_counter_stops_at_7fff() {
+        let mut cart = n163();
+        cart.cpu_write(0x5000, 0xFD);
+        cart.cpu_write(0x5800, 0xFF);
+        assert_eq!(cart.cpu_peek(0x5800), Some(0xFF));
+
+        cart.clock();
+        assert!(!cart.irq_state());
+        cart.clock();
+        assert!(cart.irq_state());
+        for _ in 0..10 {
+            cart.clock();
+        }
+        assert_eq!(cart.cpu_peek(0x5000), Some(0xFF));
+        assert_eq!(cart.cpu_peek(0x5800), Some(0xFF));
+
+        cart.cpu_write(0x5000, 0x00);
+        assert!(!cart.irq_state());
+        assert_eq!(cart.cpu_peek(0x5000), Some(0x00));
+        assert_eq!(cart.cpu_peek(0x5800), Some(0xFF));
+
+        // Bit 7 of $5800 is the enable
+        cart.cpu_write(0x5800, 0x7F);
+        assert_eq!(cart.cpu_peek(0x5800), Some(0x7F));
+        cart.clock();
+        assert_eq!(cart.cpu_peek(0x5000), Some(0x00));
+    }
+
+    #[test]
+    fn sound_ram_address_auto_increments() {
+        let mut cart = n163();
+        cart.cpu_write(0xF800, 0x80 | 0x7E);
+        for data in [0x11, 0x22, 0x33] {
+            cart.cpu_write(0x4800, data);
+        }
+
+        // The address wraps within the 128 bytes
+        cart.cpu_write(0xF800, 0x80 | 0x7E);
+        assert_eq!(cart.cpu_peek(0x4800), Some(0x11));
+        let read: Vec<_> = (0..3).map(|_| cart.cpu_read(0x4800).unwrap()).collect();
+        assert_eq!(read, [0x11, 0x22, 0x33]);
+
+        cart.cpu_write(0xF800, 0x7F);
+        assert_eq!(cart.cpu_read(0x4800), Some(0x22));
+        assert_eq!(cart.cpu_read(0x4800), Some(0x22));
+    }
+
+    #[test]
+    fn prg_ram_write_protect() {
+        let mut cart = n163();
+
+        // Power-on $FF doesn't have the $4x enable in the top nibble
+        cart.cpu_write(0x6000, 0x01);
+        assert_eq!(cart.cpu_peek(0x6000), Some(0x00));
+
+        cart.cpu_write(0xF800, 0x40);
+        cart.cpu_write(0x6000, 0x01);
+        assert_eq!(cart.cpu_peek(0x6000), Some(0x01));
+
+        // Each low bit protects one 2KB quarter
+        cart.cpu_write(0xF800, 0x42);
+        for addr in [0x6000, 0x6800, 0x7000, 0x7800] {
+            cart.cpu_write(addr, 0x55);
+        }
+        let read: Vec<_> = [0x6000, 0x6800, 0x7000, 0x7800]
+            .into_iter()
+            .map(|addr| cart.cpu_peek(addr).unwrap())
+            .collect();
+        assert_eq!(read, [0x55, 0x00, 0x55, 0x55]);
+
+        cart.cpu_write(0xF800, 0x00);
+        cart.cpu_write(0x7000, 0x66);
+        assert_eq!(cart.cpu_peek(0x7000), Some(0x55));
+    }
+}
@@ -0,0 +1,302 @@
+use crate::audio::{ExpansionChip, ExpansionOutput, Sunsoft5BAudio};
+use crate::cartridge::Mirroring;
+use crate::mapper::{CartridgeMemory, Mapper};
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+// Sunsoft FME-7 / 5A / 5B. Registers are reached through a command port at
+// $8000 and a parameter port at $A000; the 5B adds its sound chip behind
+// $C000/$E000.
+pub struct Mapper069 {
+    memory: CartridgeMemory,
+
+    command: u8,
+    chr_banks: [u8; 8],
+    prg_banks: [u8; 3],
+    prg_6000: u8,
+    mirroring: Mirroring,
+
+    irq_enabled: bool,
+    irq_counter_enabled: bool,
+    irq_counter: u16,
+    irq_pending: bool,
+
+    audio: Sunsoft5BAudio,
+}
+
+impl Mapper069 {
+    pub fn new(memory: CartridgeMemory) -> Self {
+        Mapper069 {
+            memory,
+            command: 0,
+            chr_banks: [0; 8],
+            prg_banks: [0; 3],
+            prg_6000: 0,
+            mirroring: Mirroring::Vertical,
+            irq_enabled: false,
+            irq_counter_enabled: false,
+            irq_counter: 0,
+            irq_pending: false,
+            audio: Sunsoft5BAudio::new(),
+        }
+    }
+
+    fn write_parameter(&mut self, data: u8) {
+        match self.command {
+            0x0..=0x7 => self.chr_banks[self.command as usize] = data,
+            0x8 => self.prg_6000 = data,
+            0x9..=0xB => self.prg_banks[(self.command - 0x9) as usize] = data & 0x3F,
+            0xC => {
+                self.mirroring = match data & 0x03 {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::OneScreenLower,
+                    _ => Mirroring::OneScreenUpper,
+                }
+            }
+            0xD => {
+                self.irq_enabled = data & 0x01 != 0;
+                self.irq_counter_enabled = data & 0x80 != 0;
+                self.irq_pending = false;
+            }
+            0xE => self.irq_counter = (self.irq_counter & 0xFF00) | data as u16,
+            0xF => self.irq_counter = (self.irq_counter & 0x00FF) | ((data as u16) << 8),
+            _ => unreachable!(),
+        }
+    }
+
+    fn ram_selected(&self) -> bool {
+        self.prg_6000 & 0x40 != 0
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.prg_6000 & 0x80 != 0
+    }
+}
+
+impl Mapper for Mapper069 {
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF => {
+                let bank = (self.prg_6000 & 0x3F) as usize;
+                if !self.ram_selected() {
+                    Some(self.memory.read_prg(bank, 0x2000, addr))
+                } else if self.ram_enabled() {
+                    self.memory.read_prg_ram(bank, 0x2000, addr)
+                } else {
+                    None
+                }
+            }
+            0x8000..=0xDFFF => {
+                let bank = self.prg_banks[((addr - 0x8000) / 0x2000) as usize] as usize;
+                Some(self.memory.read_prg(bank, 0x2000, addr))
+            }
+            0xE000..=0xFFFF => {
+                let last = self.memory.prg_banks(0x2000) - 1;
+                Some(self.memory.read_prg(last, 0x2000, addr))
+            }
+            _ => None,
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x6000..=0x7FFF if !self.ram_selected() => {
+                self.memory
+                    .prg_offset((self.prg_6000 & 0x3F) as usize, 0x2000, addr)
+            }
+            0x8000..=0xDFFF => {
+                let bank = self.prg_banks[((addr - 0x8000) / 0x2000) as usize] as usize;
+                self.memory.prg_offset(bank, 0x2000, addr)
+            }
+            0xE000..=0xFFFF => {
+                let last = self.memory.prg_banks(0x2000) - 1;
+                self.memory.prg_offset(last, 0x2000, addr)
+            }
+            _ => None,
+        }
+    }
+
+    fn prg_ram_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x6000..=0x7FFF if self.ram_selected() && self.ram_enabled() => self
+                .memory
+                .prg_ram_offset((self.prg_6000 & 0x3F) as usize, 0x2000, addr),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            0x6000..=0x7FFF if self.ram_selected() && self.ram_enabled() => {
+                let bank = (self.prg_6000 & 0x3F) as usize;
+                self.memory.write_prg_ram(bank, 0x2000, addr, data)
+            }
+            0x8000..=0x9FFF => {
+                self.command = data & 0x0F;
+                true
+            }
+            0xA000..=0xBFFF => {
+                self.write_parameter(data);
+                true
+            }
+            0xC000..=0xDFFF => {
+                self.audio.select(data);
+                true
+            }
+            0xE000..=0xFFFF => {
+                self.audio.write(data);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x0000..=0x1FFF => {
+                let bank = self.chr_banks[(addr / 0x0400) as usize] as usize;
+                Some(self.memory.read_chr(bank, 0x0400, addr))
+            }
+            _ => None,
+        }
+    }
+
+    fn chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x0000..=0x1FFF => {
+                let bank = self.chr_banks[(addr / 0x0400) as usize] as usize;
+                self.memory.chr_offset(bank, 0x0400, addr)
+            }
+            _ => None,
+        }
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            0x0000..=0x1FFF => {
+                let bank = self.chr_banks[(addr / 0x0400) as usize] as usize;
+                self.memory.write_chr(bank, 0x0400, addr, data);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn memory(&self) -> &CartridgeMemory {
+        &self.memory
+    }
+
+    fn memory_mut(&mut self) -> &mut CartridgeMemory {
+        &mut self.memory
+    }
+
+    fn clock(&mut self) {
+        if self.irq_counter_enabled {
+            self.irq_counter = self.irq_counter.wrapping_sub(1);
+            if self.irq_counter == 0xFFFF && self.irq_enabled {
+                self.irq_pending = true;
+            }
+        }
+
+        self.audio.clock();
+    }
+
+    fn irq_state(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn expansion_audio(&self) -> ExpansionOutput {
+        ExpansionOutput::single(ExpansionChip::Sunsoft5B, self.audio.output())
+    }
+}
+
+impl Snapshot for Mapper069 {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.memory.save_state(w);
+        w.u8(self.command);
+        w.bytes(&self.chr_banks);
+        w.bytes(&self.prg_banks);
+        w.u8(self.prg_6000);
+        self.mirroring.save_state(w);
+        w.bool(self.irq_enabled);
+        w.bool(self.irq_counter_enabled);
+        w.u16(self.irq_counter);
+        w.bool(self.irq_pending);
+        self.audio.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.memory.load_state(r)?;
+        self.command = r.u8()?;
+        r.bytes_into(&mut self.chr_banks, "CHR banks")?;
+        r.bytes_into(&mut self.prg_banks, "PRG banks")?;
+        self.prg_6000 = r.u8()?;
+        self.mirroring.load_state(r)?;
+        self.irq_enabled = r.bool()?;
+        self.irq_counter_enabled = r.bool()?;
+        self.irq_counter = r.u16()?;
+        self.irq_pending = r.bool()?;
+        self.audio.load_state(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cartridge::Cartridge;
+
+    fn fme7() -> Cartridge {
+        let mut bytes = b"NES\x1A\x02\x01\x50\x40".to_vec();
+        bytes.resize(16 + 0x8000 + 0x2000, 0);
+        Cartridge::from_bytes(&bytes).unwrap()
+    }
+
+    fn command(cart: &mut Cartridge, command: u8, data: u8) {
+        cart.cpu_write(0x8000, command);
+        cart.cpu_write(0xA000, data);
+    }
+
+    // Clocks until the IRQ goes up, returning how many clocks that took
+    fn clock_until_irq(cart: &mut Cartridge, limit: u32) -> Option<u32> {
+        (1..=limit).find(|_| {
+            cart.clock();
+            cart.irq_state()
+        })
+    }
+
+    #[test]
+    fn irq_fires_when_the_counter_wraps() {
+        let mut cart = fme7();
+        command(&mut cart, 0xE, 0x02);
+        command(&mut cart, 0xF, 0x00);
+        command(&mut cart, 0xD, 0x81);
+        assert_eq!(clock_until_irq(&mut cart, 10), Some(3));
+
+        // Rewriting $D acknowledges, and the counter carries on from $FFFF
+        command(&mut cart, 0xD, 0x81);
+        assert!(!cart.irq_state());
+        assert_eq!(clock_until_irq(&mut cart, 0x10000), Some(0x10000));
+    }
+
+    #[test]
+    fn irq_enable_and_counter_enable_are_separate() {
+        let mut cart = fme7();
+
+        // Counting with the IRQ off passes through zero silently
+        command(&mut cart, 0xE, 0x01);
+        command(&mut cart, 0xF, 0x00);
+        command(&mut cart, 0xD, 0x80);
+        assert_eq!(clock_until_irq(&mut cart, 10), None);
+
+        // With the counter stopped, the IRQ waits on it
+        command(&mut cart, 0xE, 0x01);
+        command(&mut cart, 0xF, 0x00);
+        command(&mut cart, 0xD, 0x01);
+        assert_eq!(clock_until_irq(&mut cart, 10), None);
+        command(&mut cart, 0xD, 0x81);
+        assert_eq!(clock_until_irq(&mut cart, 10), Some(2));
+    }
+}
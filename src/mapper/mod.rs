@@ -0,0 +1,255 @@
+mod datach;
+mod disk;
+mod eeprom;
+mod fds;
+mod mapper_000;
+mod mapper_009;
+mod mapper_010;
+mod mapper_016;
+mod mapper_019;
+mod mapper_069;
+mod nsf;
+
+pub use datach::BarcodeReader;
+pub use disk::{DiskError, DiskImage};
+pub use fds::Fds;
+pub use mapper_000::Mapper000;
+pub use mapper_009::Mapper009;
+pub use mapper_010::Mapper010;
+pub use mapper_016::{BandaiBoard, Mapper016};
+pub use mapper_019::Mapper019;
+pub use mapper_069::Mapper069;
+pub use nsf::{DRIVER_IDLE, NsfMapper, SUPPORTED_CHIPS};
+
+use crate::audio::ExpansionOutput;
+use crate::cartridge::{CartridgeError, Header, Mirroring};
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+// Save states cover the board registers as well as cartridge RAM
+pub trait Mapper: Snapshot {
+    // CPU accesses in $4020-$FFFF. Returning None / false leaves the access
+    // to the bus, which is how unmapped (open bus) regions are expressed.
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        self.cpu_peek(addr)
+    }
+    fn cpu_write(&mut self, addr: u16, data: u8) -> bool;
+
+    // What cpu_read would return, minus the side effects some registers
+    // have when read (acknowledging IRQs, stepping auto-increment ports), so
+    // debuggers can look at memory without disturbing the game. Boards only
+    // override cpu_read when they have such registers.
+    fn cpu_peek(&self, addr: u16) -> Option<u8>;
+
+    // The PRG ROM byte a CPU address currently maps to, for tools that work
+    // in terms of the ROM rather than the address space (bank-aware labels,
+    // code/data logs). None for anything that isn't PRG ROM.
+    fn prg_rom_offset(&self, _addr: u16) -> Option<usize> {
+        None
+    }
+
+    // Likewise for PRG RAM, so debuggers can change it without going
+    // through cpu_write and whatever registers share its addresses
+    fn prg_ram_offset(&self, _addr: u16) -> Option<usize> {
+        None
+    }
+
+    // Likewise for PPU addresses and CHR ROM
+    fn chr_rom_offset(&self, _addr: u16) -> Option<usize> {
+        None
+    }
+
+    // PPU accesses in $0000-$3EFF. Nametable reads only need handling when
+    // the board maps something other than CIRAM there.
+    fn ppu_read(&mut self, addr: u16) -> Option<u8>;
+    fn ppu_write(&mut self, addr: u16, data: u8) -> bool;
+
+    // Sees every PPU read once its data has been returned, so boards that
+    // snoop the PPU address bus (the MMC2/MMC4 tile latches) switch banks
+    // after the triggering fetch rather than before it
+    fn on_ppu_read(&mut self, _addr: u16) {}
+
+    fn mirroring(&self) -> Mirroring;
+
+    fn memory(&self) -> &CartridgeMemory;
+    fn memory_mut(&mut self) -> &mut CartridgeMemory;
+
+    // Battery-backed contents in .sav layout: PRG RAM, followed by whatever
+    // non-volatile storage the board has of its own. The FDS stores its disk
+    // writes here instead, as a patch.
+    fn save_data(&self) -> Vec<u8> {
+        self.memory().prg_ram.clone()
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        self.memory_mut().load_prg_ram(data);
+    }
+
+    // Serial EEPROMs and disks keep their contents whatever the header says
+    // about a battery, so they always need persisting
+    fn has_nonvolatile_storage(&self) -> bool {
+        false
+    }
+
+    // Called once per CPU cycle
+    fn clock(&mut self) {}
+
+    fn irq_state(&self) -> bool {
+        false
+    }
+
+    // Current output of the board's sound chips
+    fn expansion_audio(&self) -> ExpansionOutput {
+        ExpansionOutput::default()
+    }
+
+    fn barcode_reader(&mut self) -> Option<&mut BarcodeReader> {
+        None
+    }
+
+    fn disk_drive(&mut self) -> Option<&mut Fds> {
+        None
+    }
+}
+
+pub fn create(
+    header: &Header,
+    prg_rom: Vec<u8>,
+    chr_rom: Vec<u8>,
+) -> Result<Box<dyn Mapper>, CartridgeError> {
+    let memory = CartridgeMemory::new(header, prg_rom, chr_rom);
+    let mapper: Box<dyn Mapper> = match header.mapper {
+        0 => Box::new(Mapper000::new(memory, header.mirroring)),
+        9 => Box::new(Mapper009::new(memory)),
+        10 => Box::new(Mapper010::new(memory)),
+        16 | 153 | 157 | 159 => Box::new(Mapper016::new(memory, BandaiBoard::for_mapper(header))),
+        19 => Box::new(Mapper019::new(memory)),
+        69 => Box::new(Mapper069::new(memory)),
+        n => return Err(CartridgeError::UnsupportedMapper(n)),
+    };
+    Ok(mapper)
+}
+
+// ROM and RAM chips on the cartridge board, with helpers for the banked
+// accesses every mapper ends up doing
+pub struct CartridgeMemory {
+    pub prg_rom: Vec<u8>,
+    pub prg_ram: Vec<u8>,
+    pub chr: Vec<u8>,
+    pub chr_is_ram: bool,
+}
+
+impl CartridgeMemory {
+    pub fn new(header: &Header, prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Self {
+        let chr_is_ram = chr_rom.is_empty();
+        let chr = if chr_is_ram {
+            vec![0x00; header.chr_ram_size.max(0x2000)]
+        } else {
+            chr_rom
+        };
+
+        CartridgeMemory {
+            prg_rom,
+            prg_ram: vec![0x00; header.prg_ram_size + header.prg_nvram_size],
+            chr,
+            chr_is_ram,
+        }
+    }
+
+    pub fn prg_banks(&self, bank_size: usize) -> usize {
+        (self.prg_rom.len() / bank_size).max(1)
+    }
+
+    pub fn chr_banks(&self, bank_size: usize) -> usize {
+        (self.chr.len() / bank_size).max(1)
+    }
+
+    // Bank numbers wrap around the chip size, as on boards where the
+    // upper register bits simply aren't connected
+    pub fn read_prg(&self, bank: usize, bank_size: usize, addr: u16) -> u8 {
+        self.prg_offset(bank, bank_size, addr)
+            .map_or(0, |offset| self.prg_rom[offset])
+    }
+
+    // Where in PRG ROM a banked access lands, if the chip is that big
+    pub fn prg_offset(&self, bank: usize, bank_size: usize, addr: u16) -> Option<usize> {
+        let bank = bank % self.prg_banks(bank_size);
+        let offset = bank * bank_size + (addr as usize & (bank_size - 1));
+        (offset < self.prg_rom.len()).then_some(offset)
+    }
+
+    // Where in CHR ROM a banked access lands. None for CHR RAM.
+    pub fn chr_offset(&self, bank: usize, bank_size: usize, addr: u16) -> Option<usize> {
+        if self.chr_is_ram {
+            return None;
+        }
+        let bank = bank % self.chr_banks(bank_size);
+        let offset = bank * bank_size + (addr as usize & (bank_size - 1));
+        (offset < self.chr.len()).then_some(offset)
+    }
+
+    pub fn read_chr(&self, bank: usize, bank_size: usize, addr: u16) -> u8 {
+        let bank = bank % self.chr_banks(bank_size);
+        let offset = bank * bank_size + (addr as usize & (bank_size - 1));
+        self.chr.get(offset).copied().unwrap_or(0)
+    }
+
+    pub fn write_chr(&mut self, bank: usize, bank_size: usize, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let bank = bank % self.chr_banks(bank_size);
+            let offset = bank * bank_size + (addr as usize & (bank_size - 1));
+            if let Some(byte) = self.chr.get_mut(offset) {
+                *byte = data;
+            }
+        }
+    }
+
+    // Copies as much of data as fits, so saves from emulators that size the
+    // RAM differently still load
+    pub fn load_prg_ram(&mut self, data: &[u8]) -> usize {
+        let len = data.len().min(self.prg_ram.len());
+        self.prg_ram[..len].copy_from_slice(&data[..len]);
+        len
+    }
+
+    pub fn read_prg_ram(&self, bank: usize, bank_size: usize, addr: u16) -> Option<u8> {
+        let offset = self.prg_ram_offset(bank, bank_size, addr)?;
+        Some(self.prg_ram[offset])
+    }
+
+    pub fn write_prg_ram(&mut self, bank: usize, bank_size: usize, addr: u16, data: u8) -> bool {
+        let Some(offset) = self.prg_ram_offset(bank, bank_size, addr) else {
+            return false;
+        };
+        self.prg_ram[offset] = data;
+        true
+    }
+
+    // Where in PRG RAM a banked access lands, mirrored when the RAM is
+    // smaller than the bank. None if the board has none.
+    pub fn prg_ram_offset(&self, bank: usize, bank_size: usize, addr: u16) -> Option<usize> {
+        if self.prg_ram.is_empty() {
+            return None;
+        }
+        let banks = (self.prg_ram.len() / bank_size).max(1);
+        let offset = (bank % banks) * bank_size + (addr as usize & (bank_size - 1));
+        Some(offset % self.prg_ram.len())
+    }
+}
+
+// ROM can't change, so only RAM is kept
+impl Snapshot for CartridgeMemory {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.prg_ram);
+        if self.chr_is_ram {
+            w.bytes(&self.chr);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.bytes_into(&mut self.prg_ram, "PRG RAM size")?;
+        if self.chr_is_ram {
+            r.bytes_into(&mut self.chr, "CHR RAM size")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,298 @@
+use crate::audio::{ExpansionChip, ExpansionOutput, FdsAudio, Namco163Audio, Sunsoft5BAudio};
+use crate::cartridge::{Header, Mirroring};
+use crate::mapper::{CartridgeMemory, Mapper};
+use crate::nsf::{Nsf, SoundChips};
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+// The hardware an NSF player presents to a rip: 4KB PRG banks at
+// $8000-$FFFF selected through $5FF8-$5FFF, 8KB of RAM at $6000-$7FFF and
+// whichever expansion sound chips the header asks for. With the FDS the
+// whole of $6000-$FFFF is RAM, and bank writes (including $5FF6/$5FF7 for
+// $6000-$7FFF) copy data into it instead.
+//
+// The player's own code is a single idle loop at DRIVER_IDLE that INIT and
+// PLAY return to.
+pub struct NsfMapper {
+    memory: CartridgeMemory,
+    chips: SoundChips,
+    initial_banks: [u8; 8],
+    banks: [u8; 8],
+    // FDS rips that aren't bank switched load straight into RAM
+    fds_image: Option<(u16, Vec<u8>)>,
+
+    exram: [u8; 0x400],
+    multiplier: [u8; 2],
+
+    fds: FdsAudio,
+    namco163: Namco163Audio,
+    sunsoft5b: Sunsoft5BAudio,
+}
+
+pub const DRIVER_IDLE: u16 = 0x4100;
+const DRIVER: [u8; 3] = [0x4C, DRIVER_IDLE as u8, (DRIVER_IDLE >> 8) as u8]; // JMP DRIVER_IDLE
+
+// Expansion chips there is an emulation of
+pub const SUPPORTED_CHIPS: SoundChips = SoundChips::FDS
+    .union(SoundChips::NAMCO163)
+    .union(SoundChips::SUNSOFT5B);
+
+impl NsfMapper {
+    pub fn new(nsf: &Nsf) -> Self {
+        let fds = nsf.chips.contains(SoundChips::FDS);
+        let header = Header::nsf(nsf);
+
+        // Bank switched data is laid out in 4KB banks from the load
+        // address's bank; otherwise it sits at its address in $8000-$FFFF
+        let (prg, initial_banks, fds_image) = match nsf.banks {
+            Some(banks) => {
+                let mut prg = vec![0x00; (nsf.load_addr & 0x0FFF) as usize];
+                prg.extend_from_slice(&nsf.data);
+                (prg, banks, None)
+            }
+            None if fds => (Vec::new(), [0; 8], Some((nsf.load_addr, nsf.data.clone()))),
+            None => {
+                let mut prg = vec![0x00; nsf.load_addr.saturating_sub(0x8000) as usize];
+                prg.extend_from_slice(&nsf.data);
+                (prg, [0, 1, 2, 3, 4, 5, 6, 7], None)
+            }
+        };
+
+        let mut mapper = NsfMapper {
+            memory: CartridgeMemory::new(&header, prg, Vec::new()),
+            chips: nsf.chips,
+            initial_banks,
+            banks: initial_banks,
+            fds_image,
+            exram: [0; 0x400],
+            multiplier: [0xFF; 2],
+            fds: FdsAudio::new(),
+            namco163: Namco163Audio::new(),
+            sunsoft5b: Sunsoft5BAudio::new(),
+        };
+        mapper.reset();
+        mapper
+    }
+
+    // Back to the state a track's INIT expects: RAM cleared, banks at their
+    // initial values and the sound chips silent
+    pub fn reset(&mut self) {
+        self.memory.prg_ram.fill(0x00);
+        self.exram.fill(0x00);
+        self.multiplier = [0xFF; 2];
+        self.fds = FdsAudio::new();
+        self.namco163 = Namco163Audio::new();
+        self.sunsoft5b = Sunsoft5BAudio::new();
+
+        if let Some((load_addr, data)) = &self.fds_image {
+            let offset = load_addr.saturating_sub(0x6000) as usize;
+            let len = data
+                .len()
+                .min(self.memory.prg_ram.len().saturating_sub(offset));
+            self.memory.prg_ram[offset..offset + len].copy_from_slice(&data[..len]);
+        } else if self.fds_ram() {
+            // $5FF6/$5FF7 start out with the same banks as $E000/$F000
+            let banks = self.initial_banks;
+            for (slot, bank) in banks.into_iter().enumerate() {
+                self.switch_bank(slot + 2, bank);
+            }
+            self.switch_bank(0, banks[6]);
+            self.switch_bank(1, banks[7]);
+        }
+        self.banks = self.initial_banks;
+    }
+
+    fn fds_ram(&self) -> bool {
+        self.chips.contains(SoundChips::FDS)
+    }
+
+    // slot 0 is $6000, slot 2 is $8000
+    fn switch_bank(&mut self, slot: usize, bank: u8) {
+        if self.fds_ram() {
+            let start = bank as usize * 0x1000;
+            let mut data = [0x00; 0x1000];
+            if let Some(src) = self.memory.prg_rom.get(start..) {
+                let len = src.len().min(0x1000);
+                data[..len].copy_from_slice(&src[..len]);
+            }
+            self.memory.prg_ram[slot * 0x1000..(slot + 1) * 0x1000].copy_from_slice(&data);
+        } else if slot >= 2 {
+            self.banks[slot - 2] = bank;
+        }
+    }
+}
+
+impl Mapper for NsfMapper {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4800..=0x4FFF if self.chips.contains(SoundChips::NAMCO163) => {
+                Some(self.namco163.read_data())
+            }
+            _ => self.cpu_peek(addr),
+        }
+    }
+
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            DRIVER_IDLE..=0x4102 => Some(DRIVER[(addr - DRIVER_IDLE) as usize]),
+            0x4040..=0x4097 if self.chips.contains(SoundChips::FDS) => self.fds.read(addr),
+            0x4800..=0x4FFF if self.chips.contains(SoundChips::NAMCO163) => {
+                Some(self.namco163.peek_data())
+            }
+            0x5205 if self.chips.contains(SoundChips::MMC5) => {
+                Some((self.multiplier[0] as u16 * self.multiplier[1] as u16) as u8)
+            }
+            0x5206 if self.chips.contains(SoundChips::MMC5) => {
+                Some(((self.multiplier[0] as u16 * self.multiplier[1] as u16) >> 8) as u8)
+            }
+            0x5C00..=0x5FF5 if self.chips.contains(SoundChips::MMC5) => {
+                Some(self.exram[(addr - 0x5C00) as usize])
+            }
+            0x6000..=0xFFFF if self.fds_ram() => {
+                self.memory.prg_ram.get((addr - 0x6000) as usize).copied()
+            }
+            0x6000..=0x7FFF => self.memory.read_prg_ram(0, 0x2000, addr),
+            0x8000..=0xFFFF => {
+                let bank = self.banks[((addr - 0x8000) / 0x1000) as usize] as usize;
+                Some(self.memory.read_prg(bank, 0x1000, addr))
+            }
+            _ => None,
+        }
+    }
+
+    // Banks copied into RAM for FDS tunes no longer map back to the ROM
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x8000..=0xFFFF if !self.fds_ram() => {
+                let bank = self.banks[((addr - 0x8000) / 0x1000) as usize] as usize;
+                self.memory.prg_offset(bank, 0x1000, addr)
+            }
+            _ => None,
+        }
+    }
+
+    fn prg_ram_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x6000..=0xFFFF if self.fds_ram() => Some((addr - 0x6000) as usize),
+            0x6000..=0x7FFF => self.memory.prg_ram_offset(0, 0x2000, addr),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> bool {
+        // Sound chip ports overlap RAM and ROM, so feed them first
+        if self.chips.contains(SoundChips::NAMCO163) && (0xF800..=0xFFFF).contains(&addr) {
+            self.namco163.write_address(data);
+        }
+        if self.chips.contains(SoundChips::SUNSOFT5B) {
+            match addr {
+                0xC000..=0xDFFF => self.sunsoft5b.select(data),
+                0xE000..=0xFFFF => self.sunsoft5b.write(data),
+                _ => {}
+            }
+        }
+
+        match addr {
+            0x4040..=0x4097 if self.chips.contains(SoundChips::FDS) => {
+                self.fds.write(addr, data);
+                true
+            }
+            0x4800..=0x4FFF if self.chips.contains(SoundChips::NAMCO163) => {
+                self.namco163.write_data(data);
+                true
+            }
+            0x5205..=0x5206 if self.chips.contains(SoundChips::MMC5) => {
+                self.multiplier[(addr - 0x5205) as usize] = data;
+                true
+            }
+            0x5C00..=0x5FF5 if self.chips.contains(SoundChips::MMC5) => {
+                self.exram[(addr - 0x5C00) as usize] = data;
+                true
+            }
+            0x5FF6..=0x5FF7 if self.fds_ram() => {
+                self.switch_bank((addr - 0x5FF6) as usize, data);
+                true
+            }
+            0x5FF8..=0x5FFF => {
+                self.switch_bank((addr - 0x5FF6) as usize, data);
+                true
+            }
+            0x6000..=0xFFFF if self.fds_ram() => {
+                self.memory.prg_ram[(addr - 0x6000) as usize] = data;
+                true
+            }
+            0x6000..=0x7FFF => self.memory.write_prg_ram(0, 0x2000, addr, data),
+            0x8000..=0xFFFF => true,
+            _ => false,
+        }
+    }
+
+    fn ppu_read(&mut self, _addr: u16) -> Option<u8> {
+        None
+    }
+
+    fn ppu_write(&mut self, _addr: u16, _data: u8) -> bool {
+        false
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        Mirroring::Horizontal
+    }
+
+    fn memory(&self) -> &CartridgeMemory {
+        &self.memory
+    }
+
+    fn memory_mut(&mut self) -> &mut CartridgeMemory {
+        &mut self.memory
+    }
+
+    fn clock(&mut self) {
+        if self.chips.contains(SoundChips::FDS) {
+            self.fds.clock();
+        }
+        if self.chips.contains(SoundChips::NAMCO163) {
+            self.namco163.clock();
+        }
+        if self.chips.contains(SoundChips::SUNSOFT5B) {
+            self.sunsoft5b.clock();
+        }
+    }
+
+    fn expansion_audio(&self) -> ExpansionOutput {
+        let mut output = ExpansionOutput::default();
+        if self.chips.contains(SoundChips::FDS) {
+            output.set(ExpansionChip::Fds, self.fds.output());
+        }
+        if self.chips.contains(SoundChips::NAMCO163) {
+            output.set(ExpansionChip::Namco163, self.namco163.output());
+        }
+        if self.chips.contains(SoundChips::SUNSOFT5B) {
+            output.set(ExpansionChip::Sunsoft5B, self.sunsoft5b.output());
+        }
+        output
+    }
+}
+
+// Every chip is saved whether the tune uses it or not, which keeps the
+// layout fixed
+impl Snapshot for NsfMapper {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.memory.save_state(w);
+        w.bytes(&self.banks);
+        w.bytes(&self.exram);
+        w.bytes(&self.multiplier);
+        self.fds.save_state(w);
+        self.namco163.save_state(w);
+        self.sunsoft5b.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.memory.load_state(r)?;
+        r.bytes_into(&mut self.banks, "PRG banks")?;
+        r.bytes_into(&mut self.exram, "MMC5 ExRAM")?;
+        r.bytes_into(&mut self.multiplier, "MMC5 multiplier")?;
+        self.fds.load_state(r)?;
+        self.namco163.load_state(r)?;
+        self.sunsoft5b.load_state(r)
+    }
+}
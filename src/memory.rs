@@ -0,0 +1,23 @@
+// What a 6502 is wired to. The CPU reaches everything through this, so the
+// same core runs the NES bus or any other board's memory map and devices.
+pub trait Memory {
+    fn read(&mut self, addr: u16) -> u8;
+    fn write(&mut self, addr: u16, data: u8);
+    // What a read would return, without side effects
+    fn peek(&self, addr: u16) -> u8;
+}
+
+// A flat 64K of RAM, for bare 6502 systems and running code in isolation
+impl Memory for [u8; 0x10000] {
+    fn read(&mut self, addr: u16) -> u8 {
+        self[addr as usize]
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        self[addr as usize] = data;
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        self[addr as usize]
+    }
+}
@@ -0,0 +1,222 @@
+use super::{Commands, Frame, Movie, MovieError, zip};
+use crate::controller::Buttons;
+
+// BizHawk's movie format: a zip archive of text files. Header.txt holds
+// "key value" lines, and Input Log.txt one line per frame between [Input]
+// and [/Input], its columns named by the LogKey line: groups split by #,
+// buttons by |. A button that's down is shown by its mnemonic, one that's
+// up by a dot.
+//
+// A movie starting from a save state keeps it in Core.bin, in nes-rs's
+// format, so only nes-rs can play those. RamHashes.txt is ours too; BizHawk
+// ignores files it doesn't know.
+
+const HEADER: &str = "Header.txt";
+const INPUT_LOG: &str = "Input Log.txt";
+const COMMENTS: &str = "Comments.txt";
+const START_STATE: &str = "Core.bin";
+const RAM_HASHES: &str = "RamHashes.txt";
+
+// NesHawk's log key for two gamepads
+const LOG_KEY: [&[(&str, char)]; 3] = [
+    &[("Reset", 'r'), ("Power", 'P')],
+    &[
+        ("P1 Up", 'U'),
+        ("P1 Down", 'D'),
+        ("P1 Left", 'L'),
+        ("P1 Right", 'R'),
+        ("P1 Start", 'S'),
+        ("P1 Select", 's'),
+        ("P1 B", 'B'),
+        ("P1 A", 'A'),
+    ],
+    &[
+        ("P2 Up", 'U'),
+        ("P2 Down", 'D'),
+        ("P2 Left", 'L'),
+        ("P2 Right", 'R'),
+        ("P2 Start", 'S'),
+        ("P2 Select", 's'),
+        ("P2 B", 'B'),
+        ("P2 A", 'A'),
+    ],
+];
+
+// Sets a button named in the log key on a frame. Anything else in the log
+// (other controllers, the FDS buttons) is ignored.
+fn press(frame: &mut Frame, name: &str) {
+    let (port, button) = match name.split_once(' ') {
+        Some(("P1", button)) => (0, button),
+        Some(("P2", button)) => (1, button),
+        _ => {
+            match name {
+                "Reset" => frame.commands |= Commands::RESET,
+                "Power" => frame.commands |= Commands::POWER,
+                _ => {}
+            }
+            return;
+        }
+    };
+    frame.buttons[port] |= match button {
+        "Up" => Buttons::UP,
+        "Down" => Buttons::DOWN,
+        "Left" => Buttons::LEFT,
+        "Right" => Buttons::RIGHT,
+        "Start" => Buttons::START,
+        "Select" => Buttons::SELECT,
+        "B" => Buttons::B,
+        "A" => Buttons::A,
+        _ => Buttons::empty(),
+    };
+}
+
+fn is_pressed(frame: &Frame, name: &str) -> bool {
+    let mut pressed = Frame::default();
+    press(&mut pressed, name);
+    frame.commands.contains(pressed.commands)
+        && frame.buttons[0].contains(pressed.buttons[0])
+        && frame.buttons[1].contains(pressed.buttons[1])
+}
+
+pub fn parse(data: &[u8]) -> Result<Movie, MovieError> {
+    let files = zip::read(data)?;
+    let file = |name: &str| {
+        files
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, contents)| contents.as_slice())
+    };
+    let text = |name: &str| file(name).map(String::from_utf8_lossy);
+
+    let mut movie = Movie::default();
+    let header = text(HEADER).ok_or(MovieError::Archive("no Header.txt"))?;
+    for (i, line) in header.lines().enumerate() {
+        let (key, value) = line.trim_end().split_once(' ').unwrap_or((line, ""));
+        match key {
+            "Platform" if value != "NES" => return Err(MovieError::Unsupported("platform")),
+            "Author" => movie.author = value.to_string(),
+            "GameName" => movie.rom_name = value.to_string(),
+            "SHA1" => movie.rom_sha1 = Some(value.to_uppercase()),
+            "rerecordCount" => {
+                movie.rerecords = value.parse().map_err(|_| MovieError::Malformed(i + 1))?
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(comments) = text(COMMENTS) {
+        movie.comments = comments.lines().map(str::to_string).collect();
+    }
+    movie.start_state = file(START_STATE).map(<[u8]>::to_vec);
+
+    if let Some(hashes) = text(RAM_HASHES) {
+        for (i, line) in hashes.lines().enumerate() {
+            let malformed = || MovieError::Malformed(i + 1);
+            let (frame, crc) = line.split_once(' ').ok_or_else(malformed)?;
+            let frame = frame.parse().map_err(|_| malformed())?;
+            let crc = u32::from_str_radix(crc.trim(), 16).map_err(|_| malformed())?;
+            movie.ram_hashes.push((frame, crc));
+        }
+    }
+
+    let log = text(INPUT_LOG).ok_or(MovieError::Archive("no Input Log.txt"))?;
+    let mut key: Vec<Vec<String>> = LOG_KEY
+        .iter()
+        .map(|group| group.iter().map(|(name, _)| name.to_string()).collect())
+        .collect();
+    for (i, line) in log.lines().enumerate() {
+        let line = line.trim_end();
+        if let Some(names) = line.strip_prefix("LogKey:") {
+            key = names
+                .split('#')
+                .filter(|group| !group.is_empty())
+                .map(|group| {
+                    group
+                        .split('|')
+                        .filter(|name| !name.is_empty())
+                        .map(str::to_string)
+                        .collect()
+                })
+                .collect();
+            continue;
+        }
+        let Some(columns) = line.strip_prefix('|') else {
+            continue;
+        };
+
+        let mut frame = Frame::default();
+        let mut groups = columns.split('|');
+        for names in &key {
+            let group = groups.next().ok_or(MovieError::Malformed(i + 1))?;
+            for (c, name) in group.chars().zip(names) {
+                if c != '.' {
+                    press(&mut frame, name);
+                }
+            }
+        }
+        movie.frames.push(frame);
+    }
+    Ok(movie)
+}
+
+pub fn write(movie: &Movie) -> Vec<u8> {
+    let mut header = vec![
+        "MovieVersion BizHawk v2.0.0".to_string(),
+        format!("Author {}", movie.author),
+        "Platform NES".to_string(),
+        format!("GameName {}", movie.rom_name),
+        "Core NesHawk".to_string(),
+        format!("rerecordCount {}", movie.rerecords),
+    ];
+    if let Some(sha1) = &movie.rom_sha1 {
+        header.push(format!("SHA1 {sha1}"));
+    }
+    if movie.start_state.is_some() {
+        header.push("StartsFromSavestate True".to_string());
+    }
+    header.push(String::new());
+    let header = header.join("\n");
+
+    let mut log = String::from("[Input]\nLogKey:");
+    for group in LOG_KEY {
+        log.push('#');
+        for (name, _) in group {
+            log.push_str(name);
+            log.push('|');
+        }
+    }
+    log.push('\n');
+    for frame in &movie.frames {
+        log.push('|');
+        for group in LOG_KEY {
+            for (name, mnemonic) in group {
+                log.push(if is_pressed(frame, name) {
+                    *mnemonic
+                } else {
+                    '.'
+                });
+            }
+            log.push('|');
+        }
+        log.push('\n');
+    }
+    log.push_str("[/Input]\n");
+
+    let comments: String = movie.comments.iter().map(|c| format!("{c}\n")).collect();
+    let hashes: String = movie
+        .ram_hashes
+        .iter()
+        .map(|(frame, crc)| format!("{frame} {crc:08X}\n"))
+        .collect();
+
+    let mut files = vec![
+        (HEADER, header.as_bytes()),
+        (INPUT_LOG, log.as_bytes()),
+        (COMMENTS, comments.as_bytes()),
+        (RAM_HASHES, hashes.as_bytes()),
+    ];
+    if let Some(state) = &movie.start_state {
+        files.push((START_STATE, state));
+    }
+    zip::write(&files)
+}
@@ -0,0 +1,222 @@
+use super::{Commands, Frame, Movie, MovieError};
+use crate::controller::Buttons;
+use crate::hash::{self, Md5};
+
+// FCEUX's text movie format: "key value" header lines, then one line per
+// frame of |commands|port 0|port 1|port 2|. Gamepads are written RLDUTSBA,
+// with a space or . for a button that's up.
+//
+// Save states and RAM hashes are nes-rs's own. FCEUX ignores the ramHash
+// key, but can't load a savestate it didn't write.
+
+const PAD: [(char, Buttons); 8] = [
+    ('R', Buttons::RIGHT),
+    ('L', Buttons::LEFT),
+    ('D', Buttons::DOWN),
+    ('U', Buttons::UP),
+    ('T', Buttons::START),
+    ('S', Buttons::SELECT),
+    ('B', Buttons::B),
+    ('A', Buttons::A),
+];
+
+// What's plugged into port0 and port1
+const PORT_NONE: u8 = 0;
+const PORT_GAMEPAD: u8 = 1;
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn parse(text: &str) -> Result<Movie, MovieError> {
+    let mut movie = Movie::default();
+    let mut ports = [PORT_GAMEPAD; 2];
+
+    for (i, line) in text.lines().enumerate() {
+        let malformed = || MovieError::Malformed(i + 1);
+        let line = line.trim_end_matches('\r');
+
+        if let Some(fields) = line.strip_prefix('|') {
+            let mut fields = fields.split('|');
+            let commands = fields.next().ok_or_else(malformed)?;
+            let commands = commands.trim().parse().map_err(|_| malformed())?;
+            let mut frame = Frame {
+                commands: Commands::from_bits_retain(commands),
+                ..Frame::default()
+            };
+            for (port, kind) in ports.iter().enumerate() {
+                let field = fields.next().ok_or_else(malformed)?;
+                if *kind == PORT_GAMEPAD {
+                    frame.buttons[port] = parse_pad(field).ok_or_else(malformed)?;
+                }
+            }
+            movie.frames.push(frame);
+            continue;
+        }
+
+        let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+        match key {
+            "version" if value != "3" => return Err(MovieError::Unsupported("FM2 version")),
+            "binary" if value != "0" => return Err(MovieError::Unsupported("binary FM2")),
+            "fourscore" if value != "0" => return Err(MovieError::Unsupported("Four Score")),
+            "rerecordCount" => movie.rerecords = value.parse().map_err(|_| malformed())?,
+            "romFilename" => movie.rom_name = value.to_string(),
+            "romChecksum" => {
+                let md5 = value
+                    .strip_prefix("base64:")
+                    .and_then(base64_decode)
+                    .ok_or_else(malformed)?;
+                movie.rom_md5 = Some(md5.try_into().map_err(|_| malformed())?);
+            }
+            "port0" | "port1" => {
+                let kind = value.parse().map_err(|_| malformed())?;
+                if kind != PORT_NONE && kind != PORT_GAMEPAD {
+                    return Err(MovieError::Unsupported("input device other than a gamepad"));
+                }
+                ports[(key == "port1") as usize] = kind;
+            }
+            "comment" => match value.strip_prefix("author ") {
+                Some(author) => movie.author = author.to_string(),
+                None => movie.comments.push(value.to_string()),
+            },
+            "savestate" => {
+                let state = value.strip_prefix("base64:").and_then(base64_decode);
+                movie.start_state = Some(state.ok_or_else(malformed)?);
+            }
+            "ramHash" => {
+                for hash in value.split_whitespace() {
+                    let (frame, crc) = hash.split_once(':').ok_or_else(malformed)?;
+                    let frame = frame.parse().map_err(|_| malformed())?;
+                    let crc = u32::from_str_radix(crc, 16).map_err(|_| malformed())?;
+                    movie.ram_hashes.push((frame, crc));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(movie)
+}
+
+fn parse_pad(field: &str) -> Option<Buttons> {
+    if field.is_empty() {
+        return Some(Buttons::empty());
+    }
+    if field.chars().count() != PAD.len() {
+        return None;
+    }
+    let mut buttons = Buttons::empty();
+    for (c, (_, button)) in field.chars().zip(PAD) {
+        if c != ' ' && c != '.' {
+            buttons |= button;
+        }
+    }
+    Some(buttons)
+}
+
+pub fn write(movie: &Movie) -> String {
+    let mut lines = vec![
+        "version 3".to_string(),
+        format!("rerecordCount {}", movie.rerecords),
+        "palFlag 0".to_string(),
+        format!("romFilename {}", movie.rom_name),
+    ];
+    if let Some(md5) = &movie.rom_md5 {
+        lines.push(format!("romChecksum base64:{}", base64_encode(md5)));
+    }
+    lines.push(format!("guid {}", guid(movie)));
+    lines.push("fourscore 0".to_string());
+    lines.push("microphone 0".to_string());
+    lines.push(format!("port0 {PORT_GAMEPAD}"));
+    lines.push(format!("port1 {PORT_GAMEPAD}"));
+    lines.push("port2 0".to_string());
+    lines.push("FDS 0".to_string());
+    lines.push("NewPPU 0".to_string());
+    if !movie.author.is_empty() {
+        lines.push(format!("comment author {}", movie.author));
+    }
+    for comment in &movie.comments {
+        lines.push(format!("comment {comment}"));
+    }
+    if let Some(state) = &movie.start_state {
+        lines.push(format!("savestate base64:{}", base64_encode(state)));
+    }
+    if !movie.ram_hashes.is_empty() {
+        let hashes: Vec<String> = movie
+            .ram_hashes
+            .iter()
+            .map(|(frame, crc)| format!("{frame}:{crc:08X}"))
+            .collect();
+        lines.push(format!("ramHash {}", hashes.join(" ")));
+    }
+
+    for frame in &movie.frames {
+        let mut line = format!("|{}|", frame.commands.bits());
+        for buttons in frame.buttons {
+            for (c, button) in PAD {
+                line.push(if buttons.contains(button) { c } else { '.' });
+            }
+            line.push('|');
+        }
+        line.push('|');
+        lines.push(line);
+    }
+
+    lines.push(String::new());
+    lines.join("\n")
+}
+
+// FCEUX wants a GUID to tell movies apart. There's no randomness to hand,
+// so it's made from the movie's ROM and input, which is as unique as the
+// movie is.
+fn guid(movie: &Movie) -> String {
+    let mut md5 = Md5::new();
+    md5.update(movie.rom_md5.as_ref().map_or(&[][..], |md5| &md5[..]));
+    for frame in &movie.frames {
+        md5.update(&[
+            frame.commands.bits(),
+            frame.buttons[0].bits(),
+            frame.buttons[1].bits(),
+        ]);
+    }
+    let hex = hash::hex(&md5.finish());
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |bits, (i, &b)| bits | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(bits >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut bits = 0u32;
+    let mut count = 0;
+    for c in text.trim().bytes().take_while(|&c| c != b'=') {
+        let value = BASE64.iter().position(|&b| b == c)? as u32;
+        bits = (bits << 6) | value;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+    Some(out)
+}
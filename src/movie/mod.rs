@@ -0,0 +1,542 @@
+mod bk2;
+mod fm2;
+mod zip;
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use bitflags::bitflags;
+
+use crate::bus::Bus;
+use crate::cartridge::Cartridge;
+use crate::console::Console;
+use crate::controller::Buttons;
+use crate::deflate::DeflateError;
+use crate::hash::{self, Crc32, Md5, Sha1};
+use crate::state::{State, StateError, StateReader, StateWriter};
+
+// Internal RAM is checksummed this often while recording, so playback can
+// tell close to where it went wrong when it desyncs
+pub const RAM_HASH_INTERVAL: u64 = 60;
+
+#[derive(Debug)]
+pub enum MovieError {
+    Io(io::Error),
+    // Not an .fm2 or .bk2 file
+    UnknownFormat(String),
+    // A line that couldn't be parsed, numbered from 1
+    Malformed(usize),
+    Unsupported(&'static str),
+    Archive(&'static str),
+    Deflate(DeflateError),
+    State(StateError),
+    // Recorded for a ROM other than the one inserted
+    WrongRom,
+    // Live input or a branch while playing back read-only
+    ReadOnly,
+    // A save state from a different timeline than the movie in read-only
+    // mode
+    WrongTimeline,
+    // Playback no longer matches what was recorded
+    Desync {
+        frame: u64,
+        expected: u32,
+        actual: u32,
+    },
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MovieError::Io(e) => write!(f, "i/o error: {e}"),
+            MovieError::UnknownFormat(name) => write!(f, "unknown movie format: {name}"),
+            MovieError::Malformed(line) => write!(f, "malformed movie at line {line}"),
+            MovieError::Unsupported(what) => write!(f, "unsupported movie feature: {what}"),
+            MovieError::Archive(what) => write!(f, "corrupt movie archive: {what}"),
+            MovieError::Deflate(e) => write!(f, "corrupt movie archive: {e}"),
+            MovieError::State(e) => write!(f, "{e}"),
+            MovieError::WrongRom => write!(f, "movie was recorded with a different ROM"),
+            MovieError::ReadOnly => write!(f, "movie is playing back read-only"),
+            MovieError::WrongTimeline => {
+                write!(f, "save state is not from this movie's timeline")
+            }
+            MovieError::Desync {
+                frame,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "desync at frame {frame}: RAM hash {actual:08X}, recorded {expected:08X}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+impl From<io::Error> for MovieError {
+    fn from(e: io::Error) -> Self {
+        MovieError::Io(e)
+    }
+}
+
+impl From<DeflateError> for MovieError {
+    fn from(e: DeflateError) -> Self {
+        MovieError::Deflate(e)
+    }
+}
+
+impl From<StateError> for MovieError {
+    fn from(e: StateError) -> Self {
+        MovieError::State(e)
+    }
+}
+
+bitflags! {
+    // Numbered as in FM2's command column
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct Commands: u8 {
+        const RESET = (1 << 0);
+        const POWER = (1 << 1);
+    }
+}
+
+// What happens on one frame: console buttons pressed before it starts, and
+// the pads held through it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Frame {
+    pub commands: Commands,
+    pub buttons: [Buttons; 2],
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Movie {
+    pub author: String,
+    pub rom_name: String,
+    // FM2 files name the ROM by MD5 and BK2 files by SHA-1
+    pub rom_md5: Option<[u8; 16]>,
+    pub rom_sha1: Option<String>,
+    pub rerecords: u32,
+    pub comments: Vec<String>,
+    // A save state to start from instead of power on
+    pub start_state: Option<Vec<u8>>,
+    pub frames: Vec<Frame>,
+    // CRC-32s of internal RAM, by the frame they were taken after
+    pub ram_hashes: Vec<(u64, u32)>,
+}
+
+impl Movie {
+    pub fn new(cart: &Cartridge, rom_name: &str) -> Self {
+        let (prg, chr) = cart.rom();
+        let mut md5 = Md5::new();
+        let mut sha1 = Sha1::new();
+        for data in [prg, chr] {
+            md5.update(data);
+            sha1.update(data);
+        }
+        Movie {
+            rom_name: rom_name.to_string(),
+            rom_md5: Some(md5.finish()),
+            rom_sha1: Some(hash::hex(&sha1.finish())),
+            ..Movie::default()
+        }
+    }
+
+    // The format is picked from the contents, as BK2s are zip archives
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, MovieError> {
+        let data = fs::read(path.as_ref())?;
+        if data.starts_with(zip::MAGIC) {
+            bk2::parse(&data)
+        } else if data.starts_with(b"version 3") {
+            fm2::parse(&String::from_utf8_lossy(&data))
+        } else {
+            Err(MovieError::UnknownFormat(
+                path.as_ref().display().to_string(),
+            ))
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), MovieError> {
+        let path = path.as_ref();
+        let data = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("fm2") => fm2::write(self).into_bytes(),
+            Some("bk2") => bk2::write(self),
+            _ => return Err(MovieError::UnknownFormat(path.display().to_string())),
+        };
+        fs::write(path, data)?;
+        Ok(())
+    }
+
+    pub fn len(&self) -> u64 {
+        self.frames.len() as u64
+    }
+
+    // Movies from elsewhere may name the ROM by either hash, or neither
+    pub fn matches(&self, cart: &Cartridge) -> bool {
+        let other = Movie::new(cart, "");
+        self.rom_md5.is_none_or(|md5| other.rom_md5 == Some(md5))
+            && self
+                .rom_sha1
+                .as_ref()
+                .is_none_or(|sha1| other.rom_sha1.as_ref() == Some(sha1))
+    }
+
+    fn ram_hash_at(&self, frame: u64) -> Option<u32> {
+        self.ram_hashes
+            .iter()
+            .find(|(f, _)| *f == frame)
+            .map(|(_, hash)| *hash)
+    }
+}
+
+// A movie being recorded or played back, FCEUX style. Read-only decides
+// what live input and loading a save state do: in read-only mode the movie
+// carries on playing, from the state's frame if one is loaded; otherwise
+// the movie is cut back to the current frame and recording resumes from
+// there, starting a new branch.
+pub struct Session {
+    pub movie: Movie,
+    pub read_only: bool,
+    recording: bool,
+    frame: u64,
+}
+
+impl Session {
+    // Powers on (or loads the movie's start state) and records from there
+    pub fn record(console: &mut Console, movie: Movie) -> Result<Self, MovieError> {
+        Session::start(console, &movie)?;
+        Ok(Session {
+            movie,
+            read_only: false,
+            recording: true,
+            frame: 0,
+        })
+    }
+
+    pub fn play(console: &mut Console, movie: Movie, read_only: bool) -> Result<Self, MovieError> {
+        if let Some(cart) = console.bus.cartridge()
+            && !movie.matches(cart)
+        {
+            return Err(MovieError::WrongRom);
+        }
+        Session::start(console, &movie)?;
+        Ok(Session {
+            movie,
+            read_only,
+            recording: false,
+            frame: 0,
+        })
+    }
+
+    fn start(console: &mut Console, movie: &Movie) -> Result<(), MovieError> {
+        match &movie.start_state {
+            Some(state) => console.load_state(state)?,
+            None => console.power_cycle(),
+        }
+        Ok(())
+    }
+
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    // Runs one frame. While recording, input is what's held and goes into
+    // the movie; during playback the movie's input is used instead. Returns
+    // false once playback has run out of movie.
+    pub fn run_frame(&mut self, console: &mut Console, input: Frame) -> Result<bool, MovieError> {
+        let frame = if self.recording {
+            self.cut();
+            self.movie.frames.push(input);
+            input
+        } else {
+            match self.movie.frames.get(self.frame as usize) {
+                Some(frame) => *frame,
+                None => return Ok(false),
+            }
+        };
+
+        if frame.commands.contains(Commands::POWER) {
+            console.power_cycle();
+        } else if frame.commands.contains(Commands::RESET) {
+            console.reset();
+        }
+        for (port, buttons) in frame.buttons.iter().enumerate() {
+            console.bus.set_buttons(port, *buttons);
+        }
+        console.run_frame();
+        self.frame += 1;
+
+        let actual = ram_hash(&console.bus);
+        if self.recording {
+            if self.frame.is_multiple_of(RAM_HASH_INTERVAL) {
+                self.movie.ram_hashes.push((self.frame, actual));
+            }
+        } else if let Some(expected) = self.movie.ram_hash_at(self.frame)
+            && expected != actual
+        {
+            return Err(MovieError::Desync {
+                frame: self.frame,
+                expected,
+                actual,
+            });
+        }
+        Ok(true)
+    }
+
+    // Drops the input and RAM hashes after the current frame, where a new
+    // branch starts
+    fn cut(&mut self) {
+        let frame = self.frame;
+        self.movie.frames.truncate(frame as usize);
+        self.movie.ram_hashes.retain(|(f, _)| *f <= frame);
+    }
+
+    // Ends the session, noting the RAM hash at the end of a recording so
+    // playback is checked right up to the last frame
+    pub fn finish(mut self, console: &Console) -> Movie {
+        if self.recording && self.movie.ram_hash_at(self.frame).is_none() {
+            self.movie
+                .ram_hashes
+                .push((self.frame, ram_hash(&console.bus)));
+        }
+        self.movie
+    }
+}
+
+// For frontends that feed live input. The command line only plays and
+// re-records whole movies.
+#[allow(dead_code)]
+impl Session {
+    pub fn is_recording(&self) -> bool {
+        self.recording
+    }
+
+    // Live input arrived during read-write playback: the rest of the movie
+    // is dropped, and what's held from here on is recorded
+    pub fn take_over(&mut self) -> Result<(), MovieError> {
+        if self.recording {
+            return Ok(());
+        }
+        if self.read_only {
+            return Err(MovieError::ReadOnly);
+        }
+        self.cut();
+        self.movie.rerecords += 1;
+        self.recording = true;
+        Ok(())
+    }
+
+    // A save state that also holds the movie's input up to now, so it can
+    // be checked against or branched from when loaded back
+    pub fn save_state(&self, console: &Console) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        console.write_state(&mut w);
+        w.chunk(*b"MOVI", |w| {
+            w.u64(self.frame);
+            for frame in &self.movie.frames[..self.frame as usize] {
+                w.u8(frame.commands.bits());
+                w.u8(frame.buttons[0].bits());
+                w.u8(frame.buttons[1].bits());
+            }
+        });
+        w.finish()
+    }
+
+    pub fn load_state(&mut self, console: &mut Console, data: &[u8]) -> Result<(), MovieError> {
+        let state = State::parse(data)?;
+        let (frame, frames) = read_input(&mut state.chunk(*b"MOVI")?)?;
+
+        if self.read_only && !self.movie.frames.starts_with(&frames) {
+            return Err(MovieError::WrongTimeline);
+        }
+        console.read_state(&state)?;
+
+        self.frame = frame;
+        if self.read_only {
+            self.recording = false;
+        } else {
+            self.movie.frames = frames;
+            self.cut();
+            self.movie.rerecords += 1;
+            self.recording = true;
+        }
+        Ok(())
+    }
+}
+
+fn read_input(r: &mut StateReader) -> Result<(u64, Vec<Frame>), StateError> {
+    let frame = r.u64()?;
+    let mut frames = Vec::new();
+    for _ in 0..frame {
+        let commands = Commands::from_bits_retain(r.u8()?);
+        let buttons = [
+            Buttons::from_bits_retain(r.u8()?),
+            Buttons::from_bits_retain(r.u8()?),
+        ];
+        frames.push(Frame { commands, buttons });
+    }
+    Ok((frame, frames))
+}
+
+// The console's 2K of internal RAM
+pub fn ram_hash(bus: &Bus) -> u32 {
+    let mut crc = Crc32::new();
+    for addr in 0x0000..0x0800 {
+        crc.update(&[bus.peek(addr)]);
+    }
+    crc.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Counts frames with A held into RAM, alongside a counter kept in PRG
+    // RAM that only a power cycle clears
+    const PROGRAM: &[u8] = &[
+        0xA9, 0x01, 0x8D, 0x16, 0x40, // LDA #1; STA $4016
+        0xA9, 0x00, 0x8D, 0x16, 0x40, // LDA #0; STA $4016
+        0xAD, 0x16, 0x40, 0x29, 0x01, // LDA $4016; AND #1
+        0x18, 0x65, 0x10, 0x85, 0x10, // CLC; ADC $10; STA $10
+        0xEE, 0x00, 0x60, // INC $6000
+        0xAD, 0x00, 0x60, 0x8D, 0x00, 0x03, // LDA $6000; STA $0300
+        0x4C, 0x00, 0x80, // JMP $8000
+    ];
+
+    fn record() -> Movie {
+        let cart = Cartridge::nrom(PROGRAM);
+        let movie = Movie::new(&cart, "test");
+        let mut console = Console::with_cartridge(cart);
+        let mut session = Session::record(&mut console, movie).unwrap();
+        for i in 0..130u64 {
+            let mut frame = Frame::default();
+            if i.is_multiple_of(3) {
+                frame.buttons[0] = Buttons::A;
+            }
+            if i == 70 {
+                frame.commands = Commands::POWER;
+            }
+            session.run_frame(&mut console, frame).unwrap();
+        }
+        session.finish(&console)
+    }
+
+    fn new_console() -> Console {
+        Console::with_cartridge(Cartridge::nrom(PROGRAM))
+    }
+
+    fn play(movie: Movie) -> Result<u64, MovieError> {
+        let mut console = new_console();
+        let mut session = Session::play(&mut console, movie, true)?;
+        while session.run_frame(&mut console, Frame::default())? {}
+        Ok(session.frame())
+    }
+
+    #[test]
+    fn recordings_play_back_through_both_formats() {
+        let movie = record();
+        assert_eq!(movie.ram_hashes.len(), 3);
+
+        let dir = std::env::temp_dir().join(format!("nes-rs-movie-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in ["test.fm2", "test.bk2"] {
+            let path = dir.join(name);
+            movie.save(&path).unwrap();
+            let loaded = Movie::load(&path).unwrap();
+            assert_eq!(loaded.frames, movie.frames, "{name}");
+            assert_eq!(loaded.ram_hashes, movie.ram_hashes, "{name}");
+            assert_eq!(play(loaded).unwrap(), 130, "{name}");
+        }
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut altered = movie.clone();
+        altered.frames[99].buttons[0] = Buttons::empty();
+        assert!(matches!(
+            play(altered),
+            Err(MovieError::Desync { frame: 120, .. })
+        ));
+    }
+
+    fn hash_frames(movie: &Movie) -> Vec<u64> {
+        movie.ram_hashes.iter().map(|(frame, _)| *frame).collect()
+    }
+
+    #[test]
+    fn live_input_takes_over_read_write_playback() {
+        let movie = record();
+        let mut console = new_console();
+        let mut session = Session::play(&mut console, movie.clone(), false).unwrap();
+        for _ in 0..50 {
+            session.run_frame(&mut console, Frame::default()).unwrap();
+        }
+        session.take_over().unwrap();
+        assert!(session.is_recording());
+        let held = Frame {
+            buttons: [Buttons::A, Buttons::empty()],
+            ..Frame::default()
+        };
+        for _ in 0..30 {
+            session.run_frame(&mut console, held).unwrap();
+        }
+        let branch = session.finish(&console);
+
+        assert_eq!(branch.len(), 80);
+        assert_eq!(branch.frames[..50], movie.frames[..50]);
+        assert!(branch.frames[50..].iter().all(|frame| *frame == held));
+        assert_eq!(branch.rerecords, 1);
+        assert_eq!(hash_frames(&branch), [60, 80]);
+        assert_ne!(branch.ram_hashes[0], movie.ram_hashes[0]);
+        assert_eq!(play(branch).unwrap(), 80);
+
+        let mut console = new_console();
+        let mut session = Session::play(&mut console, movie, true).unwrap();
+        assert!(matches!(session.take_over(), Err(MovieError::ReadOnly)));
+        assert!(!session.is_recording());
+    }
+
+    #[test]
+    fn save_states_branch_in_read_write_mode() {
+        let movie = record();
+        let mut console = new_console();
+        let mut session = Session::play(&mut console, movie.clone(), false).unwrap();
+        for _ in 0..65 {
+            session.run_frame(&mut console, Frame::default()).unwrap();
+        }
+        let state = session.save_state(&console);
+        while session.run_frame(&mut console, Frame::default()).unwrap() {}
+        assert_eq!(session.frame(), 130);
+
+        session.load_state(&mut console, &state).unwrap();
+        assert_eq!(session.frame(), 65);
+        assert!(session.is_recording());
+        assert_eq!(session.movie.frames, movie.frames[..65]);
+        assert_eq!(session.movie.ram_hashes, movie.ram_hashes[..1]);
+        assert_eq!(session.movie.rerecords, 1);
+        for _ in 0..10 {
+            session.run_frame(&mut console, Frame::default()).unwrap();
+        }
+        let other_timeline = session.save_state(&console);
+        let branch = session.finish(&console);
+        assert_eq!(branch.len(), 75);
+        assert_eq!(hash_frames(&branch), [60, 75]);
+        assert_eq!(play(branch).unwrap(), 75);
+
+        // Read-only, a state from the movie's own timeline carries playback
+        // on from its frame, and one from a branch is refused
+        let mut console = new_console();
+        let mut session = Session::play(&mut console, movie.clone(), true).unwrap();
+        assert!(matches!(
+            session.load_state(&mut console, &other_timeline),
+            Err(MovieError::WrongTimeline)
+        ));
+        session.load_state(&mut console, &state).unwrap();
+        assert_eq!(session.frame(), 65);
+        assert!(!session.is_recording());
+        while session.run_frame(&mut console, Frame::default()).unwrap() {}
+        assert_eq!(session.frame(), 130);
+        assert_eq!(session.movie.frames, movie.frames);
+        assert_eq!(session.movie.rerecords, 0);
+    }
+}
@@ -0,0 +1,140 @@
+use crate::deflate;
+use crate::hash::Crc32;
+
+use super::MovieError;
+
+// Just enough of the zip format for BK2 movies: reading stored and deflated
+// entries through the central directory, and writing stored ones.
+
+pub const MAGIC: &[u8; 4] = b"PK\x03\x04";
+const CENTRAL_MAGIC: &[u8; 4] = b"PK\x01\x02";
+const END_MAGIC: &[u8; 4] = b"PK\x05\x06";
+
+const END_SIZE: usize = 22;
+const CENTRAL_SIZE: usize = 46;
+const LOCAL_SIZE: usize = 30;
+
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+
+// Needed to extract: 2.0
+const VERSION: u16 = 20;
+// 1980-01-01, the earliest DOS date
+const DATE: u16 = 0x0021;
+
+fn u16_at(data: &[u8], pos: usize) -> Result<u16, MovieError> {
+    data.get(pos..pos + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or(MovieError::Archive("truncated"))
+}
+
+fn u32_at(data: &[u8], pos: usize) -> Result<u32, MovieError> {
+    data.get(pos..pos + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or(MovieError::Archive("truncated"))
+}
+
+// Every file in the archive, by name
+pub fn read(data: &[u8]) -> Result<Vec<(String, Vec<u8>)>, MovieError> {
+    // The end record sits after everything but its own trailing comment
+    let end = (0..=data.len().saturating_sub(END_SIZE))
+        .rev()
+        .find(|&pos| data[pos..].starts_with(END_MAGIC))
+        .ok_or(MovieError::Archive("no end of central directory"))?;
+    let count = u16_at(data, end + 10)?;
+    let mut pos = u32_at(data, end + 16)? as usize;
+
+    let mut files = Vec::new();
+    for _ in 0..count {
+        if !data[pos.min(data.len())..].starts_with(CENTRAL_MAGIC) {
+            return Err(MovieError::Archive("bad central directory entry"));
+        }
+        let method = u16_at(data, pos + 10)?;
+        let crc = u32_at(data, pos + 16)?;
+        let compressed_size = u32_at(data, pos + 20)? as usize;
+        let name_len = u16_at(data, pos + 28)? as usize;
+        let extra_len = u16_at(data, pos + 30)? as usize;
+        let comment_len = u16_at(data, pos + 32)? as usize;
+        let local = u32_at(data, pos + 42)? as usize;
+        let name = data
+            .get(pos + CENTRAL_SIZE..pos + CENTRAL_SIZE + name_len)
+            .ok_or(MovieError::Archive("truncated"))?;
+        let name = String::from_utf8_lossy(name).into_owned();
+        pos += CENTRAL_SIZE + name_len + extra_len + comment_len;
+
+        // The local header's own name and extra field lengths can differ
+        // from the central directory's
+        if !data[local.min(data.len())..].starts_with(MAGIC) {
+            return Err(MovieError::Archive("bad local header"));
+        }
+        let start = local
+            + LOCAL_SIZE
+            + u16_at(data, local + 26)? as usize
+            + u16_at(data, local + 28)? as usize;
+        let compressed = data
+            .get(start..start + compressed_size)
+            .ok_or(MovieError::Archive("truncated"))?;
+
+        let contents = match method {
+            STORED => compressed.to_vec(),
+            DEFLATED => deflate::inflate(compressed)?,
+            _ => return Err(MovieError::Archive("unsupported compression method")),
+        };
+        let mut check = Crc32::new();
+        check.update(&contents);
+        if check.finish() != crc {
+            return Err(MovieError::Archive("checksum mismatch"));
+        }
+        files.push((name, contents));
+    }
+    Ok(files)
+}
+
+// An archive of the files uncompressed
+pub fn write(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut central = Vec::new();
+    for (name, contents) in files {
+        let mut crc = Crc32::new();
+        crc.update(contents);
+        let crc = crc.finish();
+        let offset = out.len() as u32;
+
+        // The fields local and central headers have in common
+        let mut common = Vec::new();
+        common.extend_from_slice(&VERSION.to_le_bytes());
+        common.extend_from_slice(&0u16.to_le_bytes()); // flags
+        common.extend_from_slice(&STORED.to_le_bytes());
+        common.extend_from_slice(&0u16.to_le_bytes()); // time
+        common.extend_from_slice(&DATE.to_le_bytes());
+        common.extend_from_slice(&crc.to_le_bytes());
+        common.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+        common.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+        common.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        common.extend_from_slice(&0u16.to_le_bytes()); // extra field length
+
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&common);
+        out.extend_from_slice(name.as_bytes());
+        out.extend_from_slice(contents);
+
+        central.extend_from_slice(CENTRAL_MAGIC);
+        central.extend_from_slice(&VERSION.to_le_bytes()); // made by
+        central.extend_from_slice(&common);
+        central.extend_from_slice(&[0; 6]); // comment length, disk, internal attributes
+        central.extend_from_slice(&0u32.to_le_bytes()); // external attributes
+        central.extend_from_slice(&offset.to_le_bytes());
+        central.extend_from_slice(name.as_bytes());
+    }
+
+    let central_offset = out.len() as u32;
+    out.extend_from_slice(&central);
+    out.extend_from_slice(END_MAGIC);
+    out.extend_from_slice(&[0; 4]); // disk numbers
+    out.extend_from_slice(&(files.len() as u16).to_le_bytes());
+    out.extend_from_slice(&(files.len() as u16).to_le_bytes());
+    out.extend_from_slice(&(central.len() as u32).to_le_bytes());
+    out.extend_from_slice(&central_offset.to_le_bytes());
+    out.extend_from_slice(&0u16.to_le_bytes()); // comment length
+    out
+}
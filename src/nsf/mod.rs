@@ -0,0 +1,580 @@
+mod player;
+
+pub use player::Player;
+
+use std::time::Duration;
+use std::{fmt, fs, io, path::Path};
+
+use bitflags::bitflags;
+
+// NSF music rips and their chunked NSFe successor. Both come down to the
+// same thing: a blob of 6502 code and data, where to load it, how to bank
+// it, and the INIT and PLAY routines a player calls to drive it. NSFe (and
+// NSF2's optional trailer) adds track names, durations and a playlist.
+
+bitflags! {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct SoundChips: u8 {
+        const VRC6      = (1 << 0);
+        const VRC7      = (1 << 1);
+        const FDS       = (1 << 2);
+        const MMC5      = (1 << 3);
+        const NAMCO163  = (1 << 4);
+        const SUNSOFT5B = (1 << 5);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Region {
+    Ntsc,
+    Pal,
+}
+
+impl Region {
+    pub fn cpu_clock(self) -> u64 {
+        match self {
+            Region::Ntsc => 1_789_773,
+            Region::Pal => 1_662_607,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum NsfError {
+    Io(io::Error),
+    InvalidHeader,
+    Truncated,
+    MissingChunk(&'static str),
+    // An NSFe chunk a player is required to understand
+    UnknownChunk([u8; 4]),
+}
+
+impl fmt::Display for NsfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NsfError::Io(e) => write!(f, "i/o error: {e}"),
+            NsfError::InvalidHeader => write!(f, "not an NSF or NSFe file"),
+            NsfError::Truncated => write!(f, "file is shorter than its header claims"),
+            NsfError::MissingChunk(id) => write!(f, "NSFe file has no {id} chunk"),
+            NsfError::UnknownChunk(id) => {
+                write!(
+                    f,
+                    "NSFe chunk {} is not supported",
+                    String::from_utf8_lossy(id)
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for NsfError {}
+
+impl From<io::Error> for NsfError {
+    fn from(e: io::Error) -> Self {
+        NsfError::Io(e)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TrackInfo {
+    pub name: Option<String>,
+    pub duration: Option<Duration>,
+    pub fade: Option<Duration>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Nsf {
+    pub load_addr: u16,
+    pub init_addr: u16,
+    pub play_addr: u16,
+    // Initial $5FF8-$5FFF values; None when the tune isn't bank switched
+    pub banks: Option<[u8; 8]>,
+    pub data: Vec<u8>,
+
+    pub chips: SoundChips,
+    pub region: Region,
+    pub dual_region: bool,
+    // Microseconds between PLAY calls
+    pub play_speed_ntsc: u16,
+    pub play_speed_pal: u16,
+
+    pub track_count: u8,
+    // Zero-based, unlike the NSF header field
+    pub starting_track: u8,
+
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+    pub ripper: String,
+    pub tracks: Vec<TrackInfo>,
+    // Order to play tracks in, if the rip suggests one
+    pub playlist: Option<Vec<u8>>,
+}
+
+const NSF_HEADER: usize = 0x80;
+
+// One frame at 60.0988Hz and 50.0070Hz
+const DEFAULT_SPEED_NTSC: u16 = 16639;
+const DEFAULT_SPEED_PAL: u16 = 19997;
+
+impl Nsf {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, NsfError> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, NsfError> {
+        if bytes.starts_with(b"NESM\x1A") {
+            Self::parse_nsf(bytes)
+        } else if bytes.starts_with(b"NSFE") {
+            Self::parse_nsfe(bytes)
+        } else {
+            Err(NsfError::InvalidHeader)
+        }
+    }
+
+    fn parse_nsf(bytes: &[u8]) -> Result<Self, NsfError> {
+        if bytes.len() < NSF_HEADER {
+            return Err(NsfError::Truncated);
+        }
+
+        let word = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+        let text = |offset: usize| c_string(&bytes[offset..offset + 32]);
+
+        let mut banks = [0; 8];
+        banks.copy_from_slice(&bytes[0x70..0x78]);
+        let region_flags = bytes[0x7A];
+
+        // NSF2 gives the program length so metadata chunks can follow it
+        let version = bytes[0x05];
+        let program_len =
+            bytes[0x7D] as usize | (bytes[0x7E] as usize) << 8 | (bytes[0x7F] as usize) << 16;
+        let data_end = if version >= 2 && program_len != 0 {
+            (NSF_HEADER + program_len).min(bytes.len())
+        } else {
+            bytes.len()
+        };
+
+        let track_count = bytes[0x06];
+        let mut nsf = Nsf {
+            load_addr: word(0x08),
+            init_addr: word(0x0A),
+            play_addr: word(0x0C),
+            banks: banks.iter().any(|&b| b != 0).then_some(banks),
+            data: bytes[NSF_HEADER..data_end].to_vec(),
+            chips: SoundChips::from_bits_truncate(bytes[0x7B]),
+            region: if region_flags & 0x01 != 0 {
+                Region::Pal
+            } else {
+                Region::Ntsc
+            },
+            dual_region: region_flags & 0x02 != 0,
+            play_speed_ntsc: word(0x6E),
+            play_speed_pal: word(0x78),
+            track_count,
+            starting_track: bytes[0x07].saturating_sub(1),
+            title: text(0x0E),
+            artist: text(0x2E),
+            copyright: text(0x4E),
+            ripper: String::new(),
+            tracks: vec![TrackInfo::default(); track_count as usize],
+            playlist: None,
+        };
+
+        if data_end < bytes.len() {
+            for chunk in Chunks::new(&bytes[data_end..]) {
+                let (id, data) = chunk?;
+                match &id {
+                    b"NEND" => break,
+                    // The header already says all of this
+                    b"INFO" | b"DATA" | b"BANK" | b"RATE" => {}
+                    _ => nsf.apply_metadata(id, data)?,
+                }
+            }
+        }
+
+        Ok(nsf)
+    }
+
+    fn parse_nsfe(bytes: &[u8]) -> Result<Self, NsfError> {
+        let mut nsf = Nsf {
+            load_addr: 0,
+            init_addr: 0,
+            play_addr: 0,
+            banks: None,
+            data: Vec::new(),
+            chips: SoundChips::empty(),
+            region: Region::Ntsc,
+            dual_region: false,
+            play_speed_ntsc: DEFAULT_SPEED_NTSC,
+            play_speed_pal: DEFAULT_SPEED_PAL,
+            track_count: 1,
+            starting_track: 0,
+            title: String::new(),
+            artist: String::new(),
+            copyright: String::new(),
+            ripper: String::new(),
+            tracks: Vec::new(),
+            playlist: None,
+        };
+
+        let mut has_info = false;
+        let mut has_data = false;
+        for chunk in Chunks::new(&bytes[4..]) {
+            let (id, data) = chunk?;
+            match &id {
+                b"INFO" => {
+                    if data.len() < 8 {
+                        return Err(NsfError::Truncated);
+                    }
+                    let word = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+                    nsf.load_addr = word(0);
+                    nsf.init_addr = word(2);
+                    nsf.play_addr = word(4);
+                    nsf.region = if data[6] & 0x01 != 0 {
+                        Region::Pal
+                    } else {
+                        Region::Ntsc
+                    };
+                    nsf.dual_region = data[6] & 0x02 != 0;
+                    nsf.chips = SoundChips::from_bits_truncate(data[7]);
+                    nsf.track_count = data.get(8).copied().unwrap_or(1);
+                    nsf.starting_track = data.get(9).copied().unwrap_or(0);
+                    has_info = true;
+                }
+                b"DATA" => {
+                    nsf.data = data.to_vec();
+                    has_data = true;
+                }
+                b"BANK" => {
+                    let mut banks = [0; 8];
+                    let len = data.len().min(8);
+                    banks[..len].copy_from_slice(&data[..len]);
+                    nsf.banks = Some(banks);
+                }
+                b"RATE" => {
+                    if let Some(speed) = data.get(0..2) {
+                        nsf.play_speed_ntsc = u16::from_le_bytes([speed[0], speed[1]]);
+                    }
+                    if let Some(speed) = data.get(2..4) {
+                        nsf.play_speed_pal = u16::from_le_bytes([speed[0], speed[1]]);
+                    }
+                }
+                b"NEND" => break,
+                _ => nsf.apply_metadata(id, data)?,
+            }
+        }
+
+        if !has_info {
+            return Err(NsfError::MissingChunk("INFO"));
+        }
+        if !has_data {
+            return Err(NsfError::MissingChunk("DATA"));
+        }
+        nsf.tracks
+            .resize(nsf.track_count as usize, TrackInfo::default());
+        Ok(nsf)
+    }
+
+    // Chunks shared by NSFe and the NSF2 trailer
+    fn apply_metadata(&mut self, id: [u8; 4], data: &[u8]) -> Result<(), NsfError> {
+        let track_count = self.track_count as usize;
+        self.tracks
+            .resize(track_count.max(self.tracks.len()), TrackInfo::default());
+
+        match &id {
+            b"auth" => {
+                let mut fields = data.split(|&b| b == 0).map(c_string);
+                self.title = fields.next().unwrap_or_default();
+                self.artist = fields.next().unwrap_or_default();
+                self.copyright = fields.next().unwrap_or_default();
+                self.ripper = fields.next().unwrap_or_default();
+            }
+            b"tlbl" => {
+                for (track, name) in self.tracks.iter_mut().zip(data.split(|&b| b == 0)) {
+                    track.name = Some(c_string(name));
+                }
+            }
+            b"time" | b"fade" => {
+                for (track, ms) in self.tracks.iter_mut().zip(data.chunks_exact(4)) {
+                    // Negative times mean "use the player's default"
+                    let ms = i32::from_le_bytes([ms[0], ms[1], ms[2], ms[3]]);
+                    let value = u64::try_from(ms).ok().map(Duration::from_millis);
+                    if &id == b"time" {
+                        track.duration = value;
+                    } else {
+                        track.fade = value;
+                    }
+                }
+            }
+            b"plst" => self.playlist = Some(data.to_vec()),
+            // Chunks starting with an upper-case letter must be understood
+            _ if id[0].is_ascii_uppercase() => return Err(NsfError::UnknownChunk(id)),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // Microseconds between PLAY calls in the given region
+    pub fn play_speed(&self, region: Region) -> u16 {
+        let speed = match region {
+            Region::Ntsc => self.play_speed_ntsc,
+            Region::Pal => self.play_speed_pal,
+        };
+        if speed != 0 {
+            speed
+        } else if region == Region::Pal {
+            DEFAULT_SPEED_PAL
+        } else {
+            DEFAULT_SPEED_NTSC
+        }
+    }
+
+    pub fn track(&self, track: u8) -> Option<&TrackInfo> {
+        self.tracks.get(track as usize)
+    }
+}
+
+fn c_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+// NSFe chunks: a little-endian u32 length, a four character ID, then data
+struct Chunks<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Chunks<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Chunks { bytes }
+    }
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = Result<([u8; 4], &'a [u8]), NsfError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.bytes.is_empty() {
+            return None;
+        }
+        if self.bytes.len() < 8 {
+            self.bytes = &[];
+            return Some(Err(NsfError::Truncated));
+        }
+
+        let len = u32::from_le_bytes([self.bytes[0], self.bytes[1], self.bytes[2], self.bytes[3]])
+            as usize;
+        let id = [self.bytes[4], self.bytes[5], self.bytes[6], self.bytes[7]];
+        let Some(data) = self.bytes.get(8..8 + len) else {
+            self.bytes = &[];
+            return Some(Err(NsfError::Truncated));
+        };
+        self.bytes = &self.bytes[8 + len..];
+        Some(Ok((id, data)))
+    }
+}
+
+// A three-track NSF with INIT at $8000 and PLAY at $8010, starting on track
+// 2 of 3. Version 2 headers give the program length.
+#[cfg(test)]
+fn test_image(version: u8, program: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![0; NSF_HEADER];
+    bytes[..5].copy_from_slice(b"NESM\x1A");
+    bytes[0x05] = version;
+    bytes[0x06] = 3;
+    bytes[0x07] = 2;
+    bytes[0x08..0x0E].copy_from_slice(&[0x00, 0x80, 0x00, 0x80, 0x10, 0x80]);
+    bytes[0x0E..0x13].copy_from_slice(b"Title");
+    bytes[0x2E..0x34].copy_from_slice(b"Artist");
+    bytes[0x4E..0x52].copy_from_slice(b"1987");
+    bytes[0x6E..0x70].copy_from_slice(&16639u16.to_le_bytes());
+    bytes[0x78..0x7A].copy_from_slice(&19997u16.to_le_bytes());
+    if version >= 2 {
+        bytes[0x7D..0x80].copy_from_slice(&(program.len() as u32).to_le_bytes()[..3]);
+    }
+    bytes.extend_from_slice(program);
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = (data.len() as u32).to_le_bytes().to_vec();
+        chunk.extend_from_slice(id);
+        chunk.extend_from_slice(data);
+        chunk
+    }
+
+    fn millis(ms: &[i32]) -> Vec<u8> {
+        ms.iter().flat_map(|ms| ms.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn parses_an_nsf_header() {
+        let mut bytes = test_image(1, &[0x60; 0x20]);
+        bytes[0x70..0x78].copy_from_slice(&[0, 1, 2, 3, 4, 5, 6, 7]);
+        bytes[0x7A] = 0x03;
+        bytes[0x7B] = 0x24;
+
+        let nsf = Nsf::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            (nsf.load_addr, nsf.init_addr, nsf.play_addr),
+            (0x8000, 0x8000, 0x8010)
+        );
+        assert_eq!(nsf.banks, Some([0, 1, 2, 3, 4, 5, 6, 7]));
+        assert_eq!(nsf.data, [0x60; 0x20]);
+        assert_eq!(nsf.chips, SoundChips::FDS | SoundChips::SUNSOFT5B);
+        assert_eq!((nsf.region, nsf.dual_region), (Region::Pal, true));
+        assert_eq!(nsf.play_speed(Region::Ntsc), 16639);
+        assert_eq!(nsf.play_speed(Region::Pal), 19997);
+        assert_eq!((nsf.track_count, nsf.starting_track), (3, 1));
+        assert_eq!(
+            (nsf.title.as_str(), nsf.artist.as_str()),
+            ("Title", "Artist")
+        );
+        assert_eq!(nsf.copyright, "1987");
+        assert_eq!(nsf.tracks.len(), 3);
+
+        // All-zero banks mean no bank switching
+        bytes[0x70..0x78].fill(0);
+        assert_eq!(Nsf::from_bytes(&bytes).unwrap().banks, None);
+        assert!(matches!(
+            Nsf::from_bytes(&bytes[..0x7F]),
+            Err(NsfError::Truncated)
+        ));
+        assert!(matches!(
+            Nsf::from_bytes(b"NESM\x1B"),
+            Err(NsfError::InvalidHeader)
+        ));
+    }
+
+    #[test]
+    fn reads_the_nsf2_metadata_trailer() {
+        let mut bytes = test_image(2, &[0x60; 0x20]);
+        bytes.extend(chunk(b"auth", b"Game\0Composer\0\0Ripper\0"));
+        bytes.extend(chunk(b"tlbl", b"One\0Two\0Three\0"));
+        bytes.extend(chunk(b"time", &millis(&[90_000, -1, 1500])));
+        bytes.extend(chunk(b"fade", &millis(&[5000])));
+        bytes.extend(chunk(b"plst", &[2, 0, 1]));
+        bytes.extend(chunk(b"xtra", &[0xFF]));
+        bytes.extend(chunk(b"NEND", &[]));
+
+        let nsf = Nsf::from_bytes(&bytes).unwrap();
+        // The trailer isn't part of the program
+        assert_eq!(nsf.data, [0x60; 0x20]);
+        assert_eq!(
+            (
+                nsf.title.as_str(),
+                nsf.artist.as_str(),
+                nsf.copyright.as_str()
+            ),
+            ("Game", "Composer", "")
+        );
+        assert_eq!(nsf.ripper, "Ripper");
+        let names: Vec<_> = nsf.tracks.iter().map(|t| t.name.as_deref()).collect();
+        assert_eq!(names, [Some("One"), Some("Two"), Some("Three")]);
+        assert_eq!(
+            nsf.track(0).unwrap().duration,
+            Some(Duration::from_secs(90))
+        );
+        assert_eq!(nsf.track(1).unwrap().duration, None);
+        assert_eq!(
+            nsf.track(2).unwrap().duration,
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(nsf.track(0).unwrap().fade, Some(Duration::from_secs(5)));
+        assert_eq!(nsf.track(1).unwrap().fade, None);
+        assert_eq!(nsf.playlist, Some(vec![2, 0, 1]));
+
+        // Version 1 files have no trailer, so it's all program
+        let mut v1 = bytes.clone();
+        v1[0x05] = 1;
+        assert_eq!(Nsf::from_bytes(&v1).unwrap().data, bytes[NSF_HEADER..]);
+    }
+
+    fn nsfe(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = b"NSFE".to_vec();
+        chunks.iter().for_each(|chunk| bytes.extend(chunk));
+        bytes
+    }
+
+    #[test]
+    fn parses_an_nsfe_file() {
+        let info = [0x00, 0x80, 0x03, 0x80, 0x06, 0x80, 0x01, 0x20, 0x02, 0x01];
+        let bytes = nsfe(&[
+            chunk(b"INFO", &info),
+            chunk(b"DATA", &[0xEA; 0x10]),
+            chunk(b"BANK", &[4, 5, 6]),
+            chunk(b"RATE", &[0x10, 0x27, 0x20, 0x4E]),
+            chunk(b"auth", b"Game\0Composer\0Company\0Ripper"),
+            chunk(b"tlbl", b"Intro\0Loop\0"),
+            chunk(b"time", &millis(&[1000, 2000])),
+            chunk(b"fade", &millis(&[-1, 3000])),
+            chunk(b"NEND", &[]),
+            chunk(b"AFTR", &[]),
+        ]);
+
+        let nsf = Nsf::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            (nsf.load_addr, nsf.init_addr, nsf.play_addr),
+            (0x8000, 0x8003, 0x8006)
+        );
+        assert_eq!((nsf.region, nsf.dual_region), (Region::Pal, false));
+        assert_eq!(nsf.chips, SoundChips::SUNSOFT5B);
+        assert_eq!((nsf.track_count, nsf.starting_track), (2, 1));
+        assert_eq!(nsf.data, [0xEA; 0x10]);
+        assert_eq!(nsf.banks, Some([4, 5, 6, 0, 0, 0, 0, 0]));
+        assert_eq!(nsf.play_speed(Region::Ntsc), 10000);
+        assert_eq!(nsf.play_speed(Region::Pal), 20000);
+        assert_eq!(
+            (
+                nsf.title.as_str(),
+                nsf.copyright.as_str(),
+                nsf.ripper.as_str()
+            ),
+            ("Game", "Company", "Ripper")
+        );
+        assert_eq!(nsf.tracks.len(), 2);
+        assert_eq!(nsf.track(1).unwrap().name.as_deref(), Some("Loop"));
+        assert_eq!(nsf.track(1).unwrap().duration, Some(Duration::from_secs(2)));
+        assert_eq!(nsf.track(0).unwrap().fade, None);
+        assert_eq!(nsf.track(1).unwrap().fade, Some(Duration::from_secs(3)));
+
+        // Without RATE the speeds fall back to one frame
+        let plain = nsfe(&[chunk(b"INFO", &info[..8]), chunk(b"DATA", &[0x60])]);
+        let nsf = Nsf::from_bytes(&plain).unwrap();
+        assert_eq!(nsf.play_speed(Region::Ntsc), DEFAULT_SPEED_NTSC);
+        assert_eq!((nsf.track_count, nsf.tracks.len()), (1, 1));
+    }
+
+    #[test]
+    fn rejects_nsfe_files_it_cannot_play() {
+        let info = chunk(b"INFO", &[0x00, 0x80, 0x00, 0x80, 0x00, 0x80, 0x00, 0x00]);
+        let data = chunk(b"DATA", &[0x60]);
+
+        let required = nsfe(&[info.clone(), chunk(b"VRC9", &[]), data.clone()]);
+        assert!(matches!(
+            Nsf::from_bytes(&required),
+            Err(NsfError::UnknownChunk(id)) if &id == b"VRC9"
+        ));
+        let optional = nsfe(&[info.clone(), chunk(b"vrc9", &[]), data.clone()]);
+        assert!(Nsf::from_bytes(&optional).is_ok());
+
+        assert!(matches!(
+            Nsf::from_bytes(&nsfe(std::slice::from_ref(&info))),
+            Err(NsfError::MissingChunk("DATA"))
+        ));
+        assert!(matches!(
+            Nsf::from_bytes(&nsfe(std::slice::from_ref(&data))),
+            Err(NsfError::MissingChunk("INFO"))
+        ));
+        let mut truncated = nsfe(&[info, data]);
+        truncated.pop();
+        assert!(matches!(
+            Nsf::from_bytes(&truncated),
+            Err(NsfError::Truncated)
+        ));
+    }
+}
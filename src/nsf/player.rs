@@ -0,0 +1,266 @@
+use std::io::{self, Seek, Write};
+use std::time::Duration;
+
+use crate::audio::{Mixer, WavWriter};
+use crate::cartridge::Cartridge;
+use crate::console::Console;
+use crate::cpu::StatusFlags;
+use crate::mapper::{DRIVER_IDLE, SUPPORTED_CHIPS};
+use crate::nsf::{Nsf, Region, SoundChips};
+
+// Plays NSF tracks on a bare Console: no PPU, just the CPU calling INIT once
+// and then PLAY at the rate the header asks for, returning to an idle loop
+// in between. PLAY calls that come due while the previous one is still
+// running wait for it, as on a real NSF player.
+pub struct Player {
+    nsf: Nsf,
+    console: Console,
+    region: Region,
+    sample_rate: u32,
+
+    track: u8,
+    play_period: u64,
+    cycles_until_play: u64,
+    play_pending: bool,
+    cycles: u64,
+    sample_phase: u64,
+}
+
+// What tracks without a recorded length are played for, and faded out over
+const DEFAULT_DURATION: Duration = Duration::from_secs(150);
+const DEFAULT_FADE: Duration = Duration::from_secs(8);
+
+impl Player {
+    pub fn new(nsf: Nsf, sample_rate: u32) -> Self {
+        // Dual region rips play as NTSC unless asked otherwise
+        let region = if nsf.dual_region {
+            Region::Ntsc
+        } else {
+            nsf.region
+        };
+        let track = nsf.starting_track;
+
+        let mut player = Player {
+            nsf,
+            console: Console::new(),
+            region,
+            sample_rate,
+            track,
+            play_period: 0,
+            cycles_until_play: 0,
+            play_pending: false,
+            cycles: 0,
+            sample_phase: 0,
+        };
+        player.start_track(track);
+        player
+    }
+
+    // Kept across tracks, so what's muted stays muted
+    pub fn mixer_mut(&mut self) -> &mut Mixer {
+        &mut self.console.bus.mixer
+    }
+
+    // Chips the rip uses that this emulator has no emulation of, and which
+    // will be missing from the output
+    pub fn unsupported_chips(&self) -> SoundChips {
+        self.nsf.chips.difference(SUPPORTED_CHIPS)
+    }
+
+    // Resets the machine and calls INIT for a track (zero-based)
+    pub fn start_track(&mut self, track: u8) {
+        self.track = track.min(self.nsf.track_count.saturating_sub(1));
+
+        let bus = &mut self.console.bus;
+        bus.insert_cartridge(Cartridge::from_nsf(&self.nsf));
+        for addr in 0x0000..0x0800 {
+            bus.write(addr, 0x00);
+        }
+        // Silence the APU and enable its channels, as INIT expects
+        for addr in 0x4000..=0x4013 {
+            bus.write(addr, 0x00);
+        }
+        bus.write(0x4015, 0x00);
+        bus.write(0x4015, 0x0F);
+        bus.write(0x4017, 0x40);
+
+        let cpu = &mut self.console.cpu;
+        cpu.a = self.track;
+        cpu.x = match self.region {
+            Region::Ntsc => 0,
+            Region::Pal => 1,
+        };
+        cpu.y = 0;
+        cpu.stkp = 0xFD;
+        cpu.status = StatusFlags::INTERRUPT_DISABLE | StatusFlags::UNUSED;
+        cpu.pc = DRIVER_IDLE;
+        self.call(self.nsf.init_addr);
+
+        let speed = self.nsf.play_speed(self.region) as u64;
+        self.play_period = (speed * self.region.cpu_clock() / 1_000_000).max(1);
+        self.cycles_until_play = self.play_period;
+        self.play_pending = false;
+        self.cycles = 0;
+        self.sample_phase = 0;
+    }
+
+    // JSR from the idle loop
+    fn call(&mut self, addr: u16) {
+        let Console { cpu, bus } = &mut self.console;
+        let ret = DRIVER_IDLE - 1;
+        for data in [(ret >> 8) as u8, ret as u8] {
+            let sp = 0x0100 + cpu.stkp as u16;
+            bus.write(sp, data);
+            cpu.stkp = cpu.stkp.wrapping_sub(1);
+        }
+        cpu.pc = addr;
+    }
+
+    fn idle(&self) -> bool {
+        let cpu = &self.console.cpu;
+        cpu.complete() && cpu.pc == DRIVER_IDLE
+    }
+
+    // Advances one CPU cycle
+    pub fn clock(&mut self) {
+        self.cycles_until_play -= 1;
+        if self.cycles_until_play == 0 {
+            self.play_pending = true;
+            self.cycles_until_play = self.play_period;
+        }
+
+        if self.play_pending && self.idle() {
+            self.play_pending = false;
+            self.call(self.nsf.play_addr);
+        }
+
+        self.console.clock();
+        self.cycles += 1;
+    }
+
+    // Fills out with samples at the player's sample rate, each the average
+    // of the CPU cycles it covers
+    pub fn render(&mut self, out: &mut [f32]) {
+        let cpu_clock = self.region.cpu_clock();
+        for sample in out {
+            let mut sum = 0.0;
+            let mut count = 0;
+            loop {
+                self.clock();
+                sum += self.console.bus.audio_sample();
+                count += 1;
+
+                self.sample_phase += self.sample_rate as u64;
+                if self.sample_phase >= cpu_clock {
+                    self.sample_phase -= cpu_clock;
+                    break;
+                }
+            }
+            *sample = sum / count as f32;
+        }
+    }
+
+    // Renders a whole track into a WAV file: its recorded length (or
+    // duration, if given), followed by its fade out
+    pub fn record<W: Write + Seek>(
+        &mut self,
+        track: u8,
+        out: W,
+        duration: Option<Duration>,
+    ) -> io::Result<W> {
+        let info = self.nsf.track(track).cloned().unwrap_or_default();
+        let duration = duration.or(info.duration).unwrap_or(DEFAULT_DURATION);
+        let fade = info.fade.unwrap_or(DEFAULT_FADE);
+
+        let rate = self.sample_rate as f64;
+        let play_samples = (duration.as_secs_f64() * rate) as usize;
+        let fade_samples = (fade.as_secs_f64() * rate) as usize;
+
+        let mut wav = WavWriter::new(out, self.sample_rate)?;
+        self.start_track(track);
+
+        let mut buffer = vec![0.0; 4096];
+        let mut written = 0;
+        while written < play_samples + fade_samples {
+            let len = buffer.len().min(play_samples + fade_samples - written);
+            let chunk = &mut buffer[..len];
+            self.render(chunk);
+
+            for (i, sample) in chunk.iter_mut().enumerate() {
+                let pos = written + i;
+                if pos >= play_samples {
+                    *sample *= 1.0 - (pos - play_samples) as f32 / fade_samples as f32;
+                }
+            }
+
+            wav.write_samples(chunk)?;
+            written += len;
+        }
+
+        wav.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nsf::test_image;
+
+    // INIT stores A and X and counts its calls in $12; PLAY counts its own
+    // in $13
+    fn new_player(region_flags: u8) -> Player {
+        let mut program = vec![0x85, 0x10, 0x86, 0x11, 0xE6, 0x12, 0x60];
+        program.resize(0x10, 0xEA);
+        program.extend([0xE6, 0x13, 0x60]);
+        let mut bytes = test_image(1, &program);
+        bytes[0x7A] = region_flags;
+        Player::new(Nsf::from_bytes(&bytes).unwrap(), 44100)
+    }
+
+    fn ram(player: &Player, addr: u16) -> u8 {
+        player.console.bus.peek(addr)
+    }
+
+    #[test]
+    fn init_runs_once_then_play_at_the_header_rate() {
+        let mut player = new_player(0x00);
+        // 16639us of 1.789773MHz
+        assert_eq!(player.play_period, 29780);
+
+        for _ in 0..100 {
+            player.clock();
+        }
+        assert_eq!((ram(&player, 0x10), ram(&player, 0x11)), (1, 0));
+        assert_eq!((ram(&player, 0x12), ram(&player, 0x13)), (1, 0));
+
+        for _ in 100..29780 {
+            player.clock();
+        }
+        assert_eq!(ram(&player, 0x13), 0);
+        for _ in 0..29780 * 9 + 100 {
+            player.clock();
+        }
+        assert_eq!((ram(&player, 0x12), ram(&player, 0x13)), (1, 10));
+    }
+
+    #[test]
+    fn starting_a_track_reruns_init_for_the_region() {
+        let mut player = new_player(0x01);
+        assert_eq!(player.play_period, 19997 * 1_662_607 / 1_000_000);
+        player.start_track(2);
+        for _ in 0..100 {
+            player.clock();
+        }
+        assert_eq!((ram(&player, 0x10), ram(&player, 0x11)), (2, 1));
+        assert_eq!((ram(&player, 0x12), ram(&player, 0x13)), (1, 0));
+
+        // Out of range tracks play the last one; dual region rips play as
+        // NTSC
+        let mut player = new_player(0x03);
+        player.start_track(7);
+        for _ in 0..100 {
+            player.clock();
+        }
+        assert_eq!((ram(&player, 0x10), ram(&player, 0x11)), (2, 0));
+    }
+}
@@ -0,0 +1,104 @@
+use super::{FOOTER_SIZE, PatchError, crc32, read_footer, read_number};
+
+// BPS patches: "BPS1", the source and target sizes, a metadata blob, then
+// actions that build the target front to back by copying runs out of the
+// source, the patch, or what has already been written.
+
+pub const MAGIC: &[u8] = b"BPS1";
+
+const SOURCE_READ: usize = 0;
+const TARGET_READ: usize = 1;
+const SOURCE_COPY: usize = 2;
+const TARGET_COPY: usize = 3;
+
+pub fn apply(data: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if !patch.starts_with(MAGIC) {
+        return Err(PatchError::InvalidHeader);
+    }
+    let footer = read_footer(patch)?;
+    let end = patch.len() - FOOTER_SIZE;
+    let patch = &patch[..end];
+
+    let mut pos = MAGIC.len();
+    let source_size = read_number(patch, &mut pos)?;
+    let target_size = read_number(patch, &mut pos)?;
+    let metadata_size = read_number(patch, &mut pos)?;
+    pos = pos
+        .checked_add(metadata_size)
+        .filter(|&pos| pos <= end)
+        .ok_or(PatchError::Truncated)?;
+
+    let actual = crc32(data);
+    if data.len() != source_size || actual != footer.source {
+        return Err(PatchError::SourceMismatch {
+            expected: footer.source,
+            actual,
+        });
+    }
+
+    // The size comes from the patch, so is only trusted as far as the
+    // inputs could plausibly make it
+    let mut out = Vec::with_capacity(target_size.min(data.len() + patch.len()));
+    let mut source_offset: usize = 0;
+    let mut target_offset: usize = 0;
+    while pos < end {
+        let action = read_number(patch, &mut pos)?;
+        let len = (action >> 2) + 1;
+        if out.len() + len > target_size {
+            return Err(PatchError::Truncated);
+        }
+
+        match action & 0x03 {
+            SOURCE_READ => {
+                let start = out.len();
+                let run = data.get(start..start + len).ok_or(PatchError::Truncated)?;
+                out.extend_from_slice(run);
+            }
+            TARGET_READ => {
+                let run = patch.get(pos..pos + len).ok_or(PatchError::Truncated)?;
+                out.extend_from_slice(run);
+                pos += len;
+            }
+            SOURCE_COPY => {
+                source_offset = seek(source_offset, read_number(patch, &mut pos)?)?;
+                let run = data
+                    .get(source_offset..source_offset + len)
+                    .ok_or(PatchError::Truncated)?;
+                out.extend_from_slice(run);
+                source_offset += len;
+            }
+            TARGET_COPY => {
+                target_offset = seek(target_offset, read_number(patch, &mut pos)?)?;
+                // The run can overlap what it's writing, repeating a pattern,
+                // so it has to go a byte at a time
+                for _ in 0..len {
+                    let byte = *out.get(target_offset).ok_or(PatchError::Truncated)?;
+                    out.push(byte);
+                    target_offset += 1;
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    let actual = crc32(&out);
+    if out.len() != target_size || actual != footer.target {
+        return Err(PatchError::TargetMismatch {
+            expected: footer.target,
+            actual,
+        });
+    }
+    Ok(out)
+}
+
+// Copy offsets are relative to where the last copy left off: the low bit is
+// the sign and the rest the distance
+fn seek(offset: usize, delta: usize) -> Result<usize, PatchError> {
+    let distance = delta >> 1;
+    if delta & 1 != 0 {
+        offset.checked_sub(distance)
+    } else {
+        offset.checked_add(distance)
+    }
+    .ok_or(PatchError::Truncated)
+}
@@ -0,0 +1,93 @@
+use super::PatchError;
+
+// IPS patches: "PATCH", then records of a 24-bit offset and 16-bit length
+// followed by that many bytes (or, for length 0, a 16-bit run length and a
+// fill byte), then "EOF" and an optional 24-bit size to truncate to.
+
+pub const MAGIC: &[u8] = b"PATCH";
+const EOF: &[u8] = b"EOF";
+const MAX_RECORD: usize = 0xFFFF;
+
+pub fn apply(data: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if !patch.starts_with(MAGIC) {
+        return Err(PatchError::InvalidHeader);
+    }
+
+    let mut out = data.to_vec();
+    let mut pos = MAGIC.len();
+
+    let take = |pos: &mut usize, n: usize| -> Result<&[u8], PatchError> {
+        let bytes = patch.get(*pos..*pos + n).ok_or(PatchError::Truncated)?;
+        *pos += n;
+        Ok(bytes)
+    };
+
+    loop {
+        let offset = take(&mut pos, 3)?;
+        if offset == EOF {
+            break;
+        }
+        let offset = (offset[0] as usize) << 16 | (offset[1] as usize) << 8 | offset[2] as usize;
+
+        let len = take(&mut pos, 2)?;
+        let len = (len[0] as usize) << 8 | len[1] as usize;
+
+        let (len, bytes) = if len == 0 {
+            let rle = take(&mut pos, 3)?;
+            let run = (rle[0] as usize) << 8 | rle[1] as usize;
+            (run, vec![rle[2]; run])
+        } else {
+            (len, take(&mut pos, len)?.to_vec())
+        };
+
+        if out.len() < offset + len {
+            out.resize(offset + len, 0x00);
+        }
+        out[offset..offset + len].copy_from_slice(&bytes);
+    }
+
+    if let Some(size) = patch.get(pos..pos + 3) {
+        let size = (size[0] as usize) << 16 | (size[1] as usize) << 8 | size[2] as usize;
+        out.truncate(size);
+    }
+
+    Ok(out)
+}
+
+pub fn create(original: &[u8], modified: &[u8]) -> Vec<u8> {
+    let mut patch = MAGIC.to_vec();
+
+    let mut i = 0;
+    while i < modified.len() {
+        if original.get(i) == Some(&modified[i]) {
+            i += 1;
+            continue;
+        }
+
+        // A record can't start at an offset that reads as "EOF"
+        let mut start = i;
+        if start == 0x454F46 {
+            start -= 1;
+        }
+
+        let mut end = i;
+        while end < modified.len()
+            && end - start < MAX_RECORD
+            && original.get(end) != Some(&modified[end])
+        {
+            end += 1;
+        }
+
+        patch.extend_from_slice(&[(start >> 16) as u8, (start >> 8) as u8, start as u8]);
+        patch.extend_from_slice(&[((end - start) >> 8) as u8, (end - start) as u8]);
+        patch.extend_from_slice(&modified[start..end]);
+        i = end;
+    }
+
+    patch.extend_from_slice(EOF);
+    if modified.len() < original.len() {
+        let len = modified.len();
+        patch.extend_from_slice(&[(len >> 16) as u8, (len >> 8) as u8, len as u8]);
+    }
+    patch
+}
@@ -0,0 +1,305 @@
+pub mod bps;
+pub mod ips;
+pub mod ups;
+
+use std::fmt;
+
+use crate::hash::Crc32;
+
+// ROM hacks and translations ship as patches against the original dump.
+// These apply them in memory, so the patched image never has to be written
+// out; the format is recognised from the patch's magic bytes.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Ips,
+    Ups,
+    Bps,
+}
+
+impl Format {
+    pub fn detect(patch: &[u8]) -> Option<Self> {
+        if patch.starts_with(ips::MAGIC) {
+            Some(Format::Ips)
+        } else if patch.starts_with(ups::MAGIC) {
+            Some(Format::Ups)
+        } else if patch.starts_with(bps::MAGIC) {
+            Some(Format::Bps)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PatchError {
+    InvalidHeader,
+    Truncated,
+    // The patch itself is corrupt
+    PatchChecksum,
+    // The patch was made for a different ROM
+    SourceMismatch { expected: u32, actual: u32 },
+    // Applying it produced something other than what it was made from
+    TargetMismatch { expected: u32, actual: u32 },
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatchError::InvalidHeader => write!(f, "not an IPS, UPS or BPS patch"),
+            PatchError::Truncated => write!(f, "patch ends mid-record"),
+            PatchError::PatchChecksum => write!(f, "patch is corrupt (checksum mismatch)"),
+            PatchError::SourceMismatch { expected, actual } => write!(
+                f,
+                "patch is for a ROM with CRC-32 {expected:08X}, not {actual:08X}"
+            ),
+            PatchError::TargetMismatch { expected, actual } => write!(
+                f,
+                "patched ROM has CRC-32 {actual:08X} instead of {expected:08X}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+pub fn apply(data: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    match Format::detect(patch) {
+        Some(Format::Ips) => ips::apply(data, patch),
+        Some(Format::Ups) => ups::apply(data, patch),
+        Some(Format::Bps) => bps::apply(data, patch),
+        None => Err(PatchError::InvalidHeader),
+    }
+}
+
+// Applies patches one on top of the other, in order, as hacks built on
+// other hacks expect
+pub fn apply_all<P: AsRef<[u8]>>(data: &[u8], patches: &[P]) -> Result<Vec<u8>, PatchError> {
+    let mut out = data.to_vec();
+    for patch in patches {
+        out = apply(&out, patch.as_ref())?;
+    }
+    Ok(out)
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
+
+// UPS and BPS share a trailer of three CRC-32s: source, target and the
+// patch up to the last checksum
+struct Footer {
+    source: u32,
+    target: u32,
+}
+
+const FOOTER_SIZE: usize = 12;
+
+fn read_footer(patch: &[u8]) -> Result<Footer, PatchError> {
+    if patch.len() < 4 + FOOTER_SIZE {
+        return Err(PatchError::Truncated);
+    }
+    let footer = &patch[patch.len() - FOOTER_SIZE..];
+    let word = |i: usize| u32::from_le_bytes(footer[i..i + 4].try_into().unwrap());
+    if crc32(&patch[..patch.len() - 4]) != word(8) {
+        return Err(PatchError::PatchChecksum);
+    }
+    Ok(Footer {
+        source: word(0),
+        target: word(4),
+    })
+}
+
+// Variable-length numbers: seven bits per byte, least significant first,
+// with the top bit marking the last byte. Each continuation also adds one,
+// so every number has exactly one encoding.
+fn read_number(patch: &[u8], pos: &mut usize) -> Result<usize, PatchError> {
+    let mut value: usize = 0;
+    let mut shift: usize = 1;
+    loop {
+        let byte = *patch.get(*pos).ok_or(PatchError::Truncated)?;
+        *pos += 1;
+        value = (byte as usize & 0x7F)
+            .checked_mul(shift)
+            .and_then(|n| value.checked_add(n))
+            .ok_or(PatchError::Truncated)?;
+        if byte & 0x80 != 0 {
+            return Ok(value);
+        }
+        shift = shift.checked_mul(0x80).ok_or(PatchError::Truncated)?;
+        value = value.checked_add(shift).ok_or(PatchError::Truncated)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_number(out: &mut Vec<u8>, mut value: usize) {
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(byte | 0x80);
+                return;
+            }
+            out.push(byte);
+            value -= 1;
+        }
+    }
+
+    // The three checksums, with the target's given so it can be got wrong
+    fn finish(mut patch: Vec<u8>, source: &[u8], target_crc: u32) -> Vec<u8> {
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&target_crc.to_le_bytes());
+        patch.extend_from_slice(&crc32(&patch).to_le_bytes());
+        patch
+    }
+
+    // XOR runs over each stretch of differing bytes. A run's terminating
+    // zero covers the byte after it, which is always one that matches.
+    fn ups(source: &[u8], target: &[u8], target_crc: u32) -> Vec<u8> {
+        let mut patch = ups::MAGIC.to_vec();
+        write_number(&mut patch, source.len());
+        write_number(&mut patch, target.len());
+        let byte = |data: &[u8], i: usize| data.get(i).copied().unwrap_or(0);
+        let len = source.len().max(target.len());
+        let (mut i, mut last) = (0, 0);
+        while i < len {
+            if byte(source, i) == byte(target, i) {
+                i += 1;
+                continue;
+            }
+            write_number(&mut patch, i - last);
+            while i < len && byte(source, i) != byte(target, i) {
+                patch.push(byte(source, i) ^ byte(target, i));
+                i += 1;
+            }
+            patch.push(0x00);
+            i += 1;
+            last = i;
+        }
+        finish(patch, source, target_crc)
+    }
+
+    // What the two have in common at the start is read from the source,
+    // the rest comes from the patch
+    fn bps(source: &[u8], target: &[u8], target_crc: u32) -> Vec<u8> {
+        let mut patch = bps::MAGIC.to_vec();
+        write_number(&mut patch, source.len());
+        write_number(&mut patch, target.len());
+        write_number(&mut patch, 0);
+        let common = source
+            .iter()
+            .zip(target)
+            .take_while(|(a, b)| a == b)
+            .count();
+        write_number(&mut patch, (common - 1) << 2);
+        write_number(&mut patch, (target.len() - common - 1) << 2 | 1);
+        patch.extend_from_slice(&target[common..]);
+        finish(patch, source, target_crc)
+    }
+
+    const SOURCE: &[u8] = b"The quick brown fox jumps over the lazy dog";
+    const TARGET: &[u8] = b"The quick brown cat jumps over the lazy frog";
+
+    fn corrupt(mut patch: Vec<u8>) -> Vec<u8> {
+        patch[6] ^= 0x01;
+        patch
+    }
+
+    #[test]
+    fn ups_checks_its_checksums() {
+        let patch = ups(SOURCE, TARGET, crc32(TARGET));
+        assert_eq!(apply(SOURCE, &patch).as_deref(), Ok(TARGET));
+        // UPS patches work in both directions
+        assert_eq!(apply(TARGET, &patch).as_deref(), Ok(SOURCE));
+
+        assert!(matches!(
+            apply(b"The quick brown fox jumps over the lazy cog", &patch),
+            Err(PatchError::SourceMismatch { .. })
+        ));
+        assert_eq!(
+            apply(SOURCE, &corrupt(patch)),
+            Err(PatchError::PatchChecksum)
+        );
+        assert!(matches!(
+            apply(SOURCE, &ups(SOURCE, TARGET, 0x12345678)),
+            Err(PatchError::TargetMismatch {
+                expected: 0x12345678,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn bps_checks_its_checksums() {
+        let patch = bps(SOURCE, TARGET, crc32(TARGET));
+        assert_eq!(apply(SOURCE, &patch).as_deref(), Ok(TARGET));
+
+        assert!(matches!(
+            apply(TARGET, &patch),
+            Err(PatchError::SourceMismatch { .. })
+        ));
+        assert_eq!(
+            apply(SOURCE, &corrupt(patch)),
+            Err(PatchError::PatchChecksum)
+        );
+        assert!(matches!(
+            apply(SOURCE, &bps(SOURCE, TARGET, 0x12345678)),
+            Err(PatchError::TargetMismatch {
+                expected: 0x12345678,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn sizes_from_the_patch_are_not_trusted() {
+        // A UPS skip that runs the offset past the end of the address space
+        let mut patch = ups::MAGIC.to_vec();
+        write_number(&mut patch, SOURCE.len());
+        write_number(&mut patch, SOURCE.len());
+        for _ in 0..2 {
+            write_number(&mut patch, usize::MAX / 2);
+            patch.push(0x00);
+        }
+        let patch = finish(patch, SOURCE, crc32(SOURCE));
+        assert_eq!(apply(SOURCE, &patch), Err(PatchError::Truncated));
+
+        // A BPS target far bigger than the patch could produce
+        let mut patch = bps::MAGIC.to_vec();
+        write_number(&mut patch, SOURCE.len());
+        write_number(&mut patch, 1 << 40);
+        write_number(&mut patch, 0);
+        write_number(&mut patch, (SOURCE.len() - 1) << 2);
+        let patch = finish(patch, SOURCE, crc32(SOURCE));
+        assert!(matches!(
+            apply(SOURCE, &patch),
+            Err(PatchError::TargetMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn patches_stack_in_order() {
+        let ips = ips::create(SOURCE, TARGET);
+        let reverse = ups(SOURCE, TARGET, crc32(TARGET));
+        let bps = bps(SOURCE, TARGET, crc32(TARGET));
+
+        // IPS gets to TARGET, the UPS patch takes it back, BPS forward again
+        assert_eq!(
+            apply_all(SOURCE, &[&ips, &reverse, &bps]).as_deref(),
+            Ok(TARGET)
+        );
+        assert!(matches!(
+            apply_all(SOURCE, &[&ips, &bps]),
+            Err(PatchError::SourceMismatch { .. })
+        ));
+        assert_eq!(
+            apply_all(SOURCE, &[b"not a patch"]),
+            Err(PatchError::InvalidHeader)
+        );
+    }
+}
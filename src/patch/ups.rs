@@ -0,0 +1,59 @@
+use super::{FOOTER_SIZE, PatchError, crc32, read_footer, read_number};
+
+// UPS patches: "UPS1", the source and target sizes, then records of a
+// distance to skip followed by bytes to XOR in, ending at a zero byte. The
+// same patch turns the target back into the source, and is applied that
+// way round if it's given the target.
+
+pub const MAGIC: &[u8] = b"UPS1";
+
+pub fn apply(data: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if !patch.starts_with(MAGIC) {
+        return Err(PatchError::InvalidHeader);
+    }
+    let footer = read_footer(patch)?;
+    let end = patch.len() - FOOTER_SIZE;
+
+    let mut pos = MAGIC.len();
+    let source_size = read_number(patch, &mut pos)?;
+    let target_size = read_number(patch, &mut pos)?;
+
+    let actual = crc32(data);
+    let (out_size, expected) = if data.len() == source_size && actual == footer.source {
+        (target_size, footer.target)
+    } else if data.len() == target_size && actual == footer.target {
+        (source_size, footer.source)
+    } else {
+        return Err(PatchError::SourceMismatch {
+            expected: footer.source,
+            actual,
+        });
+    };
+
+    let mut out = data.to_vec();
+    out.resize(out_size, 0x00);
+
+    let mut offset: usize = 0;
+    while pos < end {
+        offset = offset
+            .checked_add(read_number(patch, &mut pos)?)
+            .ok_or(PatchError::Truncated)?;
+        loop {
+            let byte = *patch[..end].get(pos).ok_or(PatchError::Truncated)?;
+            pos += 1;
+            if offset < out.len() {
+                out[offset] ^= byte;
+            }
+            offset = offset.checked_add(1).ok_or(PatchError::Truncated)?;
+            if byte == 0 {
+                break;
+            }
+        }
+    }
+
+    let actual = crc32(&out);
+    if actual != expected {
+        return Err(PatchError::TargetMismatch { expected, actual });
+    }
+    Ok(out)
+}
@@ -0,0 +1,202 @@
+use std::fmt;
+
+use crate::deflate::{self, DeflateError};
+use crate::framebuffer::Framebuffer;
+use crate::hash::Crc32;
+
+// Reading and writing framebuffers as PNG. Writing produces 8-bit RGB
+// without compression; reading takes the 8-bit, non-interlaced RGB, RGBA
+// and paletted images image editors usually save, dropping any alpha.
+
+const SIGNATURE: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+
+const COLOR_RGB: u8 = 2;
+const COLOR_PALETTE: u8 = 3;
+const COLOR_RGBA: u8 = 6;
+
+#[derive(Debug)]
+pub enum PngError {
+    InvalidSignature,
+    Truncated,
+    // A chunk whose CRC doesn't match
+    Checksum,
+    Unsupported(&'static str),
+    Malformed(&'static str),
+    Deflate(DeflateError),
+}
+
+impl fmt::Display for PngError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PngError::InvalidSignature => write!(f, "not a PNG image"),
+            PngError::Truncated => write!(f, "PNG image is truncated"),
+            PngError::Checksum => write!(f, "PNG image is corrupt (checksum mismatch)"),
+            PngError::Unsupported(what) => write!(f, "unsupported PNG image: {what}"),
+            PngError::Malformed(what) => write!(f, "malformed PNG image: {what}"),
+            PngError::Deflate(e) => write!(f, "corrupt PNG image data: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for PngError {}
+
+impl From<DeflateError> for PngError {
+    fn from(e: DeflateError) -> Self {
+        PngError::Deflate(e)
+    }
+}
+
+pub fn encode(image: &Framebuffer) -> Vec<u8> {
+    let mut header = Vec::new();
+    header.extend_from_slice(&(image.width() as u32).to_be_bytes());
+    header.extend_from_slice(&(image.height() as u32).to_be_bytes());
+    // 8 bits per sample, RGB, deflate, adaptive filtering, no interlace
+    header.extend_from_slice(&[8, COLOR_RGB, 0, 0, 0]);
+
+    // Every row unfiltered
+    let mut raw = Vec::new();
+    for row in image.pixels().chunks(image.width() * 3) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    let mut out = SIGNATURE.to_vec();
+    write_chunk(&mut out, b"IHDR", &header);
+    write_chunk(&mut out, b"IDAT", &deflate::zlib_compress(&raw));
+    write_chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+fn write_chunk(out: &mut Vec<u8>, tag: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(tag);
+    out.extend_from_slice(data);
+    let mut crc = Crc32::new();
+    crc.update(tag);
+    crc.update(data);
+    out.extend_from_slice(&crc.finish().to_be_bytes());
+}
+
+pub fn decode(data: &[u8]) -> Result<Framebuffer, PngError> {
+    let mut rest = data
+        .strip_prefix(SIGNATURE)
+        .ok_or(PngError::InvalidSignature)?;
+
+    let mut header = None;
+    let mut palette: &[u8] = &[];
+    let mut compressed = Vec::new();
+    while !rest.is_empty() {
+        let len = rest
+            .first_chunk()
+            .map(|&bytes| u32::from_be_bytes(bytes) as usize)
+            .ok_or(PngError::Truncated)?;
+        if rest.len() < len + 12 {
+            return Err(PngError::Truncated);
+        }
+        let (tag, data) = (&rest[4..8], &rest[8..8 + len]);
+        let mut crc = Crc32::new();
+        crc.update(&rest[4..8 + len]);
+        if crc.finish().to_be_bytes() != rest[8 + len..12 + len] {
+            return Err(PngError::Checksum);
+        }
+        rest = &rest[12 + len..];
+
+        match tag {
+            b"IHDR" => header = Some(data),
+            b"PLTE" => palette = data,
+            b"IDAT" => compressed.extend_from_slice(data),
+            b"IEND" => break,
+            _ => {}
+        }
+    }
+
+    let header = header
+        .filter(|header| header.len() == 13)
+        .ok_or(PngError::Malformed("no image header"))?;
+    let width = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let height = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    let (depth, color, interlace) = (header[8], header[9], header[12]);
+    if depth != 8 {
+        return Err(PngError::Unsupported("bit depth other than 8"));
+    }
+    if interlace != 0 {
+        return Err(PngError::Unsupported("interlacing"));
+    }
+    let channels = match color {
+        COLOR_RGB => 3,
+        COLOR_PALETTE => 1,
+        COLOR_RGBA => 4,
+        0 | 4 => return Err(PngError::Unsupported("grayscale")),
+        _ => return Err(PngError::Malformed("unknown colour type")),
+    };
+
+    let raw = deflate::zlib_decompress(&compressed)?;
+    let rows = unfilter(&raw, width * channels, height, channels)?;
+
+    let mut pixels = Vec::with_capacity(width * height * 3);
+    for sample in rows.chunks_exact(channels) {
+        match color {
+            COLOR_PALETTE => {
+                let i = sample[0] as usize * 3;
+                let rgb = palette
+                    .get(i..i + 3)
+                    .ok_or(PngError::Malformed("palette index out of range"))?;
+                pixels.extend_from_slice(rgb);
+            }
+            _ => pixels.extend_from_slice(&sample[..3]),
+        }
+    }
+    Framebuffer::from_rgb(width, height, pixels).ok_or(PngError::Truncated)
+}
+
+// Undoes each row's filter, which predicts bytes from the pixel to the
+// left (bpp bytes back), the row above, or both
+fn unfilter(raw: &[u8], stride: usize, height: usize, bpp: usize) -> Result<Vec<u8>, PngError> {
+    if raw.len() < (stride + 1) * height {
+        return Err(PngError::Truncated);
+    }
+    let mut out = vec![0u8; stride * height];
+    for y in 0..height {
+        let filter = raw[y * (stride + 1)];
+        let line = &raw[y * (stride + 1) + 1..(y + 1) * (stride + 1)];
+        for x in 0..stride {
+            let a = if x >= bpp {
+                out[y * stride + x - bpp]
+            } else {
+                0
+            };
+            let b = if y > 0 { out[(y - 1) * stride + x] } else { 0 };
+            let c = if x >= bpp && y > 0 {
+                out[(y - 1) * stride + x - bpp]
+            } else {
+                0
+            };
+            let prediction = match filter {
+                0 => 0,
+                1 => a,
+                2 => b,
+                3 => ((a as u16 + b as u16) / 2) as u8,
+                4 => paeth(a, b, c),
+                _ => return Err(PngError::Malformed("unknown filter")),
+            };
+            out[y * stride + x] = line[x].wrapping_add(prediction);
+        }
+    }
+    Ok(out)
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = (
+        (p - a as i16).abs(),
+        (p - b as i16).abs(),
+        (p - c as i16).abs(),
+    );
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
@@ -0,0 +1,302 @@
+use std::collections::VecDeque;
+
+use crate::console::Console;
+use crate::controller::Buttons;
+use crate::state::StateError;
+
+// Rewind history. Every `interval` frames the machine is snapshotted; the
+// newest snapshot is kept whole and each older one only as the compressed
+// difference from the one after it, so going back walks the chain from the
+// newest end. When the history outgrows its memory budget the oldest
+// snapshots are dropped.
+//
+// Stepping back to a frame restores the nearest snapshot at or before it and
+// runs forward from there, replaying the buttons held on each frame.
+pub struct Rewind {
+    interval: u64,
+    budget: usize,
+    // Frames run since recording started
+    frame: u64,
+
+    newest: Option<(u64, Vec<u8>)>,
+    // Oldest first, each a delta to the snapshot after it
+    older: VecDeque<(u64, Vec<u8>)>,
+    used: usize,
+    // The buttons for each frame run since the oldest snapshot
+    inputs: VecDeque<[Buttons; 2]>,
+}
+
+impl Rewind {
+    pub fn new(interval: u64, budget: usize) -> Self {
+        Rewind {
+            interval: interval.max(1),
+            budget,
+            frame: 0,
+            newest: None,
+            older: VecDeque::new(),
+            used: 0,
+            inputs: VecDeque::new(),
+        }
+    }
+
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    // How far back the history reaches
+    pub fn oldest_frame(&self) -> Option<u64> {
+        self.older
+            .front()
+            .or(self.newest.as_ref())
+            .map(|(frame, _)| *frame)
+    }
+
+    pub fn len(&self) -> usize {
+        self.older.len() + self.newest.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.newest.is_none()
+    }
+
+    pub fn memory_used(&self) -> usize {
+        self.used
+    }
+
+    // Call once a frame, after running it
+    pub fn push_frame(&mut self, console: &Console) {
+        if !self.is_empty() {
+            self.inputs.push_back(console.bus.buttons());
+        }
+        self.frame += 1;
+        if self.frame.is_multiple_of(self.interval) {
+            self.capture(console);
+        }
+    }
+
+    fn capture(&mut self, console: &Console) {
+        let state = console.save_state();
+        if let Some((frame, previous)) = self.newest.take() {
+            let delta = encode_delta(&state, &previous);
+            self.used = self.used - previous.len() + delta.len();
+            self.older.push_back((frame, delta));
+        }
+        self.used += state.len();
+        self.newest = Some((self.frame, state));
+
+        while self.used > self.budget
+            && let Some((frame, delta)) = self.older.pop_front()
+        {
+            self.used -= delta.len();
+            let dropped = self.oldest_frame().map_or(0, |oldest| oldest - frame);
+            self.inputs.drain(..dropped as usize);
+        }
+    }
+
+    // Puts the machine back at an earlier frame. Snapshots after it are
+    // discarded, as the timeline is about to be rewritten. Returns false,
+    // leaving everything as it was, if the history doesn't reach that far.
+    pub fn seek(&mut self, console: &mut Console, frame: u64) -> Result<bool, StateError> {
+        if frame > self.frame || self.oldest_frame().is_none_or(|oldest| oldest > frame) {
+            return Ok(false);
+        }
+
+        while let Some((newest_frame, newest)) = self.newest.take() {
+            if newest_frame <= frame {
+                self.newest = Some((newest_frame, newest));
+                break;
+            }
+            // The oldest_frame check guarantees there's an older one
+            let Some((older_frame, delta)) = self.older.pop_back() else {
+                break;
+            };
+            let state = decode_delta(&newest, &delta);
+            self.used = self.used - newest.len() - delta.len() + state.len();
+            self.newest = Some((older_frame, state));
+        }
+
+        let (Some(oldest), Some((snapshot_frame, state))) = (self.oldest_frame(), &self.newest)
+        else {
+            return Ok(false);
+        };
+        console.load_state(state)?;
+        let replay = (*snapshot_frame - oldest) as usize..(frame - oldest) as usize;
+        for buttons in self.inputs.range(replay) {
+            for (port, buttons) in buttons.iter().enumerate() {
+                console.bus.set_buttons(port, *buttons);
+            }
+            console.run_frame();
+        }
+        self.inputs.truncate((frame - oldest) as usize);
+        self.frame = frame;
+        Ok(true)
+    }
+
+    pub fn step_back(&mut self, console: &mut Console) -> Result<bool, StateError> {
+        match self.frame.checked_sub(1) {
+            Some(frame) => self.seek(console, frame),
+            None => Ok(false),
+        }
+    }
+}
+
+// The bytes of target XORed with base (padded with zeros to target's
+// length), run-length encoded: pairs of a count of unchanged bytes and a
+// count of changed ones followed by them, as LEB128 numbers. Consecutive
+// states differ in few places, so the zero runs make up nearly all of it.
+fn encode_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let diff = |i: usize| target[i] ^ base.get(i).copied().unwrap_or(0);
+
+    let mut out = Vec::new();
+    write_number(&mut out, target.len());
+    let mut i = 0;
+    while i < target.len() {
+        let same = (i..target.len()).take_while(|&j| diff(j) == 0).count();
+        i += same;
+        let changed = (i..target.len()).take_while(|&j| diff(j) != 0).count();
+        write_number(&mut out, same);
+        write_number(&mut out, changed);
+        out.extend((i..i + changed).map(diff));
+        i += changed;
+    }
+    out
+}
+
+fn decode_delta(base: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut pos = 0;
+    let len = read_number(delta, &mut pos);
+    let mut out: Vec<u8> = (0..len)
+        .map(|i| base.get(i).copied().unwrap_or(0))
+        .collect();
+
+    let mut i = 0;
+    while pos < delta.len() {
+        i += read_number(delta, &mut pos);
+        let changed = read_number(delta, &mut pos);
+        for (byte, diff) in out[i..i + changed].iter_mut().zip(&delta[pos..]) {
+            *byte ^= diff;
+        }
+        pos += changed;
+        i += changed;
+    }
+    out
+}
+
+fn write_number(out: &mut Vec<u8>, mut n: usize) {
+    while n >= 0x80 {
+        out.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+fn read_number(data: &[u8], pos: &mut usize) -> usize {
+    let mut n = 0;
+    let mut shift = 0;
+    while let Some(&byte) = data.get(*pos) {
+        *pos += 1;
+        n |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::Cartridge;
+
+    // Sums the A button into $10 and counts polls in $11
+    const PROGRAM: &[u8] = &[
+        0xA9, 0x01, //       LDA #$01
+        0x8D, 0x16, 0x40, // STA $4016
+        0x4A, //             LSR A
+        0x8D, 0x16, 0x40, // STA $4016
+        0xAD, 0x16, 0x40, // LDA $4016
+        0x29, 0x01, //       AND #$01
+        0x18, //             CLC
+        0x65, 0x10, //       ADC $10
+        0x85, 0x10, //       STA $10
+        0xE6, 0x11, //       INC $11
+        0x4C, 0x00, 0x80, // JMP $8000
+    ];
+
+    fn buttons(frame: u64) -> Buttons {
+        if frame.is_multiple_of(3) {
+            Buttons::A
+        } else {
+            Buttons::empty()
+        }
+    }
+
+    #[test]
+    fn rewinding_and_replaying_reproduces_every_frame() {
+        let mut console = Console::with_cartridge(Cartridge::nrom(PROGRAM));
+        console.reset();
+        let mut rewind = Rewind::new(4, usize::MAX);
+
+        // The state after each frame, with the buttons held during it
+        let mut states = vec![console.save_state()];
+        for frame in 1..=20 {
+            console.bus.set_buttons(0, buttons(frame));
+            console.run_frame();
+            rewind.push_frame(&console);
+            states.push(console.save_state());
+        }
+
+        // Between snapshots, so frames are replayed to get there
+        assert!(rewind.seek(&mut console, 10).unwrap());
+        assert_eq!(rewind.frame(), 10);
+        assert_eq!(console.save_state(), states[10]);
+        assert!(rewind.step_back(&mut console).unwrap());
+        assert_eq!(console.save_state(), states[9]);
+
+        for frame in 10..=20 {
+            console.bus.set_buttons(0, buttons(frame));
+            console.run_frame();
+            rewind.push_frame(&console);
+            assert_eq!(console.save_state(), states[frame as usize]);
+        }
+
+        // Nothing was kept from before the first snapshot
+        assert!(!rewind.seek(&mut console, 3).unwrap());
+        assert_eq!(console.save_state(), states[20]);
+    }
+
+    #[test]
+    fn a_small_budget_drops_the_oldest_snapshots() {
+        let mut console = Console::with_cartridge(Cartridge::nrom(PROGRAM));
+        console.reset();
+        // Room for the newest snapshot and a handful of deltas
+        let budget = console.save_state().len() + 256;
+        let mut rewind = Rewind::new(2, budget);
+
+        let mut states = vec![console.save_state()];
+        let mut oldest = 0;
+        for frame in 1..=60 {
+            console.bus.set_buttons(0, buttons(frame));
+            console.run_frame();
+            rewind.push_frame(&console);
+            states.push(console.save_state());
+
+            let frame = rewind.oldest_frame().unwrap_or(0);
+            assert!(frame >= oldest);
+            oldest = frame;
+            assert!(rewind.memory_used() <= budget);
+        }
+        assert!(oldest > 2, "oldest kept frame is {oldest}");
+        assert!(rewind.len() < 30);
+
+        // Still bit-exact from the oldest snapshot, both on it and replayed
+        // forward from it
+        assert!(!rewind.seek(&mut console, oldest - 1).unwrap());
+        assert!(rewind.seek(&mut console, oldest + 1).unwrap());
+        assert_eq!(console.save_state(), states[oldest as usize + 1]);
+        assert!(rewind.seek(&mut console, oldest).unwrap());
+        assert_eq!(console.save_state(), states[oldest as usize]);
+        assert_eq!(rewind.len(), 1);
+    }
+}
@@ -0,0 +1,357 @@
+use std::borrow::Cow;
+use std::fmt;
+
+// Save state layout:
+//
+//   "NESS"  magic
+//   u16     format version
+//   chunks  [tag: 4 bytes][length: u32][payload], until the end
+//
+// All numbers are little endian. Each part of the machine writes its own
+// chunk, so parts added later (the PPU and APU) only need a new chunk, and
+// loaders skip chunks they don't know. Payload changes bump VERSION and get
+// a step in migrate(), so older states keep loading.
+pub const MAGIC: &[u8; 4] = b"NESS";
+pub const VERSION: u16 = 3;
+
+pub type Tag = [u8; 4];
+
+#[derive(Debug)]
+pub enum StateError {
+    InvalidHeader,
+    // Written by a newer build
+    UnsupportedVersion(u16),
+    Truncated,
+    // A chunk the machine needs isn't there
+    MissingChunk(Tag),
+    // Made with a different ROM inserted
+    WrongCartridge,
+    // A value out of range for what it describes
+    Invalid(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::InvalidHeader => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(v) => write!(f, "unsupported save state version {v}"),
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::MissingChunk(tag) => {
+                write!(
+                    f,
+                    "save state has no {} chunk",
+                    String::from_utf8_lossy(tag)
+                )
+            }
+            StateError::WrongCartridge => write!(f, "save state is for a different game"),
+            StateError::Invalid(what) => write!(f, "invalid {what} in save state"),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+// Implemented by every part of the machine with state worth keeping
+pub trait Snapshot {
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError>;
+}
+
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&VERSION.to_le_bytes());
+        StateWriter { data }
+    }
+
+    // For one part's state on its own, with no header or chunks around it,
+    // to be read back with StateReader::new
+    pub fn bare() -> Self {
+        StateWriter { data: Vec::new() }
+    }
+
+    pub fn chunk(&mut self, tag: Tag, f: impl FnOnce(&mut StateWriter)) {
+        self.data.extend_from_slice(&tag);
+        let start = self.data.len();
+        self.u32(0);
+        f(self);
+        let len = (self.data.len() - start - 4) as u32;
+        self.data[start..start + 4].copy_from_slice(&len.to_le_bytes());
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn u8(&mut self, v: u8) {
+        self.data.push(v);
+    }
+
+    pub fn bool(&mut self, v: bool) {
+        self.u8(v as u8);
+    }
+
+    pub fn u16(&mut self, v: u16) {
+        self.data.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, v: u32) {
+        self.data.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, v: u64) {
+        self.data.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn f32(&mut self, v: f32) {
+        self.u32(v.to_bits());
+    }
+
+    // Length prefixed
+    pub fn bytes(&mut self, v: &[u8]) {
+        self.u32(v.len() as u32);
+        self.data.extend_from_slice(v);
+    }
+}
+
+// The chunks of a save state, brought up to the current version
+pub struct State<'a> {
+    chunks: Vec<(Tag, Cow<'a, [u8]>)>,
+}
+
+impl<'a> State<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, StateError> {
+        let rest = data.strip_prefix(MAGIC).ok_or(StateError::InvalidHeader)?;
+        let mut r = StateReader::new(rest);
+        let version = r.u16()?;
+        if version > VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+
+        let mut chunks = Vec::new();
+        while !r.is_empty() {
+            let tag = r.take(4)?.try_into().unwrap_or_default();
+            let len = r.u32()? as usize;
+            chunks.push((tag, Cow::Borrowed(r.take(len)?)));
+        }
+
+        let mut state = State { chunks };
+        state.migrate(version)?;
+        Ok(state)
+    }
+
+    pub fn chunk(&self, tag: Tag) -> Result<StateReader<'_>, StateError> {
+        self.chunks
+            .iter()
+            .find(|(t, _)| *t == tag)
+            .map(|(_, data)| StateReader::new(data))
+            .ok_or(StateError::MissingChunk(tag))
+    }
+
+    pub fn has_chunk(&self, tag: Tag) -> bool {
+        self.chunks.iter().any(|(t, _)| *t == tag)
+    }
+
+    // Each format change adds a step here rewriting the affected chunks
+    // from the version before it, so a state is upgraded one version at a
+    // time
+    fn migrate(&mut self, version: u16) -> Result<(), StateError> {
+        if version == 0 {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        if version < 2 {
+            self.migrate_cpu_cycles()?;
+        }
+        if version < 3 {
+            self.migrate_cpu_interrupt_lines()?;
+        }
+        Ok(())
+    }
+
+    fn cpu_chunk(&mut self) -> Option<&mut Cow<'a, [u8]>> {
+        self.chunks
+            .iter_mut()
+            .find(|(t, _)| t == b"CPU ")
+            .map(|(_, data)| data)
+    }
+
+    // Version 2 made the CPU cycle-stepped. Version 1 CPUs did all of an
+    // instruction on its first cycle and idled for the rest, which is what
+    // the new one does while stalled, so the idle cycles left become that.
+    fn migrate_cpu_cycles(&mut self) -> Result<(), StateError> {
+        let Some(data) = self.cpu_chunk() else {
+            return Ok(());
+        };
+        let mut r = StateReader::new(data);
+        let registers = r.take(7)?.to_vec();
+        let _fetched = r.u8()?;
+        let _addr_abs = r.u16()?;
+        let _addr_rel = r.u16()?;
+        let opcode = r.u8()?;
+        let cycles = r.u8()?;
+
+        // Between instructions, with nothing latched or pending
+        let mut cpu = registers;
+        cpu.extend_from_slice(&[opcode, 0, 0, 0, 0, 0, 0, cycles, 0, 0, 0]);
+        *data = Cow::Owned(cpu);
+        Ok(())
+    }
+
+    // Version 3 gave the CPU interrupt lines in place of requests. A pending
+    // NMI becomes one detected, and a pending IRQ the line held.
+    fn migrate_cpu_interrupt_lines(&mut self) -> Result<(), StateError> {
+        let Some(data) = self.cpu_chunk() else {
+            return Ok(());
+        };
+        let mut r = StateReader::new(data);
+        let mut cpu = r.take(16)?.to_vec();
+        let nmi_pending = r.u8()?;
+        let irq_pending = r.u8()?;
+
+        // Lines, the NMI edge, then the polls
+        cpu.extend_from_slice(&[0, irq_pending, 0, nmi_pending, 0, 0]);
+        *data = Cow::Owned(cpu);
+        Ok(())
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        StateReader { data }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() < len {
+            return Err(StateError::Truncated);
+        }
+        let (head, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(head)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], StateError> {
+        let mut bytes = [0; N];
+        bytes.copy_from_slice(self.take(N)?);
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    pub fn f32(&mut self) -> Result<f32, StateError> {
+        Ok(f32::from_bits(self.u32()?))
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], StateError> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    // For buffers whose size is fixed by the hardware or the cartridge
+    pub fn bytes_into(&mut self, out: &mut [u8], what: &'static str) -> Result<(), StateError> {
+        let bytes = self.bytes()?;
+        if bytes.len() != out.len() {
+            return Err(StateError::Invalid(what));
+        }
+        out.copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu;
+
+    const REGISTERS: [u8; 7] = [0x11, 0x22, 0x33, 0xFD, 0x34, 0x12, 0x24];
+
+    fn state(version: u16, cpu: &[u8]) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&version.to_le_bytes());
+        data.extend_from_slice(b"CPU ");
+        data.extend_from_slice(&(cpu.len() as u32).to_le_bytes());
+        data.extend_from_slice(cpu);
+        data
+    }
+
+    // What a CPU chunk from this version holds once loaded and saved again
+    fn current_cpu(data: &[u8]) -> Vec<u8> {
+        let state = State::parse(data).unwrap();
+        let mut cpu = Cpu::new();
+        cpu.load_state(&mut state.chunk(*b"CPU ").unwrap()).unwrap();
+        let mut w = StateWriter::new();
+        cpu.save_state(&mut w);
+        w.finish()[6..].to_vec()
+    }
+
+    #[test]
+    fn version_1_cpus_idle_out_their_instruction() {
+        // Registers, fetched, addr_abs, addr_rel, opcode, cycles left
+        let mut v1 = REGISTERS.to_vec();
+        v1.extend_from_slice(&[0x99, 0x00, 0x03, 0xFE, 0xFF, 0xEA, 0x02]);
+
+        let mut expected = REGISTERS.to_vec();
+        // Opcode and step, addr, ptr, fetched, then two cycles of stall
+        expected.extend_from_slice(&[0xEA, 0, 0, 0, 0, 0, 0, 2]);
+        // Servicing nothing, with every line and poll clear
+        expected.extend_from_slice(&[0; 7]);
+        assert_eq!(current_cpu(&state(1, &v1)), expected);
+    }
+
+    #[test]
+    fn version_2_interrupt_requests_become_lines() {
+        let mut v2 = REGISTERS.to_vec();
+        v2.extend_from_slice(&[0x6C, 3, 0x00, 0x03, 0xFF, 0x02, 0x42, 0, 2]);
+        let pending = |nmi: u8, irq: u8| {
+            let mut v2 = v2.clone();
+            v2.extend_from_slice(&[nmi, irq]);
+            current_cpu(&state(2, &v2))
+        };
+
+        // Lines, the NMI edge, then detected NMI and the polls
+        let mut expected = v2.clone();
+        expected.extend_from_slice(&[0, 1, 0, 0, 0, 0]);
+        assert_eq!(pending(0, 1), expected);
+
+        let mut expected = v2.clone();
+        expected.extend_from_slice(&[0, 0, 0, 1, 0, 0]);
+        assert_eq!(pending(1, 0), expected);
+    }
+
+    #[test]
+    fn states_from_newer_builds_are_refused() {
+        assert!(matches!(
+            State::parse(&state(VERSION + 1, &[])),
+            Err(StateError::UnsupportedVersion(v)) if v == VERSION + 1
+        ));
+    }
+}
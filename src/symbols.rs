@@ -0,0 +1,405 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::bus::Bus;
+
+// iNES header ld65 writes ahead of PRG ROM in .nes output files
+const INES_HEADER_SIZE: usize = 16;
+
+// FCEUX name lists split ROM labels into one file per 16K bank
+const NL_BANK_SIZE: usize = 0x4000;
+
+#[derive(Debug)]
+pub enum SymbolError {
+    Io(io::Error),
+    // Not a .dbg, .nl or .mlb file
+    UnknownFormat(String),
+    // A line that couldn't be parsed, numbered from 1
+    Malformed(usize),
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SymbolError::Io(e) => write!(f, "i/o error: {e}"),
+            SymbolError::UnknownFormat(name) => write!(f, "unknown symbol file format: {name}"),
+            SymbolError::Malformed(line) => write!(f, "malformed symbol file at line {line}"),
+        }
+    }
+}
+
+impl std::error::Error for SymbolError {}
+
+impl From<io::Error> for SymbolError {
+    fn from(e: io::Error) -> Self {
+        SymbolError::Io(e)
+    }
+}
+
+// Labels in banked ROM are tied to the ROM byte rather than the CPU
+// address, so the same address can be named differently in each bank
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Location {
+    Cpu(u16),
+    PrgRom(usize),
+}
+
+impl Location {
+    fn offset(self, n: usize) -> Location {
+        match self {
+            Location::Cpu(addr) => Location::Cpu(addr.wrapping_add(n as u16)),
+            Location::PrgRom(offset) => Location::PrgRom(offset + n),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Label {
+    pub name: String,
+    pub location: Location,
+    // Bytes covered, for arrays and multi-byte variables
+    pub size: usize,
+    pub comment: Option<String>,
+}
+
+// Labels loaded from ca65/ld65 debug info, FCEUX name lists and Mesen label
+// files. Several files can be loaded into the same set.
+#[derive(Default)]
+pub struct Symbols {
+    labels: Vec<Label>,
+    // Every byte a label covers: the label and how far into it the byte is
+    by_location: HashMap<Location, (usize, usize)>,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Loads whatever symbol files sit next to a ROM under the names the
+    // tools that wrote them use: game.dbg, game.mlb, game.nes.ram.nl and
+    // game.nes.<bank>.nl
+    pub fn discover<P: AsRef<Path>>(rom: P) -> Result<Self, SymbolError> {
+        let rom = rom.as_ref();
+        let mut symbols = Symbols::new();
+        for ext in ["dbg", "mlb"] {
+            let path = rom.with_extension(ext);
+            if path.is_file() {
+                symbols.load(path)?;
+            }
+        }
+
+        let (Some(dir), Some(name)) = (rom.parent(), rom.file_name()) else {
+            return Ok(symbols);
+        };
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+        let prefix = format!("{}.", name.to_string_lossy());
+        let mut lists: Vec<_> = fs::read_dir(dir)?
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.file_name()
+                    .map(|n| n.to_string_lossy())
+                    .is_some_and(|n| n.starts_with(&prefix) && n.ends_with(".nl"))
+            })
+            .collect();
+        lists.sort();
+        for path in lists {
+            symbols.load(path)?;
+        }
+        Ok(symbols)
+    }
+
+    // Picks the format from the file name
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<(), SymbolError> {
+        let path = path.as_ref();
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let text = fs::read_to_string(path)?;
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("dbg") => self.parse_dbg(&text),
+            Some("mlb") => self.parse_mlb(&text),
+            Some("nl") => {
+                // game.nes.ram.nl holds RAM labels; game.nes.3.nl holds
+                // labels for the fourth 16K bank, numbered in hex
+                let stem = name.trim_end_matches(".nl");
+                let bank = match stem.rsplit_once('.').map(|(_, bank)| bank) {
+                    Some("ram") | None => None,
+                    Some(bank) => Some(
+                        usize::from_str_radix(bank, 16)
+                            .map_err(|_| SymbolError::UnknownFormat(name.clone()))?,
+                    ),
+                };
+                self.parse_nl(&text, bank)
+            }
+            _ => Err(SymbolError::UnknownFormat(name)),
+        }
+    }
+
+    pub fn add(&mut self, label: Label) {
+        let index = self.labels.len();
+        for n in 0..label.size.max(1) {
+            // The first label to claim a byte keeps it
+            self.by_location
+                .entry(label.location.offset(n))
+                .or_insert((index, n));
+        }
+        self.labels.push(label);
+    }
+
+    // The label covering a CPU address as currently mapped, and how far into
+    // the label the address is
+    pub fn lookup(&self, bus: &Bus, addr: u16) -> Option<(&Label, usize)> {
+        let rom = bus
+            .cartridge()
+            .and_then(|cart| cart.prg_rom_offset(addr))
+            .and_then(|offset| self.by_location.get(&Location::PrgRom(offset)));
+        let (index, n) = rom.or_else(|| self.by_location.get(&Location::Cpu(addr)))?;
+        Some((&self.labels[*index], *n))
+    }
+
+    // A label starting exactly at addr
+    pub fn label(&self, bus: &Bus, addr: u16) -> Option<&Label> {
+        match self.lookup(bus, addr)? {
+            (label, 0) => Some(label),
+            _ => None,
+        }
+    }
+
+    // How an operand address should be shown: Name or Name+n
+    pub fn name(&self, bus: &Bus, addr: u16) -> Option<String> {
+        match self.lookup(bus, addr)? {
+            (label, 0) => Some(label.name.clone()),
+            (label, n) => Some(format!("{}+{n}", label.name)),
+        }
+    }
+
+    // Where a label can be reached from the CPU right now. ROM labels are
+    // only found while their bank is mapped in.
+    pub fn address(&self, bus: &Bus, name: &str) -> Option<u16> {
+        let label = self.labels.iter().find(|label| label.name == name)?;
+        match label.location {
+            Location::Cpu(addr) => Some(addr),
+            Location::PrgRom(offset) => {
+                let cart = bus.cartridge()?;
+                (0x4020..=0xFFFF).find(|&addr| cart.prg_rom_offset(addr) == Some(offset))
+            }
+        }
+    }
+
+    // ld65's --dbgfile output. Labels in segments written to the ROM are
+    // placed by their file offset; everything else (RAM, equates in the
+    // zero page) by address.
+    pub fn parse_dbg(&mut self, text: &str) -> Result<(), SymbolError> {
+        struct Segment {
+            start: usize,
+            // Offset into PRG ROM, for segments that end up in it
+            rom: Option<usize>,
+        }
+
+        let mut segments = HashMap::new();
+        let mut symbols = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let Some((kind, rest)) = line.split_once(char::is_whitespace) else {
+                continue;
+            };
+            if kind != "seg" && kind != "sym" {
+                continue;
+            }
+            let fields = dbg_fields(rest).ok_or(SymbolError::Malformed(i + 1))?;
+            let field = |name: &str| fields.get(name).map(String::as_str);
+            let number = |name: &str| field(name).and_then(parse_dbg_number);
+
+            if kind == "seg" {
+                let id = number("id").ok_or(SymbolError::Malformed(i + 1))?;
+                let start = number("start").ok_or(SymbolError::Malformed(i + 1))?;
+                let header = match field("oname") {
+                    Some(oname) if oname.to_ascii_lowercase().ends_with(".nes") => INES_HEADER_SIZE,
+                    _ => 0,
+                };
+                let rom = match (field("type"), number("ooffs")) {
+                    (Some("ro"), Some(ooffs)) => ooffs.checked_sub(header),
+                    _ => None,
+                };
+                segments.insert(id, Segment { start, rom });
+            } else if field("type") == Some("lab") {
+                let name = field("name").ok_or(SymbolError::Malformed(i + 1))?;
+                let val = number("val").ok_or(SymbolError::Malformed(i + 1))?;
+                let size = number("size").unwrap_or(1);
+                symbols.push((name.to_string(), val, size, number("seg")));
+            }
+        }
+
+        for (name, val, size, seg) in symbols {
+            let rom = seg
+                .and_then(|seg| segments.get(&seg))
+                .and_then(|seg| Some(seg.rom? + val.checked_sub(seg.start)?));
+            let location = match rom {
+                Some(offset) => Location::PrgRom(offset),
+                None => Location::Cpu(val as u16),
+            };
+            self.add(Label {
+                name,
+                location,
+                size,
+                comment: None,
+            });
+        }
+        Ok(())
+    }
+
+    // FCEUX name lists: `$C3A0#UpdatePlayer#comment`, or `$0300/10#Buffer#`
+    // for a 16 byte array. Addresses are CPU addresses; those at $8000 and
+    // up belong to the given 16K bank.
+    pub fn parse_nl(&mut self, text: &str, bank: Option<usize>) -> Result<(), SymbolError> {
+        for (i, line) in text.lines().enumerate() {
+            // Lines not starting with $ continue a multi-line comment
+            let Some(line) = line.strip_prefix('$') else {
+                continue;
+            };
+            let mut parts = line.splitn(3, '#');
+            let addr = parts.next().unwrap_or_default();
+            let name = parts.next().ok_or(SymbolError::Malformed(i + 1))?;
+            let comment = parts.next().filter(|c| !c.is_empty()).map(str::to_string);
+
+            let (addr, size) = match addr.split_once('/') {
+                Some((addr, size)) => (addr, usize::from_str_radix(size, 16).ok()),
+                None => (addr, Some(1)),
+            };
+            let addr = u16::from_str_radix(addr, 16).map_err(|_| SymbolError::Malformed(i + 1))?;
+            let size = size.ok_or(SymbolError::Malformed(i + 1))?;
+            if name.is_empty() {
+                continue;
+            }
+
+            let location = match bank {
+                Some(bank) if addr >= 0x8000 => {
+                    Location::PrgRom(bank * NL_BANK_SIZE + (addr as usize & (NL_BANK_SIZE - 1)))
+                }
+                _ => Location::Cpu(addr),
+            };
+            self.add(Label {
+                name: name.to_string(),
+                location,
+                size,
+                comment,
+            });
+        }
+        Ok(())
+    }
+
+    // Mesen label files: `<type>:<address>[-<end>]:<name>[:<comment>]`.
+    // PRG ROM labels are by ROM offset. Work and save RAM are taken to be a
+    // single unbanked chip at $6000, so labels past its end are skipped.
+    pub fn parse_mlb(&mut self, text: &str) -> Result<(), SymbolError> {
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim_end_matches('\r');
+            if line.is_empty() {
+                continue;
+            }
+            let mut parts = line.splitn(4, ':');
+            let (Some(kind), Some(range), Some(name)) = (parts.next(), parts.next(), parts.next())
+            else {
+                return Err(SymbolError::Malformed(i + 1));
+            };
+            let comment = parts
+                .next()
+                .filter(|c| !c.is_empty())
+                .map(|c| c.replace("\\n", "\n"));
+
+            let parse = |s: &str| usize::from_str_radix(s, 16).ok();
+            let (start, end) = match range.split_once('-') {
+                Some((start, end)) => (parse(start), parse(end)),
+                None => (parse(range), parse(range)),
+            };
+            let (Some(start), Some(end)) = (start, end) else {
+                return Err(SymbolError::Malformed(i + 1));
+            };
+            // Comment-only entries have no name
+            if name.is_empty() || end < start {
+                continue;
+            }
+
+            // Labels must fit in the window they're in, end to end
+            let cpu = |base: usize, top: usize| {
+                (base.checked_add(end)? <= top).then(|| Location::Cpu((base + start) as u16))
+            };
+            let location = match kind {
+                // Mesen 1 single-letter types, then Mesen 2 names
+                "P" | "NesPrgRom" => Some(Location::PrgRom(start)),
+                "R" | "NesInternalRam" => Some(Location::Cpu((start & 0x07FF) as u16)),
+                "W" | "S" | "NesWorkRam" | "NesSaveRam" => cpu(0x6000, 0x7FFF),
+                "G" | "NesMemory" => cpu(0, 0xFFFF),
+                // CHR, palette and the like aren't CPU addresses
+                _ => None,
+            };
+            let Some(location) = location else {
+                continue;
+            };
+            self.add(Label {
+                name: name.to_string(),
+                location,
+                size: end - start + 1,
+                comment,
+            });
+        }
+        Ok(())
+    }
+}
+
+// key=value pairs separated by commas, where values may be quoted
+fn dbg_fields(text: &str) -> Option<HashMap<&str, String>> {
+    let mut fields = HashMap::new();
+    let mut rest = text.trim();
+    while !rest.is_empty() {
+        let (key, after) = rest.split_once('=')?;
+        let (value, after) = if let Some(quoted) = after.strip_prefix('"') {
+            let end = quoted.find('"')?;
+            (quoted[..end].to_string(), &quoted[end + 1..])
+        } else {
+            let end = after.find(',').unwrap_or(after.len());
+            (after[..end].to_string(), &after[end..])
+        };
+        fields.insert(key.trim(), value);
+        rest = after.strip_prefix(',').unwrap_or(after).trim_start();
+    }
+    Some(fields)
+}
+
+fn parse_dbg_number(text: &str) -> Option<usize> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mesen_labels_outside_their_window_are_skipped() {
+        let mut symbols = Symbols::new();
+        symbols
+            .parse_mlb(
+                "W:0000:Start\nS:1FFE-1FFF:Last:two bytes\nS:1FFF-2000:Across\n\
+                 W:A000:Wrapped\nG:FFFF:Top\nG:FFFF-10000:Over\n",
+            )
+            .unwrap();
+
+        let names: Vec<_> = symbols.labels.iter().map(|l| l.name.as_str()).collect();
+        assert_eq!(names, ["Start", "Last", "Top"]);
+        assert_eq!(symbols.labels[1].location, Location::Cpu(0x7FFE));
+        assert_eq!(symbols.labels[1].comment.as_deref(), Some("two bytes"));
+        assert_eq!(symbols.labels[2].location, Location::Cpu(0xFFFF));
+    }
+}
@@ -0,0 +1,251 @@
+use crate::cartridge::{CartridgeError, Header, InputDevices, Mirroring, Region};
+
+// UNIF images: a 32-byte header ("UNIF", a revision number, padding) and
+// then chunks of a four character ID, a little-endian u32 length and data.
+// Rather than a mapper number, MAPR names the board, which is translated
+// into the mapper that emulates it.
+
+const HEADER_SIZE: usize = 32;
+
+pub struct Unif {
+    pub board: String,
+    pub header: Header,
+    pub prg_rom: Vec<u8>,
+    pub chr_rom: Vec<u8>,
+}
+
+pub fn parse(bytes: &[u8]) -> Result<Unif, CartridgeError> {
+    if bytes.len() < HEADER_SIZE || !bytes.starts_with(b"UNIF") {
+        return Err(CartridgeError::InvalidHeader);
+    }
+
+    let mut board = None;
+    // PRG0-PRGF and CHR0-CHRF are concatenated in order, whatever order the
+    // chunks appear in the file
+    let mut prg: [Option<&[u8]>; 16] = [None; 16];
+    let mut chr: [Option<&[u8]>; 16] = [None; 16];
+    let mut mirroring = None;
+    let mut battery = false;
+    let mut input_devices = InputDevices::empty();
+
+    let mut pos = HEADER_SIZE;
+    while pos < bytes.len() {
+        let Some(chunk) = bytes.get(pos..pos + 8) else {
+            return Err(CartridgeError::Truncated);
+        };
+        let id = &chunk[0..4];
+        let len = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]) as usize;
+        let data = bytes
+            .get(pos + 8..pos + 8 + len)
+            .ok_or(CartridgeError::Truncated)?;
+        pos += 8 + len;
+
+        match id {
+            b"MAPR" => {
+                let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+                board = Some(String::from_utf8_lossy(&data[..end]).into_owned());
+            }
+            [b'P', b'R', b'G', n] | [b'C', b'H', b'R', n] => {
+                let Some(index) = (*n as char).to_digit(16) else {
+                    continue;
+                };
+                let banks = if id.starts_with(b"PRG") {
+                    &mut prg
+                } else {
+                    &mut chr
+                };
+                banks[index as usize] = Some(data);
+            }
+            b"MIRR" => {
+                mirroring = data.first().map(|&m| match m {
+                    0 => Mirroring::Horizontal,
+                    1 => Mirroring::Vertical,
+                    2 => Mirroring::OneScreenLower,
+                    3 => Mirroring::OneScreenUpper,
+                    4 => Mirroring::FourScreen,
+                    // Mapper controlled; the mapper ignores this anyway
+                    _ => Mirroring::Horizontal,
+                });
+            }
+            b"BATR" => battery = true,
+            b"CTRL" => {
+                input_devices =
+                    InputDevices::from_bits_truncate(data.first().copied().unwrap_or(0));
+            }
+            // Name, dumper info, CRCs and the like don't affect emulation
+            _ => {}
+        }
+    }
+
+    let board = board.ok_or(CartridgeError::InvalidHeader)?;
+    let (mapper, submapper) =
+        board_mapper(&board).ok_or_else(|| CartridgeError::UnsupportedBoard(board.clone()))?;
+
+    let prg_rom: Vec<u8> = prg
+        .iter()
+        .flatten()
+        .flat_map(|data| data.iter().copied())
+        .collect();
+    let chr_rom: Vec<u8> = chr
+        .iter()
+        .flatten()
+        .flat_map(|data| data.iter().copied())
+        .collect();
+
+    // UNIF has no RAM size fields, so assume what iNES 1.0 does, except
+    // where the board name says which EEPROM it saves to
+    let eeprom = if board.ends_with("+24C01") {
+        Some(128)
+    } else if board.ends_with("+24C02") {
+        Some(256)
+    } else {
+        None
+    };
+    let battery = battery || eeprom.is_some();
+    let header = Header {
+        mapper,
+        submapper,
+        prg_rom_size: prg_rom.len(),
+        chr_rom_size: chr_rom.len(),
+        prg_ram_size: if battery { 0 } else { 0x2000 },
+        prg_nvram_size: eeprom.unwrap_or(if battery { 0x2000 } else { 0 }),
+        chr_ram_size: if chr_rom.is_empty() { 0x2000 } else { 0 },
+        mirroring: mirroring.unwrap_or(Mirroring::Horizontal),
+        battery,
+        trainer: false,
+        nes2: false,
+        region: Region::Ntsc,
+        input_devices,
+    };
+
+    Ok(Unif {
+        board,
+        header,
+        prg_rom,
+        chr_rom,
+    })
+}
+
+// Board names carry a prefix for who made the board ("NES-", "HVC-", "UNL-"
+// and so on) which doesn't change how it behaves
+fn board_mapper(board: &str) -> Option<(u16, u8)> {
+    let name = ["NES-", "HVC-", "UNL-", "BTL-", "BMC-", "IREM-", "KONAMI-"]
+        .iter()
+        .find_map(|prefix| board.strip_prefix(prefix))
+        .unwrap_or(board);
+
+    let mapper = match name {
+        "NROM" | "NROM-128" | "NROM-256" | "RROM" | "RROM-128" => (0, 0),
+        "PNROM" | "PEEOROM" => (9, 0),
+        "FJROM" | "FKROM" => (10, 0),
+        "BANDAI-FCG-1" | "BANDAI-FCG-2" => (16, 4),
+        "BANDAI-LZ93D50+24C01" => (159, 0),
+        "BANDAI-LZ93D50+24C02" => (16, 5),
+        "NAMCOT-163" | "NAMCOT-129" => (19, 0),
+        "JLROM" | "JSROM" | "BTR" | "SUNSOFT-FME-7" | "SUNSOFT-5B" => (69, 0),
+        _ => return None,
+    };
+    Some(mapper)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::Cartridge;
+
+    fn unif(chunks: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+        let mut bytes = b"UNIF".to_vec();
+        bytes.extend(7u32.to_le_bytes());
+        bytes.resize(HEADER_SIZE, 0);
+        for (id, data) in chunks {
+            bytes.extend_from_slice(*id);
+            bytes.extend((data.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(data);
+        }
+        bytes
+    }
+
+    #[test]
+    fn joins_rom_chunks_in_order() {
+        let bytes = unif(&[
+            (b"CHR1", &[0xC1; 0x1000]),
+            (b"PRG1", &[0x01; 0x4000]),
+            (b"MAPR", b"NES-NROM-256\0"),
+            (b"PRG0", &[0x00; 0x4000]),
+            (b"CHR0", &[0xC0; 0x1000]),
+            (b"MIRR", &[1]),
+            (b"BATR", &[1]),
+            (b"CTRL", &[0x03]),
+            (b"NAME", b"Game\0"),
+        ]);
+        let image = parse(&bytes).unwrap();
+        assert_eq!(image.board, "NES-NROM-256");
+        assert_eq!((image.header.mapper, image.header.submapper), (0, 0));
+        assert_eq!(image.prg_rom[..0x4000], [0x00; 0x4000]);
+        assert_eq!(image.prg_rom[0x4000..], [0x01; 0x4000]);
+        assert_eq!(image.chr_rom[..0x1000], [0xC0; 0x1000]);
+        assert_eq!(image.chr_rom[0x1000..], [0xC1; 0x1000]);
+        assert_eq!(image.header.prg_rom_size, 0x8000);
+        assert_eq!(image.header.mirroring, Mirroring::Vertical);
+        assert!(image.header.battery);
+        assert_eq!(image.header.prg_nvram_size, 0x2000);
+        assert_eq!(
+            image.header.input_devices,
+            InputDevices::STANDARD | InputDevices::ZAPPER
+        );
+
+        // No MIRR or BATR
+        let image = parse(&unif(&[(b"MAPR", b"NROM"), (b"PRG0", &[0; 0x4000])])).unwrap();
+        assert_eq!(image.header.mirroring, Mirroring::Horizontal);
+        assert!(!image.header.battery);
+        assert_eq!(image.header.prg_ram_size, 0x2000);
+        assert_eq!(image.header.chr_ram_size, 0x2000);
+        for (mirr, mirroring) in [(0, Mirroring::Horizontal), (4, Mirroring::FourScreen)] {
+            let bytes = unif(&[(b"MAPR", b"NROM"), (b"MIRR", &[mirr])]);
+            assert_eq!(parse(&bytes).unwrap().header.mirroring, mirroring);
+        }
+    }
+
+    #[test]
+    fn board_names_pick_the_mapper() {
+        let mapper = |name: &str| {
+            let image = parse(&unif(&[(b"MAPR", name.as_bytes())])).unwrap();
+            (image.header.mapper, image.header.submapper)
+        };
+        assert_eq!(mapper("HVC-PNROM"), (9, 0));
+        assert_eq!(mapper("UNL-SUNSOFT-FME-7"), (69, 0));
+        assert_eq!(mapper("BANDAI-FCG-1"), (16, 4));
+        assert_eq!(mapper("NAMCOT-163"), (19, 0));
+
+        let err = parse(&unif(&[(b"MAPR", b"NES-TLROM")])).err().unwrap();
+        assert!(matches!(err, CartridgeError::UnsupportedBoard(name) if name == "NES-TLROM"));
+        assert!(matches!(
+            parse(&unif(&[(b"PRG0", &[0; 16])])),
+            Err(CartridgeError::InvalidHeader)
+        ));
+        let mut truncated = unif(&[(b"MAPR", b"NROM")]);
+        truncated.pop();
+        assert!(matches!(parse(&truncated), Err(CartridgeError::Truncated)));
+    }
+
+    #[test]
+    fn bandai_boards_get_their_eeprom() {
+        let boards = [
+            ("BANDAI-LZ93D50+24C01", 159, 0, 128),
+            ("BANDAI-LZ93D50+24C02", 16, 5, 256),
+        ];
+        for (name, mapper, submapper, eeprom) in boards {
+            let bytes = unif(&[
+                (b"MAPR", name.as_bytes()),
+                (b"PRG0", &[0; 0x8000]),
+                (b"CHR0", &[0; 0x2000]),
+            ]);
+            let header = parse(&bytes).unwrap().header;
+            assert_eq!((header.mapper, header.submapper), (mapper, submapper));
+            assert_eq!(header.prg_nvram_size, eeprom);
+            // The save is the EEPROM alone
+            let cart = Cartridge::from_bytes(&bytes).unwrap();
+            assert_eq!(cart.export_save().map(|save| save.len()), Some(eeprom));
+        }
+    }
+}